@@ -1,3 +1,4 @@
+use crate::traces::BranchPoint;
 use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use syn::spanned::Spanned;
@@ -54,6 +55,22 @@ impl BranchAnalysis {
             _ => {}
         }
     }
+
+    /// Gets the branch points found with the line ranges of each branch
+    pub fn branch_points(&self) -> Vec<BranchPoint> {
+        self.branches
+            .iter()
+            .map(|(k, v)| BranchPoint {
+                line: k.start as u64,
+                arms: v
+                    .ranges
+                    .iter()
+                    .map(|x| (x.start as u64, x.end as u64))
+                    .collect(),
+                implicit_default: v.implicit_default,
+            })
+            .collect()
+    }
 }
 
 /// Represents possible branches through an execution
@@ -1,14 +1,17 @@
 use crate::config::*;
 use crate::errors::RunError;
 use crate::path_utils::{fix_unc_path, get_source_walker};
-use cargo_metadata::{diagnostic::DiagnosticLevel, CargoOpt, Message, Metadata, MetadataCommand};
+use crate::test_loader::is_instrumented;
+use cargo_metadata::{
+    diagnostic::DiagnosticLevel, CargoOpt, Message, Metadata, MetadataCommand, Package,
+};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsStr;
-use std::fs::{read_dir, read_to_string, remove_dir_all, remove_file, File};
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, write, File};
 use std::io;
 use std::io::{BufRead, BufReader};
 use std::path::{Component, Path, PathBuf};
@@ -18,6 +21,15 @@ use tracing::{debug, error, info, trace, warn};
 use walkdir::{DirEntry, WalkDir};
 
 const BUILD_PROFRAW: &str = "build_rs_cov.profraw";
+const VERSION_FILE: &str = "tarpaulin-version";
+const BUILT_BINARIES_FILE: &str = "test-binaries.json";
+/// Paths of the test binaries from a `no_run` build, one per line for scripts to read
+const BUILT_BINARIES_LIST: &str = "test-binaries.txt";
+const WORKSPACE_WRAPPER: &str = "RUSTC_WORKSPACE_WRAPPER";
+/// Environment variables used to pass the settings to tarpaulin when it's the workspace wrapper
+const USER_WORKSPACE_WRAPPER: &str = "TARPAULIN_USER_WORKSPACE_WRAPPER";
+const INSTRUMENT_PACKAGES: &str = "TARPAULIN_INSTRUMENT_PACKAGES";
+const INSTRUMENT_FLAGS: &str = "TARPAULIN_INSTRUMENT_FLAGS";
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 enum Channel {
@@ -39,13 +51,37 @@ impl CargoVersionInfo {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct CargoOutput {
     /// This contains all binaries we want to run to collect coverage from.
     pub test_binaries: Vec<TestBinary>,
     /// This covers binaries we don't want to run explicitly but may be called as part of tracing
     /// execution of other processes.
     pub binaries: Vec<PathBuf>,
+    /// The build script executables compiled by the build, only used with `cover_build_scripts`
+    #[serde(default)]
+    pub build_scripts: Vec<PathBuf>,
+}
+
+impl CargoOutput {
+    /// Adds the binaries from another build, such as a build for a different target
+    pub fn extend(&mut self, other: CargoOutput) {
+        for binary in other.test_binaries {
+            if !self.test_binaries.contains(&binary) {
+                self.test_binaries.push(binary);
+            }
+        }
+        for binary in other.binaries {
+            if !self.binaries.contains(&binary) {
+                self.binaries.push(binary);
+            }
+        }
+        for binary in other.build_scripts {
+            if !self.build_scripts.contains(&binary) {
+                self.build_scripts.push(binary);
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
@@ -57,6 +93,16 @@ pub struct TestBinary {
     pkg_version: Option<String>,
     pkg_authors: Option<Vec<String>>,
     should_panic: bool,
+    /// Name of a single test to run within the binary. This is set when each test is run in its
+    /// own process as is done by cargo-nextest
+    test_name: Option<String>,
+    /// Command and arguments to run the binary with, from the runner of the target it was
+    /// built for
+    #[serde(default)]
+    runner: Vec<String>,
+    /// Name of the example if this is one of the `run-examples`
+    #[serde(default)]
+    example: Option<String>,
     /// Linker paths used when linking the binary, this should be accessed via
     /// `Self::has_linker_paths` and `Self::ld_library_path` as there may be interaction with
     /// current environment. It's only made pub(crate) for the purpose of testing.
@@ -69,6 +115,37 @@ struct DocTestBinaryMeta {
     line: usize,
 }
 
+/// Output of `cargo nextest list --message-format json`, only the parts tarpaulin needs are
+/// deserialized.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct NextestList {
+    rust_suites: BTreeMap<String, NextestSuite>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct NextestSuite {
+    binary_path: PathBuf,
+    package_id: String,
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    testcases: BTreeMap<String, NextestTestCase>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct NextestTestCase {
+    #[serde(default)]
+    ignored: bool,
+    filter_match: NextestFilterMatch,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct NextestFilterMatch {
+    status: String,
+}
+
 impl TestBinary {
     pub fn new(path: PathBuf, ty: Option<RunType>) -> Self {
         Self {
@@ -79,6 +156,9 @@ impl TestBinary {
             pkg_authors: None,
             cargo_dir: None,
             should_panic: false,
+            test_name: None,
+            runner: vec![],
+            example: None,
             linker_paths: vec![],
         }
     }
@@ -107,12 +187,28 @@ impl TestBinary {
         &self.pkg_authors
     }
 
+    /// The single test this binary should run, if it's limited to one
+    pub fn test_name(&self) -> &Option<String> {
+        &self.test_name
+    }
+
+    /// The command to run the binary with, empty if it's run directly
+    pub fn runner(&self) -> &[String] {
+        &self.runner
+    }
+
+    /// The name of the example if this binary is an example's `main` rather than a test, it's
+    /// run with the example's `example-args` instead of the test arguments
+    pub fn example(&self) -> &Option<String> {
+        &self.example
+    }
+
     pub fn has_linker_paths(&self) -> bool {
         !self.linker_paths.is_empty()
     }
 
     pub fn is_test_type(&self) -> bool {
-        matches!(self.ty, None | Some(RunType::Tests))
+        self.example.is_none() && matches!(self.ty, None | Some(RunType::Tests))
     }
 
     /// Convert linker paths to an LD_LIBRARY_PATH.
@@ -137,6 +233,33 @@ impl TestBinary {
         self.should_panic
     }
 
+    /// Name of the binary which stays the same between builds, used to pick its shard. The hash
+    /// cargo adds to the file name changes with the build flags so it's left out
+    fn shard_key(&self) -> String {
+        let name = if self.ty == Some(RunType::Doctests) {
+            // Every doctest binary has the same name, the folder it's in names the doctest
+            self.path.parent().and_then(Path::file_name)
+        } else {
+            self.path.file_stem()
+        }
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+        let name = match name.rsplit_once('-') {
+            Some((name, hash))
+                if hash.len() == 16 && hash.chars().all(|x| x.is_ascii_hexdigit()) =>
+            {
+                name.to_string()
+            }
+            _ => name,
+        };
+        let mut key = format!("{}/{}", self.pkg_name.as_deref().unwrap_or_default(), name);
+        if let Some(test) = self.test_name.as_ref() {
+            key.push_str("::");
+            key.push_str(test);
+        }
+        key
+    }
+
     /// Convenience function to get the file name of the binary as a string, default string if the
     /// path has no filename as this should _never_ happen
     pub fn file_name(&self) -> String {
@@ -204,45 +327,331 @@ lazy_static! {
 
 pub fn get_tests(config: &Config) -> Result<CargoOutput, RunError> {
     let mut result = CargoOutput::default();
-    if config.force_clean() {
-        let cleanup_dir = if config.release {
+    if config.command == Mode::Nextest && !nextest_installed() {
+        return Err(RunError::Cargo(
+            "cargo-nextest doesn't appear to be installed. Install it with `cargo install cargo-nextest` or use a different command".to_string(),
+        ));
+    }
+    let man_binding = config.manifest();
+    let manifest = man_binding.as_path().to_str().unwrap_or("Cargo.toml");
+    // Ran before cleaning so a stale lock file fails before any of the build is thrown away
+    let metadata = lockfile_checked_metadata(manifest, config)?;
+
+    let version_changed = tarpaulin_version_changed(config);
+    if config.force_clean() || version_changed {
+        // cargo bench uses the bench profile which inherits from release
+        let cleanup_dir = if config.release || config.command == Mode::Bench {
             config.target_dir().join("release")
         } else {
             config.target_dir().join("debug")
         };
-        info!("Cleaning project");
+        if version_changed && !config.force_clean() {
+            info!("Tarpaulin has been updated since the last build, cleaning project");
+        } else {
+            info!("Cleaning project");
+        }
         if cleanup_dir.exists() {
             if let Err(e) = remove_dir_all(cleanup_dir) {
                 error!("Cargo clean failed: {e}");
             }
         }
     }
-    let man_binding = config.manifest();
-    let manifest = man_binding.as_path().to_str().unwrap_or("Cargo.toml");
-    let metadata = MetadataCommand::new()
+
+    if config.cover_build_scripts {
+        let profraw_dir = config.build_script_profraw_dir();
+        let _ = remove_dir_all(&profraw_dir);
+        create_dir_all(&profraw_dir)?;
+    }
+    for ty in cargo_run_types(config) {
+        run_cargo(&metadata, manifest, config, ty, &mut result)?;
+    }
+    if let Some(example_config) = run_examples_config(config) {
+        let examples_from = result.test_binaries.len();
+        run_cargo(&metadata, manifest, &example_config, None, &mut result)?;
+        for binary in result.test_binaries.iter_mut().skip(examples_from) {
+            binary.example = binary
+                .path
+                .file_stem()
+                .map(|x| x.to_string_lossy().to_string());
+        }
+    }
+    if let Some(runner) = config.runner() {
+        for binary in &mut result.test_binaries {
+            binary.runner = runner.clone();
+        }
+    }
+    // Only matters for llvm cov and who knows, one day may not be needed
+    let _ = remove_file(config.root().join(BUILD_PROFRAW));
+    write_tarpaulin_version(config);
+    Ok(result)
+}
+
+/// Gets the metadata of the project with the `--locked`, `--frozen` and `--offline` flags, if
+/// cargo refuses to update the lock file this is a `StaleLockfile` error rather than the error
+/// cargo would give part way through the build
+fn lockfile_checked_metadata(manifest: &str, config: &Config) -> Result<Metadata, RunError> {
+    MetadataCommand::new()
         .manifest_path(manifest)
         .features(CargoOpt::AllFeatures)
+        .other_options(config.cargo_lock_flags())
         .exec()
-        .map_err(|e| RunError::Cargo(e.to_string()))?;
+        .map_err(|e| match e {
+            cargo_metadata::Error::CargoMetadata { stderr }
+                if config.locked() && is_stale_lockfile(&stderr) =>
+            {
+                RunError::StaleLockfile(stale_lockfile_path(&stderr, Path::new(manifest)))
+            }
+            e => RunError::Cargo(e.to_string()),
+        })
+}
 
-    for ty in &config.run_types {
-        run_cargo(&metadata, manifest, config, Some(*ty), &mut result)?;
-    }
+/// Whether cargo failed because it needed to update the lock file. Older versions of cargo say
+/// the lock file "needs to be updated" and newer ones that they "cannot update the lock file"
+fn is_stale_lockfile(stderr: &str) -> bool {
+    stderr.contains("lock file")
+        && (stderr.contains("--locked was passed") || stderr.contains("--frozen was passed"))
+}
+
+/// The lock file cargo refused to update, from the path in its error. If it isn't there this is
+/// the closest `Cargo.lock` to the manifest, which for a workspace member is the workspace's
+fn stale_lockfile_path(stderr: &str, manifest: &Path) -> PathBuf {
+    let printed = stderr
+        .split_whitespace()
+        .map(|x| x.trim_matches(|c| c == '`' || c == '\'' || c == '"'))
+        .find(|x| x.ends_with("Cargo.lock"))
+        .map(PathBuf::from);
+    printed.unwrap_or_else(|| {
+        let dir = manifest.parent().unwrap_or_else(|| Path::new("."));
+        dir.ancestors()
+            .map(|x| x.join("Cargo.lock"))
+            .find(|x| x.exists())
+            .unwrap_or_else(|| dir.join("Cargo.lock"))
+    })
+}
+
+/// The type of each cargo command run to build the tests, `None` builds the targets named in the
+/// config or the default targets of the command
+fn cargo_run_types(config: &Config) -> Vec<Option<RunType>> {
+    let mut types = config
+        .run_types
+        .iter()
+        .copied()
+        .map(Some)
+        .collect::<Vec<_>>();
     if config.has_named_tests() {
-        run_cargo(&metadata, manifest, config, None, &mut result)?;
+        types.push(None);
     } else if config.run_types.is_empty() {
-        let ty = if config.command == Mode::Test {
-            Some(RunType::Tests)
+        if matches!(config.command, Mode::Test | Mode::Nextest) {
+            types.push(Some(RunType::Tests));
         } else {
-            None
+            types.push(None);
+        }
+    }
+    if config.run_doctests && !types.contains(&Some(RunType::Doctests)) {
+        types.push(Some(RunType::Doctests));
+    }
+    types
+}
+
+/// The config the `run-examples` are built with. They're built by `cargo build` so running them
+/// runs their `main` instead of a test harness, and only the examples are built
+fn run_examples_config(config: &Config) -> Option<Config> {
+    if config.run_examples.is_empty() {
+        return None;
+    }
+    let mut example_config = config.clone();
+    example_config.command = Mode::Build;
+    example_config.run_types.clear();
+    example_config.run_doctests = false;
+    // Not a flag cargo build has
+    example_config.no_fail_fast = false;
+    example_config.test_names.clear();
+    example_config.bin_names.clear();
+    example_config.bench_names.clear();
+    example_config.example_names = config.run_examples.clone();
+    Some(example_config)
+}
+
+/// The cargo commands tarpaulin would run to build the tests, formatted to be pasted into a
+/// shell with the environment variables tarpaulin sets before them
+pub fn cargo_command_lines(config: &Config) -> Vec<String> {
+    let man_binding = config.manifest();
+    let manifest = man_binding.as_path().to_str().unwrap_or("Cargo.toml");
+    config
+        .per_target()
+        .iter()
+        .flat_map(|config| {
+            let examples = run_examples_config(config).map(|x| create_command(manifest, &x, None));
+            cargo_run_types(config)
+                .into_iter()
+                .map(move |ty| create_command(manifest, config, ty))
+                .chain(examples)
+        })
+        .map(|cmd| {
+            let envs = cmd.get_envs().filter_map(|(k, v)| {
+                v.map(|v| format!("{}={}", k.to_string_lossy(), shell_quote(v)))
+            });
+            let args = std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(shell_quote);
+            envs.chain(args).collect::<Vec<_>>().join(" ")
+        })
+        .collect()
+}
+
+/// Quotes an argument for a POSIX shell if it contains anything other than characters which are
+/// safe unquoted
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=+:,@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// The test binaries from a `no_run` build along with the `RUSTFLAGS` they were built with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BuiltBinaries {
+    rustflags: String,
+    output: CargoOutput,
+}
+
+fn built_binaries_file(config: &Config) -> PathBuf {
+    config
+        .target_dir()
+        .join("tarpaulin")
+        .join(BUILT_BINARIES_FILE)
+}
+
+/// Saves the test binaries from a `no_run` build so a later run with `input_binaries` can run
+/// them with the information from the build, along with a list of their paths
+pub fn save_built_binaries(config: &Config, output: &CargoOutput) -> Result<(), RunError> {
+    let file = built_binaries_file(config);
+    if let Some(parent) = file.parent() {
+        create_dir_all(parent)?;
+    }
+    let list = file.with_file_name(BUILT_BINARIES_LIST);
+    let paths = output
+        .test_binaries
+        .iter()
+        .map(|x| format!("{}\n", x.path().display()))
+        .collect::<String>();
+    write(&list, paths)?;
+    info!("Test binary paths written to {}", list.display());
+    let built = BuiltBinaries {
+        rustflags: rust_flags(config),
+        output: output.clone(),
+    };
+    let json = serde_json::to_string_pretty(&built)
+        .map_err(|e| RunError::Cargo(format!("failed to save test binaries: {e}")))?;
+    write(file, json)?;
+    Ok(())
+}
+
+/// Gets the test binaries set in `input_binaries`, using the information saved by the `no_run`
+/// build that made them. This is an error if the binaries were built with different `RUSTFLAGS`
+/// to the ones this config would use as tarpaulin can't collect coverage from them correctly
+pub fn load_input_binaries(config: &Config) -> Result<CargoOutput, RunError> {
+    let built = match read_to_string(built_binaries_file(config)) {
+        Ok(json) => Some(
+            serde_json::from_str::<BuiltBinaries>(&json)
+                .map_err(|e| RunError::TestLaunch(format!("failed to read test binaries: {e}")))?,
+        ),
+        Err(_) => None,
+    };
+    let canonical = |path: &Path| {
+        path.canonicalize()
+            .map(|x| fix_unc_path(&x))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+    if let Some(built) = built.as_ref() {
+        let rustflags = rust_flags(config);
+        if built.rustflags != rustflags {
+            return Err(RunError::TestLaunch(format!(
+                "the test binaries were built with RUSTFLAGS='{}' but this run uses RUSTFLAGS='{}', rebuild them with --no-run",
+                built.rustflags, rustflags
+            )));
+        }
+    }
+    let runner = config.runner();
+    let mut result = CargoOutput::default();
+    for path in &config.input_binaries {
+        if !path.is_file() {
+            return Err(RunError::TestLaunch(format!(
+                "test binary {} doesn't exist",
+                path.display()
+            )));
+        }
+        let engine = config.engine();
+        let instrumented = is_instrumented(path, engine).map_err(|e| {
+            RunError::TestLaunch(format!(
+                "failed to read test binary {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        if !instrumented {
+            let engine = engine.to_string().to_lowercase();
+            return Err(RunError::TestLaunch(format!(
+                "test binary {} isn't instrumented for the {} engine, rebuild it with `--no-run --engine {}`",
+                path.display(),
+                engine,
+                engine
+            )));
+        }
+        let path = canonical(path);
+        let binary = built.as_ref().and_then(|x| {
+            x.output
+                .test_binaries
+                .iter()
+                .find(|binary| canonical(binary.path()) == path)
+        });
+        let mut binary = match binary {
+            Some(binary) => binary.clone(),
+            None => {
+                warn!(
+                    "{} wasn't built by tarpaulin with --no-run, running it without package information",
+                    path.display()
+                );
+                TestBinary::new(path, None)
+            }
         };
-        run_cargo(&metadata, manifest, config, ty, &mut result)?;
+        if let Some(runner) = runner.as_ref() {
+            binary.runner = runner.clone();
+        }
+        result.test_binaries.push(binary);
+    }
+    if let Some(built) = built {
+        result.binaries = built.output.binaries;
     }
-    // Only matters for llvm cov and who knows, one day may not be needed
-    let _ = remove_file(config.root().join(BUILD_PROFRAW));
     Ok(result)
 }
 
+fn tarpaulin_version_file(config: &Config) -> PathBuf {
+    config.target_dir().join("tarpaulin").join(VERSION_FILE)
+}
+
+/// Returns true if the last build was made by a different version of tarpaulin. Builds without a
+/// version file are assumed to be from this version
+fn tarpaulin_version_changed(config: &Config) -> bool {
+    match read_to_string(tarpaulin_version_file(config)) {
+        Ok(version) => version.trim() != env!("CARGO_PKG_VERSION"),
+        Err(_) => false,
+    }
+}
+
+fn write_tarpaulin_version(config: &Config) {
+    let file = tarpaulin_version_file(config);
+    if let Some(parent) = file.parent() {
+        let _ = create_dir_all(parent);
+    }
+    if let Err(e) = write(&file, env!("CARGO_PKG_VERSION")) {
+        warn!("Failed to write {}: {}", file.display(), e);
+    }
+}
+
 fn run_cargo(
     metadata: &Metadata,
     manifest: &str,
@@ -262,15 +671,52 @@ fn run_cargo(
     let update_from = result.test_binaries.len();
     let mut paths = vec![];
 
-    if ty != Some(RunType::Doctests) {
+    if is_nextest(config, ty) {
+        let reader = std::io::BufReader::new(child.stdout.take().unwrap());
+        let list = serde_json::from_reader::<_, NextestList>(reader);
+        let status = child.wait().map_err(|e| RunError::Cargo(e.to_string()))?;
+        if !status.success() {
+            return Err(RunError::Cargo("cargo nextest list failed".to_string()));
+        }
+        let list = list
+            .map_err(|e| RunError::Cargo(format!("Failed to parse cargo nextest output: {e}")))?;
+        for (mut binary, package_id) in nextest_binaries(list, config, ty) {
+            let package_id = cargo_metadata::PackageId { repr: package_id };
+            if let Some(package) = metadata.packages.iter().find(|x| x.id == package_id) {
+                if binary.cargo_dir.is_none() {
+                    binary.cargo_dir = package
+                        .manifest_path
+                        .parent()
+                        .map(|x| fix_unc_path(x.as_std_path()));
+                }
+                binary.pkg_name = Some(package.name.clone());
+                binary.pkg_version = Some(package.version.to_string());
+                binary.pkg_authors = Some(package.authors.clone());
+            }
+            result.test_binaries.push(binary);
+        }
+    } else if ty != Some(RunType::Doctests) {
         let mut package_ids = vec![None; result.test_binaries.len()];
         let reader = std::io::BufReader::new(child.stdout.take().unwrap());
         let mut error = None;
+        // Whether every artifact was already built and up to date
+        let mut reused = None;
         for msg in Message::parse_stream(reader) {
             match msg {
                 Ok(Message::CompilerArtifact(art)) => {
+                    reused = Some(reused.unwrap_or(true) && art.fresh);
+                    if art.target.kind.iter().any(|x| x == "custom-build") {
+                        result.build_scripts.extend(
+                            art.filenames
+                                .iter()
+                                .map(|x| x.as_std_path())
+                                .filter(|x| is_build_script(x))
+                                .map(fix_unc_path),
+                        );
+                        continue;
+                    }
                     if let Some(path) = art.executable.as_ref() {
-                        if !art.profile.test && config.command == Mode::Test {
+                        if !art.profile.test && matches!(config.command, Mode::Test | Mode::Bench) {
                             result.binaries.push(PathBuf::from(path));
                             continue;
                         }
@@ -328,6 +774,9 @@ fn run_cargo(
         if !status.success() {
             return Err(RunError::Cargo("cargo run failed".to_string()));
         };
+        if reused == Some(true) {
+            info!("Nothing has changed since the last build so it's being reused, use --force-clean to rebuild");
+        }
         for (res, package) in result
             .test_binaries
             .iter_mut()
@@ -405,6 +854,81 @@ fn run_cargo(
     Ok(())
 }
 
+/// Turns the tests listed by cargo-nextest into test binaries limited to a single test each,
+/// mirroring how nextest runs every test in its own process. Returned alongside each binary is
+/// the package ID for the package it belongs to.
+fn nextest_binaries(
+    list: NextestList,
+    config: &Config,
+    ty: Option<RunType>,
+) -> Vec<(TestBinary, String)> {
+    let mut result = vec![];
+    for suite in list.rust_suites.into_values() {
+        for (name, case) in suite.testcases {
+            if case.filter_match.status != "matches" || (case.ignored && !config.run_ignored) {
+                continue;
+            }
+            let mut binary = TestBinary::new(fix_unc_path(&suite.binary_path), ty);
+            binary.cargo_dir = suite.cwd.as_deref().map(fix_unc_path);
+            binary.test_name = Some(name);
+            result.push((binary, suite.package_id.clone()));
+        }
+    }
+    result
+}
+
+/// Keeps the test binaries in the shard picked by `shard_index` out of `shard_count`. The
+/// binaries are dealt out to the shards in the order of their package and target name, and the
+/// test with nextest, so the shards are the same size give or take one and a binary is in the
+/// same shard in every run with the same set of binaries
+pub fn select_shard(config: &Config, binaries: &mut Vec<TestBinary>) {
+    let (shard_index, shard_count) = config.shard();
+    if shard_count > 1 {
+        let total = binaries.len();
+        let mut order = (0..total).collect::<Vec<_>>();
+        order.sort_by_cached_key(|x| (binaries[*x].shard_key(), binaries[*x].path().to_path_buf()));
+        let shard = order
+            .into_iter()
+            .enumerate()
+            .filter(|(position, _)| position % shard_count == shard_index)
+            .map(|(_, x)| x)
+            .collect::<HashSet<_>>();
+        let mut index = 0;
+        binaries.retain(|_| {
+            index += 1;
+            shard.contains(&(index - 1))
+        });
+        if binaries.is_empty() {
+            warn!(
+                "Shard {} of {} has no test executables to run, there are only {}",
+                shard_index, shard_count, total
+            );
+        }
+        info!(
+            "Running shard {} of {}, {} of the {} test executables",
+            shard_index,
+            shard_count,
+            binaries.len(),
+            total
+        );
+    }
+}
+
+fn nextest_installed() -> bool {
+    Command::new("cargo")
+        .args(["nextest", "--version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|x| x.success())
+        .unwrap_or(false)
+}
+
+/// Doctests aren't supported by nextest so are still built via `cargo test`
+fn is_nextest(config: &Config, ty: Option<RunType>) -> bool {
+    config.command == Mode::Nextest && ty != Some(RunType::Doctests)
+}
+
 fn convert_to_prefix(p: &Path) -> Option<String> {
     // Need to go from directory after last one with Cargo.toml
     let convert_name = |p: &Path| {
@@ -525,10 +1049,19 @@ fn create_command(manifest_path: &str, config: &Config, ty: Option<RunType>) ->
         if let Ok(toolchain) = env::var("RUSTUP_TOOLCHAIN") {
             test_cmd.arg(format!("+{toolchain}"));
         }
-        if config.command == Mode::Test {
-            test_cmd.args(["test", "--no-run"]);
-        } else {
-            test_cmd.arg("build");
+        match config.command {
+            Mode::Test => {
+                test_cmd.args(["test", "--no-run"]);
+            }
+            Mode::Build => {
+                test_cmd.arg("build");
+            }
+            Mode::Nextest => {
+                test_cmd.args(["nextest", "list"]);
+            }
+            Mode::Bench => {
+                test_cmd.args(["bench", "--no-run"]);
+            }
         }
     }
     test_cmd.args(["--message-format", "json", "--manifest-path", manifest_path]);
@@ -560,44 +1093,48 @@ fn create_command(manifest_path: &str, config: &Config, ty: Option<RunType>) ->
             test_cmd.arg(test);
         }
     }
-    init_args(&mut test_cmd, config);
+    init_args(&mut test_cmd, config, ty);
     setup_environment(&mut test_cmd, config);
     test_cmd
 }
 
-fn init_args(test_cmd: &mut Command, config: &Config) {
-    if config.debug {
-        test_cmd.arg("-vvv");
-    } else if config.verbose {
-        test_cmd.arg("-v");
-    }
-    if config.locked {
-        test_cmd.arg("--locked");
-    }
-    if config.frozen {
-        test_cmd.arg("--frozen");
-    }
-    if config.no_fail_fast {
-        test_cmd.arg("--no-fail-fast");
-    }
-    if let Some(profile) = config.profile.as_ref() {
-        test_cmd.arg("--profile");
-        test_cmd.arg(profile);
-    }
-    if let Some(jobs) = config.jobs {
-        test_cmd.arg("--jobs");
-        test_cmd.arg(jobs.to_string());
-    }
-    if let Some(features) = config.features.as_ref() {
-        test_cmd.arg("--features");
-        test_cmd.arg(features);
-    }
-    if config.all_features {
-        test_cmd.arg("--all-features");
-    }
-    if config.no_default_features {
-        test_cmd.arg("--no-default-features");
+fn init_args(test_cmd: &mut Command, config: &Config, ty: Option<RunType>) {
+    let nextest = is_nextest(config, ty);
+    if nextest {
+        // nextest uses different flags for the cargo build verbosity, profile and jobs
+        if let Some(profile) = config.profile.as_ref() {
+            test_cmd.arg("--cargo-profile");
+            test_cmd.arg(profile);
+        }
+        if let Some(jobs) = config.jobs() {
+            test_cmd.arg("--build-jobs");
+            test_cmd.arg(jobs.to_string());
+        }
+        if config.quiet {
+            test_cmd.arg("--cargo-quiet");
+        }
+    } else {
+        if config.debug {
+            test_cmd.arg("-vvv");
+        } else if config.verbose {
+            test_cmd.arg("-v");
+        } else if config.quiet {
+            test_cmd.arg("--quiet");
+        }
+        if config.no_fail_fast {
+            test_cmd.arg("--no-fail-fast");
+        }
+        if let Some(profile) = config.profile.as_ref() {
+            test_cmd.arg("--profile");
+            test_cmd.arg(profile);
+        }
+        if let Some(jobs) = config.jobs() {
+            test_cmd.arg("--jobs");
+            test_cmd.arg(jobs.to_string());
+        }
     }
+    test_cmd.args(config.cargo_lock_flags());
+    test_cmd.args(feature_args(config));
     if config.all {
         test_cmd.arg("--workspace");
     }
@@ -614,7 +1151,7 @@ fn init_args(test_cmd: &mut Command, config: &Config) {
     });
     test_cmd.arg("--color");
     test_cmd.arg(config.color.to_string().to_ascii_lowercase());
-    if let Some(target) = config.target.as_ref() {
+    for target in &config.target {
         test_cmd.args(["--target", target]);
     }
     let args = vec![
@@ -622,15 +1159,24 @@ fn init_args(test_cmd: &mut Command, config: &Config) {
         format!("{}", config.target_dir().display()),
     ];
     test_cmd.args(args);
-    if config.offline {
-        test_cmd.arg("--offline");
-    }
     for feat in &config.unstable_features {
         test_cmd.arg(format!("-Z{feat}"));
     }
-    if config.command == Mode::Test && !config.varargs.is_empty() {
-        let mut args = vec!["--".to_string()];
+    if let Some(filter) = config.test_filter.as_ref().filter(|_| nextest) {
+        // Only the matching tests are listed and then run one at a time
+        test_cmd.arg(filter);
+    }
+    let mut args = vec![];
+    if ty == Some(RunType::Doctests) {
+        // Doctests are run by cargo so it's given the filter, other executables get it when
+        // tarpaulin runs them
+        args.extend(config.test_filter.iter().cloned());
+    }
+    if config.command == Mode::Test {
         args.extend_from_slice(&config.varargs);
+    }
+    if !args.is_empty() {
+        test_cmd.arg("--");
         test_cmd.args(args);
     }
 }
@@ -655,8 +1201,8 @@ fn clean_doctest_folder<P: AsRef<Path>>(doctest_dir: P) {
     }
 }
 
-fn handle_llvm_flags(value: &mut String, config: &Config) {
-    if config.engine() == TraceEngine::Llvm {
+fn handle_llvm_flags(value: &mut String, config: &Config, instrument: bool) {
+    if config.engine() == TraceEngine::Llvm && instrument {
         value.push_str(llvm_coverage_rustflag());
     }
     if cfg!(not(windows)) && !config.no_dead_code {
@@ -688,23 +1234,21 @@ fn look_for_field_in_table(value: &Value, field: &str) -> String {
     }
 }
 
+/// Looks for the field in a section of a cargo config file, a section such as `target.<triple>`
+/// is a table nested in the `target` table
 fn look_for_field_in_file(path: &Path, section: &str, field: &str) -> Option<String> {
     if let Ok(contents) = read_to_string(path) {
         let value = contents.parse::<Value>().ok()?;
-
-        let value: Vec<String> = value
-            .as_table()?
-            .into_iter()
-            .map(|(s, v)| {
-                if s.as_str() == section {
-                    look_for_field_in_table(v, field)
-                } else {
-                    String::new()
-                }
-            })
-            .collect();
-
-        Some(value.join(" "))
+        let table = match section.split_once('.') {
+            Some((outer, inner)) => value.get(outer).and_then(|x| x.get(inner)),
+            None => value.get(section),
+        };
+        Some(
+            table
+                .filter(|x| x.is_table())
+                .map(|x| look_for_field_in_table(x, field))
+                .unwrap_or_default(),
+        )
     } else {
         None
     }
@@ -755,6 +1299,46 @@ fn gather_config_field_from_section(config: &Config, section: &str, field: &str)
     String::new()
 }
 
+/// The flags cargo would pass to rustc or rustdoc without tarpaulin, from the first of
+/// `CARGO_ENCODED_<VAR>`, `<VAR>`, the field in the `target.<triple>` section of the cargo
+/// config files for rustflags and then in the `build` section which is set. Tarpaulin sets
+/// `<VAR>` for the build so cargo won't read any of these itself
+fn user_flags(config: &Config, var: &str, field: &str) -> String {
+    if let Some(flags) = env::var(format!("CARGO_ENCODED_{var}"))
+        .ok()
+        .filter(|x| !x.is_empty())
+    {
+        return decode_flags(&flags);
+    }
+    if let Ok(flags) = env::var(var) {
+        return flags;
+    }
+    if field == "rustflags" {
+        let target = match config.target.as_slice() {
+            [target] => Some(target.clone()),
+            _ => host_target(config),
+        };
+        if let Some(target) = target {
+            let flags =
+                gather_config_field_from_section(config, &format!("target.{target}"), field);
+            if !flags.trim().is_empty() {
+                return flags;
+            }
+        }
+    }
+    gather_config_field_from_section(config, "build", field)
+}
+
+/// Converts flags separated by the `0x1f` character, like in `CARGO_ENCODED_RUSTFLAGS`, to space
+/// separated flags
+fn decode_flags(flags: &str) -> String {
+    let flags = flags.split('\x1f').collect::<Vec<_>>();
+    if flags.iter().any(|x| x.contains(char::is_whitespace)) {
+        warn!("Flags containing spaces can't be passed through RUSTFLAGS and will be split");
+    }
+    flags.join(" ")
+}
+
 pub fn rust_flags(config: &Config) -> String {
     const RUSTFLAGS: &str = "RUSTFLAGS";
     let mut value = config.rustflags.clone().unwrap_or_default();
@@ -762,19 +1346,18 @@ pub fn rust_flags(config: &Config) -> String {
     if !config.avoid_cfg_tarpaulin {
         value.push_str("--cfg=tarpaulin ");
     }
+    value.push_str(&custom_cfgs(config));
     if config.release {
         value.push_str("-Cdebug-assertions=off ");
     }
-    handle_llvm_flags(&mut value, config);
+    // With a wrapper the instrumentation flags are only added for the selected packages
+    handle_llvm_flags(&mut value, config, rustc_wrapper(config).is_none());
     lazy_static! {
         static ref DEBUG_INFO: Regex = Regex::new(r#"\-C\s*debuginfo=\d"#).unwrap();
     }
-    if let Ok(vtemp) = env::var(RUSTFLAGS) {
-        value.push_str(&DEBUG_INFO.replace_all(&vtemp, " "));
-    } else {
-        let vtemp = gather_config_field_from_section(config, "build", "rustflags");
-        value.push_str(&DEBUG_INFO.replace_all(&vtemp, " "));
-    }
+    let vtemp = user_flags(config, RUSTFLAGS, "rustflags");
+    value.push(' ');
+    value.push_str(&DEBUG_INFO.replace_all(&vtemp, " "));
     deduplicate_flags(&value)
 }
 
@@ -782,22 +1365,25 @@ pub fn rustdoc_flags(config: &Config) -> String {
     const RUSTDOC: &str = "RUSTDOCFLAGS";
     let common_opts = " -Cdebuginfo=2 --cfg=tarpaulin ";
     let mut value = format!(
-        "{} --persist-doctests {} -Zunstable-options ",
+        "{}{}--persist-doctests {} -Zunstable-options ",
         common_opts,
+        custom_cfgs(config),
         config.doctest_dir().display()
     );
-    if let Ok(vtemp) = env::var(RUSTDOC) {
-        if !vtemp.contains("--persist-doctests") {
-            value.push_str(vtemp.as_ref());
-        }
-    } else {
-        let vtemp = gather_config_field_from_section(config, "build", "rustdocflags");
-        value.push_str(&vtemp);
+    let vtemp = user_flags(config, RUSTDOC, "rustdocflags");
+    if !vtemp.contains("--persist-doctests") {
+        value.push_str(vtemp.as_ref());
     }
-    handle_llvm_flags(&mut value, config);
+    // rustdoc isn't run through the workspace wrapper and only builds workspace members
+    handle_llvm_flags(&mut value, config, true);
     deduplicate_flags(&value)
 }
 
+/// The `--cfg` flags for the custom cfgs in the config, followed by a space
+fn custom_cfgs(config: &Config) -> String {
+    config.cfg.iter().map(|x| format!("--cfg={x} ")).collect()
+}
+
 fn deduplicate_flags(flags: &str) -> String {
     lazy_static! {
         static ref CFG_FLAG: Regex = Regex::new(r#"\--cfg\s+"#).unwrap();
@@ -816,16 +1402,19 @@ fn deduplicate_flags(flags: &str) -> String {
     let res = A_FLAG.replace_all(&res, "-A");
     let res = D_FLAG.replace_all(&res, "-D");
 
+    // Codegen options which can be given more than once with every value used
+    const REPEATABLE: [&str; 3] = ["-Ctarget-feature", "-Clink-arg", "-Cllvm-args"];
+
     let mut flag_set = HashSet::new();
     let mut result = vec![];
     for val in res.split_whitespace() {
-        if val.starts_with("--cfg") {
+        let id = val.split('=').next().unwrap();
+        if val.starts_with("--cfg") || REPEATABLE.contains(&id) {
             if !flag_set.contains(&val) {
                 result.push(val);
                 flag_set.insert(val);
             }
         } else {
-            let id = val.split('=').next().unwrap();
             if !flag_set.contains(id) {
                 flag_set.insert(id);
                 result.push(val);
@@ -835,9 +1424,31 @@ fn deduplicate_flags(flags: &str) -> String {
     result.join(" ")
 }
 
+/// Whether a file from a custom build artifact is the build script executable rather than its
+/// debug info
+fn is_build_script(path: &Path) -> bool {
+    path.file_stem()
+        .map(|x| x.to_string_lossy().starts_with("build-script-"))
+        .unwrap_or(false)
+        && matches!(
+            path.extension().and_then(|x| x.to_str()),
+            None | Some("exe")
+        )
+}
+
 fn setup_environment(cmd: &mut Command, config: &Config) {
-    // https://github.com/rust-lang/rust/issues/107447
-    cmd.env("LLVM_PROFILE_FILE", config.root().join(BUILD_PROFRAW));
+    if config.cover_build_scripts {
+        // Build scripts and proc-macros run in separate processes so each needs its own profile
+        cmd.env(
+            "LLVM_PROFILE_FILE",
+            config
+                .build_script_profraw_dir()
+                .join("build-script_%m-%p.profraw"),
+        );
+    } else {
+        // https://github.com/rust-lang/rust/issues/107447
+        cmd.env("LLVM_PROFILE_FILE", config.root().join(BUILD_PROFRAW));
+    }
     cmd.env("TARPAULIN", "1");
     let rustflags = "RUSTFLAGS";
     let value = rust_flags(config);
@@ -847,6 +1458,21 @@ fn setup_environment(cmd: &mut Command, config: &Config) {
     let value = rustdoc_flags(config);
     trace!("Setting RUSTDOCFLAGS='{}'", value);
     cmd.env(rustdoc, value);
+    // Cargo prefers these to the flags set above, any user flags in them are already included
+    cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    cmd.env_remove("CARGO_ENCODED_RUSTDOCFLAGS");
+    if let Some(wrapper) = rustc_wrapper(config) {
+        if let Ok(user_wrapper) = env::var(WORKSPACE_WRAPPER) {
+            cmd.env(USER_WORKSPACE_WRAPPER, user_wrapper);
+        }
+        cmd.env(WORKSPACE_WRAPPER, wrapper);
+        cmd.env(INSTRUMENT_PACKAGES, instrumented_packages(config));
+        cmd.env(INSTRUMENT_FLAGS, llvm_coverage_rustflag().trim());
+    }
+    if config.command == Mode::Bench {
+        // The bench profile strips debuginfo by default which the coverage needs
+        cmd.env("CARGO_PROFILE_BENCH_STRIP", "none");
+    }
     if let Ok(bootstrap) = env::var("RUSTC_BOOTSTRAP") {
         cmd.env("RUSTC_BOOTSTRAP", bootstrap);
     }
@@ -860,6 +1486,428 @@ pub fn supports_llvm_coverage() -> bool {
     }
 }
 
+/// The `--features`, `--all-features` and `--no-default-features` arguments for the build.
+/// Disabled features and any features which enable them are removed from the features. If the
+/// default features or `all_features` would enable a disabled feature then the features of each
+/// selected package are listed explicitly instead. Default features which only enable an optional
+/// dependency with `dep:` can't be given on the command line so are left out as well
+fn feature_args(config: &Config) -> Vec<String> {
+    let disabled = &config.disable_features;
+    let mut features = config.features.clone();
+    let mut all_features = config.all_features;
+    let mut no_default_features = config.no_default_features;
+    if !disabled.is_empty() {
+        let packages = selected_package_features(config);
+        let excluded = packages
+            .iter()
+            .map(|(_, features)| excluded_features(features, disabled))
+            .collect::<Vec<_>>();
+        features.retain(|feature| {
+            let keep = match feature.split_once('/') {
+                _ if disabled.contains(feature) => false,
+                Some((package, feature)) => !packages
+                    .iter()
+                    .zip(&excluded)
+                    .any(|((name, _), excluded)| name == package && excluded.contains(feature)),
+                None => !excluded.iter().any(|x| x.contains(feature)),
+            };
+            if !keep {
+                info!(
+                    "Not enabling {}, it's disabled or enables a disabled feature",
+                    feature
+                );
+            }
+            keep
+        });
+        let enables_disabled = if all_features {
+            packages
+                .iter()
+                .zip(&excluded)
+                .any(|((_, features), excluded)| excluded.iter().any(|x| features.contains_key(x)))
+        } else {
+            !no_default_features && excluded.iter().any(|x| x.contains("default"))
+        };
+        if enables_disabled || excluded.iter().any(|x| x.contains("default")) {
+            let qualify = packages.len() > 1;
+            for ((name, package_features), excluded) in packages.iter().zip(&excluded) {
+                let enabled = if all_features {
+                    package_features.keys().cloned().collect::<Vec<_>>()
+                } else {
+                    package_features.get("default").cloned().unwrap_or_default()
+                };
+                for feature in enabled {
+                    if feature == "default"
+                        || feature.starts_with("dep:")
+                        || excluded.contains(&feature)
+                        || (qualify && feature.contains('/'))
+                    {
+                        continue;
+                    }
+                    let feature = if qualify {
+                        format!("{name}/{feature}")
+                    } else {
+                        feature
+                    };
+                    if !features.contains(&feature) {
+                        features.push(feature);
+                    }
+                }
+            }
+            all_features = false;
+            no_default_features = true;
+        }
+    }
+
+    let mut args = vec![];
+    if !features.is_empty() {
+        args.push("--features".to_string());
+        args.push(features.join(" "));
+    }
+    if all_features {
+        args.push("--all-features".to_string());
+    }
+    if no_default_features {
+        args.push("--no-default-features".to_string());
+    }
+    args
+}
+
+/// The packages cargo builds, these are the packages given with `packages`, the workspace
+/// members with `all` or the package of the manifest
+fn selected_packages<'a>(config: &Config, meta: &'a Metadata) -> Vec<&'a Package> {
+    let root = meta.root_package().map(|x| &x.id);
+    meta.packages
+        .iter()
+        .filter(|x| meta.workspace_members.contains(&x.id))
+        .filter(|x| {
+            if !config.packages.is_empty() {
+                config.packages.contains(&x.name)
+            } else if config.all || root.is_none() {
+                !config.exclude.contains(&x.name)
+            } else {
+                root == Some(&x.id)
+            }
+        })
+        .collect()
+}
+
+/// The names and features of the packages cargo builds
+fn selected_package_features(config: &Config) -> Vec<(String, BTreeMap<String, Vec<String>>)> {
+    match config.get_metadata().as_ref() {
+        Some(meta) => selected_packages(config, meta)
+            .into_iter()
+            .map(|x| (x.name.clone(), x.features.clone()))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The features cargo enables in the packages it builds with the feature arguments of the
+/// config, in order. With more than one package each feature is prefixed with its package like
+/// `package/feature`. The result is kept in the config as it needs another `cargo metadata`
+pub fn resolved_features(config: &Config) -> Vec<String> {
+    if let Some(features) = config.resolved_features.borrow().as_ref() {
+        return features.clone();
+    }
+    let mut options = feature_args(config);
+    options.extend(config.cargo_lock_flags());
+    let features = match MetadataCommand::new()
+        .manifest_path(config.manifest())
+        .other_options(options)
+        .exec()
+    {
+        Ok(meta) => {
+            let packages = selected_packages(config, &meta);
+            let qualify = packages.len() > 1;
+            let nodes = meta.resolve.as_ref().map(|x| x.nodes.as_slice());
+            let mut features = packages
+                .iter()
+                .filter_map(|package| {
+                    let node = nodes?.iter().find(|x| x.id == package.id)?;
+                    Some(node.features.iter().map(move |feature| {
+                        if qualify {
+                            format!("{}/{}", package.name, feature)
+                        } else {
+                            feature.clone()
+                        }
+                    }))
+                })
+                .flatten()
+                .collect::<Vec<_>>();
+            features.sort();
+            features
+        }
+        Err(e) => {
+            warn!("Couldn't get the features of the project {}", e);
+            vec![]
+        }
+    };
+    config.resolved_features.replace(Some(features.clone()));
+    features
+}
+
+/// Warnings about the features in the config which likely don't do what was intended. These are
+/// `all_features` with `no_default_features`, where cargo enables every feature anyway, and
+/// features which aren't in the manifests of the packages built. Features of dependencies are
+/// left for cargo to check
+pub fn feature_warnings(config: &Config) -> Vec<String> {
+    let mut warnings = vec![];
+    if config.all_features && config.no_default_features && config.disable_features.is_empty() {
+        let features = resolved_features(config);
+        warnings.push(format!(
+            "all-features and no-default-features are both set, cargo enables every feature \
+             including the default ones so no-default-features has no effect. The features \
+             enabled are: {}. Use disable-features to leave features out",
+            if features.is_empty() {
+                "none".to_string()
+            } else {
+                features.join(" ")
+            }
+        ));
+    }
+    let packages = selected_package_features(config);
+    if packages.is_empty() {
+        return warnings;
+    }
+    let exists = |features: &BTreeMap<String, Vec<String>>, feature: &str| {
+        features.contains_key(feature) || feature == "default"
+    };
+    for feature in config.features.iter().chain(&config.disable_features) {
+        let missing = match feature.split_once('/') {
+            Some((package, name)) => packages
+                .iter()
+                .find(|(x, _)| x == package)
+                .is_some_and(|(_, features)| !exists(features, name)),
+            None => !packages
+                .iter()
+                .any(|(_, features)| exists(features, feature)),
+        };
+        if missing {
+            let names = packages.iter().map(|(x, _)| x.as_str()).collect::<Vec<_>>();
+            warnings.push(format!(
+                "feature {} isn't in the manifest of {}, cargo will fail to build it",
+                feature,
+                names.join(", ")
+            ));
+        }
+    }
+    warnings
+}
+
+/// The disabled features of a package and every feature which enables one of them, directly or
+/// through other features
+fn excluded_features(
+    features: &BTreeMap<String, Vec<String>>,
+    disabled: &[String],
+) -> HashSet<String> {
+    // `dep:name` and `name/feature` enable the dependency name, `name?/feature` doesn't
+    let enabled = |x: &str| {
+        let x = x.strip_prefix("dep:").unwrap_or(x);
+        x.split('/').next().unwrap_or(x).to_string()
+    };
+    let mut excluded = disabled.iter().cloned().collect::<HashSet<_>>();
+    loop {
+        let enables_excluded = features
+            .iter()
+            .filter(|(name, enables)| {
+                !excluded.contains(*name) && enables.iter().any(|x| excluded.contains(&enabled(x)))
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        if enables_excluded.is_empty() {
+            break;
+        }
+        excluded.extend(enables_excluded);
+    }
+    excluded
+}
+
+/// Output of running the toolchain's rustc in the project with the given arguments
+pub(crate) fn rustc_output(config: &Config, args: &[&str]) -> Option<String> {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    Command::new(rustc)
+        .args(args)
+        .current_dir(config.root())
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_string())
+}
+
+/// The target triple of the host the toolchain runs on
+pub(crate) fn host_target(config: &Config) -> Option<String> {
+    rustc_output(config, &["-vV"])?
+        .lines()
+        .find_map(|x| x.strip_prefix("host: ").map(str::to_string))
+}
+
+/// Looks for the profiler runtime in the toolchain's sysroot, without it builds instrumented for
+/// llvm coverage fail to link. If the sysroot can't be found or is for a custom target that isn't
+/// installed then this assumes the runtime is available
+pub fn has_profiler_runtime(config: &Config) -> bool {
+    let host = host_target(config);
+    let targets = if config.target.len() < 2 {
+        config.target.iter().cloned().chain(host).take(1).collect()
+    } else {
+        config
+            .target
+            .iter()
+            .filter(|x| host.as_ref().is_none_or(|host| host_can_run(host, x)))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let sysroot = match rustc_output(config, &["--print", "sysroot"]) {
+        Some(sysroot) => sysroot,
+        None => return true,
+    };
+    targets.iter().all(|target| {
+        let libs = Path::new(&sysroot)
+            .join("lib")
+            .join("rustlib")
+            .join(target)
+            .join("lib");
+        if !libs.is_dir() {
+            return true;
+        }
+        let pattern = libs.join("libprofiler_builtins-*.rlib");
+        glob::glob(&pattern.to_string_lossy())
+            .map(|mut x| x.next().is_some())
+            .unwrap_or(true)
+    })
+}
+
+/// Splits a config with multiple targets into a config for each target the host can run, or
+/// which has a runner, with a warning for the targets which are skipped. With a single target
+/// it's built and run as before so it's left to the build or test run to report it can't run.
+/// Only the first target cleans the project as the targets share the build script outputs in
+/// the target directory
+pub fn runnable_targets(config: &Config) -> Vec<Config> {
+    if config.target.len() < 2 {
+        return vec![config.clone()];
+    }
+    let host = host_target(config);
+    let mut targets = config
+        .per_target()
+        .into_iter()
+        .filter(|x| {
+            let target = &x.target[0];
+            match host.as_ref() {
+                Some(host) if !host_can_run(host, target) && x.runner().is_none() => {
+                    warn!(
+                        "Skipping target {}, it can't run on the host {}",
+                        target, host
+                    );
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect::<Vec<_>>();
+    for target in targets.iter_mut().skip(1) {
+        target.set_clean(false);
+    }
+    targets
+}
+
+/// Returns true if binaries built for the target can run on the host. This is the case when they
+/// have the same architecture and operating system, the environment such as gnu or musl doesn't
+/// matter and an x86_64 host can run 32 bit x86 binaries
+fn host_can_run(host: &str, target: &str) -> bool {
+    if host == target {
+        return true;
+    }
+    let split = |triple: &str| {
+        let parts = triple.split('-').collect::<Vec<_>>();
+        let arch = match parts[0] {
+            "i386" | "i586" | "i686" => "x86",
+            arch => arch,
+        };
+        (arch.to_string(), parts.get(2).map(|x| x.to_string()))
+    };
+    let (host_arch, host_os) = split(host);
+    let (target_arch, target_os) = split(target);
+    let arch_runs = host_arch == target_arch || (host_arch == "x86_64" && target_arch == "x86");
+    arch_runs && target_os.is_some() && host_os == target_os
+}
+
+/// The executable cargo runs workspace members' rustc invocations through to only instrument the
+/// packages in `instrument_coverage_packages`, or every workspace member and none of the
+/// dependencies unless `instrument_deps` is set. This is the tarpaulin binary, found either as the
+/// running executable or in `PATH` when tarpaulin is used as a library and packages are selected.
+/// Returns `None` when every package should be instrumented with `RUSTFLAGS` instead
+fn rustc_wrapper(config: &Config) -> Option<PathBuf> {
+    if config.engine() != TraceEngine::Llvm {
+        return None;
+    }
+    lazy_static! {
+        static ref EXE_NAME: String = format!("cargo-tarpaulin{}", env::consts::EXE_SUFFIX);
+        static ref CURRENT: Option<PathBuf> = env::current_exe()
+            .ok()
+            .filter(|x| x.file_name() == Some(OsStr::new(EXE_NAME.as_str())));
+        static ref WRAPPER: Option<PathBuf> = {
+            let wrapper = CURRENT.clone().or_else(|| {
+                env::split_paths(&env::var_os("PATH")?)
+                    .map(|x| x.join(EXE_NAME.as_str()))
+                    .find(|x| x.is_file())
+            });
+            if wrapper.is_none() {
+                warn!("Unable to find cargo-tarpaulin to only instrument instrument-coverage-packages, instrumenting every package");
+            }
+            wrapper
+        };
+    }
+    if !config.instrument_coverage_packages.is_empty() {
+        WRAPPER.clone()
+    } else if !config.instrument_deps {
+        // A different cargo-tarpaulin in `PATH` may not be able to act as the wrapper, so library
+        // users keep instrumenting everything unless they've selected packages
+        CURRENT.clone()
+    } else {
+        None
+    }
+}
+
+/// The value of `TARPAULIN_INSTRUMENT_PACKAGES` for the wrapper, `*` instruments every package
+/// it's run for which are the workspace members
+fn instrumented_packages(config: &Config) -> String {
+    if config.instrument_coverage_packages.is_empty() {
+        "*".to_string()
+    } else {
+        config.instrument_coverage_packages.join(",")
+    }
+}
+
+/// When tarpaulin is run as the `RUSTC_WORKSPACE_WRAPPER` this compiles the crate, adding the
+/// coverage instrumentation flags if the package is one of the packages to instrument, and returns
+/// the exit code of the compiler. Returns `None` if tarpaulin isn't being run as the wrapper
+pub fn run_rustc_wrapper() -> Option<i32> {
+    let packages = env::var(INSTRUMENT_PACKAGES).ok()?;
+    let mut args = env::args_os().skip(1);
+    let rustc = args.next()?;
+    let mut cmd = match env::var_os(USER_WORKSPACE_WRAPPER) {
+        Some(user_wrapper) => {
+            let mut cmd = Command::new(user_wrapper);
+            cmd.arg(rustc);
+            cmd
+        }
+        None => Command::new(rustc),
+    };
+    cmd.args(args);
+    let package = env::var("CARGO_PKG_NAME").unwrap_or_default();
+    if packages == "*" || packages.split(',').any(|x| x == package) {
+        let flags = env::var(INSTRUMENT_FLAGS).unwrap_or_default();
+        cmd.args(flags.split_whitespace());
+    }
+    let code = match cmd.status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("tarpaulin failed to run rustc: {e}");
+            1
+        }
+    };
+    Some(code)
+}
+
 pub fn llvm_coverage_rustflag() -> &'static str {
     match CARGO_VERSION_INFO.as_ref() {
         Some(v) if v.minor >= 60 => " -Cinstrument-coverage ",
@@ -870,8 +1918,202 @@ pub fn llvm_coverage_rustflag() -> &'static str {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rusty_fork::rusty_fork_test;
     use toml::toml;
 
+    #[test]
+    fn version_change_detected() {
+        let mut config = Config::default();
+        let target = env::temp_dir().join(format!("tarpaulin-version-{}", std::process::id()));
+        config.set_target_dir(target.clone());
+        assert!(!tarpaulin_version_changed(&config));
+
+        write_tarpaulin_version(&config);
+        assert!(!tarpaulin_version_changed(&config));
+
+        write(tarpaulin_version_file(&config), "0.0.1").unwrap();
+        assert!(tarpaulin_version_changed(&config));
+        let _ = remove_dir_all(target);
+    }
+
+    #[test]
+    fn doctests_added_to_run_types() {
+        let mut config = Config::default();
+        config.run_doctests = true;
+        assert_eq!(
+            cargo_run_types(&config),
+            vec![Some(RunType::Tests), Some(RunType::Doctests)]
+        );
+
+        config.run_types = vec![RunType::Doctests, RunType::Lib];
+        assert_eq!(
+            cargo_run_types(&config),
+            vec![Some(RunType::Doctests), Some(RunType::Lib)]
+        );
+    }
+
+    #[test]
+    fn build_script_artifacts() {
+        let build = Path::new("target/debug/build/foo-0123456789abcdef");
+        assert!(is_build_script(&build.join("build-script-build")));
+        assert!(is_build_script(&build.join("build-script-build.exe")));
+        assert!(!is_build_script(&build.join("build_script_build.pdb")));
+        assert!(!is_build_script(
+            &build.join("build_script_build-0123456789abcdef.d")
+        ));
+        assert!(!is_build_script(&build.join("build-script-build.dwp")));
+    }
+
+    #[test]
+    fn dry_run_command_lines() {
+        let mut config = Config::default();
+        config.set_manifest(PathBuf::from("/my project/Cargo.toml"));
+        config.features = vec!["a".to_string(), "b".to_string()];
+        config.release = true;
+        config.run_types = vec![RunType::Lib, RunType::Doctests];
+
+        let lines = cargo_command_lines(&config);
+        assert_eq!(lines.len(), 2);
+        let rustflags = format!("RUSTFLAGS={}", shell_quote(rust_flags(&config).as_ref()));
+        assert!(lines[0].starts_with("LLVM_PROFILE_FILE="));
+        assert!(lines[0].contains(&rustflags));
+        assert!(lines[0].contains(" test --no-run "));
+        assert!(lines[0].contains(" --manifest-path '/my project/Cargo.toml' "));
+        assert!(lines[0].contains(" --lib "));
+        assert!(lines[0].contains(" --features 'a b' --release "));
+        assert!(lines[1].contains(" --doc "));
+
+        assert_eq!(shell_quote(OsStr::new("--target-dir")), "--target-dir");
+        assert_eq!(shell_quote(OsStr::new("")), "''");
+        assert_eq!(shell_quote(OsStr::new("it's")), r"'it'\''s'");
+
+        config.target = vec![
+            "x86_64-unknown-linux-gnu".to_string(),
+            "wasm32-wasi".to_string(),
+        ];
+        let lines = cargo_command_lines(&config);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains(" --target x86_64-unknown-linux-gnu "));
+        assert!(lines[2].contains(" --target wasm32-wasi "));
+        assert!(!lines[2].contains("--target x86_64-unknown-linux-gnu"));
+    }
+
+    #[test]
+    fn examples_built_to_run() {
+        let mut config = Config::default();
+        config.set_manifest(PathBuf::from("Cargo.toml"));
+        config.no_fail_fast = true;
+        config.example_names.insert("tested".to_string());
+        config.run_examples.insert("demo".to_string());
+
+        let lines = cargo_command_lines(&config);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" test --no-run "));
+        assert!(lines[0].contains(" --example tested "));
+        assert!(lines[1].contains(" build "));
+        assert!(lines[1].contains(" --example demo "));
+        assert!(!lines[1].contains("--example tested"));
+        assert!(!lines[1].contains("--no-fail-fast"));
+    }
+
+    #[test]
+    fn host_runnable_targets() {
+        let host = "x86_64-unknown-linux-gnu";
+        assert!(host_can_run(host, host));
+        assert!(host_can_run(host, "x86_64-unknown-linux-musl"));
+        assert!(host_can_run(host, "i686-unknown-linux-gnu"));
+        assert!(!host_can_run(host, "aarch64-unknown-linux-gnu"));
+        assert!(!host_can_run(host, "x86_64-pc-windows-msvc"));
+        assert!(!host_can_run(host, "wasm32-unknown-unknown"));
+        assert!(!host_can_run(host, "custom.json"));
+        assert!(host_can_run(
+            "x86_64-pc-windows-msvc",
+            "x86_64-pc-windows-gnu"
+        ));
+        assert!(!host_can_run("i686-unknown-linux-gnu", host));
+    }
+
+    #[test]
+    fn disabled_feature_args() {
+        let mut config = Config::default();
+        config.set_manifest(
+            env::current_dir()
+                .unwrap()
+                .join("tests/data/disable_features/Cargo.toml"),
+        );
+        assert!(feature_args(&config).is_empty());
+
+        // full enables allocator so is left out along with it, and the defaults don't enable it
+        config.disable_features = vec!["allocator".to_string()];
+        config.features = vec![
+            "allocator".to_string(),
+            "full".to_string(),
+            "extra".to_string(),
+        ];
+        assert_eq!(feature_args(&config), vec!["--features", "extra"]);
+
+        config.features = vec![];
+        config.all_features = true;
+        assert_eq!(
+            feature_args(&config),
+            vec!["--features", "extra fast", "--no-default-features"]
+        );
+
+        // Disabling a default feature turns off the defaults and enables the rest explicitly
+        config.all_features = false;
+        config.disable_features = vec!["fast".to_string()];
+        assert_eq!(feature_args(&config), vec!["--no-default-features"]);
+        config.features = vec!["extra".to_string()];
+        assert_eq!(
+            feature_args(&config),
+            vec!["--features", "extra", "--no-default-features"]
+        );
+
+        config.disable_features = vec!["missing".to_string()];
+        config.all_features = true;
+        assert_eq!(
+            feature_args(&config),
+            vec!["--features", "extra", "--all-features"]
+        );
+    }
+
+    #[test]
+    fn feature_warnings_found() {
+        let mut config = Config::default();
+        config.set_manifest(
+            env::current_dir()
+                .unwrap()
+                .join("tests/data/disable_features/Cargo.toml"),
+        );
+        assert!(feature_warnings(&config).is_empty());
+
+        config.features = vec![
+            "extra".to_string(),
+            "missing".to_string(),
+            "disable_features/full".to_string(),
+            "disable_features/nope".to_string(),
+            "some_dependency/feature".to_string(),
+        ];
+        let warnings = feature_warnings(&config);
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(
+            warnings[0].starts_with("feature missing isn't in the manifest of disable_features")
+        );
+        assert!(warnings[1].starts_with("feature disable_features/nope isn't"));
+
+        config.features = vec![];
+        config.all_features = true;
+        config.no_default_features = true;
+        let warnings = feature_warnings(&config);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("no-default-features has no effect"));
+        assert!(warnings[0].contains("enabled are: allocator default extra fast full."));
+
+        // With disabled features the features are listed explicitly so the flags don't clash
+        config.disable_features = vec!["allocator".to_string()];
+        assert!(feature_warnings(&config).is_empty());
+    }
+
     #[test]
     #[cfg(not(windows))]
     fn check_dead_code_flags() {
@@ -884,6 +2126,24 @@ mod tests {
         assert!(!rust_flags(&config).contains("link-dead-code"));
     }
 
+    #[test]
+    fn custom_cfgs_in_flags() {
+        let mut config = Config::default();
+        assert!(!rust_flags(&config).contains("--cfg=loom"));
+        config.cfg = vec!["loom".to_string(), r#"backend="a""#.to_string()];
+        config.rustflags = Some("--cfg loom".to_string());
+        for flags in [rust_flags(&config), rustdoc_flags(&config)] {
+            let flags = flags.split_whitespace().collect::<Vec<_>>();
+            assert!(flags.contains(&"--cfg=tarpaulin"));
+            assert!(flags.contains(&r#"--cfg=backend="a""#));
+            assert_eq!(flags.iter().filter(|x| **x == "--cfg=loom").count(), 1);
+        }
+        assert!(config.check_cfgs().is_ok());
+
+        config.cfg.push("my flag".to_string());
+        assert!(config.check_cfgs().is_err());
+    }
+
     #[test]
     fn parse_rustflags_from_toml() {
         let list_flags = toml! {
@@ -940,6 +2200,234 @@ mod tests {
         assert!(!version.supports_llvm_cov());
     }
 
+    #[test]
+    fn nextest_list_parsing() {
+        let output = r#"{
+            "rust-build-meta": {},
+            "test-count": 3,
+            "rust-suites": {
+                "foo::bar": {
+                    "package-name": "foo",
+                    "binary-id": "foo::bar",
+                    "binary-name": "bar",
+                    "package-id": "foo 0.1.0 (path+file:///foo)",
+                    "kind": "test",
+                    "binary-path": "/foo/target/debug/deps/bar-1234",
+                    "build-platform": "target",
+                    "cwd": "/foo",
+                    "status": "listed",
+                    "testcases": {
+                        "it_works": {
+                            "ignored": false,
+                            "filter-match": { "status": "matches" }
+                        },
+                        "slow_test": {
+                            "ignored": true,
+                            "filter-match": { "status": "matches" }
+                        },
+                        "filtered_out": {
+                            "ignored": false,
+                            "filter-match": { "status": "mismatch", "reason": "string" }
+                        }
+                    }
+                }
+            }
+        }"#;
+        let list: NextestList = serde_json::from_str(output).unwrap();
+        let mut config = Config::default();
+        config.command = Mode::Nextest;
+
+        let binaries = nextest_binaries(list.clone(), &config, Some(RunType::Tests));
+        assert_eq!(binaries.len(), 1);
+        let (binary, package) = &binaries[0];
+        assert_eq!(package, "foo 0.1.0 (path+file:///foo)");
+        assert_eq!(binary.path(), Path::new("/foo/target/debug/deps/bar-1234"));
+        assert_eq!(binary.test_name(), &Some("it_works".to_string()));
+        assert_eq!(binary.manifest_dir(), &Some(PathBuf::from("/foo")));
+
+        config.run_ignored = true;
+        let binaries = nextest_binaries(list, &config, Some(RunType::Tests));
+        assert_eq!(binaries.len(), 2);
+    }
+
+    #[test]
+    fn shards_are_stable() {
+        let binary = |path: &str, ty, test: Option<&str>| {
+            let mut binary = TestBinary::new(PathBuf::from(path), ty);
+            binary.pkg_name = Some("foo".to_string());
+            binary.test_name = test.map(ToString::to_string);
+            binary
+        };
+        let tests = Some(RunType::Tests);
+        let rebuilt = binary("/t/debug/deps/foo-0123456789abcdef", tests, None);
+        assert_eq!(rebuilt.shard_key(), "foo/foo");
+        assert_eq!(
+            rebuilt.shard_key(),
+            binary("/t/debug/deps/foo-fedcba9876543210", tests, None).shard_key()
+        );
+        assert_eq!(
+            binary("/t/debug/deps/foo-0123456789abcdef", tests, Some("a::b")).shard_key(),
+            "foo/foo::a::b"
+        );
+        assert_eq!(
+            binary(
+                "/t/doctests/src_lib_rs_10_0/rust_out",
+                Some(RunType::Doctests),
+                None
+            )
+            .shard_key(),
+            "foo/src_lib_rs_10_0"
+        );
+
+        let binaries = (0..20)
+            .map(|x| {
+                binary(
+                    "/t/debug/deps/foo-0123456789abcdef",
+                    tests,
+                    Some(&x.to_string()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut config = Config::default();
+        config.shard_count = Some(3);
+        let shard_of = |config: &Config, binaries: &[TestBinary]| {
+            let mut shard = binaries.to_vec();
+            select_shard(config, &mut shard);
+            let mut names = shard
+                .into_iter()
+                .map(|x| x.test_name.unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let mut sharded = vec![];
+        let mut reversed = binaries.clone();
+        reversed.reverse();
+        for index in 0..3 {
+            config.shard_index = Some(index);
+            let shard = shard_of(&config, &binaries);
+            // The shards are balanced and don't depend on the order the binaries are found in
+            assert!(shard.len() == 6 || shard.len() == 7, "{:?}", shard);
+            assert_eq!(shard, shard_of(&config, &reversed));
+            sharded.extend(shard);
+        }
+        // Every binary is in exactly one shard
+        sharded.sort_by_key(|x| x.parse::<usize>().unwrap());
+        let all = (0..20).map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(sharded, all);
+
+        // With fewer binaries than shards each binary gets a shard of its own
+        config.shard_index = Some(1);
+        assert_eq!(shard_of(&config, &binaries[..2]).len(), 1);
+        config.shard_index = Some(2);
+        assert!(shard_of(&config, &binaries[..2]).is_empty());
+    }
+
+    #[test]
+    fn nextest_skips_doctests() {
+        let mut config = Config::default();
+        config.command = Mode::Nextest;
+        assert!(is_nextest(&config, Some(RunType::Tests)));
+        assert!(is_nextest(&config, None));
+        assert!(!is_nextest(&config, Some(RunType::Doctests)));
+        config.command = Mode::Test;
+        assert!(!is_nextest(&config, Some(RunType::Tests)));
+    }
+
+    #[test]
+    fn test_filter_args() {
+        let args = |config: &Config, ty| {
+            create_command("Cargo.toml", config, ty)
+                .get_args()
+                .map(|x| x.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut config = Config::default();
+        config.test_filter = Some("parser".to_string());
+        config.varargs = vec!["--nocapture".to_string()];
+        // The test executables are given the filter when they're run
+        let build = args(&config, Some(RunType::Tests));
+        assert!(!build.contains(&"parser".to_string()));
+        assert!(build.ends_with(&["--".to_string(), "--nocapture".to_string()]));
+        assert!(args(&config, Some(RunType::Doctests)).ends_with(&[
+            "--".to_string(),
+            "parser".to_string(),
+            "--nocapture".to_string()
+        ]));
+
+        config.command = Mode::Nextest;
+        let list = args(&config, None);
+        assert!(list.contains(&"parser".to_string()));
+        assert!(!list.contains(&"--".to_string()));
+    }
+
+    #[test]
+    fn all_cpus_with_zero_jobs() {
+        let mut config = Config::default();
+        config.jobs = Some(0);
+        let args = create_command("Cargo.toml", &config, Some(RunType::Tests))
+            .get_args()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let jobs = args.iter().position(|x| x == "--jobs").unwrap();
+        assert_eq!(args[jobs + 1], num_cpus::get().to_string());
+    }
+
+    rusty_fork_test! {
+    // Forked as it changes the environment variables the flags are read from
+    #[test]
+    fn user_rustflags_kept() {
+        env::remove_var("RUSTFLAGS");
+        env::remove_var("CARGO_ENCODED_RUSTFLAGS");
+        let root = env::temp_dir().join(format!("tarpaulin-rustflags-{}", std::process::id()));
+        create_dir_all(root.join(".cargo")).unwrap();
+        write(
+            root.join(".cargo").join("config.toml"),
+            r#"[build]
+            rustflags = ["-C", "target-cpu=native"]
+
+            [target.x86_64-unknown-linux-gnu]
+            rustflags = ["-C", "target-feature=+avx2", "-C", "target-feature=+fma"]
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        config.rustflags = Some("-C link-arg=-fuse-ld=lld".to_string());
+        config.target = vec!["x86_64-unknown-linux-gnu".to_string()];
+        let flags = rust_flags(&config);
+        let flags = flags.split_whitespace().collect::<Vec<_>>();
+        assert!(flags.contains(&"-Ctarget-feature=+avx2"));
+        assert!(flags.contains(&"-Ctarget-feature=+fma"));
+        assert!(flags.contains(&"-Clink-arg=-fuse-ld=lld"));
+        assert!(flags.contains(&"-Cdebuginfo=2"));
+        assert!(flags.contains(&"--cfg=tarpaulin"));
+        assert!(!flags.contains(&"-Ctarget-cpu=native"));
+
+        config.target = vec!["aarch64-unknown-linux-gnu".to_string()];
+        let flags = rust_flags(&config);
+        assert!(flags.contains("-Ctarget-cpu=native"));
+        assert!(!flags.contains("target-feature"));
+
+        // RUSTFLAGS takes the place of the cargo config file flags like it does for cargo
+        env::set_var("RUSTFLAGS", "-C opt-level=1 -C debuginfo=1");
+        let flags = rust_flags(&config);
+        let flags = flags.split_whitespace().collect::<Vec<_>>();
+        assert!(flags.contains(&"-Copt-level=1"));
+        assert!(flags.contains(&"-Clink-arg=-fuse-ld=lld"));
+        assert!(flags.contains(&"-Cdebuginfo=2"));
+        assert!(!flags.contains(&"-Cdebuginfo=1"));
+        assert!(!flags.contains(&"-Ctarget-cpu=native"));
+        env::remove_var("RUSTFLAGS");
+        let _ = remove_dir_all(root);
+
+        assert_eq!(
+            decode_flags("-C\x1ftarget-feature=+avx2\x1f--cfg\x1ffoo"),
+            "-C target-feature=+avx2 --cfg foo"
+        );
+    }
+    }
+
     #[test]
     fn no_duplicate_flags() {
         assert_eq!(
@@ -963,5 +2451,42 @@ mod tests {
             deduplicate_flags("--cfg=tarpaulin --cfg tarpauline --cfg=tarp"),
             "--cfg=tarpaulin --cfg=tarpauline --cfg=tarp"
         );
+        assert_eq!(
+            deduplicate_flags(
+                "-C target-feature=+avx2 -Ctarget-feature=+fma -C target-feature=+avx2"
+            ),
+            "-Ctarget-feature=+avx2 -Ctarget-feature=+fma"
+        );
+    }
+
+    #[test]
+    fn stale_lockfile_messages() {
+        assert!(is_stale_lockfile(
+            "error: the lock file /a/Cargo.lock needs to be updated but --locked was passed to prevent this"
+        ));
+        assert!(is_stale_lockfile(
+            "error: cannot update the lock file /a/Cargo.lock because --frozen was passed to prevent this"
+        ));
+        assert!(!is_stale_lockfile(
+            "error: failed to parse manifest at `/a/Cargo.toml`"
+        ));
+
+        let member = Path::new("/ws/member/Cargo.toml");
+        assert_eq!(
+            stale_lockfile_path(
+                "error: the lock file /ws/Cargo.lock needs to be updated but --locked was passed to prevent this",
+                member
+            ),
+            Path::new("/ws/Cargo.lock")
+        );
+        // Without a path in the error the workspace's lock file is found from the member
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(
+            stale_lockfile_path(
+                "error: --locked was passed",
+                &root.join("tests/data/instrument_deps/app/Cargo.toml")
+            ),
+            root.join("tests/data/instrument_deps/Cargo.lock")
+        );
     }
 }
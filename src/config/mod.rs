@@ -1,30 +1,33 @@
+pub use self::parse::parse_duration;
 use self::parse::*;
 pub use self::types::*;
-use crate::cargo::supports_llvm_coverage;
+use crate::cargo::{feature_warnings, has_profiler_runtime, supports_llvm_coverage};
+use crate::errors::{ConfigError, RunError};
 use crate::path_utils::fix_unc_path;
 use cargo_metadata::{Metadata, MetadataCommand, Package};
 use clap::{value_t, ArgMatches};
 use coveralls_api::CiService;
-use humantime_serde::deserialize as humantime_serde;
 use indexmap::IndexMap;
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::cell::{Ref, RefCell};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tracing::{error, info, warn};
 
 mod parse;
+pub mod schema;
 pub mod types;
 
 #[derive(Debug)]
 pub struct ConfigWrapper(pub Vec<Config>);
 
 /// Specifies the current configuration tarpaulin is using.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(default)]
 pub struct Config {
     pub name: String,
@@ -33,7 +36,7 @@ pub struct Config {
     manifest: PathBuf,
     /// Path to a tarpaulin.toml config file
     pub config: Option<PathBuf>,
-    /// Path to the projects cargo manifest
+    /// Root directory of the project, paths in the results are relative to it
     root: Option<PathBuf>,
     /// Flag to also run tests with the ignored attribute
     #[serde(rename = "ignored")]
@@ -51,9 +54,15 @@ pub struct Config {
     pub verbose: bool,
     /// Debug flag for printing internal debugging information to the user
     pub debug: bool,
+    /// Quiet flag for building without cargo's progress output
+    pub quiet: bool,
     /// Enable the event logger
     #[serde(rename = "dump-traces")]
     pub dump_traces: bool,
+    /// File to write the trace map to before the results are filtered, with why any files or
+    /// lines are left out of the results
+    #[serde(rename = "dump-trace-map")]
+    pub dump_trace_map: Option<PathBuf>,
     /// Flag to count hits in coverage
     pub count: bool,
     /// Flag specifying to run line coverage (default)
@@ -65,16 +74,31 @@ pub struct Config {
     /// Directory to write output files
     #[serde(rename = "output-dir")]
     pub output_directory: Option<PathBuf>,
+    /// Paths to write a report format to instead of its default file name, relative paths
+    /// are relative to the output directory
+    #[serde(rename = "output-files")]
+    pub output_files: HashMap<OutputFile, PathBuf>,
+    /// Write the paths of source files in the reports relative to the workspace root (default)
+    #[serde(rename = "relative-paths")]
+    pub relative_paths: bool,
+    /// Base the relative paths in the reports are written under, for tools expecting paths
+    /// from somewhere other than the workspace root
+    #[serde(rename = "path-prefix")]
+    pub path_prefix: Option<PathBuf>,
     /// Key relating to coveralls service or repo
     pub coveralls: Option<String>,
     /// Enum representing CI tool used.
     #[serde(rename = "ciserver", deserialize_with = "deserialize_ci_server")]
+    #[schemars(with = "Option<String>")]
     pub ci_tool: Option<CiService>,
     /// Only valid if coveralls option is set. If coveralls option is set,
     /// as well as report_uri, then the report will be sent to this endpoint
     /// instead.
     #[serde(rename = "report-uri")]
     pub report_uri: Option<String>,
+    /// Git reference to diff against, if set only lines changed since it are reported on
+    #[serde(rename = "changed-since")]
+    pub changed_since: Option<String>,
     /// Forward unexpected signals back to the tracee. Used for tests which
     /// rely on signals to work.
     #[serde(rename = "forward")]
@@ -92,50 +116,147 @@ pub struct Config {
     #[serde(alias = "workspace")]
     pub all: bool,
     /// Duration to wait before a timeout occurs
-    #[serde(deserialize_with = "humantime_serde", rename = "timeout")]
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "humantime_serde::serialize",
+        rename = "timeout"
+    )]
+    #[schemars(with = "schema::Duration")]
     pub test_timeout: Duration,
     /// Build in release mode
     pub release: bool,
     /// Build the tests only don't run coverage
     #[serde(rename = "no-run")]
     pub no_run: bool,
-    /// Don't update `Cargo.lock`.
-    pub locked: bool,
-    /// Don't update `Cargo.lock` or any caches.
+    /// Don't update `Cargo.lock`, implied by `frozen`
+    pub locked: Option<bool>,
+    /// Don't update `Cargo.lock` or any caches, this is `locked` and `offline` together
     pub frozen: bool,
-    /// Build for the target triple.
-    pub target: Option<String>,
+    /// Build for the target triples, coverage is collected for each target the host can run and
+    /// merged into one report
+    #[serde(deserialize_with = "deserialize_targets")]
+    #[schemars(with = "schema::Targets")]
+    pub target: Vec<String>,
     /// Directory for generated artifacts
     #[serde(rename = "target-dir")]
     target_dir: Option<PathBuf>,
-    /// Run tarpaulin on project without accessing the network
-    pub offline: bool,
-    /// Cargo subcommand to run. So far only test and build are supported
+    /// Run tarpaulin on project without accessing the network, implied by `frozen`
+    pub offline: Option<bool>,
+    /// Cargo subcommand to run, one of test, build, nextest or bench
     pub command: Mode,
     /// Types of tests for tarpaulin to collect coverage on
     #[serde(rename = "run-types")]
     pub run_types: Vec<RunType>,
+    /// Run the doctests as well as the other run types, or the default tests if none are set
+    #[serde(rename = "run-doctests")]
+    pub run_doctests: bool,
     /// Packages to include when building the target project
     pub packages: Vec<String>,
     /// Packages to exclude from testing
     pub exclude: Vec<String>,
+    /// Workspace members to instrument with the llvm engine, other packages and dependencies are
+    /// built without coverage instrumentation. Instruments everything if empty
+    #[serde(rename = "instrument-coverage-packages")]
+    pub instrument_coverage_packages: Vec<String>,
+    /// Instrument dependencies which aren't workspace members with the llvm engine as well as
+    /// the workspace members
+    #[serde(rename = "instrument-deps")]
+    pub instrument_deps: bool,
     /// Files to exclude from testing in their compiled form
     #[serde(skip_deserializing, skip_serializing)]
     excluded_files: RefCell<Vec<glob::Pattern>>,
-    /// Files to exclude from testing in uncompiled form (for serde)
+    /// Glob patterns of files to exclude from the results
     #[serde(rename = "exclude-files")]
     excluded_files_raw: Vec<String>,
+    /// Files to only include in testing in their compiled form
+    #[serde(skip_deserializing, skip_serializing)]
+    included_files: RefCell<Vec<glob::Pattern>>,
+    /// Glob patterns of files to only include in the results
+    #[serde(rename = "include-files")]
+    included_files_raw: Vec<String>,
+    /// Regexes of lines to exclude from the results in their compiled form, `None` until
+    /// they're compiled
+    #[serde(skip_deserializing, skip_serializing)]
+    excluded_lines: RefCell<Option<Vec<Regex>>>,
+    /// Regexes of source lines to exclude from the results, matched against the text of each line
+    #[serde(rename = "exclude-line-patterns")]
+    pub exclude_line_patterns: Vec<String>,
+    /// Include files ignored by git in the results
+    #[serde(rename = "no-gitignore")]
+    pub no_gitignore: bool,
+    /// Exclude files with an `@generated` marker in a comment near the top (default)
+    #[serde(rename = "exclude-generated")]
+    pub exclude_generated: bool,
+    /// Exclude the lines of `todo!`, `unimplemented!` and `unreachable!` calls and the macros in
+    /// `exclude_macros` (default)
+    #[serde(rename = "exclude-macro-lines")]
+    pub exclude_macro_lines: bool,
+    /// Other macros whose calls are excluded along with `todo!`, `unimplemented!` and
+    /// `unreachable!`
+    #[serde(rename = "exclude-macros")]
+    pub exclude_macros: Vec<String>,
+    /// Modules excluded from the coverage results by their path from the crate root, such as
+    /// `internal::generated`, along with their submodules
+    #[serde(rename = "exclude-modules")]
+    pub exclude_modules: Vec<String>,
     /// Varargs to be forwarded to the test executables.
     #[serde(rename = "args")]
     pub varargs: Vec<String>,
+    /// Only run tests with names containing this, passed to each test executable like the
+    /// filter in `cargo test <filter>`
+    #[serde(rename = "test-filter")]
+    pub test_filter: Option<String>,
+    /// Command the test executables are run with, such as an emulator for a cross compiled
+    /// target. Defaults to cargo's `target.<triple>.runner` for a target set with `target`
+    #[serde(rename = "target-runner")]
+    pub target_runner: Option<String>,
+    /// Path of `llvm-profdata`, which the llvm engine merges the profiles with when it can't read
+    /// them itself. Defaults to `LLVM_PROFDATA`, then the rustup `llvm-tools` component and then
+    /// `PATH`, a name without a directory such as `llvm-profdata-17` is looked for in `PATH`
+    #[serde(rename = "llvm-profdata-path")]
+    pub llvm_profdata_path: Option<PathBuf>,
+    /// Path of `llvm-cov`, which the llvm engine reads the coverage with when it can't read the
+    /// profiles itself. Defaults to `LLVM_COV` and is then found like `llvm-profdata-path`
+    #[serde(rename = "llvm-cov-path")]
+    pub llvm_cov_path: Option<PathBuf>,
+    /// Environment variables to set for the test executables
+    pub env: HashMap<String, String>,
+    /// Inherited environment variables to remove for the test executables
+    #[serde(rename = "env-remove")]
+    pub env_remove: Vec<String>,
     /// Features to include in the target project build, e.g. "feature1 feature2"
-    pub features: Option<String>,
+    #[serde(
+        deserialize_with = "deserialize_features",
+        serialize_with = "serialize_features"
+    )]
+    #[schemars(with = "schema::Features")]
+    pub features: Vec<String>,
+    /// Features which are never enabled, even if another config enables them or they would be
+    /// enabled by `all-features` or the default features
+    #[serde(
+        rename = "disable-features",
+        deserialize_with = "deserialize_features",
+        serialize_with = "serialize_features"
+    )]
+    #[schemars(with = "schema::Features")]
+    pub disable_features: Vec<String>,
     /// Unstable cargo features to use
     #[serde(rename = "Z")]
     pub unstable_features: Vec<String>,
+    /// Custom cfgs set with `--cfg` when building the tests, such as `loom` or `backend="a"`
+    pub cfg: Vec<String>,
     /// Output files to generate
     #[serde(rename = "out")]
     pub generate: Vec<OutputFile>,
+    /// Only print the coverage summary, no reports are written or printed whatever `out` is
+    #[serde(rename = "summary-only")]
+    pub summary_only: bool,
+    /// Print the coverage of the lines inside `unsafe` blocks and list the uncovered ones
+    #[serde(rename = "report-unsafe")]
+    pub report_unsafe: bool,
+    /// How the coverage of lines with several llvm counters is reported, only the llvm engine
+    /// has counters for parts of a line
+    pub granularity: Granularity,
     /// Names of tests to run corresponding to `cargo --test <NAME>...`
     #[serde(rename = "test")]
     pub test_names: HashSet<String>,
@@ -145,6 +266,13 @@ pub struct Config {
     /// Names of examples to run corresponding to `cargo --example <NAME>...`
     #[serde(rename = "example")]
     pub example_names: HashSet<String>,
+    /// Names of examples to build with `cargo build --example <NAME>...` and run, so the
+    /// coverage of their `main` is collected instead of any tests in them
+    #[serde(rename = "run-examples")]
+    pub run_examples: HashSet<String>,
+    /// Arguments to run each example in `run_examples` with, by the example's name
+    #[serde(rename = "example-args")]
+    pub example_args: HashMap<String, Vec<String>>,
     /// Names of benches to run corresponding to `cargo --bench <NAME>...`
     #[serde(rename = "bench")]
     pub bench_names: HashSet<String>,
@@ -156,34 +284,138 @@ pub struct Config {
     /// returns a non-zero code if coverage is below the threshold
     #[serde(rename = "fail-under")]
     pub fail_under: Option<f64>,
+    /// Per package coverage thresholds, packages without an entry use `fail_under`
+    #[serde(rename = "fail-under-per-package")]
+    pub fail_under_per_package: HashMap<String, f64>,
+    /// File of path globs and the minimum coverage of the files they match, defaults to
+    /// `.coveragethresholds` in the project root if it exists
+    #[serde(rename = "coverage-thresholds")]
+    pub coverage_thresholds: Option<PathBuf>,
+    /// Exit code to use when the coverage is below a threshold or decreased from the baseline,
+    /// instead of 3
+    #[serde(rename = "exit-code-on-threshold")]
+    pub exit_code_on_threshold: Option<i32>,
     /// Result of cargo_metadata ran on the crate
     #[serde(skip_deserializing, skip_serializing)]
     pub metadata: RefCell<Option<Metadata>>,
+    /// Features enabled in the packages built, found by `cargo::resolved_features`
+    #[serde(skip_deserializing, skip_serializing)]
+    pub resolved_features: RefCell<Option<Vec<String>>>,
     /// Don't pass --cfg=tarpaulin to the 'RUSTFLAG'
     pub avoid_cfg_tarpaulin: bool,
     /// Colouring of logging
+    #[serde(deserialize_with = "deserialize_color")]
     pub color: Color,
     /// Follow traced executables down
     #[serde(rename = "follow-exec")]
     pub follow_exec: bool,
-    /// Number of jobs used for building the tests
+    /// Keep tracing forked processes after the test exits until they exit
+    #[serde(rename = "follow-forks")]
+    pub follow_forks: bool,
+    /// Number of jobs used for building the tests, 0 uses every CPU. Without it
+    /// `CARGO_BUILD_JOBS` is used
     pub jobs: Option<usize>,
     /// Allow test to use an implicit test threads
     #[serde(rename = "implicit-test-threads")]
     pub implicit_test_threads: bool,
+    /// Number of threads the test executables are run with, passed as `--test-threads`
+    #[serde(rename = "test-threads")]
+    pub test_threads: Option<usize>,
     /// Engine to use to collect coverage
     engine: RefCell<TraceEngine>,
-    /// Specifying per-config rust flags
+    /// Specifying per-config rust flags, added to the flags from `RUSTFLAGS` or the cargo config
+    /// files
+    #[serde(deserialize_with = "deserialize_rustflags")]
+    #[schemars(with = "Option<schema::Rustflags>")]
     pub rustflags: Option<String>,
     /// Flag to include test functions in coverage statistics
     #[serde(rename = "include-tests")]
     include_tests: bool,
-    #[serde(rename = "post-test-delay")]
-    /// Delay after test to collect instrumentation files (LLVM only)
+    /// Delay after the test exits before collecting coverage. With LLVM this gives time for
+    /// the profiles to be written and defaults to 1 second, with ptrace any processes the test
+    /// spawned are traced for this long and there's no delay unless it's set
+    #[serde(
+        deserialize_with = "deserialize_optional_duration",
+        serialize_with = "humantime_serde::serialize",
+        rename = "post-test-delay"
+    )]
+    #[schemars(with = "Option<schema::Duration>")]
     pub post_test_delay: Option<Duration>,
+    /// Signal sent to stop a test which has timed out and every process a test spawned which is
+    /// still running when it exits, so their shutdown code is covered
+    #[serde(rename = "graceful-shutdown-signal")]
+    pub graceful_shutdown_signal: Option<String>,
+    /// Time a process sent the shutdown signal has to exit before it's killed, the test timeout
+    /// is used when `graceful-shutdown-signal` is set without this
+    #[serde(
+        deserialize_with = "deserialize_optional_duration",
+        serialize_with = "humantime_serde::serialize",
+        rename = "shutdown-timeout"
+    )]
+    #[schemars(with = "Option<schema::Duration>")]
+    pub shutdown_timeout: Option<Duration>,
+    /// Number of times to rerun a test executable that crashes or fails before giving up
+    pub retries: usize,
+    /// Number of times to run all the tests, the coverage of every run is merged and the lines
+    /// only some runs covered are listed
+    #[serde(rename = "run-count")]
+    pub run_count: usize,
+    /// Which of the `shard-count` shards of the test executables to run, counting from 0
+    #[serde(rename = "shard-index")]
+    pub shard_index: Option<usize>,
+    /// Number of shards the test executables are split into, each test executable is always in
+    /// the same shard
+    #[serde(rename = "shard-count")]
+    pub shard_count: Option<usize>,
+    /// Rerun coverage whenever a source file in the project changes
+    pub watch: bool,
+    /// Print the cargo commands used to build the tests and exit without running them
+    #[serde(rename = "dry-run")]
+    pub dry_run: bool,
     /// Other objects that should be included to get counter values from for instrumentation
     /// coverage
     objects: Vec<PathBuf>,
+    /// Coverage results saved by previous runs of tarpaulin to merge into this one
+    #[serde(rename = "input-files")]
+    pub input_files: Vec<PathBuf>,
+    /// Test binaries built by a previous `no-run` build to run instead of building the tests
+    #[serde(rename = "input-binaries")]
+    pub input_binaries: Vec<PathBuf>,
+    /// Profiles written by running the `input-binaries` outside of tarpaulin to collect the
+    /// coverage from instead of running the tests, this needs the llvm engine
+    pub profraws: Vec<PathBuf>,
+    /// Coverage results saved by a previous run of tarpaulin to compare this one against
+    pub baseline: Option<PathBuf>,
+    /// Fail if the coverage has decreased from the baseline
+    #[serde(rename = "fail-on-decrease")]
+    pub fail_on_decrease: bool,
+    /// Percentage the coverage can decrease from the baseline by without failing
+    #[serde(rename = "decrease-tolerance")]
+    pub decrease_tolerance: Option<f64>,
+    /// Fail if a test binary exits successfully without running any tests or covering any lines
+    #[serde(rename = "fail-on-empty-binary")]
+    pub fail_on_empty_binary: bool,
+    /// List the covered lines hit fewer times than this in the summary and highlight them in the
+    /// html report
+    #[serde(rename = "warn-if-hits-below")]
+    pub warn_if_hits_below: Option<u64>,
+    /// Print the covered and coverable lines of each directory as a tree with the lowest coverage
+    /// first
+    #[serde(rename = "print-summary-table")]
+    pub print_summary_table: bool,
+    /// Number of functions listed by `--out Hotspots`, defaults to 10
+    #[serde(rename = "hotspot-limit")]
+    pub hotspot_limit: Option<usize>,
+    /// Shell command run after the reports are written, with the report directory and coverage in
+    /// `TARPAULIN_REPORT_DIR` and `TARPAULIN_COVERAGE`
+    #[serde(rename = "post-report-command")]
+    pub post_report_command: Option<String>,
+    /// Fail the run if the post report command fails instead of warning
+    #[serde(rename = "post-report-required")]
+    pub post_report_required: bool,
+    /// Collect coverage of the build scripts run during the build, needs the llvm engine
+    #[serde(rename = "cover-build-scripts")]
+    pub cover_build_scripts: bool,
     /// Joined to target/tarpaulin to store profraws
     profraw_folder: PathBuf,
 }
@@ -192,12 +424,21 @@ fn default_test_timeout() -> Duration {
     Duration::from_secs(60)
 }
 
+/// The number of jobs from the `jobs` option, or the value of `CARGO_BUILD_JOBS` if it isn't
+/// set, with 0 meaning the number of CPUs. `None` leaves it to cargo, which also happens if
+/// `CARGO_BUILD_JOBS` isn't a number of jobs tarpaulin understands like cargo's negative values
+fn resolve_jobs(jobs: Option<usize>, env_jobs: Option<&str>) -> Option<usize> {
+    jobs.or_else(|| env_jobs.and_then(|x| x.trim().parse().ok()))
+        .map(|x| if x == 0 { num_cpus::get() } else { x })
+}
+
 impl Default for Config {
     fn default() -> Config {
         Config {
             name: String::new(),
             command: Mode::Test,
             run_types: vec![],
+            run_doctests: false,
             manifest: default_manifest(),
             config: None,
             root: Default::default(),
@@ -209,51 +450,108 @@ impl Default for Config {
             no_dead_code: false,
             verbose: false,
             debug: false,
+            quiet: false,
             follow_exec: false,
+            follow_forks: false,
             dump_traces: false,
+            dump_trace_map: None,
             count: false,
             line_coverage: true,
             branch_coverage: false,
             generate: vec![],
+            summary_only: false,
+            report_unsafe: false,
+            granularity: Granularity::Line,
             output_directory: Default::default(),
+            output_files: HashMap::new(),
+            relative_paths: true,
+            path_prefix: None,
             coveralls: None,
             ci_tool: None,
             report_uri: None,
+            changed_since: None,
             forward_signals: true,
             no_default_features: false,
-            features: None,
+            features: vec![],
             unstable_features: vec![],
+            cfg: vec![],
             all: false,
             packages: vec![],
             exclude: vec![],
+            instrument_coverage_packages: vec![],
+            instrument_deps: false,
             excluded_files: RefCell::new(vec![]),
             excluded_files_raw: vec![],
+            included_files: RefCell::new(vec![]),
+            included_files_raw: vec![],
+            excluded_lines: RefCell::new(None),
+            exclude_line_patterns: vec![],
+            no_gitignore: false,
+            exclude_generated: true,
+            exclude_macro_lines: true,
+            exclude_macros: vec![],
+            exclude_modules: vec![],
             varargs: vec![],
+            test_filter: None,
+            target_runner: None,
+            llvm_profdata_path: None,
+            llvm_cov_path: None,
+            env: HashMap::new(),
+            env_remove: vec![],
             test_timeout: default_test_timeout(),
             release: false,
             all_features: false,
+            disable_features: vec![],
             no_run: false,
-            locked: false,
+            locked: None,
             frozen: false,
             implicit_test_threads: false,
-            target: None,
+            test_threads: None,
+            target: vec![],
             target_dir: None,
-            offline: false,
+            offline: None,
             test_names: HashSet::new(),
             example_names: HashSet::new(),
+            run_examples: HashSet::new(),
+            example_args: HashMap::new(),
             bin_names: HashSet::new(),
             bench_names: HashSet::new(),
             no_fail_fast: false,
             profile: None,
             fail_under: None,
+            exit_code_on_threshold: None,
+            fail_under_per_package: HashMap::new(),
+            coverage_thresholds: None,
             metadata: RefCell::new(None),
+            resolved_features: RefCell::new(None),
             avoid_cfg_tarpaulin: false,
             jobs: None,
             color: Color::Auto,
             engine: RefCell::default(),
             rustflags: None,
-            post_test_delay: Some(Duration::from_secs(1)),
+            post_test_delay: None,
+            graceful_shutdown_signal: None,
+            shutdown_timeout: None,
+            retries: 0,
+            run_count: 1,
+            shard_index: None,
+            shard_count: None,
+            watch: false,
+            dry_run: false,
             objects: vec![],
+            input_files: vec![],
+            input_binaries: vec![],
+            profraws: vec![],
+            baseline: None,
+            fail_on_decrease: false,
+            fail_on_empty_binary: false,
+            decrease_tolerance: None,
+            warn_if_hits_below: None,
+            print_summary_table: false,
+            hotspot_limit: None,
+            post_report_command: None,
+            post_report_required: false,
+            cover_build_scripts: false,
             profraw_folder: PathBuf::from("profraws"),
         }
     }
@@ -261,18 +559,75 @@ impl Default for Config {
 
 impl<'a> From<&'a ArgMatches<'a>> for ConfigWrapper {
     fn from(args: &'a ArgMatches<'a>) -> Self {
+        match Self::load(args) {
+            Ok(configs) => configs,
+            Err(e) => {
+                warn!("{}. Falling back to provided args", e);
+                Self(vec![Config::from_args(args)])
+            }
+        }
+    }
+}
+
+impl ConfigWrapper {
+    /// Creates the configs from the command line arguments and any config file they use,
+    /// returning an error if the config file can't be loaded
+    pub fn load(args: &ArgMatches) -> Result<Self, ConfigError> {
+        let mut args_config = Config::from_args(args);
+        args_config.expand_package_globs();
+        if let Some(conflict) = args_config.remove_excluded_packages() {
+            warn!("{}", conflict);
+        }
+        let mut configs = if args.is_present("ignore-config") {
+            Self(vec![args_config])
+        } else if args.is_present("config") {
+            let mut path = PathBuf::from(args.value_of("config").unwrap());
+            if path.is_relative() {
+                path = env::current_dir().unwrap().join(path);
+            }
+            let path = path
+                .canonicalize()
+                .map_err(|e| ConfigError::read(&path, e))?;
+            let confs = Config::load_config_file(path);
+            Config::get_config_vec(confs, args_config)?
+        } else if let cfgs @ [_, ..] = args_config.check_for_configs().as_slice() {
+            let confs = Config::load_config_files(cfgs);
+            Config::get_config_vec(confs, args_config)?
+        } else if let Some(confs) = Config::load_manifest_config(args_config.manifest()) {
+            Config::get_config_vec(confs, args_config)?
+        } else {
+            Self(vec![args_config])
+        };
+        for config in &mut configs.0 {
+            config.normalize_cargo_flags();
+            config.check_manifest()?;
+            config.check_profile()?;
+            config.check_line_patterns()?;
+            config.check_profraws()?;
+            config.check_shutdown_signal()?;
+            config.check_shard()?;
+            config.check_engine_options()?;
+            config.check_cfgs()?;
+            config.file_thresholds()?;
+            for warning in feature_warnings(config) {
+                warn!("{}", warning);
+            }
+        }
+        Ok(configs)
+    }
+}
+
+impl Config {
+    /// Creates a config from only the command line arguments
+    fn from_args(args: &ArgMatches) -> Self {
         info!("Creating config");
         let debug = args.is_present("debug");
         let dump_traces = debug || args.is_present("dump-traces");
         let verbose = args.is_present("verbose") || debug;
         let excluded_files = get_excluded(args);
         let excluded_files_raw = get_list(args, "exclude-files");
-        let features = get_list(args, "features");
-        let features = if features.is_empty() {
-            None
-        } else {
-            Some(features.join(" "))
-        };
+        let included_files_raw = get_list(args, "include-files");
+        let features = get_features(args, "features");
         let force_clean = match (
             args.is_present("force-clean"),
             args.is_present("skip-clean"),
@@ -287,7 +642,7 @@ impl<'a> From<&'a ArgMatches<'a>> for ConfigWrapper {
 
         let engine = value_t!(args.value_of("engine"), TraceEngine).unwrap_or_default();
 
-        let args_config = Config {
+        Config {
             name: String::new(),
             manifest: get_manifest(args),
             config: None,
@@ -296,6 +651,7 @@ impl<'a> From<&'a ArgMatches<'a>> for ConfigWrapper {
             command: value_t!(args.value_of("command"), Mode).unwrap_or(Mode::Test),
             color: value_t!(args.value_of("color"), Color).unwrap_or(Color::Auto),
             run_types: get_run_types(args),
+            run_doctests: args.is_present("run-doctests"),
             run_ignored: args.is_present("ignored"),
             include_tests: args.is_present("include-tests"),
             ignore_panics: args.is_present("ignore-panics"),
@@ -304,69 +660,112 @@ impl<'a> From<&'a ArgMatches<'a>> for ConfigWrapper {
             skip_clean: !force_clean,
             no_fail_fast: args.is_present("no-fail-fast"),
             follow_exec: args.is_present("follow-exec"),
+            follow_forks: args.is_present("follow-forks"),
             verbose,
             debug,
+            quiet: args.is_present("quiet"),
             dump_traces,
+            dump_trace_map: get_absolute_path(args, "dump-trace-map"),
             count: args.is_present("count"),
             line_coverage: get_line_cov(args),
             branch_coverage: get_branch_cov(args),
             generate: get_outputs(args),
+            summary_only: args.is_present("summary-only"),
+            report_unsafe: args.is_present("report-unsafe"),
+            granularity: value_t!(args.value_of("granularity"), Granularity)
+                .unwrap_or(Granularity::Line),
             output_directory: get_output_directory(args),
+            output_files: HashMap::new(),
+            relative_paths: !args.is_present("absolute-paths"),
+            path_prefix: args.value_of("path-prefix").map(PathBuf::from),
             coveralls: get_coveralls(args),
             ci_tool: get_ci(args),
             report_uri: get_report_uri(args),
+            changed_since: get_changed_since(args),
             forward_signals: true, // No longer an option
             all_features: args.is_present("all-features"),
             no_default_features: args.is_present("no-default-features"),
             features,
+            disable_features: get_features(args, "disable-features"),
             unstable_features: get_list(args, "Z"),
+            cfg: get_list(args, "cfg"),
             all: args.is_present("all") | args.is_present("workspace"),
             packages: get_list(args, "packages"),
             exclude: get_list(args, "exclude"),
+            instrument_coverage_packages: get_list(args, "instrument-coverage-packages"),
+            instrument_deps: args.is_present("instrument-deps"),
             excluded_files: RefCell::new(excluded_files),
             excluded_files_raw,
+            included_files: RefCell::new(globs_from_excluded(&included_files_raw)),
+            included_files_raw,
+            excluded_lines: RefCell::new(None),
+            exclude_line_patterns: get_list(args, "exclude-line-patterns"),
+            no_gitignore: args.is_present("no-gitignore"),
+            exclude_generated: !args.is_present("include-generated"),
+            exclude_macro_lines: !args.is_present("include-macro-lines"),
+            exclude_macros: get_list(args, "exclude-macros"),
+            exclude_modules: get_list(args, "exclude-modules"),
             varargs: get_list(args, "args"),
+            test_filter: args.value_of("test-filter").map(ToString::to_string),
+            target_runner: args.value_of("target-runner").map(ToString::to_string),
+            llvm_profdata_path: args.value_of("llvm-profdata-path").map(PathBuf::from),
+            llvm_cov_path: args.value_of("llvm-cov-path").map(PathBuf::from),
+            env: HashMap::new(),
+            env_remove: vec![],
             test_timeout: get_timeout(args),
             release: args.is_present("release"),
             no_run: args.is_present("no-run"),
-            locked: args.is_present("locked"),
+            locked: args.is_present("locked").then_some(true),
             frozen: args.is_present("frozen"),
-            target: get_target(args),
+            target: get_list(args, "target"),
             target_dir: get_target_dir(args),
-            offline: args.is_present("offline"),
+            offline: args.is_present("offline").then_some(true),
             test_names: get_list(args, "test").iter().cloned().collect(),
             bin_names: get_list(args, "bin").iter().cloned().collect(),
             bench_names: get_list(args, "bench").iter().cloned().collect(),
             example_names: get_list(args, "example").iter().cloned().collect(),
+            run_examples: get_list(args, "run-example").iter().cloned().collect(),
+            example_args: HashMap::new(),
             fail_under: value_t!(args.value_of("fail-under"), f64).ok(),
+            exit_code_on_threshold: value_t!(args.value_of("exit-code-on-threshold"), i32).ok(),
+            fail_under_per_package: HashMap::new(),
+            coverage_thresholds: get_absolute_path(args, "coverage-thresholds"),
             jobs: value_t!(args.value_of("jobs"), usize).ok(),
             profile: get_profile(args),
             metadata: RefCell::new(None),
+            resolved_features: RefCell::new(None),
             avoid_cfg_tarpaulin: args.is_present("avoid-cfg-tarpaulin"),
             implicit_test_threads: args.is_present("implicit-test-threads"),
+            test_threads: value_t!(args.value_of("test-threads"), usize).ok(),
             rustflags: get_rustflags(args),
             post_test_delay: get_post_test_delay(args),
+            graceful_shutdown_signal: args
+                .value_of("graceful-shutdown-signal")
+                .map(ToString::to_string),
+            shutdown_timeout: get_shutdown_timeout(args),
+            retries: value_t!(args.value_of("retries"), usize).unwrap_or_default(),
+            run_count: value_t!(args.value_of("run-count"), usize).unwrap_or(1),
+            shard_index: value_t!(args.value_of("shard-index"), usize).ok(),
+            shard_count: value_t!(args.value_of("shard-count"), usize).ok(),
+            watch: args.is_present("watch"),
+            dry_run: args.is_present("dry-run"),
             objects: get_objects(args),
+            input_files: get_input_files(args),
+            input_binaries: get_absolute_paths(args, "input-binaries"),
+            profraws: get_absolute_paths(args, "profraws"),
+            baseline: get_absolute_path(args, "baseline"),
+            fail_on_decrease: args.is_present("fail-on-decrease"),
+            fail_on_empty_binary: args.is_present("fail-on-empty-binary"),
+            decrease_tolerance: value_t!(args.value_of("decrease-tolerance"), f64).ok(),
+            warn_if_hits_below: value_t!(args.value_of("warn-if-hits-below"), u64).ok(),
+            print_summary_table: args.is_present("print-summary-table"),
+            hotspot_limit: value_t!(args.value_of("hotspot-limit"), usize).ok(),
+            post_report_command: args
+                .value_of("post-report-command")
+                .map(ToString::to_string),
+            post_report_required: args.is_present("post-report-required"),
+            cover_build_scripts: args.is_present("cover-build-scripts"),
             profraw_folder: PathBuf::from("profraws"),
-        };
-        if args.is_present("ignore-config") {
-            Self(vec![args_config])
-        } else if args.is_present("config") {
-            let mut path = PathBuf::from(args.value_of("config").unwrap());
-            if path.is_relative() {
-                path = env::current_dir()
-                    .unwrap()
-                    .join(path)
-                    .canonicalize()
-                    .unwrap();
-            }
-            let confs = Config::load_config_file(path);
-            Config::get_config_vec(confs, args_config)
-        } else if let Some(cfg) = args_config.check_for_configs() {
-            let confs = Config::load_config_file(cfg);
-            Config::get_config_vec(confs, args_config)
-        } else {
-            Self(vec![args_config])
         }
     }
 }
@@ -392,11 +791,190 @@ impl Config {
         self.engine.replace(engine);
     }
 
+    /// Checks the selected engine can be used on this platform with the toolchain building the
+    /// project. `Auto` picks llvm if it can be used and ptrace otherwise
+    pub fn check_engine(&self) -> Result<(), RunError> {
+        let llvm = || {
+            if !supports_llvm_coverage() {
+                Err("The llvm engine needs rust 1.60 or newer, update the toolchain with `rustup update`".to_string())
+            } else if !has_profiler_runtime(self) {
+                Err("The toolchain is missing the profiler runtime needed by the llvm engine. Use a toolchain installed with rustup, or build rust with `profiler = true`".to_string())
+            } else {
+                Ok(())
+            }
+        };
+        let ptrace = || {
+            if TraceEngine::supported().contains(&TraceEngine::Ptrace) {
+                Ok(())
+            } else {
+                Err("The ptrace engine is only supported on x86_64 linux, use `--engine llvm` instead".to_string())
+            }
+        };
+        let engine = *self.engine.borrow();
+        let runner = self.per_target().iter().any(|x| x.runner().is_some());
+        let result = match engine {
+            TraceEngine::Llvm => llvm().map_err(RunError::Engine),
+            TraceEngine::Ptrace if runner => Err(RunError::Engine(
+                "The ptrace engine can't trace tests through a target runner, use `--engine llvm` instead".to_string(),
+            )),
+            TraceEngine::Ptrace if self.cover_build_scripts => Err(RunError::Engine(
+                "The ptrace engine can't collect coverage from build scripts, use `--engine llvm` instead".to_string(),
+            )),
+            TraceEngine::Ptrace => ptrace().map_err(RunError::Engine),
+            TraceEngine::Auto if runner || self.cover_build_scripts => {
+                llvm().map_err(RunError::Engine)?;
+                self.set_engine(TraceEngine::Llvm);
+                Ok(())
+            }
+            TraceEngine::Auto => match (llvm(), ptrace()) {
+                (Ok(()), _) => {
+                    self.set_engine(TraceEngine::Llvm);
+                    Ok(())
+                }
+                (Err(e), Ok(())) => {
+                    info!("{}, using the ptrace engine", e);
+                    self.set_engine(TraceEngine::Ptrace);
+                    Ok(())
+                }
+                (Err(e), Err(_)) => Err(RunError::Engine(e)),
+            },
+        };
+        if engine == TraceEngine::Auto && result.is_ok() {
+            let options = self.incompatible_options(self.engine());
+            if !options.is_empty() {
+                warn!(
+                    "{} are ignored by the {} engine",
+                    options.join(", "),
+                    self.engine().to_string().to_lowercase()
+                );
+            }
+        }
+        result
+    }
+
+    /// Options set in this config which are only used by the other engine, or which the engine
+    /// can't use on this platform. `post-test-delay` is used by both engines
+    pub fn incompatible_options(&self, engine: TraceEngine) -> Vec<&'static str> {
+        let options = match engine {
+            TraceEngine::Ptrace => vec![
+                ("objects", !self.objects.is_empty()),
+                (
+                    "instrument-coverage-packages",
+                    !self.instrument_coverage_packages.is_empty(),
+                ),
+                ("instrument-deps", self.instrument_deps),
+                ("granularity", self.granularity != Granularity::Line),
+                ("llvm-profdata-path", self.llvm_profdata_path.is_some()),
+                ("llvm-cov-path", self.llvm_cov_path.is_some()),
+                ("profraws", !self.profraws.is_empty()),
+                // Forks are only followed with ptrace on Linux
+                (
+                    "follow-forks",
+                    self.follow_forks && !cfg!(target_os = "linux"),
+                ),
+            ],
+            TraceEngine::Llvm => vec![("follow-forks", self.follow_forks)],
+            TraceEngine::Auto => vec![],
+        };
+        options
+            .into_iter()
+            .filter_map(|(option, set)| set.then_some(option))
+            .collect()
+    }
+
+    /// Checks the selected engine uses all of the options set, this is checked after merging as
+    /// the engine and options may come from different configs. The `Auto` engine is only
+    /// resolved when tarpaulin runs so options it ignores are a warning in `check_engine`
+    pub fn check_engine_options(&self) -> Result<(), ConfigError> {
+        let engine = *self.engine.borrow();
+        let options = self.incompatible_options(engine);
+        if options.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::IncompatibleOptions {
+                name: self.name.clone(),
+                engine,
+                options,
+            })
+        }
+    }
+
+    /// Number of jobs to build the tests with, `jobs` if it's set and otherwise
+    /// `CARGO_BUILD_JOBS`. A value of 0 is the number of CPUs and `None` leaves it to cargo
+    pub fn jobs(&self) -> Option<usize> {
+        resolve_jobs(self.jobs, env::var("CARGO_BUILD_JOBS").ok().as_deref())
+    }
+
+    /// Time waited for the profiles to be written after a test exits with the llvm engine,
+    /// `post_test_delay` if it's set and otherwise 1 second
+    pub fn profile_delay(&self) -> Duration {
+        self.post_test_delay.unwrap_or(Duration::from_secs(1))
+    }
+
+    /// Number of test executables to run at the same time. This uses the number of jobs but only
+    /// the llvm engine can run executables in parallel. Ptrace follows each test with `waitpid`
+    /// on any child of the process so would need a tracer thread per executable that only waits
+    /// on its own children, as well as making the fork and working directory changes thread-safe.
+    pub fn test_jobs(&self) -> usize {
+        if self.engine() == TraceEngine::Llvm && !self.dump_traces {
+            self.jobs().unwrap_or(1).max(1)
+        } else {
+            1
+        }
+    }
+
     pub fn set_clean(&mut self, clean: bool) {
         self.force_clean = clean;
         self.skip_clean = !clean;
     }
 
+    /// Don't update `Cargo.lock`
+    pub fn locked(&self) -> bool {
+        self.frozen || self.locked == Some(true)
+    }
+
+    /// Build without accessing the network
+    pub fn offline(&self) -> bool {
+        self.frozen || self.offline == Some(true)
+    }
+
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = Some(locked);
+    }
+
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = Some(offline);
+    }
+
+    /// The `--frozen`, `--locked` and `--offline` flags to pass to cargo. `frozen` is the other
+    /// two flags together so they're all passed when it's set
+    pub fn cargo_lock_flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if self.frozen {
+            flags.push("--frozen".to_string());
+        }
+        if self.locked() {
+            flags.push("--locked".to_string());
+        }
+        if self.offline() {
+            flags.push("--offline".to_string());
+        }
+        flags
+    }
+
+    /// `frozen` forces `locked` and `offline` on, with a warning if either was set to false
+    pub fn normalize_cargo_flags(&mut self) {
+        if !self.frozen {
+            return;
+        }
+        for (name, flag) in [("locked", &mut self.locked), ("offline", &mut self.offline)] {
+            if *flag == Some(false) {
+                warn!("frozen implies {} so `{} = false` is ignored", name, name);
+            }
+            *flag = Some(true);
+        }
+    }
+
     pub fn set_include_tests(&mut self, include: bool) {
         self.include_tests = include;
     }
@@ -411,9 +989,18 @@ impl Config {
         self.force_clean && !self.skip_clean
     }
 
+    /// Directory build artefacts are placed in. In order of precedence this is the explicitly
+    /// set target directory, `CARGO_TARGET_DIR`, then the target directory cargo reports.
     pub fn target_dir(&self) -> PathBuf {
         let res = if let Some(s) = &self.target_dir {
             s.clone()
+        } else if let Some(dir) = env::var_os("CARGO_TARGET_DIR").filter(|x| !x.is_empty()) {
+            let dir = PathBuf::from(dir);
+            if dir.is_relative() {
+                env::current_dir().unwrap_or_default().join(dir)
+            } else {
+                dir
+            }
         } else {
             match *self.get_metadata() {
                 Some(ref meta) => PathBuf::from(meta.target_directory.clone()),
@@ -428,6 +1015,14 @@ impl Config {
         fix_unc_path(&res)
     }
 
+    /// Where the profiles written by build scripts go when `cover_build_scripts` is set, kept
+    /// apart from the test profiles so they're never mistaken for a test's
+    pub fn build_script_profraw_dir(&self) -> PathBuf {
+        self.target_dir()
+            .join("tarpaulin")
+            .join("build-script-profraws")
+    }
+
     /// Get directory profraws are stored in
     pub fn profraw_dir(&self) -> PathBuf {
         if self.profraw_folder.is_relative() {
@@ -459,7 +1054,11 @@ impl Config {
 
     pub(crate) fn get_metadata(&self) -> Ref<Option<Metadata>> {
         if self.metadata.borrow().is_none() {
-            match MetadataCommand::new().manifest_path(&self.manifest).exec() {
+            match MetadataCommand::new()
+                .manifest_path(&self.manifest)
+                .other_options(self.cargo_lock_flags())
+                .exec()
+            {
                 Ok(meta) => {
                     self.metadata.replace(Some(meta));
                 }
@@ -496,6 +1095,34 @@ impl Config {
         }
     }
 
+    /// Paths holding the integration tests of the workspace members, these are the `tests`
+    /// directory of each package and the source of any test targets outside of it. When
+    /// `include_tests` isn't set code under these paths is excluded from the results
+    pub fn integration_test_paths(&self) -> Vec<PathBuf> {
+        match *self.get_metadata() {
+            Some(ref meta) => {
+                let mut paths = vec![];
+                for package in meta
+                    .packages
+                    .iter()
+                    .filter(|x| meta.workspace_members.contains(&x.id))
+                {
+                    if let Some(dir) = package.manifest_path.parent() {
+                        paths.push(fix_unc_path(dir.join("tests").as_std_path()));
+                    }
+                    for target in package.targets.iter().filter(|x| x.is_test()) {
+                        let target = fix_unc_path(target.src_path.as_std_path());
+                        if !paths.iter().any(|x| target.starts_with(x)) {
+                            paths.push(target);
+                        }
+                    }
+                }
+                paths
+            }
+            None => vec![self.root().join("tests")],
+        }
+    }
+
     pub fn output_dir(&self) -> PathBuf {
         let path = if let Some(ref path) = self.output_directory {
             if path.is_relative() {
@@ -509,31 +1136,177 @@ impl Config {
         fix_unc_path(&path)
     }
 
-    pub fn get_config_vec(file_configs: std::io::Result<Vec<Self>>, backup: Self) -> ConfigWrapper {
-        if let Ok(mut confs) = file_configs {
-            for c in &mut confs {
-                c.merge(&backup);
-            }
-            if confs.is_empty() {
-                ConfigWrapper(vec![backup])
-            } else {
-                ConfigWrapper(confs)
+    /// Path the report for the given format is written to, `None` for formats which aren't
+    /// written to a file
+    pub fn output_file(&self, format: OutputFile) -> Option<PathBuf> {
+        let path = match self.output_files.get(&format) {
+            Some(path) => path.clone(),
+            None => PathBuf::from(format.default_file_name()?),
+        };
+        if path.is_relative() {
+            Some(self.output_dir().join(path))
+        } else {
+            Some(fix_unc_path(&path))
+        }
+    }
+
+    /// Whether the output of the tests is read to get the results of each test for the JUnit
+    /// report, or the number of tests each executable ran for `fail-on-empty-binary`
+    pub fn capture_test_output(&self) -> bool {
+        self.write_junit() || self.fail_on_empty_binary
+    }
+
+    /// Whether the JUnit report of the tests is written
+    pub fn write_junit(&self) -> bool {
+        !self.summary_only && self.generate.contains(&OutputFile::Junit)
+    }
+
+    pub fn get_config_vec(
+        file_configs: Result<Vec<Self>, ConfigError>,
+        backup: Self,
+    ) -> Result<ConfigWrapper, ConfigError> {
+        let mut confs = file_configs?;
+        for c in &mut confs {
+            c.merge(&backup);
+        }
+        if confs.is_empty() {
+            Ok(ConfigWrapper(vec![backup]))
+        } else {
+            Ok(ConfigWrapper(confs))
+        }
+    }
+
+    /// Checks the custom cfgs can be passed in `RUSTFLAGS`, which cargo splits on whitespace
+    pub fn check_cfgs(&self) -> Result<(), ConfigError> {
+        match self
+            .cfg
+            .iter()
+            .find(|x| x.is_empty() || x.contains(char::is_whitespace))
+        {
+            Some(cfg) => Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: format!("cfg `{cfg}` is empty or contains whitespace"),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the manifest the project is built from exists
+    pub fn check_manifest(&self) -> Result<(), ConfigError> {
+        if self.manifest.is_file() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: format!("no cargo manifest found at {}", self.manifest.display()),
+            })
+        }
+    }
+
+    /// Checks the profile exists, either as one of cargo's built in profiles or in a
+    /// `[profile.*]` table in the manifest, workspace root manifest or a cargo config file.
+    /// If none of these files can be read the check is skipped and left to cargo
+    pub fn check_profile(&self) -> Result<(), ConfigError> {
+        const BUILT_IN: [&str; 4] = ["dev", "release", "test", "bench"];
+        let profile = match self.profile.as_ref() {
+            Some(profile) => profile,
+            None => return Ok(()),
+        };
+        if self.release {
+            warn!(
+                "release and profile {} are both set, cargo doesn't allow these to be used together",
+                profile
+            );
+        }
+        if BUILT_IN.contains(&profile.as_str()) {
+            return Ok(());
+        }
+        let root = self.root();
+        let mut files = vec![self.manifest(), root.join("Cargo.toml")];
+        for dir in root.ancestors() {
+            files.push(dir.join(".cargo").join("config.toml"));
+            files.push(dir.join(".cargo").join("config"));
+        }
+        let buffers = files
+            .iter()
+            .filter_map(|x| fs::read_to_string(x).ok())
+            .collect::<Vec<_>>();
+        if buffers.is_empty() {
+            return Ok(());
+        }
+        let mut available = BUILT_IN.iter().map(ToString::to_string).collect::<Vec<_>>();
+        for profile in buffers.iter().flat_map(|x| declared_profiles(x)) {
+            if !available.contains(&profile) {
+                available.push(profile);
             }
+        }
+        if available.contains(profile) {
+            Ok(())
         } else {
-            warn!("Failed to deserialize config file falling back to provided args");
-            ConfigWrapper(vec![backup])
+            Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: format!(
+                    "profile {} doesn't exist, available profiles are: {}",
+                    profile,
+                    available.join(", ")
+                ),
+            })
+        }
+    }
+
+    /// The command and arguments to run the test executables with. This is `target-runner`, or
+    /// with a single target `CARGO_TARGET_<TRIPLE>_RUNNER` then `target.<triple>.runner` from
+    /// the closest cargo config file like cargo uses for `cargo run` and `cargo test`
+    pub fn runner(&self) -> Option<Vec<String>> {
+        self.runner_with_env(|var| env::var(var).ok())
+    }
+
+    /// [`Config::runner`] reading the environment variables with `var`
+    fn runner_with_env(&self, var: impl Fn(&str) -> Option<String>) -> Option<Vec<String>> {
+        if let Some(runner) = self.target_runner.as_ref() {
+            return split_runner(runner);
+        }
+        let target = match self.target.as_slice() {
+            [target] => target,
+            _ => return None,
+        };
+        let name = format!(
+            "CARGO_TARGET_{}_RUNNER",
+            target.to_ascii_uppercase().replace(['-', '.'], "_")
+        );
+        if let Some(runner) = var(&name).filter(|x| !x.trim().is_empty()) {
+            return split_runner(&runner);
         }
+        self.root()
+            .ancestors()
+            .flat_map(|dir| {
+                let dir = dir.join(".cargo");
+                [dir.join("config.toml"), dir.join("config")]
+            })
+            .filter_map(|x| fs::read_to_string(x).ok())
+            .find_map(|x| declared_runner(&x, target))
     }
 
-    /// Taking an existing config look for any relevant config files
-    pub fn check_for_configs(&self) -> Option<PathBuf> {
-        if let Some(root) = &self.root {
-            Self::check_path_for_configs(root)
-        } else if let Some(root) = self.manifest.clone().parent() {
-            Self::check_path_for_configs(root)
+    /// Taking an existing config look for any relevant config files. The search starts in the
+    /// project directory and walks up to the workspace root, the closest config file is first
+    pub fn check_for_configs(&self) -> Vec<PathBuf> {
+        let start = if let Some(root) = &self.root {
+            fix_unc_path(root)
+        } else if let Some(root) = self.manifest().parent() {
+            root.to_path_buf()
         } else {
-            None
+            return vec![];
+        };
+        let workspace = self.root();
+        let mut result = vec![];
+        for dir in start.ancestors() {
+            result.extend(Self::check_path_for_configs(dir));
+            // Only the project directory is searched if it's outside of the workspace
+            if dir == workspace || !dir.starts_with(&workspace) {
+                break;
+            }
         }
+        result
     }
 
     fn check_path_for_configs<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
@@ -550,57 +1323,183 @@ impl Config {
         }
     }
 
-    pub fn load_config_file<P: AsRef<Path>>(file: P) -> std::io::Result<Vec<Self>> {
-        let buffer = fs::read_to_string(file.as_ref())?;
-        let mut res = Self::parse_config_toml(&buffer);
-        let parent = match file.as_ref().parent() {
+    /// Loads several config files, configs with the same name are merged with configs from
+    /// files earlier in the list taking priority
+    pub fn load_config_files(files: &[PathBuf]) -> Result<Vec<Self>, ConfigError> {
+        let mut result: Vec<Self> = vec![];
+        for file in files.iter().rev() {
+            for config in Self::load_config_file(file)? {
+                match result.iter_mut().find(|x| x.name == config.name) {
+                    Some(existing) => existing.merge(&config),
+                    None => result.push(config),
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn load_config_file<P: AsRef<Path>>(file: P) -> Result<Vec<Self>, ConfigError> {
+        let file = file.as_ref();
+        let buffer = fs::read_to_string(file).map_err(|e| ConfigError::read(file, e))?;
+        let mut cfs = Self::parse_config_toml(&buffer).map_err(|e| e.in_file(file))?;
+        Self::set_config_source(&mut cfs, file);
+        Ok(cfs)
+    }
+
+    /// Loads configs from a `[tool.tarpaulin]` or `[package.metadata.tarpaulin]` table in the
+    /// given manifest. Returns `None` if the manifest can't be read or has no such table.
+    pub fn load_manifest_config<P: AsRef<Path>>(
+        manifest: P,
+    ) -> Option<Result<Vec<Self>, ConfigError>> {
+        let manifest = manifest.as_ref();
+        let buffer = fs::read_to_string(manifest).ok()?;
+        let res = Self::parse_manifest_toml(&buffer)?.map(|mut cfs| {
+            Self::set_config_source(&mut cfs, manifest);
+            cfs
+        });
+        Some(res.map_err(|e| e.in_file(manifest)))
+    }
+
+    /// Records the file the configs came from and makes any paths in them relative to it
+    fn set_config_source(configs: &mut [Self], file: &Path) {
+        let parent = match file.parent() {
             Some(p) => p.to_path_buf(),
             None => PathBuf::new(),
         };
-        if let Ok(cfs) = res.as_mut() {
-            for mut c in cfs.iter_mut() {
-                c.config = Some(file.as_ref().to_path_buf());
-                c.manifest = make_absolute_with_parent(&c.manifest, &parent);
-                if let Some(root) = c.root.as_mut() {
-                    *root = make_absolute_with_parent(&root, &parent);
-                }
-                if let Some(root) = c.output_directory.as_mut() {
-                    *root = make_absolute_with_parent(&root, &parent);
-                }
-                if let Some(root) = c.target_dir.as_mut() {
-                    *root = make_absolute_with_parent(&root, &parent);
-                }
+        for c in configs.iter_mut() {
+            c.config = Some(file.to_path_buf());
+            c.manifest = make_absolute_with_parent(&c.manifest, &parent);
+            if let Some(root) = c.root.as_mut() {
+                *root = make_absolute_with_parent(&root, &parent);
+            }
+            if let Some(root) = c.output_directory.as_mut() {
+                *root = make_absolute_with_parent(&root, &parent);
+            }
+            if let Some(root) = c.target_dir.as_mut() {
+                *root = make_absolute_with_parent(&root, &parent);
+            }
+            for input in c.input_files.iter_mut() {
+                *input = make_absolute_with_parent(&input, &parent);
+            }
+            for input in c.input_binaries.iter_mut() {
+                *input = make_absolute_with_parent(&input, &parent);
+            }
+            for input in c.profraws.iter_mut() {
+                *input = make_absolute_with_parent(&input, &parent);
+            }
+            if let Some(baseline) = c.baseline.as_mut() {
+                *baseline = make_absolute_with_parent(&baseline, &parent);
+            }
+            if let Some(thresholds) = c.coverage_thresholds.as_mut() {
+                *thresholds = make_absolute_with_parent(&thresholds, &parent);
+            }
+            if let Some(dump) = c.dump_trace_map.as_mut() {
+                *dump = make_absolute_with_parent(&dump, &parent);
             }
         }
-        res
     }
 
-    pub fn parse_config_toml(buffer: &str) -> std::io::Result<Vec<Self>> {
-        let mut map: IndexMap<String, Self> = toml::from_str(buffer).map_err(|e| {
-            error!("Invalid config file {}", e);
-            Error::new(ErrorKind::InvalidData, format!("{e}"))
-        })?;
+    pub fn parse_config_toml(buffer: &str) -> Result<Vec<Self>, ConfigError> {
+        let map: IndexMap<String, Self> =
+            serde_ignored::deserialize(toml::Deserializer::new(buffer), |path| {
+                warn!("Ignoring unknown config key: {}", path)
+            })
+            .map_err(|e| ConfigError::parse(&e, Some(buffer)))?;
+        Self::named_configs(map)
+    }
+
+    /// Parses the tarpaulin table out of a Cargo.toml. The table can either hold config options
+    /// directly or, like a tarpaulin.toml, a series of named config tables.
+    pub fn parse_manifest_toml(buffer: &str) -> Option<Result<Vec<Self>, ConfigError>> {
+        // If the manifest is invalid cargo will report it later on
+        let manifest: toml::Table = toml::from_str(buffer).ok()?;
+        let table = manifest
+            .get("tool")
+            .and_then(|x| x.get("tarpaulin"))
+            .or_else(|| {
+                manifest
+                    .get("package")
+                    .and_then(|x| x.get("metadata"))
+                    .and_then(|x| x.get("tarpaulin"))
+            })?
+            .clone();
+        let is_single = match table {
+            toml::Value::Table(ref t) => t.values().any(|x| !x.is_table()),
+            _ => true,
+        };
+        let res = if is_single {
+            serde_ignored::deserialize(table, |path| warn!("Ignoring unknown config key: {}", path))
+                .map(|conf: Self| {
+                    let mut map = IndexMap::new();
+                    map.insert(String::new(), conf);
+                    map
+                })
+        } else {
+            serde_ignored::deserialize(table, |path| warn!("Ignoring unknown config key: {}", path))
+        };
+        // The table has lost its position in the manifest so there's no line or column
+        let res = res.map_err(|e| ConfigError::parse(&e, None));
+        Some(res.and_then(Self::named_configs))
+    }
 
+    fn named_configs(mut map: IndexMap<String, Self>) -> Result<Vec<Self>, ConfigError> {
         let mut result = Vec::new();
-        for (name, mut conf) in map.iter_mut() {
+        for (name, conf) in map.iter_mut() {
             conf.name = name.to_string();
+            conf.validate()?;
             result.push(conf.clone());
         }
-        if result.is_empty() {
-            Err(Error::new(ErrorKind::InvalidData, "No config tables"))
-        } else {
-            Ok(result)
+        Ok(result)
+    }
+
+    /// Checks options which deserialize fine but can't be used together or are out of range
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| ConfigError::Invalid {
+            name: self.name.clone(),
+            reason,
+        };
+        if self.test_threads == Some(0) {
+            return Err(invalid("test-threads must be greater than 0".to_string()));
+        }
+        let thresholds = self
+            .fail_under
+            .iter()
+            .map(|x| ("fail-under".to_string(), *x))
+            .chain(
+                self.fail_under_per_package
+                    .iter()
+                    .map(|(k, v)| (format!("fail-under-per-package.{k}"), *v)),
+            )
+            .chain(
+                self.decrease_tolerance
+                    .iter()
+                    .map(|x| ("decrease-tolerance".to_string(), *x)),
+            );
+        for (option, threshold) in thresholds {
+            if !(0.0..=100.0).contains(&threshold) {
+                return Err(invalid(format!(
+                    "{option} must be a percentage between 0 and 100, found {threshold}"
+                )));
+            }
         }
+        Ok(())
     }
 
     /// Given a config made from args ignoring the config file take the
-    /// relevant settings that should be carried across and move them
+    /// relevant settings that should be carried across and move them. `other` is the higher
+    /// priority config so its optional values replace the ones set in `self`
     pub fn merge(&mut self, other: &Config) {
         if other.debug {
             self.debug = other.debug;
             self.verbose = other.verbose;
+            self.quiet = false;
         } else if other.verbose {
             self.verbose = other.verbose;
+            self.quiet = false;
+        } else if other.quiet {
+            self.debug = false;
+            self.verbose = false;
+            self.quiet = true;
         }
         self.no_run |= other.no_run;
         self.no_default_features |= other.no_default_features;
@@ -613,10 +1512,16 @@ impl Config {
         self.count |= other.count;
         self.all_features |= other.all_features;
         self.implicit_test_threads |= other.implicit_test_threads;
+        self.instrument_deps |= other.instrument_deps;
+        self.follow_forks |= other.follow_forks;
         self.line_coverage |= other.line_coverage;
         self.branch_coverage |= other.branch_coverage;
         self.dump_traces |= other.dump_traces;
-        self.offline |= other.offline;
+        self.dump_trace_map =
+            Config::pick_optional_config(&self.dump_trace_map, &other.dump_trace_map);
+        // Unset is less than false so an explicit value from either config is kept, and true
+        // wins over false like the other flags
+        self.offline = self.offline.max(other.offline);
         if self.manifest != other.manifest && self.manifest == default_manifest() {
             self.manifest = other.manifest.clone();
         }
@@ -625,23 +1530,84 @@ impl Config {
                 self.objects.push(obj.clone());
             }
         }
+        for input in &other.input_files {
+            if !self.input_files.contains(input) {
+                self.input_files.push(input.clone());
+            }
+        }
+        for input in &other.input_binaries {
+            if !self.input_binaries.contains(input) {
+                self.input_binaries.push(input.clone());
+            }
+        }
+        for input in &other.profraws {
+            if !self.profraws.contains(input) {
+                self.profraws.push(input.clone());
+            }
+        }
+        self.baseline = Config::pick_optional_config(&self.baseline, &other.baseline);
+        self.test_filter = Config::pick_optional_config(&self.test_filter, &other.test_filter);
+        self.target_runner =
+            Config::pick_optional_config(&self.target_runner, &other.target_runner);
+        self.llvm_profdata_path =
+            Config::pick_optional_config(&self.llvm_profdata_path, &other.llvm_profdata_path);
+        self.llvm_cov_path =
+            Config::pick_optional_config(&self.llvm_cov_path, &other.llvm_cov_path);
+        self.coverage_thresholds =
+            Config::pick_optional_config(&self.coverage_thresholds, &other.coverage_thresholds);
+        self.fail_on_decrease |= other.fail_on_decrease;
+        self.fail_on_empty_binary |= other.fail_on_empty_binary;
+        self.print_summary_table |= other.print_summary_table;
+        self.no_gitignore |= other.no_gitignore;
+        // Like force_clean true is the default so including generated files in either is
+        // non-default
+        self.exclude_generated &= other.exclude_generated;
+        self.exclude_macro_lines &= other.exclude_macro_lines;
+        self.decrease_tolerance =
+            Config::pick_optional_config(&self.decrease_tolerance, &other.decrease_tolerance);
+        self.warn_if_hits_below =
+            Config::pick_optional_config(&self.warn_if_hits_below, &other.warn_if_hits_below);
+        self.hotspot_limit =
+            Config::pick_optional_config(&self.hotspot_limit, &other.hotspot_limit);
+        self.post_report_command =
+            Config::pick_optional_config(&self.post_report_command, &other.post_report_command);
+        self.post_report_required |= other.post_report_required;
+        self.cover_build_scripts |= other.cover_build_scripts;
         self.root = Config::pick_optional_config(&self.root, &other.root);
         self.coveralls = Config::pick_optional_config(&self.coveralls, &other.coveralls);
         self.ci_tool = Config::pick_optional_config(&self.ci_tool, &other.ci_tool);
         self.report_uri = Config::pick_optional_config(&self.report_uri, &other.report_uri);
-        self.target = Config::pick_optional_config(&self.target, &other.target);
+        self.changed_since =
+            Config::pick_optional_config(&self.changed_since, &other.changed_since);
+        if !other.target.is_empty() {
+            self.target = other.target.clone();
+        }
         self.target_dir = Config::pick_optional_config(&self.target_dir, &other.target_dir);
         self.output_directory =
             Config::pick_optional_config(&self.output_directory, &other.output_directory);
+        for (format, path) in &other.output_files {
+            self.output_files.insert(*format, path.clone());
+        }
+        self.relative_paths &= other.relative_paths;
+        self.path_prefix = Config::pick_optional_config(&self.path_prefix, &other.path_prefix);
         self.all |= other.all;
         self.frozen |= other.frozen;
-        self.locked |= other.locked;
+        self.locked = self.locked.max(other.locked);
         // This is &= because force_clean true is the default. If one is false then that is
         // non-default
         self.force_clean &= other.force_clean;
         self.skip_clean |= other.skip_clean;
         self.include_tests |= other.include_tests;
         self.no_fail_fast |= other.no_fail_fast;
+        self.watch |= other.watch;
+        self.dry_run |= other.dry_run;
+        self.run_doctests |= other.run_doctests;
+        if other.color != Color::Auto {
+            self.color = other.color;
+        }
+        if other.granularity != Granularity::Line {
+            self.granularity = other.granularity;
+        }
 
         let end_delay = match (self.post_test_delay, other.post_test_delay) {
             (Some(d), None) | (None, Some(d)) => Some(d),
@@ -649,6 +1615,16 @@ impl Config {
             (Some(a), Some(b)) => Some(a.max(b)),
         };
         self.post_test_delay = end_delay;
+        self.graceful_shutdown_signal = Config::pick_optional_config(
+            &self.graceful_shutdown_signal,
+            &other.graceful_shutdown_signal,
+        );
+        self.shutdown_timeout =
+            Config::pick_optional_config(&self.shutdown_timeout, &other.shutdown_timeout);
+        self.retries = self.retries.max(other.retries);
+        self.run_count = self.run_count.max(other.run_count);
+        self.shard_index = Config::pick_optional_config(&self.shard_index, &other.shard_index);
+        self.shard_count = Config::pick_optional_config(&self.shard_count, &other.shard_count);
         // The two flags now don't agree, if one is set to non-default then prioritise that
         match (self.force_clean, self.skip_clean) {
             (true, false) | (false, true) => {}
@@ -668,30 +1644,46 @@ impl Config {
         };
         self.rustflags = new_flags;
 
-        if self.jobs.is_none() {
-            self.jobs = other.jobs;
-        }
+        self.jobs = Config::pick_optional_config(&self.jobs, &other.jobs);
+        self.exit_code_on_threshold = Config::pick_optional_config(
+            &self.exit_code_on_threshold,
+            &other.exit_code_on_threshold,
+        );
+        self.test_threads = Config::pick_optional_config(&self.test_threads, &other.test_threads);
         if self.fail_under.is_none()
             || other.fail_under.is_some() && other.fail_under.unwrap() < self.fail_under.unwrap()
         {
             self.fail_under = other.fail_under;
         }
+        // Like `fail_under` the lower of two thresholds for a package is kept
+        for (package, threshold) in &other.fail_under_per_package {
+            self.fail_under_per_package
+                .entry(package.clone())
+                .and_modify(|x| *x = x.min(*threshold))
+                .or_insert(*threshold);
+        }
 
         if other.test_timeout != default_test_timeout() {
             self.test_timeout = other.test_timeout;
         }
 
-        if self.profile.is_none() && other.profile.is_some() {
-            self.profile = other.profile.clone();
-        }
-        if other.features.is_some() {
-            if self.features.is_none() {
-                self.features = other.features.clone();
-            } else if let Some(features) = self.features.as_mut() {
-                features.push(' ');
-                features.push_str(other.features.as_ref().unwrap());
-            }
-        }
+        self.profile = Config::pick_optional_config(&self.profile, &other.profile);
+        let additional_features = other
+            .features
+            .iter()
+            .filter(|feature| !self.features.contains(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.features.extend(additional_features);
+        let additional_disabled = other
+            .disable_features
+            .iter()
+            .filter(|feature| !self.disable_features.contains(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.disable_features.extend(additional_disabled);
+        let disabled = &self.disable_features;
+        self.features.retain(|feature| !disabled.contains(feature));
 
         let additional_packages = other
             .packages
@@ -701,6 +1693,15 @@ impl Config {
             .collect::<Vec<String>>();
         self.packages.extend(additional_packages);
 
+        let additional_instrumented = other
+            .instrument_coverage_packages
+            .iter()
+            .filter(|package| !self.instrument_coverage_packages.contains(package))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.instrument_coverage_packages
+            .extend(additional_instrumented);
+
         let additional_outs = other
             .generate
             .iter()
@@ -708,6 +1709,8 @@ impl Config {
             .copied()
             .collect::<Vec<_>>();
         self.generate.extend(additional_outs);
+        self.summary_only |= other.summary_only;
+        self.report_unsafe |= other.report_unsafe;
 
         let additional_excludes = other
             .exclude
@@ -725,6 +1728,18 @@ impl Config {
             .collect::<Vec<String>>();
         self.varargs.extend(additional_varargs);
 
+        for (key, value) in &other.env {
+            self.env.insert(key.clone(), value.clone());
+        }
+
+        let additional_env_remove = other
+            .env_remove
+            .iter()
+            .filter(|var| !self.env_remove.contains(var))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.env_remove.extend(additional_env_remove);
+
         let additional_z_opts = other
             .unstable_features
             .iter()
@@ -733,14 +1748,34 @@ impl Config {
             .collect::<Vec<String>>();
         self.unstable_features.extend(additional_z_opts);
 
-        let exclude = &self.exclude;
-        self.packages.retain(|package| {
-            let keep = !exclude.contains(package);
-            if !keep {
-                info!("{} is in exclude list removing from packages", package);
-            }
-            keep
-        });
+        let additional_macros = other
+            .exclude_macros
+            .iter()
+            .filter(|x| !self.exclude_macros.contains(x))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.exclude_macros.extend(additional_macros);
+
+        let additional_modules = other
+            .exclude_modules
+            .iter()
+            .filter(|x| !self.exclude_modules.contains(x))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.exclude_modules.extend(additional_modules);
+
+        let additional_cfgs = other
+            .cfg
+            .iter()
+            .filter(|cfg| !self.cfg.contains(cfg))
+            .cloned()
+            .collect::<Vec<String>>();
+        self.cfg.extend(additional_cfgs);
+
+        self.expand_package_globs();
+        if let Some(conflict) = self.remove_excluded_packages() {
+            warn!("{}", conflict);
+        }
 
         for test in &other.test_names {
             self.test_names.insert(test.clone());
@@ -751,6 +1786,12 @@ impl Config {
         for test in &other.example_names {
             self.example_names.insert(test.clone());
         }
+        for example in &other.run_examples {
+            self.run_examples.insert(example.clone());
+        }
+        for (example, args) in &other.example_args {
+            self.example_args.insert(example.clone(), args.clone());
+        }
         for test in &other.bench_names {
             self.bench_names.insert(test.clone());
         }
@@ -768,23 +1809,149 @@ impl Config {
             let mut excluded_files = self.excluded_files.borrow_mut();
             excluded_files.clear();
         }
-    }
-
-    pub fn pick_optional_config<T: Clone>(
-        base_config: &Option<T>,
-        override_config: &Option<T>,
-    ) -> Option<T> {
-        if override_config.is_some() {
-            override_config.clone()
-        } else {
-            base_config.clone()
+        if !other.included_files_raw.is_empty() {
+            let additional_includes = other
+                .included_files_raw
+                .iter()
+                .filter(|x| !self.included_files_raw.contains(x))
+                .cloned()
+                .collect::<Vec<_>>();
+            self.included_files_raw.extend(additional_includes);
+            self.included_files.borrow_mut().clear();
+        }
+        if !other.exclude_line_patterns.is_empty() {
+            let additional_patterns = other
+                .exclude_line_patterns
+                .iter()
+                .filter(|x| !self.exclude_line_patterns.contains(x))
+                .cloned()
+                .collect::<Vec<_>>();
+            self.exclude_line_patterns.extend(additional_patterns);
+            *self.excluded_lines.borrow_mut() = None;
         }
     }
 
-    pub fn objects(&self) -> &[PathBuf] {
-        &self.objects
-    }
-
+    /// Replaces glob patterns in `packages` and `exclude` with the names of the workspace
+    /// members they match. Patterns are matched against the package name and the path of the
+    /// package relative to the workspace root, so `crates/service-*` and `service-*` both work
+    fn expand_package_globs(&mut self) {
+        let is_glob = |x: &String| x.contains(['*', '?', '[']);
+        if !self.packages.iter().chain(self.exclude.iter()).any(is_glob) {
+            return;
+        }
+        let members = match *self.get_metadata() {
+            Some(ref meta) => meta
+                .packages
+                .iter()
+                .filter(|x| meta.workspace_members.contains(&x.id))
+                .map(|x| {
+                    let dir = x
+                        .manifest_path
+                        .parent()
+                        .and_then(|dir| dir.strip_prefix(&meta.workspace_root).ok())
+                        .map(|dir| dir.as_std_path().to_path_buf())
+                        .unwrap_or_default();
+                    (x.name.clone(), dir)
+                })
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let expand = |names: &[String]| {
+            let mut result: Vec<String> = vec![];
+            for name in names {
+                if !is_glob(name) {
+                    if !result.contains(name) {
+                        result.push(name.clone());
+                    }
+                    continue;
+                }
+                let pattern = match glob::Pattern::new(name) {
+                    Ok(pattern) => pattern,
+                    Err(e) => {
+                        error!("Ignoring invalid package pattern '{}': {}", name, e);
+                        continue;
+                    }
+                };
+                let matches = members
+                    .iter()
+                    .filter(|(package, dir)| pattern.matches(package) || pattern.matches_path(dir))
+                    .map(|(package, _)| package)
+                    .collect::<Vec<_>>();
+                if matches.is_empty() {
+                    warn!("No workspace members match the package pattern '{}'", name);
+                }
+                for package in matches {
+                    if !result.contains(package) {
+                        result.push(package.clone());
+                    }
+                }
+            }
+            result
+        };
+        self.packages = expand(&self.packages);
+        self.exclude = expand(&self.exclude);
+    }
+
+    /// Removes packages in the exclude list from the selected packages. If this removes every
+    /// selected package a diagnostic naming the conflict is returned, as cargo would otherwise
+    /// go on to build packages the user didn't select
+    fn remove_excluded_packages(&mut self) -> Option<String> {
+        let selected = !self.packages.is_empty();
+        let exclude = &self.exclude;
+        self.packages.retain(|package| {
+            let keep = !exclude.contains(package);
+            if !keep {
+                info!("{} is in exclude list removing from packages", package);
+            }
+            keep
+        });
+        if selected && self.packages.is_empty() {
+            Some(format!(
+                "All selected packages are excluded ({}), no packages are left to test",
+                self.exclude.join(", ")
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Splits the config into one config per target so each target is built in turn. A config
+    /// with one or no targets is returned as is
+    pub fn per_target(&self) -> Vec<Config> {
+        if self.target.len() < 2 {
+            return vec![self.clone()];
+        }
+        self.target
+            .iter()
+            .map(|target| {
+                let mut config = self.clone();
+                config.target = vec![target.clone()];
+                config
+            })
+            .collect()
+    }
+
+    pub fn pick_optional_config<T: Clone>(
+        base_config: &Option<T>,
+        override_config: &Option<T>,
+    ) -> Option<T> {
+        if override_config.is_some() {
+            override_config.clone()
+        } else {
+            base_config.clone()
+        }
+    }
+
+    pub fn objects(&self) -> &[PathBuf] {
+        &self.objects
+    }
+
+    /// Whether the config only merges the results in `input_files` into the reports, which is
+    /// `no_run` with input files. Nothing is built or run
+    pub fn merge_only(&self) -> bool {
+        self.no_run && !self.input_files.is_empty()
+    }
+
     pub fn has_named_tests(&self) -> bool {
         !(self.test_names.is_empty()
             && self.bin_names.is_empty()
@@ -797,20 +1964,153 @@ impl Config {
         self.coveralls.is_some()
     }
 
+    /// Checks the binaries which wrote the profiles in `profraws` are given, this is checked
+    /// after merging as they may come from different configs
+    pub fn check_profraws(&self) -> Result<(), ConfigError> {
+        if !self.profraws.is_empty() && self.input_binaries.is_empty() {
+            Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: "profraws needs the binaries which wrote the profiles in input-binaries"
+                    .to_string(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks the graceful shutdown signal is the name of a signal, with or without the `SIG`
+    /// prefix
+    pub fn check_shutdown_signal(&self) -> Result<(), ConfigError> {
+        match self.graceful_shutdown_signal.as_ref() {
+            Some(signal) if !crate::statemachine::is_signal(signal) => Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: format!("graceful-shutdown-signal {signal} isn't a signal"),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The index of the shard to run and the number of shards, without sharding this is the
+    /// only shard of one
+    pub fn shard(&self) -> (usize, usize) {
+        (self.shard_index.unwrap_or(0), self.shard_count.unwrap_or(1))
+    }
+
+    /// Checks the shard index is one of the shards
+    pub fn check_shard(&self) -> Result<(), ConfigError> {
+        let (shard_index, shard_count) = self.shard();
+        if shard_count == 0 {
+            Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: "shard-count must be at least 1".to_string(),
+            })
+        } else if shard_index >= shard_count {
+            Err(ConfigError::Invalid {
+                name: self.name.clone(),
+                reason: format!(
+                    "shard-index {shard_index} isn't one of the {shard_count} shards, shards are counted from 0"
+                ),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The file of per file coverage thresholds, `coverage-thresholds` if it's set otherwise
+    /// `.coveragethresholds` in the project root if it exists
+    pub fn coverage_thresholds_file(&self) -> Option<PathBuf> {
+        self.coverage_thresholds.clone().or_else(|| {
+            let default = self.root().join(".coveragethresholds");
+            default.is_file().then_some(default)
+        })
+    }
+
+    /// The path globs from the coverage thresholds file with the minimum coverage of the files
+    /// they match, the globs are relative to the project root like `exclude-files`
+    pub fn file_thresholds(&self) -> Result<Vec<(glob::Pattern, f64)>, ConfigError> {
+        let path = match self.coverage_thresholds_file() {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+        let invalid = |reason: String| ConfigError::Invalid {
+            name: self.name.clone(),
+            reason: format!("coverage thresholds {}: {}", path.display(), reason),
+        };
+        let text = fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+        parse_file_thresholds(&text).map_err(invalid)
+    }
+
+    /// Checks all of the `exclude-line-patterns` are valid regexes, keeping the compiled
+    /// patterns for `excluded_lines` if they are
+    pub fn check_line_patterns(&self) -> Result<(), ConfigError> {
+        let checked = |x: &Vec<Regex>| x.len() == self.exclude_line_patterns.len();
+        if self.excluded_lines.borrow().as_ref().is_some_and(checked) {
+            return Ok(());
+        }
+        let mut compiled = Vec::with_capacity(self.exclude_line_patterns.len());
+        for pattern in &self.exclude_line_patterns {
+            match Regex::new(pattern) {
+                Ok(regex) => compiled.push(regex),
+                Err(e) => {
+                    return Err(ConfigError::Invalid {
+                        name: self.name.clone(),
+                        reason: format!("invalid exclude-line-patterns regex '{pattern}': {e}"),
+                    })
+                }
+            }
+        }
+        *self.excluded_lines.borrow_mut() = Some(compiled);
+        Ok(())
+    }
+
+    /// The compiled `exclude-line-patterns`, invalid patterns are reported by
+    /// `check_line_patterns` when loading the config so are skipped here
+    pub fn excluded_lines(&self) -> Ref<'_, Vec<Regex>> {
+        if self.excluded_lines.borrow().is_none() {
+            let compiled = self
+                .exclude_line_patterns
+                .iter()
+                .filter_map(|x| Regex::new(x).ok())
+                .collect();
+            *self.excluded_lines.borrow_mut() = Some(compiled);
+        }
+        Ref::map(self.excluded_lines.borrow(), |x| x.as_ref().unwrap())
+    }
+
+    /// Whether the file is excluded from the results. A file is excluded if it matches one of
+    /// the `exclude-files` patterns, or if there are `include-files` patterns and it matches
+    /// none of them. Exclusion takes precedence so a file matching both is excluded
     #[inline]
     pub fn exclude_path(&self, path: &Path) -> bool {
-        if self.excluded_files.borrow().len() != self.excluded_files_raw.len() {
-            let mut excluded_files = self.excluded_files.borrow_mut();
-            let mut compiled = globs_from_excluded(&self.excluded_files_raw);
-            excluded_files.clear();
-            excluded_files.append(&mut compiled);
-        }
+        let compile = |compiled: &RefCell<Vec<glob::Pattern>>, raw: &[String]| {
+            if compiled.borrow().len() != raw.len() {
+                let mut compiled = compiled.borrow_mut();
+                compiled.clear();
+                compiled.append(&mut globs_from_excluded(raw));
+            }
+        };
+        compile(&self.excluded_files, &self.excluded_files_raw);
+        compile(&self.included_files, &self.included_files_raw);
         let project = self.strip_base_dir(path);
 
-        self.excluded_files
+        let excluded = self
+            .excluded_files
             .borrow()
             .iter()
-            .any(|x| x.matches_path(&project))
+            .any(|x| x.matches_path(&project));
+        let included = self.included_files.borrow();
+        excluded || (!included.is_empty() && !included.iter().any(|x| x.matches_path(&project)))
+    }
+
+    /// Whether the module with the given path from the crate root is in `exclude_modules` or is
+    /// inside one of them. The paths in `exclude_modules` can start with `crate::`
+    pub fn exclude_module(&self, module: &[String]) -> bool {
+        self.exclude_modules.iter().any(|excluded| {
+            let excluded = excluded.trim();
+            let excluded = excluded.strip_prefix("crate::").unwrap_or(excluded);
+            let excluded = excluded.split("::").collect::<Vec<_>>();
+            excluded.len() <= module.len() && excluded.iter().zip(module).all(|(a, b)| a == b)
+        })
     }
 
     /// returns the relative path from the base_dir
@@ -837,6 +2137,19 @@ impl Config {
         path_relative_from(path, &self.get_base_dir()).unwrap_or_else(|| path.to_path_buf())
     }
 
+    /// The path of a source file as it's written in the reports. Unless `relative-paths` is
+    /// off files in the workspace are relative to the root, under `path-prefix` if it's set
+    pub fn report_path(&self, path: &Path) -> PathBuf {
+        if !self.relative_paths {
+            return path.to_path_buf();
+        }
+        let path = self.strip_base_dir(path);
+        match self.path_prefix {
+            Some(ref prefix) if path.is_relative() => prefix.join(path),
+            _ => path,
+        }
+    }
+
     #[inline]
     pub fn is_default_output_dir(&self) -> bool {
         self.output_directory.is_none()
@@ -900,7 +2213,109 @@ pub fn path_relative_from(path: &Path, base: &Path) -> Option<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::traces::{Trace, TraceMap};
     use clap::App;
+    use rusty_fork::rusty_fork_test;
+
+    #[test]
+    fn manifest_from_root_or_path() {
+        let project = env::current_dir()
+            .unwrap()
+            .join("tests/data/simple_project")
+            .canonicalize()
+            .unwrap();
+        let manifest_args = |args: &[&str]| {
+            let matches = App::new("tarpaulin")
+                .args_from_usage(
+                    "--root -r [DIR] 'Calculates relative paths to root directory'
+                     --manifest-path [PATH] 'Path to Cargo.toml'",
+                )
+                .get_matches_from_safe(args)
+                .unwrap();
+            Config::from_args(&matches)
+        };
+        for args in [
+            ["tarpaulin", "--root", "tests/data/simple_project"],
+            ["tarpaulin", "--manifest-path", "tests/data/simple_project"],
+            [
+                "tarpaulin",
+                "--manifest-path",
+                "tests/data/simple_project/Cargo.toml",
+            ],
+        ] {
+            let config = manifest_args(&args);
+            assert_eq!(config.manifest(), project.join("Cargo.toml"));
+            assert_eq!(config.root(), project);
+            assert_eq!(config.output_dir(), project);
+            assert!(config.check_manifest().is_ok());
+        }
+
+        let config = manifest_args(&["tarpaulin", "--root", "tests/data/not_a_project"]);
+        match config.check_manifest() {
+            Err(ConfigError::Invalid { reason, .. }) => {
+                assert!(reason.contains("not_a_project"), "{}", reason)
+            }
+            e => panic!("Expected invalid config error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn cli_overrides_file_scalars() {
+        let args_config = |args: &[&str]| {
+            let matches = App::new("tarpaulin")
+                .args_from_usage(
+                    "--profile [NAME] 'Build artefacts with the specified profile'
+                     --target [TRIPLE]... 'Compilation target triples'
+                     -j --jobs [N] 'Number of parallel jobs, defaults to # of CPUs'
+                     --test-threads [N] 'Number of threads to run each test executable with'",
+                )
+                .get_matches_from_safe(args)
+                .unwrap();
+            Config::from_args(&matches)
+        };
+        let toml = r#"[coverage]
+        profile = "coverage"
+        target = "wasm32-unknown-unknown"
+        jobs = 2
+        test-threads = 2
+        "#;
+
+        let cli = args_config(&[
+            "tarpaulin",
+            "--profile",
+            "release",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+            "--jobs",
+            "8",
+            "--test-threads",
+            "1",
+        ]);
+        let configs = Config::get_config_vec(Config::parse_config_toml(toml), cli).unwrap();
+        assert_eq!(configs.0[0].profile, Some("release".to_string()));
+        assert_eq!(configs.0[0].target, vec!["x86_64-unknown-linux-gnu"]);
+        assert_eq!(configs.0[0].jobs, Some(8));
+        assert_eq!(configs.0[0].test_threads, Some(1));
+
+        // Options which aren't passed on the command line are left as the file sets them
+        let cli = args_config(&["tarpaulin"]);
+        let configs = Config::get_config_vec(Config::parse_config_toml(toml), cli).unwrap();
+        assert_eq!(configs.0[0].profile, Some("coverage".to_string()));
+        assert_eq!(configs.0[0].target, vec!["wasm32-unknown-unknown"]);
+        assert_eq!(configs.0[0].jobs, Some(2));
+        assert_eq!(configs.0[0].test_threads, Some(2));
+        let cli = args_config(&[
+            "tarpaulin",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+            "i686-unknown-linux-gnu",
+        ]);
+        let configs = Config::get_config_vec(Config::parse_config_toml(toml), cli).unwrap();
+        assert_eq!(
+            configs.0[0].target,
+            vec!["x86_64-unknown-linux-gnu", "i686-unknown-linux-gnu"]
+        );
+    }
 
     #[test]
     fn features_args() {
@@ -920,7 +2335,7 @@ mod tests {
             .unwrap();
         let conf = ConfigWrapper::from(&matches).0;
         assert_eq!(conf.len(), 1);
-        assert_eq!(conf[0].features, Some("a b".to_string()));
+        assert_eq!(conf[0].features, vec!["a", "b"]);
 
         let matches = App::new("tarpaulin")
             .args_from_usage(
@@ -931,7 +2346,23 @@ mod tests {
             .unwrap();
         let conf = ConfigWrapper::from(&matches).0;
         assert_eq!(conf.len(), 1);
-        assert_eq!(conf[0].features, Some("a b".to_string()));
+        assert_eq!(conf[0].features, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn excluded_modules() {
+        let config = Config {
+            exclude_modules: vec!["crate::internal::generated".to_string(), "ffi".to_string()],
+            ..Config::default()
+        };
+        let module = |x: &str| x.split("::").map(ToString::to_string).collect::<Vec<_>>();
+        assert!(config.exclude_module(&module("internal::generated")));
+        assert!(config.exclude_module(&module("internal::generated::tables")));
+        assert!(config.exclude_module(&module("ffi")));
+        assert!(!config.exclude_module(&module("internal")));
+        assert!(!config.exclude_module(&module("internal::generated_extra")));
+        assert!(!config.exclude_module(&module("other::ffi")));
+        assert!(!config.exclude_module(&[]));
     }
 
     #[test]
@@ -1088,15 +2519,36 @@ mod tests {
         let mut b: Config = toml::from_str(toml_b).unwrap();
         let c: Config = toml::from_str(toml_c).unwrap();
 
-        assert_eq!(a.target, None);
-        assert_eq!(b.target, Some(String::from("wasm32-unknown-unknown")));
-        assert_eq!(c.target, Some(String::from("x86_64-linux-gnu")));
+        assert!(a.target.is_empty());
+        assert_eq!(b.target, vec!["wasm32-unknown-unknown"]);
+        assert_eq!(c.target, vec!["x86_64-linux-gnu"]);
 
         b.merge(&c);
-        assert_eq!(b.target, Some(String::from("x86_64-linux-gnu")));
+        assert_eq!(b.target, vec!["x86_64-linux-gnu"]);
 
         a.merge(&b);
-        assert_eq!(a.target, Some(String::from("x86_64-linux-gnu")));
+        assert_eq!(a.target, vec!["x86_64-linux-gnu"]);
+    }
+
+    #[test]
+    fn target_list() {
+        let config: Config =
+            toml::from_str(r#"target = ["x86_64-unknown-linux-gnu", "i686-unknown-linux-gnu"]"#)
+                .unwrap();
+        assert_eq!(
+            config.target,
+            vec!["x86_64-unknown-linux-gnu", "i686-unknown-linux-gnu"]
+        );
+
+        let targets = config.per_target();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].target, vec!["x86_64-unknown-linux-gnu"]);
+        assert_eq!(targets[1].target, vec!["i686-unknown-linux-gnu"]);
+
+        let config = Config::default();
+        let targets = config.per_target();
+        assert_eq!(targets.len(), 1);
+        assert!(targets[0].target.is_empty());
     }
 
     #[test]
@@ -1161,6 +2613,389 @@ mod tests {
         assert_eq!(b.exclude, vec![String::from("b"), String::from("c")]);
     }
 
+    #[test]
+    fn integration_test_paths_found() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = Config {
+            manifest: root.join("Cargo.toml"),
+            ..Default::default()
+        };
+        let paths = config.integration_test_paths();
+        assert!(paths.contains(&fix_unc_path(&root.join("tests"))));
+        assert!(!paths.iter().any(|x| x.starts_with(root.join("src"))));
+    }
+
+    #[test]
+    fn package_globs_merge() {
+        let manifest =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/workspace/Cargo.toml");
+        let mut a = Config {
+            manifest: manifest.clone(),
+            packages: vec!["foo".to_string(), "bar".to_string()],
+            ..Default::default()
+        };
+        let b = Config {
+            manifest,
+            exclude: vec!["f*".to_string()],
+            ..Default::default()
+        };
+        a.merge(&b);
+        assert_eq!(a.packages, vec!["bar"]);
+        assert_eq!(a.exclude, vec!["foo"]);
+
+        let mut a = Config {
+            manifest: a.manifest.clone(),
+            packages: vec!["ba?".to_string(), "bar".to_string(), "[fz]oo".to_string()],
+            ..Default::default()
+        };
+        a.merge(&Config::default());
+        assert_eq!(a.packages, vec!["bar", "foo"]);
+    }
+
+    #[test]
+    fn profile_exists() {
+        let manifest = r#"[package]
+        name = "foo"
+
+        [profile.coverage]
+        inherits = "test"
+
+        [profile.release]
+        lto = true
+        "#;
+        assert_eq!(declared_profiles(manifest), vec!["coverage", "release"]);
+        assert!(declared_profiles("[package]\nname = \"foo\"").is_empty());
+
+        let manifest =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/simple_project/Cargo.toml");
+        let mut config = Config {
+            manifest,
+            profile: Some("release".to_string()),
+            ..Default::default()
+        };
+        assert!(config.check_profile().is_ok());
+
+        config.profile = Some("not-a-profile".to_string());
+        match config.check_profile() {
+            Err(ConfigError::Invalid { reason, .. }) => {
+                assert!(reason.contains("dev, release, test, bench"));
+            }
+            e => panic!("Expected invalid profile error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn target_runners() {
+        let cargo_config = r#"[target.aarch64-unknown-linux-gnu]
+        runner = "qemu-aarch64 -L /usr/aarch64-linux-gnu"
+
+        [target.riscv64gc-unknown-linux-gnu]
+        runner = ["qemu-riscv64", "-cpu", "rv64"]
+        "#;
+        assert_eq!(
+            declared_runner(cargo_config, "aarch64-unknown-linux-gnu"),
+            Some(vec![
+                "qemu-aarch64".to_string(),
+                "-L".to_string(),
+                "/usr/aarch64-linux-gnu".to_string()
+            ])
+        );
+        assert_eq!(
+            declared_runner(cargo_config, "riscv64gc-unknown-linux-gnu"),
+            Some(vec![
+                "qemu-riscv64".to_string(),
+                "-cpu".to_string(),
+                "rv64".to_string()
+            ])
+        );
+        assert_eq!(declared_runner(cargo_config, "wasm32-wasi"), None);
+
+        let mut config = Config {
+            target: vec!["tarpaulin-runner-test".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config.runner(), None);
+        let env = |var: &str| {
+            (var == "CARGO_TARGET_TARPAULIN_RUNNER_TEST_RUNNER").then(|| "wasmtime run".to_string())
+        };
+        assert_eq!(
+            config.runner_with_env(env),
+            Some(vec!["wasmtime".to_string(), "run".to_string()])
+        );
+        config.target_runner = Some("qemu-arm".to_string());
+        assert_eq!(
+            config.runner_with_env(env),
+            Some(vec!["qemu-arm".to_string()])
+        );
+
+        config.set_engine(TraceEngine::Ptrace);
+        match config.check_engine() {
+            Err(RunError::Engine(e)) => assert!(e.contains("target runner")),
+            e => panic!("Expected ptrace engine error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn build_scripts_need_llvm() {
+        let toml = r#"[build_scripts]
+        cover-build-scripts = true
+
+        [tests]
+        engine = "Ptrace"
+        "#;
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut config = configs
+            .iter()
+            .find(|x| x.name == "build_scripts")
+            .unwrap()
+            .clone();
+        assert!(config.cover_build_scripts);
+        config.merge(configs.iter().find(|x| x.name == "tests").unwrap());
+        assert!(config.cover_build_scripts);
+        match config.check_engine() {
+            Err(RunError::Engine(e)) => assert!(e.contains("build scripts")),
+            e => panic!("Expected ptrace engine error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn shutdown_signals() {
+        let toml = r#"graceful-shutdown-signal = "int"
+        shutdown-timeout = "5s"
+        "#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.graceful_shutdown_signal.as_deref(), Some("int"));
+        assert!(config.check_shutdown_signal().is_ok());
+        assert_eq!(
+            crate::statemachine::shutdown_grace(&config),
+            Duration::from_secs(5)
+        );
+
+        config.shutdown_timeout = None;
+        assert_eq!(
+            crate::statemachine::shutdown_grace(&config),
+            config.test_timeout
+        );
+        config.graceful_shutdown_signal = None;
+        assert_eq!(
+            crate::statemachine::shutdown_grace(&config),
+            crate::statemachine::TIMEOUT_GRACE
+        );
+
+        config.graceful_shutdown_signal = Some("SIGNOTREAL".to_string());
+        if cfg!(unix) {
+            assert!(config.check_shutdown_signal().is_err());
+        }
+    }
+
+    #[test]
+    fn duration_formats() {
+        let durations = [
+            ("45", Duration::from_secs(45)),
+            ("30s", Duration::from_secs(30)),
+            ("2m", Duration::from_secs(120)),
+            ("1h30m", Duration::from_secs(5400)),
+            ("1s 500ms", Duration::from_millis(1500)),
+        ];
+        for (value, expected) in durations.iter() {
+            assert_eq!(parse_duration(value), Ok(*expected), "parsing {}", value);
+
+            let toml = format!("timeout = \"{value}\"\npost-test-delay = \"{value}\"");
+            let config: Config = toml::from_str(&toml).unwrap();
+            assert_eq!(config.test_timeout, *expected);
+            assert_eq!(config.post_test_delay, Some(*expected));
+
+            let round_trip: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+            assert_eq!(round_trip.test_timeout, *expected);
+            assert_eq!(round_trip.post_test_delay, Some(*expected));
+        }
+
+        let config: Config = toml::from_str("timeout = 45\nshutdown-timeout = 3").unwrap();
+        assert_eq!(config.test_timeout, Duration::from_secs(45));
+        assert_eq!(config.shutdown_timeout, Some(Duration::from_secs(3)));
+
+        let error = parse_duration("soon").unwrap_err();
+        assert!(error.contains("invalid duration 'soon'"), "{}", error);
+        let error = toml::from_str::<Config>("timeout = \"10 parsecs\"").unwrap_err();
+        assert!(
+            error.to_string().contains("invalid duration '10 parsecs'"),
+            "{}",
+            error
+        );
+        assert!(toml::from_str::<Config>("timeout = -5").is_err());
+    }
+
+    #[test]
+    fn durations_from_args() {
+        let matches = App::new("tarpaulin")
+            .args_from_usage(
+                "--timeout -t [DURATION] 'Timeout'
+                 --post-test-delay [DURATION] 'Delay'
+                 --shutdown-timeout [DURATION] 'Shutdown timeout'",
+            )
+            .get_matches_from_safe(vec![
+                "tarpaulin",
+                "--timeout",
+                "2m",
+                "--post-test-delay",
+                "250ms",
+                "--shutdown-timeout",
+                "10",
+            ])
+            .unwrap();
+        let config = Config::from_args(&matches);
+        assert_eq!(config.test_timeout, Duration::from_secs(120));
+        assert_eq!(config.post_test_delay, Some(Duration::from_millis(250)));
+        assert_eq!(config.shutdown_timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn shard_checked() {
+        let mut config = Config::default();
+        assert!(config.check_shard().is_ok());
+        config.shard_count = Some(2);
+        config.shard_index = Some(2);
+        assert_eq!(
+            config.check_shard().unwrap_err().to_string(),
+            "Invalid config: shard-index 2 isn't one of the 2 shards, shards are counted from 0"
+        );
+        config.shard_count = Some(0);
+        config.shard_index = None;
+        assert!(config.check_shard().is_err());
+
+        // The command line replaces the shard from a config file, including with shard 0
+        let mut file: Config = toml::from_str("shard-index = 2\nshard-count = 4").unwrap();
+        let cli = Config {
+            shard_index: Some(0),
+            ..Config::default()
+        };
+        file.merge(&cli);
+        assert_eq!(file.shard(), (0, 4));
+    }
+
+    #[test]
+    fn frozen_implies_locked_and_offline() {
+        let values = [None, Some(false), Some(true)];
+        for frozen in [false, true] {
+            for locked in values {
+                for offline in values {
+                    let mut config = Config {
+                        frozen,
+                        locked,
+                        offline,
+                        ..Config::default()
+                    };
+                    config.normalize_cargo_flags();
+
+                    let expected_locked = frozen || locked == Some(true);
+                    let expected_offline = frozen || offline == Some(true);
+                    let case = format!("{:?}", (frozen, locked, offline));
+                    assert_eq!(config.locked(), expected_locked, "{}", case);
+                    assert_eq!(config.offline(), expected_offline, "{}", case);
+                    if frozen {
+                        assert_eq!(config.locked, Some(true), "{}", case);
+                        assert_eq!(config.offline, Some(true), "{}", case);
+                    }
+                    let mut expected_flags = vec![];
+                    if frozen {
+                        expected_flags.push("--frozen");
+                    }
+                    if expected_locked {
+                        expected_flags.push("--locked");
+                    }
+                    if expected_offline {
+                        expected_flags.push("--offline");
+                    }
+                    assert_eq!(config.cargo_lock_flags(), expected_flags, "{}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn explicit_lock_flags_merged() {
+        let toml = r#"[a]
+        frozen = true
+        offline = false
+
+        [b]
+        locked = false
+        "#;
+        let mut configs = Config::parse_config_toml(toml).unwrap();
+        assert_eq!(configs[0].offline, Some(false));
+        assert_eq!(configs[0].locked, None);
+        assert_eq!(configs[1].locked, Some(false));
+
+        let mut merged = Config::default();
+        merged.merge(&configs[1]);
+        assert_eq!(merged.locked, Some(false));
+        merged.merge(&configs[0]);
+        assert_eq!(merged.offline, Some(false));
+        // Frozen is set by the other config so the explicit values are overridden
+        assert!(merged.frozen);
+        assert!(merged.locked());
+        merged.normalize_cargo_flags();
+        assert_eq!(merged.locked, Some(true));
+        assert_eq!(merged.offline, Some(true));
+
+        let mut cli = Config::default();
+        cli.set_offline(true);
+        configs[1].merge(&cli);
+        assert_eq!(configs[1].offline, Some(true));
+        configs[1].normalize_cargo_flags();
+        assert!(!configs[1].locked());
+        assert_eq!(configs[1].cargo_lock_flags(), vec!["--offline"]);
+    }
+
+    #[test]
+    fn include_and_exclude_files() {
+        let toml = r#"[a]
+        include-files = ["src/*"]
+        exclude-files = ["src/generated/*"]
+
+        [b]
+        include-files = ["benches/*", "src/*"]
+        "#;
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let root = a.root();
+        assert!(!a.exclude_path(&root.join("src/lib.rs")));
+        assert!(a.exclude_path(&root.join("src/generated/proto.rs")));
+        assert!(a.exclude_path(&root.join("benches/bench.rs")));
+
+        a.merge(configs.iter().find(|x| x.name == "b").unwrap());
+        assert_eq!(a.included_files_raw, vec!["src/*", "benches/*"]);
+        assert!(!a.exclude_path(&root.join("benches/bench.rs")));
+        assert!(a.exclude_path(&root.join("src/generated/proto.rs")));
+        assert!(a.exclude_path(&root.join("tests/mod.rs")));
+
+        let mut result = TraceMap::new();
+        for file in ["src/lib.rs", "src/generated/proto.rs", "tests/mod.rs"] {
+            result.add_trace(&root.join(file), Trace::new_stub(1));
+        }
+        result.retain_files(|x| !a.exclude_path(x));
+        assert_eq!(result.files(), vec![&root.join("src/lib.rs")]);
+    }
+
+    #[test]
+    fn exclude_all_packages() {
+        let toml = r#"packages = ["a", "b"]
+                      exclude = ["a", "b"]"#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        let conflict = config.remove_excluded_packages().unwrap();
+        assert!(config.packages.is_empty());
+        assert!(conflict.contains("a, b"));
+
+        let toml = r#"packages = ["a", "b"]
+                      exclude = ["a"]"#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        assert!(config.remove_excluded_packages().is_none());
+        assert_eq!(config.packages, vec!["b"]);
+
+        let mut config: Config = toml::from_str(r#"exclude = ["a"]"#).unwrap();
+        assert!(config.remove_excluded_packages().is_none());
+    }
+
     #[test]
     fn coveralls_merge() {
         let toml = r#"[a]
@@ -1279,6 +3114,571 @@ mod tests {
         assert!(split.contains(&"bar"));
     }
 
+    #[test]
+    fn rustflags_list() {
+        let toml = r#"rustflags = ["-C", "target-feature=+avx2"]"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        assert_eq!(config.rustflags.as_deref(), Some("-C target-feature=+avx2"));
+    }
+
+    #[test]
+    fn features_merge() {
+        let toml = r#"
+        [file]
+        features = "serde"
+
+        [cli]
+        features = "serde tokio"
+
+        [empty]
+        features = ""
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let file = configs.iter().find(|x| x.name == "file").unwrap().clone();
+        let cli = configs.iter().find(|x| x.name == "cli").unwrap().clone();
+        let empty = configs.iter().find(|x| x.name == "empty").unwrap().clone();
+
+        let mut overlapping = file.clone();
+        overlapping.merge(&cli);
+        assert_eq!(overlapping.features, vec!["serde", "tokio"]);
+
+        let mut reversed = cli.clone();
+        reversed.merge(&file);
+        assert_eq!(reversed.features, vec!["serde", "tokio"]);
+
+        let mut empty_other = file.clone();
+        empty_other.merge(&empty);
+        assert_eq!(empty_other.features, vec!["serde"]);
+
+        let mut empty_base = empty.clone();
+        empty_base.merge(&cli);
+        assert_eq!(empty_base.features, vec!["serde", "tokio"]);
+
+        let mut both_empty = empty.clone();
+        both_empty.merge(&empty);
+        assert!(both_empty.features.is_empty());
+    }
+
+    #[test]
+    fn disabled_features_merge() {
+        let toml = r#"
+        [file]
+        features = "serde allocator"
+
+        [cli]
+        disable-features = ["allocator"]
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let file = configs.iter().find(|x| x.name == "file").unwrap().clone();
+        let cli = configs.iter().find(|x| x.name == "cli").unwrap().clone();
+
+        let mut merged = file.clone();
+        merged.merge(&cli);
+        assert_eq!(merged.features, vec!["serde"]);
+        assert_eq!(merged.disable_features, vec!["allocator"]);
+
+        // A disabled feature stays disabled whichever config enables it
+        let mut reversed = cli.clone();
+        reversed.merge(&file);
+        assert_eq!(reversed.features, vec!["serde"]);
+        assert_eq!(reversed.disable_features, vec!["allocator"]);
+    }
+
+    #[test]
+    fn features_round_trip() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Features {
+            #[serde(
+                deserialize_with = "deserialize_features",
+                serialize_with = "serialize_features"
+            )]
+            features: Vec<String>,
+        }
+
+        let string_form: Features = toml::from_str(r#"features = "a b""#).unwrap();
+        assert_eq!(string_form.features, vec!["a", "b"]);
+
+        let array_form: Features = toml::from_str(r#"features = ["a", "b"]"#).unwrap();
+        assert_eq!(array_form.features, vec!["a", "b"]);
+
+        let serialized = toml::to_string(&array_form).unwrap();
+        assert_eq!(serialized.trim(), r#"features = "a b""#);
+        let round_trip: Features = toml::from_str(&serialized).unwrap();
+        assert_eq!(round_trip.features, array_form.features);
+
+        let complex = Features {
+            features: vec!["a".to_string(), "b c".to_string()],
+        };
+        let serialized = toml::to_string(&complex).unwrap();
+        let round_trip: Features = toml::from_str(&serialized).unwrap();
+        assert_eq!(round_trip.features, complex.features);
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn target_dir_precedence() {
+            let mut config = Config::default();
+            config.set_manifest(PathBuf::from("/fake/Cargo.toml"));
+            env::set_var("CARGO_TARGET_DIR", "/env/target");
+            assert_eq!(config.target_dir(), PathBuf::from("/env/target"));
+
+            config.set_target_dir(PathBuf::from("/explicit/target"));
+            assert_eq!(config.target_dir(), PathBuf::from("/explicit/target"));
+            assert_eq!(
+                config.doctest_dir(),
+                PathBuf::from("/explicit/target/doctests")
+            );
+
+            env::remove_var("CARGO_TARGET_DIR");
+            let config = Config::default();
+            assert_ne!(config.target_dir(), PathBuf::from("/env/target"));
+        }
+    }
+
+    #[test]
+    fn color_merge() {
+        let toml = r#"
+        [a]
+        color = "never"
+
+        [b]
+
+        [c]
+        color = "Always"
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let b = configs.iter().find(|x| x.name == "b").unwrap();
+        let c = configs.iter().find(|x| x.name == "c").unwrap();
+        assert_eq!(a.color, Color::Never);
+        assert_eq!(b.color, Color::Auto);
+        assert_eq!(c.color, Color::Always);
+        a.merge(b);
+        assert_eq!(a.color, Color::Never);
+        a.merge(c);
+        assert_eq!(a.color, Color::Always);
+        assert!(!Color::Never.use_ansi());
+    }
+
+    #[test]
+    fn verbosity_merge() {
+        let toml = r#"
+        [a]
+        verbose = true
+
+        [b]
+        quiet = true
+
+        [c]
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let b = configs.iter().find(|x| x.name == "b").unwrap();
+        let c = configs.iter().find(|x| x.name == "c").unwrap();
+        a.merge(c);
+        assert!(a.verbose && !a.quiet);
+        a.merge(b);
+        assert!(!a.verbose && a.quiet);
+        a.merge(configs.iter().find(|x| x.name == "a").unwrap());
+        assert!(a.verbose && !a.quiet);
+    }
+
+    #[test]
+    fn env_merge() {
+        let toml = r#"
+        [a]
+        env = { FOO = "a", BAR = "a" }
+        env-remove = ["HOME"]
+
+        [b]
+        env = { FOO = "b", BAZ = "b" }
+        env-remove = ["HOME", "USER"]
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let b = configs.iter().find(|x| x.name == "b").unwrap();
+        a.merge(b);
+        assert_eq!(a.env.len(), 3);
+        assert_eq!(a.env["FOO"], "b");
+        assert_eq!(a.env["BAR"], "a");
+        assert_eq!(a.env["BAZ"], "b");
+        assert_eq!(a.env_remove, vec!["HOME", "USER"]);
+    }
+
+    #[test]
+    fn package_threshold_merge() {
+        let toml = r#"
+        [a]
+        fail-under-per-package = { core = 90.0, playground = 20.0 }
+
+        [b]
+        fail-under-per-package = { core = 80.0, utils = 50.0 }
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let b = configs.iter().find(|x| x.name == "b").unwrap();
+        a.merge(b);
+        assert_eq!(a.fail_under_per_package.len(), 3);
+        assert_eq!(a.fail_under_per_package["core"], 80.0);
+        assert_eq!(a.fail_under_per_package["playground"], 20.0);
+        assert_eq!(a.fail_under_per_package["utils"], 50.0);
+    }
+
+    #[test]
+    fn output_files_merge() {
+        let toml = r#"
+        [a]
+        output-dir = "/reports"
+        output-files = { Xml = "coverage.xml", Lcov = "lcov/a.info" }
+
+        [b]
+        output-files = { Xml = "/tmp/cobertura.xml" }
+        "#;
+
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let mut a = configs.iter().find(|x| x.name == "a").unwrap().clone();
+        let b = configs.iter().find(|x| x.name == "b").unwrap();
+        assert_eq!(
+            a.output_file(OutputFile::Xml),
+            Some(PathBuf::from("/reports/coverage.xml"))
+        );
+        a.merge(b);
+        assert_eq!(
+            a.output_file(OutputFile::Xml),
+            Some(PathBuf::from("/tmp/cobertura.xml"))
+        );
+        assert_eq!(
+            a.output_file(OutputFile::Lcov),
+            Some(PathBuf::from("/reports/lcov/a.info"))
+        );
+        assert_eq!(
+            a.output_file(OutputFile::Html),
+            Some(PathBuf::from("/reports/tarpaulin-report.html"))
+        );
+        assert_eq!(a.output_file(OutputFile::Stdout), None);
+    }
+
+    #[test]
+    fn test_jobs_need_llvm() {
+        let mut config = Config {
+            jobs: Some(4),
+            ..Default::default()
+        };
+        config.set_engine(TraceEngine::Ptrace);
+        assert_eq!(config.test_jobs(), 1);
+        if supports_llvm_coverage() {
+            config.set_engine(TraceEngine::Llvm);
+            assert_eq!(config.test_jobs(), 4);
+            config.dump_traces = true;
+            assert_eq!(config.test_jobs(), 1);
+        }
+    }
+
+    #[test]
+    fn jobs_resolution() {
+        let cpus = num_cpus::get();
+        assert_eq!(resolve_jobs(Some(4), Some("2")), Some(4));
+        assert_eq!(resolve_jobs(Some(0), None), Some(cpus));
+        assert_eq!(resolve_jobs(None, Some("2")), Some(2));
+        assert_eq!(resolve_jobs(None, Some("0")), Some(cpus));
+        assert_eq!(resolve_jobs(None, Some("-1")), None);
+        assert_eq!(resolve_jobs(None, None), None);
+
+        let configs = Config::parse_config_toml("[coverage]\njobs = 0\n").unwrap();
+        assert_eq!(configs[0].jobs, Some(0));
+        assert_eq!(configs[0].jobs(), Some(cpus));
+    }
+
+    #[test]
+    fn engine_availability() {
+        let config = Config::default();
+        config.set_engine(TraceEngine::Auto);
+        let llvm_available = supports_llvm_coverage() && has_profiler_runtime(&config);
+        let ptrace_available = TraceEngine::supported().contains(&TraceEngine::Ptrace);
+        assert_eq!(
+            config.check_engine().is_ok(),
+            llvm_available || ptrace_available
+        );
+        if llvm_available {
+            assert_eq!(config.engine(), TraceEngine::Llvm);
+        }
+
+        config.set_engine(TraceEngine::Ptrace);
+        assert_eq!(config.check_engine().is_ok(), ptrace_available);
+        config.set_engine(TraceEngine::Llvm);
+        assert_eq!(config.check_engine().is_ok(), llvm_available);
+    }
+
+    #[test]
+    fn manifest_config_tables() {
+        let manifest = r#"[package]
+        name = "foo"
+
+        [tool.tarpaulin]
+        release = true
+        features = ["a"]
+        not-a-real-option = 5
+        "#;
+        let configs = Config::parse_manifest_toml(manifest).unwrap().unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, "");
+        assert!(configs[0].release);
+        assert_eq!(configs[0].features, vec!["a"]);
+
+        let manifest = r#"[package]
+        name = "foo"
+
+        [package.metadata.tarpaulin.coverage]
+        release = true
+
+        [package.metadata.tarpaulin.report]
+        out = ["Xml"]
+        "#;
+        let configs = Config::parse_manifest_toml(manifest).unwrap().unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].name, "coverage");
+        assert!(configs[0].release);
+        assert_eq!(configs[1].name, "report");
+        assert_eq!(configs[1].generate, vec![OutputFile::Xml]);
+
+        let manifest = r#"[package]
+        name = "foo"
+        "#;
+        assert!(Config::parse_manifest_toml(manifest).is_none());
+    }
+
+    #[test]
+    fn nested_config_discovery() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/nested_configs");
+        let root = fix_unc_path(&root.canonicalize().unwrap());
+        let inner = Config {
+            manifest: root.join("inner/Cargo.toml"),
+            ..Default::default()
+        };
+        let found = inner.check_for_configs();
+        assert_eq!(
+            found,
+            vec![
+                root.join("inner/.tarpaulin.toml"),
+                root.join("tarpaulin.toml")
+            ]
+        );
+
+        let configs = Config::load_config_files(&found).unwrap();
+        assert_eq!(configs.len(), 2);
+        let coverage = configs.iter().find(|x| x.name == "coverage").unwrap();
+        assert!(coverage.release);
+        assert_eq!(coverage.test_timeout, Duration::from_secs(20));
+        let report = configs.iter().find(|x| x.name == "report").unwrap();
+        assert_eq!(report.generate, vec![OutputFile::Xml]);
+
+        let outer = Config {
+            manifest: root.join("Cargo.toml"),
+            ..Default::default()
+        };
+        assert_eq!(outer.check_for_configs(), vec![root.join("tarpaulin.toml")]);
+    }
+
+    #[test]
+    fn explicit_config_file() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/nested_configs");
+        let root = fix_unc_path(&root.canonicalize().unwrap());
+        let matches = |args: &[&str]| {
+            App::new("tarpaulin")
+                .args_from_usage(
+                    "--config [FILE] 'Path to a toml file'
+                     --manifest-path [PATH] 'Path to Cargo.toml'
+                     -j --jobs [N] 'Number of parallel jobs, defaults to # of CPUs'",
+                )
+                .get_matches_from_safe(args)
+                .unwrap()
+        };
+        let manifest = root.join("inner/Cargo.toml");
+        let config = root.join("ci-coverage.toml");
+        let args = matches(&[
+            "tarpaulin",
+            "--manifest-path",
+            manifest.to_str().unwrap(),
+            "--config",
+            config.to_str().unwrap(),
+            "--jobs",
+            "4",
+        ]);
+        let configs = ConfigWrapper::load(&args).unwrap().0;
+        // The tarpaulin.toml files found by looking up from the project aren't used
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, "ci");
+        assert_eq!(configs[0].config.as_ref(), Some(&config));
+        assert!(configs[0].release);
+        assert_eq!(configs[0].test_timeout, Duration::from_secs(30));
+        assert_eq!(configs[0].jobs, Some(4));
+
+        let missing = root.join("missing.toml");
+        let args = matches(&["tarpaulin", "--config", missing.to_str().unwrap()]);
+        match ConfigWrapper::load(&args) {
+            Err(ConfigError::NotFound(p)) => assert_eq!(p, missing),
+            e => panic!("Expected not found error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn config_errors() {
+        let missing = Path::new("not/a/real/tarpaulin.toml");
+        match Config::load_config_file(missing) {
+            Err(ConfigError::NotFound(p)) => assert_eq!(p, missing),
+            e => panic!("Expected not found error: {:?}", e),
+        }
+
+        let toml = "[coverage]\nrelease = true\njobs = \"four\"\n";
+        match Config::parse_config_toml(toml) {
+            Err(ConfigError::Parse { file, position, .. }) => {
+                assert!(file.is_none());
+                assert_eq!(position, Some((3, 8)));
+            }
+            e => panic!("Expected parse error: {:?}", e),
+        }
+
+        let toml = "[coverage]\nfail-under = 150\n";
+        match Config::parse_config_toml(toml) {
+            Err(ConfigError::Invalid { name, .. }) => assert_eq!(name, "coverage"),
+            e => panic!("Expected invalid config error: {:?}", e),
+        }
+
+        let toml = "[coverage]\ntest-threads = 0\n";
+        assert!(matches!(
+            Config::parse_config_toml(toml),
+            Err(ConfigError::Invalid { .. })
+        ));
+
+        let config = Config {
+            exclude_line_patterns: vec!["unreachable!\\(".to_string(), "(".to_string()],
+            ..Config::default()
+        };
+        match config.check_line_patterns() {
+            Err(ConfigError::Invalid { reason, .. }) => assert!(reason.contains("'('")),
+            e => panic!("Expected invalid config error: {:?}", e),
+        }
+        assert_eq!(config.excluded_lines().len(), 1);
+        assert!(config.check_line_patterns().is_err());
+
+        assert!(Config::parse_config_toml("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn profraws_need_binaries() {
+        let toml = "[a]\nprofraws = [\"a.profraw\"]\n[b]\ninput-binaries = [\"test\"]\n";
+        let configs = Config::parse_config_toml(toml).unwrap();
+        let a = configs.iter().find(|x| x.name == "a").unwrap();
+        assert!(matches!(
+            a.check_profraws(),
+            Err(ConfigError::Invalid { .. })
+        ));
+
+        let mut merged = a.clone();
+        merged.merge(configs.iter().find(|x| x.name == "b").unwrap());
+        assert_eq!(merged.profraws.len(), 1);
+        assert!(merged.check_profraws().is_ok());
+    }
+
+    #[test]
+    fn incompatible_engine_options() {
+        let pairs = [
+            (TraceEngine::Ptrace, "objects", "objects = [\"libfoo.so\"]"),
+            (
+                TraceEngine::Ptrace,
+                "instrument-coverage-packages",
+                "instrument-coverage-packages = [\"foo\"]",
+            ),
+            (
+                TraceEngine::Ptrace,
+                "instrument-deps",
+                "instrument-deps = true",
+            ),
+            (TraceEngine::Llvm, "follow-forks", "follow-forks = true"),
+            (
+                TraceEngine::Ptrace,
+                "granularity",
+                "granularity = \"Region\"",
+            ),
+            (
+                TraceEngine::Ptrace,
+                "llvm-cov-path",
+                "llvm-cov-path = \"llvm-cov-17\"",
+            ),
+            (
+                TraceEngine::Ptrace,
+                "profraws",
+                "profraws = [\"a.profraw\"]",
+            ),
+        ];
+        for (engine, option, toml) in pairs {
+            let config = Config::parse_config_toml(&format!("[a]\n{toml}"))
+                .unwrap()
+                .remove(0);
+            config.set_engine(engine);
+            match config.check_engine_options() {
+                Err(ConfigError::IncompatibleOptions { name, options, .. }) => {
+                    assert_eq!(name, "a");
+                    assert_eq!(options, vec![option]);
+                }
+                e => panic!(
+                    "Expected {} to be incompatible with {}: {:?}",
+                    option, engine, e
+                ),
+            }
+            config.set_engine(TraceEngine::Auto);
+            assert!(config.check_engine_options().is_ok());
+        }
+
+        let config = Config::parse_config_toml(
+            "[a]\nengine = \"Llvm\"\ninstrument-deps = true\nprofraws = [\"a.profraw\"]",
+        )
+        .unwrap()
+        .remove(0);
+        assert!(config.check_engine_options().is_ok());
+        config.set_engine(TraceEngine::Ptrace);
+        assert_eq!(
+            config.check_engine_options().unwrap_err().to_string(),
+            "Invalid config a: instrument-deps, profraws can't be used with the ptrace engine, use `--engine llvm` instead"
+        );
+
+        // Both engines wait for the post test delay
+        let config =
+            Config::parse_config_toml("[a]\nfollow-forks = true\npost-test-delay = \"2s\"")
+                .unwrap()
+                .remove(0);
+        assert_eq!(
+            config.incompatible_options(TraceEngine::Ptrace).is_empty(),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            config.incompatible_options(TraceEngine::Llvm),
+            vec!["follow-forks"]
+        );
+    }
+
+    #[test]
+    fn file_thresholds_parsed() {
+        let thresholds =
+            parse_file_thresholds("# comment\n\nsrc/** = 80\n\"src/a b.rs\" = 92.5").unwrap();
+        assert_eq!(
+            thresholds
+                .iter()
+                .map(|(glob, x)| (glob.as_str(), *x))
+                .collect::<Vec<_>>(),
+            vec![("src/**", 80.0), ("src/a b.rs", 92.5)]
+        );
+        for invalid in ["src/**", "src/** = high", "src/** = 101", "[a = 10"] {
+            assert!(parse_file_thresholds(invalid).is_err(), "{}", invalid);
+        }
+    }
+
     #[test]
     fn all_toml_options() {
         let toml = r#"[all]
@@ -1298,8 +3698,24 @@ mod tests {
         workspace = true
         packages = ["pack_1"]
         exclude = ["pack_2"]
+        instrument-coverage-packages = ["pack_1"]
+        instrument-deps = true
+        exclude-macros = ["bail"]
+        exclude-modules = ["internal::generated"]
+        relative-paths = false
+        summary-only = true
+        report-unsafe = true
+        granularity = "Statement"
+        path-prefix = "crates/foo"
         exclude-files = ["fuzz/*"]
+        exclude-line-patterns = ["unreachable!"]
         timeout = "5s"
+        post-test-delay = "1s 500ms"
+        retries = 2
+        run-count = 4
+        shard-index = 1
+        shard-count = 3
+        test-threads = 3
         release = true
         no-run = true
         locked = true
@@ -1317,6 +3733,8 @@ mod tests {
         test = ["test1", "test2"]
         bin = ["bin"]
         example = ["example"]
+        run-examples = ["demo"]
+        example-args = { demo = ["--quick"] }
         bench = ["bench"]
         no-fail-fast = true
         profile = "Release"
@@ -1342,21 +3760,40 @@ mod tests {
         assert!(config.all);
         assert!(config.release);
         assert!(config.no_run);
-        assert!(config.locked);
+        assert!(config.locked());
         assert!(config.frozen);
-        assert_eq!(Some(String::from("wasm32-unknown-unknown")), config.target);
+        assert_eq!(config.target, vec!["wasm32-unknown-unknown"]);
         assert_eq!(Some(Path::new("/tmp").to_path_buf()), config.target_dir);
-        assert!(config.offline);
+        assert!(config.offline());
         assert_eq!(config.test_timeout, Duration::from_secs(5));
+        assert_eq!(config.post_test_delay, Some(Duration::from_millis(1500)));
+        assert_eq!(config.retries, 2);
+        assert_eq!(config.run_count, 4);
+        assert_eq!(config.shard(), (1, 3));
+        assert!(config.check_shard().is_ok());
+        assert_eq!(config.test_threads, Some(3));
         assert_eq!(config.unstable_features.len(), 1);
         assert_eq!(config.unstable_features[0], "something-nightly");
         assert_eq!(config.varargs.len(), 1);
         assert_eq!(config.varargs[0], "--nocapture");
-        assert_eq!(config.features, Some(String::from("a b")));
+        assert_eq!(config.features, vec!["a", "b"]);
         assert_eq!(config.excluded_files_raw.len(), 1);
         assert_eq!(config.excluded_files_raw[0], "fuzz/*");
+        assert_eq!(
+            config.exclude_line_patterns,
+            vec!["unreachable!".to_string()]
+        );
         assert_eq!(config.packages.len(), 1);
         assert_eq!(config.packages[0], "pack_1");
+        assert_eq!(config.instrument_coverage_packages, vec!["pack_1"]);
+        assert!(config.instrument_deps);
+        assert_eq!(config.exclude_macros, vec!["bail"]);
+        assert_eq!(config.exclude_modules, vec!["internal::generated"]);
+        assert!(!config.relative_paths);
+        assert!(config.summary_only);
+        assert_eq!(config.granularity, Granularity::Statement);
+        assert!(config.report_unsafe);
+        assert_eq!(config.path_prefix, Some(PathBuf::from("crates/foo")));
         assert_eq!(config.exclude.len(), 1);
         assert_eq!(config.exclude[0], "pack_2");
         assert_eq!(config.generate.len(), 1);
@@ -1372,6 +3809,8 @@ mod tests {
         assert!(config.test_names.contains("test2"));
         assert!(config.bin_names.contains("bin"));
         assert!(config.example_names.contains("example"));
+        assert!(config.run_examples.contains("demo"));
+        assert_eq!(config.example_args["demo"], vec!["--quick"]);
         assert!(config.bench_names.contains("bench"));
     }
 }
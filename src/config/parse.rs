@@ -2,7 +2,9 @@ use crate::config::types::*;
 use crate::path_utils::fix_unc_path;
 use clap::{value_t, values_t, ArgMatches};
 use coveralls_api::CiService;
-use serde::de::{self, Deserializer};
+use serde::de::{self, Deserializer, SeqAccess};
+use serde::ser::Serializer;
+use std::convert::TryFrom;
 use std::env;
 use std::fmt;
 use std::fs::create_dir_all;
@@ -15,6 +17,19 @@ pub(super) fn get_list(args: &ArgMatches, key: &str) -> Vec<String> {
     args.values_of_lossy(key).unwrap_or_default()
 }
 
+pub(super) fn get_features(args: &ArgMatches, key: &str) -> Vec<String> {
+    let mut features = vec![];
+    for feature in get_list(args, key)
+        .iter()
+        .flat_map(|x| x.split_whitespace())
+    {
+        if !features.iter().any(|x| x == feature) {
+            features.push(feature.to_string());
+        }
+    }
+    features
+}
+
 pub(super) fn get_line_cov(args: &ArgMatches) -> bool {
     let cover_lines = args.is_present("line");
     let cover_branches = args.is_present("branch");
@@ -29,26 +44,20 @@ pub(super) fn get_branch_cov(args: &ArgMatches) -> bool {
     cover_branches || !cover_lines
 }
 
+/// Gets the manifest from `--manifest-path` or else the `Cargo.toml` in `--root`. Either option
+/// can be a manifest or a directory containing one, relative paths are from the current
+/// directory. Without either the `Cargo.toml` in the current directory is used
 pub(super) fn get_manifest(args: &ArgMatches) -> PathBuf {
-    if let Some(path) = args.value_of("manifest-path") {
-        let path = PathBuf::from(path);
-        if path.is_relative() {
-            return env::current_dir()
-                .unwrap()
-                .join(path)
-                .canonicalize()
-                .unwrap();
-        }
-        return fix_unc_path(&path);
-    }
-
     let mut manifest = env::current_dir().unwrap();
-
-    if let Some(path) = args.value_of("root") {
+    if let Some(path) = args
+        .value_of("manifest-path")
+        .or_else(|| args.value_of("root"))
+    {
         manifest.push(path);
     }
-
-    manifest.push("Cargo.toml");
+    if manifest.is_dir() {
+        manifest.push("Cargo.toml");
+    }
     fix_unc_path(&manifest.canonicalize().unwrap_or(manifest))
 }
 
@@ -58,10 +67,6 @@ pub(super) fn default_manifest() -> PathBuf {
     fix_unc_path(&manifest.canonicalize().unwrap_or(manifest))
 }
 
-pub(super) fn get_target(args: &ArgMatches) -> Option<String> {
-    args.value_of("target").map(String::from)
-}
-
 pub(super) fn get_rustflags(args: &ArgMatches) -> Option<String> {
     args.value_of("rustflags").map(String::from)
 }
@@ -106,10 +111,52 @@ pub(super) fn get_report_uri(args: &ArgMatches) -> Option<String> {
     args.value_of("report-uri").map(ToString::to_string)
 }
 
+pub(super) fn get_changed_since(args: &ArgMatches) -> Option<String> {
+    args.value_of("changed-since").map(ToString::to_string)
+}
+
 pub(super) fn get_profile(args: &ArgMatches) -> Option<String> {
     args.value_of("profile").map(ToString::to_string)
 }
 
+/// Names of the profiles in the `[profile.*]` tables of a manifest or cargo config file
+pub(super) fn declared_profiles(buffer: &str) -> Vec<String> {
+    match buffer.parse::<toml::Table>() {
+        Ok(table) => table
+            .get("profile")
+            .and_then(toml::Value::as_table)
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default(),
+        Err(_) => vec![],
+    }
+}
+
+/// The runner set for the target in the `[target.<triple>]` table of a cargo config file, either
+/// a string of space separated arguments or a list of them
+pub(super) fn declared_runner(buffer: &str, target: &str) -> Option<Vec<String>> {
+    let table = buffer.parse::<toml::Table>().ok()?;
+    match table.get("target")?.get(target)?.get("runner")? {
+        toml::Value::String(runner) => split_runner(runner),
+        toml::Value::Array(args) => {
+            let args = args
+                .iter()
+                .map(|x| x.as_str().map(ToString::to_string))
+                .collect::<Option<Vec<_>>>()?;
+            (!args.is_empty()).then_some(args)
+        }
+        _ => None,
+    }
+}
+
+/// Splits a runner command on whitespace, `None` if it's empty
+pub(super) fn split_runner(runner: &str) -> Option<Vec<String>> {
+    let args = runner
+        .split_whitespace()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    (!args.is_empty()).then_some(args)
+}
+
 pub(super) fn get_outputs(args: &ArgMatches) -> Vec<OutputFile> {
     values_t!(args.values_of("out"), OutputFile).unwrap_or_else(|_| vec![])
 }
@@ -134,6 +181,74 @@ pub(super) fn get_objects(args: &ArgMatches) -> Vec<PathBuf> {
     objs
 }
 
+pub(super) fn get_input_files(args: &ArgMatches) -> Vec<PathBuf> {
+    let mut files = values_t!(args.values_of("input-files"), PathBuf).unwrap_or_else(|_| vec![]);
+    for file in files.iter_mut() {
+        if file.is_relative() {
+            *file = fix_unc_path(&env::current_dir().unwrap().join(&file));
+        }
+    }
+    files
+}
+
+pub(super) fn get_absolute_paths(args: &ArgMatches, key: &str) -> Vec<PathBuf> {
+    let mut paths = values_t!(args.values_of(key), PathBuf).unwrap_or_else(|_| vec![]);
+    for path in paths.iter_mut() {
+        if path.is_relative() {
+            *path = fix_unc_path(&env::current_dir().unwrap().join(&path));
+        }
+    }
+    paths
+}
+
+pub(super) fn get_absolute_path(args: &ArgMatches, key: &str) -> Option<PathBuf> {
+    args.value_of(key).map(|x| {
+        let file = PathBuf::from(x);
+        if file.is_relative() {
+            fix_unc_path(&env::current_dir().unwrap().join(&file))
+        } else {
+            file
+        }
+    })
+}
+
+/// Parses a coverage thresholds file, each line is a path glob and the minimum percentage
+/// separated by `=`. Blank lines and lines starting with `#` are skipped and the glob can be
+/// quoted so a TOML table of globs to percentages is also accepted
+pub(super) fn parse_file_thresholds(text: &str) -> Result<Vec<(glob::Pattern, f64)>, String> {
+    let mut thresholds = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (glob, percent) = line
+            .rsplit_once('=')
+            .ok_or_else(|| format!("line {} isn't `glob = percent`", number + 1))?;
+        let glob = glob.trim();
+        let glob = glob
+            .strip_prefix('"')
+            .and_then(|x| x.strip_suffix('"'))
+            .unwrap_or(glob);
+        let glob = glob::Pattern::new(glob)
+            .map_err(|e| format!("invalid glob '{}' on line {}: {}", glob, number + 1, e))?;
+        let percent = percent
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|x| (0.0..=100.0).contains(x))
+            .ok_or_else(|| {
+                format!(
+                    "threshold on line {} must be a percentage between 0 and 100, found {}",
+                    number + 1,
+                    percent.trim()
+                )
+            })?;
+        thresholds.push((glob, percent));
+    }
+    Ok(thresholds)
+}
+
 pub(super) fn get_run_types(args: &ArgMatches) -> Vec<RunType> {
     let mut res = values_t!(args.values_of("run-types"), RunType).unwrap_or_else(|_| vec![]);
     if args.is_present("lib") && !res.contains(&RunType::Lib) {
@@ -176,26 +291,102 @@ pub(super) fn globs_from_excluded(strs: &[String]) -> Vec<glob::Pattern> {
     files
 }
 
-pub(super) fn get_timeout(args: &ArgMatches) -> Duration {
-    if args.is_present("timeout") {
-        let duration = value_t!(args.value_of("timeout"), u64).unwrap_or(60);
-        Duration::from_secs(duration)
+/// Parses a duration given as a number of seconds, or in the humantime format such as `30s`,
+/// `2m`, `1h30m` or `1s 500ms`. The error has the offending string
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if !value.is_empty() && value.bytes().all(|x| x.is_ascii_digit()) {
+        value
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|e| format!("invalid duration '{value}': {e}"))
     } else {
-        Duration::from_secs(60)
+        humantime_serde::re::humantime::parse_duration(value).map_err(|e| {
+            format!(
+                "invalid duration '{value}': {e}, use a number of seconds or a duration like 30s, 2m or 1h30m"
+            )
+        })
     }
 }
 
+/// The duration passed to an option, the arguments are checked with `parse_duration` when
+/// they're parsed so an invalid value isn't expected here
+fn get_duration(args: &ArgMatches, key: &str) -> Option<Duration> {
+    args.value_of(key).and_then(|x| match parse_duration(x) {
+        Ok(duration) => Some(duration),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    })
+}
+
+pub(super) fn get_timeout(args: &ArgMatches) -> Duration {
+    get_duration(args, "timeout").unwrap_or_else(|| Duration::from_secs(60))
+}
+
 pub(super) fn get_post_test_delay(args: &ArgMatches) -> Option<Duration> {
     if args.is_present("post-test-delay") {
-        value_t!(args.value_of("post-test-delay"), u64)
-            .map(Duration::from_secs)
-            .ok()
-            .or_else(|| Some(Duration::from_secs(1)))
+        get_duration(args, "post-test-delay").or_else(|| Some(Duration::from_secs(1)))
     } else {
         None
     }
 }
 
+pub(super) fn get_shutdown_timeout(args: &ArgMatches) -> Option<Duration> {
+    get_duration(args, "shutdown-timeout")
+}
+
+/// Deserializes a duration written as a number of seconds or a string in a format
+/// `parse_duration` accepts
+pub fn deserialize_duration<'de, D>(d: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct DurationVisitor;
+
+    impl<'de> de::Visitor<'de> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter
+                .write_str("a number of seconds or a duration like \"30s\", \"2m\" or \"1h30m\"")
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Duration::from_secs(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            u64::try_from(v)
+                .map(Duration::from_secs)
+                .map_err(|_| E::custom(format!("invalid duration '{v}': it's negative")))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            parse_duration(v).map_err(E::custom)
+        }
+    }
+
+    d.deserialize_any(DurationVisitor)
+}
+
+pub fn deserialize_optional_duration<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_duration(d).map(Some)
+}
+
 pub fn deserialize_ci_server<'de, D>(d: D) -> Result<Option<CiService>, D::Error>
 where
     D: Deserializer<'de>,
@@ -223,3 +414,158 @@ where
 
     d.deserialize_any(CiServerVisitor)
 }
+
+pub fn deserialize_color<'de, D>(d: D) -> Result<Color, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ColorVisitor;
+
+    impl<'de> de::Visitor<'de> for ColorVisitor {
+        type Value = Color;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("one of auto, always or never")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Color::from_str(v).map_err(E::custom)
+        }
+    }
+
+    d.deserialize_str(ColorVisitor)
+}
+
+pub fn deserialize_features<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct FeaturesVisitor;
+
+    impl<'de> de::Visitor<'de> for FeaturesVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("A space separated string or a list of features")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            let mut features = vec![];
+            for feature in v.split_whitespace() {
+                if !features.iter().any(|x| x == feature) {
+                    features.push(feature.to_string());
+                }
+            }
+            Ok(features)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut features: Vec<String> = vec![];
+            while let Some(feature) = seq.next_element::<String>()? {
+                if !features.contains(&feature) {
+                    features.push(feature);
+                }
+            }
+            Ok(features)
+        }
+    }
+
+    d.deserialize_any(FeaturesVisitor)
+}
+
+/// Targets can be a single target triple or a list of them, duplicates in the list are removed
+pub fn deserialize_targets<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct TargetsVisitor;
+
+    impl<'de> de::Visitor<'de> for TargetsVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("A target triple or a list of target triples")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(vec![v.to_string()])
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut targets: Vec<String> = vec![];
+            while let Some(target) = seq.next_element::<String>()? {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+            Ok(targets)
+        }
+    }
+
+    d.deserialize_any(TargetsVisitor)
+}
+
+/// Rustflags can be a space separated string or a list of flags like `build.rustflags` in a cargo
+/// config file, they're kept as a space separated string
+pub fn deserialize_rustflags<'de, D>(d: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct RustflagsVisitor;
+
+    impl<'de> de::Visitor<'de> for RustflagsVisitor {
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("A space separated string or a list of flags")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Some(v.to_string()))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut flags: Vec<String> = vec![];
+            while let Some(flag) = seq.next_element::<String>()? {
+                flags.push(flag);
+            }
+            Ok(Some(flags.join(" ")))
+        }
+    }
+
+    d.deserialize_any(RustflagsVisitor)
+}
+
+/// Features are written back out in the space separated form unless one of them can't be
+/// represented that way, in which case a list is used.
+pub fn serialize_features<S>(features: &[String], s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if features.iter().any(|x| x.contains(char::is_whitespace)) {
+        s.collect_seq(features)
+    } else {
+        s.serialize_str(&features.join(" "))
+    }
+}
@@ -0,0 +1,261 @@
+use super::Config;
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use schemars::{schema_for, JsonSchema};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Features can be given as a space separated string or a list of features
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(super) enum Features {
+    List(Vec<String>),
+    String(String),
+}
+
+/// Targets can be given as a single target triple or a list of them
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(super) enum Targets {
+    List(Vec<String>),
+    String(String),
+}
+
+/// Rustflags can be given as a space separated string or a list of flags
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(super) enum Rustflags {
+    List(Vec<String>),
+    String(String),
+}
+
+/// Durations can be given as a number of seconds or a string like `"30s"` or `"1h30m"`
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(super) enum Duration {
+    Seconds(u64),
+    String(String),
+}
+
+/// A tarpaulin config file. Each table is a named config which is run in turn, a config named
+/// `report` only sets the options for generating reports
+#[derive(JsonSchema)]
+#[schemars(title = "Tarpaulin config")]
+#[allow(dead_code)]
+struct ConfigFile(HashMap<String, Config>);
+
+/// Fields with defaults that depend on the directory or platform the schema is generated on
+const MACHINE_SPECIFIC_DEFAULTS: &[&str] = &["manifest-path", "engine"];
+
+/// JSON Schema of a tarpaulin config file generated from the `Config` struct, using the doc
+/// comments of each field as its description
+pub fn config_schema() -> RootSchema {
+    let mut schema = schema_for!(ConfigFile);
+    if let Some(Schema::Object(config)) = schema.definitions.get_mut("Config") {
+        let properties = &mut config.object().properties;
+        for name in MACHINE_SPECIFIC_DEFAULTS {
+            if let Some(Schema::Object(field)) = properties.get_mut(*name) {
+                field.metadata().default = None;
+            }
+        }
+    }
+    schema
+}
+
+/// Width the descriptions in the starter config are wrapped to
+const STARTER_CONFIG_WIDTH: usize = 100;
+
+/// A config file with one table where every option is commented out, showing its default value
+/// and with its description above it. This is generated from the schema so it always has every
+/// option, those without a default value show their type instead
+pub fn starter_config() -> String {
+    let schema = config_schema();
+    let mut config = String::new();
+    let description = schema
+        .schema
+        .metadata
+        .as_ref()
+        .and_then(|x| x.description.as_deref())
+        .unwrap_or_default();
+    write_comment(&mut config, description);
+    config.push_str("#\n");
+    write_comment(
+        &mut config,
+        "Uncomment an option to change it from the default shown, see `cargo tarpaulin --help` for more about each option",
+    );
+    config.push_str("[coverage]\n");
+    let properties = match schema.definitions.get("Config") {
+        Some(Schema::Object(config)) => config.object.as_ref().map(|x| &x.properties),
+        _ => None,
+    };
+    for (name, property) in properties.into_iter().flatten() {
+        let property = match property {
+            Schema::Object(property) => property,
+            Schema::Bool(_) => continue,
+        };
+        config.push('\n');
+        let metadata = property.metadata.as_ref();
+        if let Some(description) = metadata.and_then(|x| x.description.as_deref()) {
+            write_comment(&mut config, description);
+        }
+        let value = match metadata.and_then(|x| x.default.as_ref()) {
+            Some(default) if !default.is_null() => toml_value(default),
+            _ => placeholder(property, &schema),
+        };
+        let _ = writeln!(config, "# {name} = {value}");
+    }
+    config
+}
+
+/// Writes the text as comment lines wrapped to `STARTER_CONFIG_WIDTH`
+fn write_comment(config: &mut String, text: &str) {
+    let mut line = String::from("#");
+    for word in text.split_whitespace() {
+        if line.len() > 1 && line.len() + word.len() + 1 > STARTER_CONFIG_WIDTH {
+            let _ = writeln!(config, "{line}");
+            line = String::from("#");
+        }
+        line.push(' ');
+        line.push_str(word);
+    }
+    if line.len() > 1 {
+        let _ = writeln!(config, "{line}");
+    }
+}
+
+/// Formats a default value from the schema as an inline TOML value
+fn toml_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => toml::Value::String(s.clone()).to_string(),
+        Value::Array(values) => format!(
+            "[{}]",
+            values.iter().map(toml_value).collect::<Vec<_>>().join(", ")
+        ),
+        Value::Object(values) if values.is_empty() => "{}".to_string(),
+        Value::Object(values) => format!(
+            "{{ {} }}",
+            values
+                .iter()
+                .map(|(k, v)| format!("{} = {}", toml::Value::String(k.clone()), toml_value(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// The type of an option without a default, such as `<string>`, or the possible values if it's
+/// an enum like `<"Ptrace" | "Llvm">`
+fn placeholder(property: &SchemaObject, schema: &RootSchema) -> String {
+    let reference = property.reference.as_deref().or_else(|| {
+        property
+            .subschemas
+            .as_ref()
+            .and_then(|x| x.all_of.as_ref().or(x.any_of.as_ref()))
+            .into_iter()
+            .flatten()
+            .find_map(|x| match x {
+                Schema::Object(x) => x.reference.as_deref(),
+                Schema::Bool(_) => None,
+            })
+    });
+    let values = reference
+        .and_then(|x| x.strip_prefix("#/definitions/"))
+        .and_then(|x| match schema.definitions.get(x) {
+            Some(Schema::Object(x)) => x.enum_values.as_ref(),
+            _ => None,
+        });
+    if let Some(values) = values {
+        let values = values.iter().map(toml_value).collect::<Vec<_>>();
+        return format!("<{}>", values.join(" | "));
+    }
+    let types = match &property.instance_type {
+        Some(SingleOrVec::Single(x)) => vec![**x],
+        Some(SingleOrVec::Vec(x)) => x.clone(),
+        None => vec![],
+    };
+    let name = types
+        .into_iter()
+        .find(|x| *x != InstanceType::Null)
+        .map(|x| match x {
+            InstanceType::Boolean => "boolean",
+            InstanceType::Object => "table",
+            InstanceType::Array => "array",
+            InstanceType::Number => "number",
+            InstanceType::String => "string",
+            InstanceType::Integer => "integer",
+            InstanceType::Null => "null",
+        })
+        .unwrap_or("value");
+    format!("<{name}>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn schema_describes_fields() {
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let properties = &schema["definitions"]["Config"]["properties"];
+        let all_features = &properties["all-features"];
+        assert_eq!(all_features["type"], "boolean");
+        assert_eq!(all_features["default"], false);
+        assert_eq!(
+            all_features["description"],
+            "Include all available features in target build"
+        );
+        assert_eq!(properties["timeout"]["default"], "1m");
+        assert_eq!(properties["command"]["default"], "Test");
+        assert!(properties["command"]["description"].is_string());
+        assert!(properties["manifest-path"].get("default").is_none());
+        assert!(properties["engine"].get("default").is_none());
+        assert!(properties.get("metadata").is_none());
+        assert_eq!(
+            schema["additionalProperties"]["$ref"],
+            Value::from("#/definitions/Config")
+        );
+    }
+
+    #[test]
+    fn starter_config_has_every_option() {
+        let starter = starter_config();
+        let configs = Config::parse_config_toml(&starter).unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, "coverage");
+
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let properties = schema["definitions"]["Config"]["properties"]
+            .as_object()
+            .unwrap();
+        for name in properties.keys() {
+            assert!(
+                starter.contains(&format!("\n# {} = ", name)),
+                "{} missing",
+                name
+            );
+        }
+        assert!(starter.contains("# timeout = \"1m\"\n"));
+        assert!(starter.contains("# fail-under = <number>\n"));
+        assert!(starter.contains("# engine = <\"Auto\" | \"Ptrace\" | \"Llvm\">\n"));
+
+        // The default values shown are valid when uncommented
+        let uncommented = starter
+            .lines()
+            .filter(|x| !x.contains(" = <"))
+            .map(|x| match x.strip_prefix("# ") {
+                Some(option) if option.contains(" = ") => option,
+                _ => x,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let configs = Config::parse_config_toml(&uncommented).unwrap();
+        assert_eq!(configs[0].test_timeout, Config::default().test_timeout);
+    }
+}
@@ -1,10 +1,12 @@
 use clap::arg_enum;
 use coveralls_api::CiService;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::io::{self, IsTerminal};
 use std::str::FromStr;
 
 arg_enum! {
-    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize, JsonSchema)]
     pub enum Color {
         Auto,
         Always,
@@ -12,8 +14,20 @@ arg_enum! {
     }
 }
 
+impl Color {
+    /// Whether output to stdout should contain ANSI escape codes, for `Auto` this is only the
+    /// case if stdout is a terminal
+    pub fn use_ansi(self) -> bool {
+        match self {
+            Color::Auto => io::stdout().is_terminal(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
 arg_enum! {
-    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize, JsonSchema)]
     pub enum TraceEngine {
         Auto,
         Ptrace,
@@ -46,15 +60,31 @@ impl Default for TraceEngine {
 }
 
 arg_enum! {
-    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+    /// How the llvm coverage counters of a line with more than one, such as a line with several
+    /// statements, are shown in the reports. With `Line` a line is covered if any of its
+    /// counters ran and lines where only some ran are partial, with `Statement` a line is only
+    /// covered if all of them ran and `Region` is `Line` with the column spans of the counters
+    /// which didn't run highlighted in the html report
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize, JsonSchema)]
+    pub enum Granularity {
+        Line,
+        Statement,
+        Region,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize, JsonSchema)]
     pub enum Mode {
         Test,
-        Build
+        Build,
+        Nextest,
+        Bench,
     }
 }
 
 arg_enum! {
-    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize, Serialize, JsonSchema)]
     pub enum RunType {
         Tests,
         Doctests,
@@ -67,7 +97,7 @@ arg_enum! {
 }
 
 arg_enum! {
-    #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize, JsonSchema)]
     #[non_exhaustive]
     pub enum OutputFile {
         Json,
@@ -75,6 +105,32 @@ arg_enum! {
         Xml,
         Html,
         Lcov,
+        UncoveredLines,
+        TeamCity,
+        Junit,
+        Hotspots,
+        StdoutJson,
+        Badge,
+    }
+}
+
+impl OutputFile {
+    /// Name of the file the report is written to in the output directory, stdout isn't
+    /// written to a file
+    pub fn default_file_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Json => Some("tarpaulin-report.json"),
+            Self::Xml => Some("cobertura.xml"),
+            Self::Html => Some("tarpaulin-report.html"),
+            Self::Lcov => Some("lcov.info"),
+            Self::Junit => Some("junit.xml"),
+            Self::Badge => Some("coverage-badge.svg"),
+            Self::Stdout
+            | Self::UncoveredLines
+            | Self::TeamCity
+            | Self::Hotspots
+            | Self::StdoutJson => None,
+        }
     }
 }
 
@@ -1,5 +1,25 @@
+use crate::config::TraceEngine;
 use crate::report::cobertura;
 use std::fmt::{self, Display, Formatter};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Exit codes of the `cargo tarpaulin` binary, these are stable so CI can tell why a run failed
+pub mod exit_code {
+    /// Tests passed and the coverage met any thresholds
+    pub const SUCCESS: i32 = 0;
+    /// Tests failed or failed to compile
+    pub const TEST_FAILURE: i32 = 1;
+    /// The config or command line arguments are invalid
+    pub const CONFIG_ERROR: i32 = 2;
+    /// Coverage is below a threshold or decreased from the baseline, this can be changed with
+    /// `exit-code-on-threshold`
+    pub const BELOW_THRESHOLD: i32 = 3;
+    /// The build produced no test executables so there was nothing to collect coverage from
+    pub const NO_TESTS: i32 = 4;
+    /// Tarpaulin failed to build, run or collect coverage from the tests
+    pub const INTERNAL_ERROR: i32 = 101;
+}
 
 /// Error states that could be returned from tarpaulin
 #[derive(Debug)]
@@ -8,6 +28,8 @@ pub enum RunError {
     Manifest(String),
     /// Cargo failed to run
     Cargo(String),
+    /// `Cargo.lock` needs updating but `locked` or `frozen` is set
+    StaleLockfile(PathBuf),
     /// Error trying to resolve package configuration in manifest
     Packages(String),
     /// Failure when attempting to launch test
@@ -32,11 +54,61 @@ pub enum RunError {
     XML(cobertura::Error),
     Lcov(String),
     Json(String),
+    Junit(String),
+    Badge(String),
     Internal,
     /// Tuple of actual coverage and threshold
     BelowThreshold(f64, f64),
+    /// List of packages with their actual coverage and threshold
+    BelowPackageThreshold(Vec<(String, f64, f64)>),
+    /// List of files with their actual coverage and threshold
+    BelowFileThreshold(Vec<(PathBuf, f64, f64)>),
+    /// Tuple of the decrease in coverage from the baseline and the tolerance
+    CoverageDecrease(f64, f64),
+    /// Tuple of the old and new coverage when the new results of a diff have lower coverage
+    DiffDecrease(f64, f64),
     /// Error relating to tracing engine selected
     Engine(String),
+    /// Failed to get the changed lines from git
+    Git(String),
+    /// Ctrl-C was pressed in watch mode
+    Interrupted,
+    /// Test binaries which exited successfully without running any tests or covering any lines,
+    /// with the reason for each
+    EmptyBinaries(Vec<(PathBuf, String)>),
+    /// The required post report command failed
+    PostReport(String),
+    /// The build didn't produce any test executables to run
+    NoTests,
+}
+
+impl RunError {
+    /// The exit code `cargo tarpaulin` exits with for this error, see [`exit_code`]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::TestFailed
+            | Self::TestRuntime(_)
+            | Self::TestCompile(_)
+            | Self::EmptyBinaries(_) => exit_code::TEST_FAILURE,
+            Self::Manifest(_)
+            | Self::StaleLockfile(_)
+            | Self::Packages(_)
+            | Self::OutFormat(_)
+            | Self::Engine(_) => exit_code::CONFIG_ERROR,
+            Self::BelowThreshold(..)
+            | Self::BelowPackageThreshold(_)
+            | Self::BelowFileThreshold(_)
+            | Self::CoverageDecrease(..)
+            | Self::DiffDecrease(..) => exit_code::BELOW_THRESHOLD,
+            Self::NoTests => exit_code::NO_TESTS,
+            _ => exit_code::INTERNAL_ERROR,
+        }
+    }
+
+    /// Returns true if this is a failure to meet a coverage threshold rather than an error
+    pub fn is_threshold(&self) -> bool {
+        self.exit_code() == exit_code::BELOW_THRESHOLD
+    }
 }
 
 impl Display for RunError {
@@ -44,6 +116,11 @@ impl Display for RunError {
         match self {
             Self::Manifest(e) => write!(f, "Failed to parse Cargo.toml! Error: {e}"),
             Self::Cargo(e) => write!(f, "Cargo failed to run! Error: {e}"),
+            Self::StaleLockfile(path) => write!(
+                f,
+                "{} is out of date; run cargo update, locked or frozen prevents cargo updating it",
+                path.display()
+            ),
             Self::Packages(e) => write!(f, "Failed to resolve package in manifest! Error: {e}"),
             Self::TestLaunch(e) => write!(f, "Failed to launch test: {e}"),
             Self::TestCompile(e) => write!(f, "Failed to compile tests!\n{e}"),
@@ -63,6 +140,8 @@ impl Display for RunError {
             Self::XML(e) => write!(f, "Failed to generate XML report! Error: {e}"),
             Self::Lcov(e) => write!(f, "Failed to generate Lcov report! Error: {e}"),
             Self::Json(e) => write!(f, "Failed to generate JSON report! Error: {e}"),
+            Self::Junit(e) => write!(f, "Failed to generate JUnit report! Error: {e}"),
+            Self::Badge(e) => write!(f, "Failed to generate coverage badge! Error: {e}"),
             Self::Internal => write!(f, "Tarpaulin experienced an internal error"),
             Self::BelowThreshold(a, e) => {
                 write!(
@@ -70,11 +149,160 @@ impl Display for RunError {
                     "Coverage is below the failure threshold {a:.2}% < {e:.2}%"
                 )
             }
+            Self::BelowPackageThreshold(packages) => {
+                write!(f, "Coverage is below the failure threshold for packages:")?;
+                for (package, actual, expected) in packages {
+                    write!(
+                        f,
+                        "\n{package}: {actual:.2}% < {expected:.2}% ({:.2}% short)",
+                        expected - actual
+                    )?;
+                }
+                Ok(())
+            }
+            Self::BelowFileThreshold(files) => {
+                write!(f, "Coverage is below the failure threshold for files:")?;
+                for (file, actual, expected) in files {
+                    write!(
+                        f,
+                        "\n{}: {actual:.2}% < {expected:.2}% ({:.2}% short)",
+                        file.display(),
+                        expected - actual
+                    )?;
+                }
+                Ok(())
+            }
+            Self::CoverageDecrease(d, t) => write!(
+                f,
+                "Coverage decreased by {d:.2}% from the baseline, more than the tolerance of {t:.2}%"
+            ),
+            Self::DiffDecrease(old, new) => write!(
+                f,
+                "Coverage decreased by {:.2}% from {old:.2}% to {new:.2}%",
+                old - new
+            ),
             Self::Engine(s) => write!(f, "Engine error: {s}"),
+            Self::Git(e) => write!(f, "Failed to get changes from git! Error: {e}"),
+            Self::Interrupted => write!(f, "Interrupted"),
+            Self::PostReport(e) => write!(f, "Post report command failed: {e}"),
+            Self::NoTests => write!(f, "No tests found to run; coverage is 0%"),
+            Self::EmptyBinaries(binaries) => {
+                write!(f, "Test executables didn't test anything:")?;
+                for (binary, reason) in binaries {
+                    write!(f, "\n{} {}", binary.display(), reason)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Errors from loading a tarpaulin config file or the tarpaulin tables in a manifest
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file doesn't exist
+    NotFound(PathBuf),
+    /// The config file exists but couldn't be read
+    Io(PathBuf, std::io::Error),
+    /// The config isn't valid toml or doesn't match the expected schema
+    Parse {
+        file: Option<PathBuf>,
+        /// 1-based line and column of the error if it's known
+        position: Option<(usize, usize)>,
+        message: String,
+    },
+    /// The config parsed but the named config has options which can't be used
+    Invalid { name: String, reason: String },
+    /// The named config sets options the selected engine ignores
+    IncompatibleOptions {
+        name: String,
+        engine: TraceEngine,
+        options: Vec<&'static str>,
+    },
+}
+
+impl ConfigError {
+    pub(crate) fn read(path: &Path, e: std::io::Error) -> Self {
+        if e.kind() == ErrorKind::NotFound {
+            Self::NotFound(path.to_path_buf())
+        } else {
+            Self::Io(path.to_path_buf(), e)
         }
     }
+
+    /// Creates a parse error, if the buffer which was parsed is provided the span of the
+    /// error is converted to a line and column
+    pub(crate) fn parse(e: &toml::de::Error, buffer: Option<&str>) -> Self {
+        let position = buffer.zip(e.span()).map(|(buffer, span)| {
+            let before = &buffer[..span.start.min(buffer.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+            (line, column)
+        });
+        Self::Parse {
+            file: None,
+            position,
+            message: e.message().trim().to_string(),
+        }
+    }
+
+    pub(crate) fn in_file(mut self, path: &Path) -> Self {
+        if let Self::Parse { file, .. } = &mut self {
+            *file = Some(path.to_path_buf());
+        }
+        self
+    }
 }
 
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound(p) => write!(f, "Config file {} not found", p.display()),
+            Self::Io(p, e) => write!(f, "Failed to read config file {}: {e}", p.display()),
+            Self::Parse {
+                file,
+                position,
+                message,
+            } => {
+                write!(f, "Invalid config")?;
+                if let Some(file) = file {
+                    write!(f, " {}", file.display())?;
+                }
+                if let Some((line, column)) = position {
+                    write!(f, " at line {line} column {column}")?;
+                }
+                write!(f, ": {message}")
+            }
+            Self::Invalid { name, reason } if name.is_empty() => {
+                write!(f, "Invalid config: {reason}")
+            }
+            Self::Invalid { name, reason } => write!(f, "Invalid config {name}: {reason}"),
+            Self::IncompatibleOptions {
+                name,
+                engine,
+                options,
+            } => {
+                write!(f, "Invalid config")?;
+                if !name.is_empty() {
+                    write!(f, " {name}")?;
+                }
+                let other = match engine {
+                    TraceEngine::Ptrace => "llvm",
+                    _ => "ptrace",
+                };
+                write!(
+                    f,
+                    ": {} can't be used with the {} engine, use `--engine {other}` instead",
+                    options.join(", "),
+                    engine.to_string().to_lowercase(),
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 impl From<std::io::Error> for RunError {
     fn from(e: std::io::Error) -> Self {
         RunError::IO(e)
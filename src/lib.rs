@@ -1,15 +1,23 @@
-use crate::cargo::TestBinary;
+use crate::cargo::{CargoOutput, TestBinary};
 use crate::config::*;
 use crate::errors::*;
 use crate::event_log::*;
 use crate::path_utils::*;
 use crate::process_handling::*;
+use crate::report::junit::TestResults;
 use crate::report::report_coverage;
 use crate::source_analysis::{LineAnalysis, SourceAnalysis};
 use crate::test_loader::*;
+use crate::trace_dump::TraceMapDump;
 use crate::traces::*;
+use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs::{create_dir_all, remove_dir_all};
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
@@ -18,28 +26,62 @@ pub mod cargo;
 pub mod config;
 pub mod errors;
 pub mod event_log;
+pub mod llvm_tools;
 pub mod path_utils;
 mod process_handling;
 pub mod report;
 pub mod source_analysis;
 pub mod statemachine;
 pub mod test_loader;
+pub mod trace_dump;
 pub mod traces;
+pub mod watch;
 
 const RUST_LOG_ENV: &str = "RUST_LOG";
 
+/// Coverage collected by running tarpaulin
+#[derive(Debug)]
+pub struct CoverageReport {
+    /// Coverage of every source file in the project
+    pub traces: TraceMap,
+    /// Set if any tests failed in configs with `no_fail_fast`, coverage is still collected for
+    /// these but the `cargo tarpaulin` binary exits with an error
+    pub tests_failed: bool,
+}
+
+impl CoverageReport {
+    /// Percentage of coverable lines covered as a fraction from 0 to 1
+    pub fn coverage_percentage(&self) -> f64 {
+        self.traces.coverage_percentage()
+    }
+}
+
+/// Sets up tarpaulin's log output. This is optional when using tarpaulin as a library, if it's
+/// not called any `tracing` subscriber can be used to handle the logs instead
 pub fn setup_logging(color: Color, debug: bool, verbose: bool) {
-    //By default, we set tarpaulin to info,debug,trace while all dependencies stay at INFO
+    let level = if debug {
+        LevelFilter::TRACE
+    } else if verbose {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+    setup_logging_with_level(color, level);
+}
+
+/// Sets up tarpaulin's log output with `level` as the most detailed level of tarpaulin's own
+/// logs, which all have targets starting with `cargo_tarpaulin`. Below `INFO` only errors are
+/// logged by dependencies as well so just the summary and any errors are shown
+pub fn setup_logging_with_level(color: Color, level: LevelFilter) {
+    //By default, we set tarpaulin to error,info,debug,trace while all dependencies stay at INFO
     let base_exceptions = |env: EnvFilter| {
-        if debug {
-            env.add_directive("cargo_tarpaulin=trace".parse().unwrap())
-                .add_directive("llvm_profparser=trace".parse().unwrap())
-        } else if verbose {
-            env.add_directive("cargo_tarpaulin=debug".parse().unwrap())
+        let env = env.add_directive(format!("cargo_tarpaulin={level}").parse().unwrap());
+        if level == LevelFilter::TRACE {
+            env.add_directive("llvm_profparser=trace".parse().unwrap())
         } else {
-            env.add_directive("cargo_tarpaulin=info".parse().unwrap())
+            env
         }
-        .add_directive(LevelFilter::INFO.into())
+        .add_directive(level.min(LevelFilter::INFO).into())
     };
 
     //If RUST_LOG is set, then first apply our default directives (which are controlled by debug an verbose).
@@ -61,7 +103,7 @@ pub fn setup_logging(color: Color, debug: bool, verbose: bool) {
         _ => base_exceptions(EnvFilter::from_env(RUST_LOG_ENV)),
     };
 
-    let with_ansi = color != Color::Never;
+    let with_ansi = color.use_ansi();
 
     let res = tracing_subscriber::FmtSubscriber::builder()
         .with_max_level(tracing::Level::ERROR)
@@ -77,6 +119,11 @@ pub fn setup_logging(color: Color, debug: bool, verbose: bool) {
 }
 
 pub fn trace(configs: &[Config]) -> Result<(TraceMap, i32), RunError> {
+    trace_recording(configs, &TestResults::default())
+}
+
+/// Runs the configs like [`trace`], recording the results of the tests in `results`
+fn trace_recording(configs: &[Config], results: &TestResults) -> Result<(TraceMap, i32), RunError> {
     let logger = create_logger(configs);
     let mut tracemap = TraceMap::new();
     let mut ret = 0;
@@ -96,7 +143,7 @@ pub fn trace(configs: &[Config]) -> Result<(TraceMap, i32), RunError> {
 
         create_target_dir(config);
 
-        match launch_tarpaulin(config, &logger) {
+        match launch_recording(config, &logger, results) {
             Ok((t, r)) => {
                 if config.no_fail_fast {
                     fail_fast_ret |= r;
@@ -172,11 +219,199 @@ fn check_fail_threshold(traces: &TraceMap, config: &Config) -> Result<(), RunErr
             error!("{}", error);
             Err(error)
         }
-        _ => Ok(()),
+        _ => {
+            check_package_thresholds(traces, config)?;
+            check_file_thresholds(traces, config)
+        }
     }
 }
 
+fn check_package_thresholds(traces: &TraceMap, config: &Config) -> Result<(), RunError> {
+    if config.fail_under_per_package.is_empty() {
+        return Ok(());
+    }
+    let packages = config
+        .get_packages()
+        .into_iter()
+        .filter_map(|x| {
+            let dir = fix_unc_path(x.manifest_path.parent()?.as_std_path());
+            Some((x.name, dir))
+        })
+        .collect::<Vec<_>>();
+
+    let mut failures = vec![];
+    for (package, percent) in package_coverage(traces, &packages) {
+        let limit = config
+            .fail_under_per_package
+            .get(&package)
+            .or(config.fail_under.as_ref());
+        if let Some(limit) = limit {
+            if percent < *limit {
+                error!(
+                    "{} coverage is {:.2}%, {:.2}% below the threshold of {:.2}%",
+                    package,
+                    percent,
+                    limit - percent,
+                    limit
+                );
+                failures.push((package, percent, *limit));
+            }
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(RunError::BelowPackageThreshold(failures))
+    }
+}
+
+/// Checks the coverage of each file against the most specific matching glob in the coverage
+/// thresholds file, the longest glob is the most specific. `fail_under` is the minimum threshold
+/// of every file matching a glob
+fn check_file_thresholds(traces: &TraceMap, config: &Config) -> Result<(), RunError> {
+    let thresholds = config
+        .file_thresholds()
+        .map_err(|e| RunError::Parse(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?;
+    if thresholds.is_empty() {
+        return Ok(());
+    }
+    let mut failures = vec![];
+    for file in traces.files() {
+        let coverable = traces.coverable_in_path(file);
+        if coverable == 0 {
+            continue;
+        }
+        let path = config.strip_base_dir(file);
+        let limit = thresholds
+            .iter()
+            .filter(|(glob, _)| glob.matches_path(&path))
+            .max_by_key(|(glob, _)| glob.as_str().len())
+            .map(|(_, limit)| limit.max(config.fail_under.unwrap_or_default()));
+        if let Some(limit) = limit {
+            let percent = 100.0 * traces.covered_in_path(file) as f64 / coverable as f64;
+            if percent < limit {
+                error!(
+                    "{} coverage is {:.2}%, {:.2}% below the threshold of {:.2}%",
+                    path.display(),
+                    percent,
+                    limit - percent,
+                    limit
+                );
+                failures.push((path, percent, limit));
+            }
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(RunError::BelowFileThreshold(failures))
+    }
+}
+
+/// The package with the closest root directory containing the file, packages are the name and
+/// root directory of each package
+pub(crate) fn closest_package<'a>(
+    file: &Path,
+    packages: &'a [(String, PathBuf)],
+) -> Option<&'a (String, PathBuf)> {
+    packages
+        .iter()
+        .filter(|(_, dir)| file.starts_with(dir))
+        .max_by_key(|(_, dir)| dir.components().count())
+}
+
+/// Assigns each file in the tracemap to the package with the closest root directory and returns
+/// the coverage percentage for each package with coverable lines.
+fn package_coverage(traces: &TraceMap, packages: &[(String, PathBuf)]) -> Vec<(String, f64)> {
+    let mut package_files: HashMap<&str, Vec<&Path>> = HashMap::new();
+    for file in traces.files() {
+        if let Some((name, _)) = closest_package(file, packages) {
+            package_files.entry(name).or_default().push(file);
+        }
+    }
+    let mut result = package_files
+        .into_iter()
+        .filter_map(|(name, files)| {
+            let file_traces = || files.iter().flat_map(|x| traces.get_child_traces(x));
+            if amount_coverable(file_traces()) == 0 {
+                None
+            } else {
+                Some((name.to_string(), coverage_percentage(file_traces()) * 100.0))
+            }
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+/// The code `cargo tarpaulin` exits with after running the configs fails with the error. This is
+/// the code from [`errors::exit_code`] for the error, unless a config sets
+/// `exit_code_on_threshold` for coverage thresholds
+pub fn error_exit_code(configs: &[Config], error: &RunError) -> i32 {
+    if error.is_threshold() {
+        if let Some(code) = configs.iter().find_map(|x| x.exit_code_on_threshold) {
+            return code;
+        }
+    }
+    error.exit_code()
+}
+
+/// Runs `cargo tarpaulin` for the configs, this is what the binary calls once it's loaded the
+/// configs and set up logging. Use [`run_coverage`] to get the coverage as well
 pub fn run(configs: &[Config]) -> Result<(), RunError> {
+    if configs.iter().any(|x| x.dry_run) {
+        return print_cargo_commands(configs);
+    }
+    let _stdout = configs
+        .iter()
+        .any(|x| x.generate.contains(&OutputFile::StdoutJson))
+        .then(report::json::redirect_stdout);
+    if configs.iter().any(|x| x.watch) {
+        watch::watch(configs, run_once)
+    } else {
+        run_once(configs)
+    }
+}
+
+/// Prints the cargo commands each config builds the tests with instead of running anything
+fn print_cargo_commands(configs: &[Config]) -> Result<(), RunError> {
+    let configs = configs
+        .iter()
+        .filter(|x| x.name != "report")
+        .collect::<Vec<_>>();
+    for config in &configs {
+        config.check_engine()?;
+        if configs.len() > 1 {
+            println!("# {}", config_name(config));
+        }
+        for line in cargo::cargo_command_lines(config) {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+fn run_once(configs: &[Config]) -> Result<(), RunError> {
+    let report = run_coverage(configs)?;
+    if report.tests_failed {
+        // So we had a test fail in a way where we still want to report coverage so since we've now
+        // done that we can return the test failed error.
+        Err(RunError::TestFailed)
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects the coverage for the configs and writes any reports they set, returning the
+/// coverage collected. Unlike [`run`] this ignores `watch` and `dry_run` and never exits the
+/// process, so it's the entry point for driving tarpaulin from other tools
+pub fn run_coverage(configs: &[Config]) -> Result<CoverageReport, RunError> {
+    // Configs built in code rather than loaded by `ConfigWrapper` haven't been checked
+    for config in configs {
+        config
+            .check_line_patterns()
+            .map_err(|e| RunError::OutFormat(e.to_string()))?;
+    }
     if configs.iter().any(|x| x.engine() == TraceEngine::Llvm) {
         let profraw_dir = configs[0].profraw_dir();
         let _ = remove_dir_all(&profraw_dir);
@@ -187,19 +422,29 @@ pub fn run(configs: &[Config]) -> Result<(), RunError> {
             );
         }
     }
-    let (tracemap, ret) = collect_tracemap(configs)?;
-    report_tracemap(configs, tracemap)?;
-    if ret != 0 {
-        // So we had a test fail in a way where we still want to report coverage so since we've now
-        // done that we can return the test failed error.
-        Err(RunError::TestFailed)
-    } else {
-        Ok(())
+    let results = TestResults::default();
+    let traced = collect_tracemap(configs, &results);
+    if let Some(config) = configs
+        .iter()
+        .find(|x| x.name == "report")
+        .or_else(|| configs.first())
+        .filter(|x| !x.no_run && x.write_junit())
+    {
+        results.export(config)?;
     }
+    let (tracemap, ret) = traced?;
+    report_tracemap(configs, &tracemap)?;
+    Ok(CoverageReport {
+        traces: tracemap,
+        tests_failed: ret != 0,
+    })
 }
 
-fn collect_tracemap(configs: &[Config]) -> Result<(TraceMap, i32), RunError> {
-    let (mut tracemap, ret) = trace(configs)?;
+fn collect_tracemap(
+    configs: &[Config],
+    results: &TestResults,
+) -> Result<(TraceMap, i32), RunError> {
+    let (mut tracemap, ret) = trace_recording(configs, results)?;
     if !configs.is_empty() {
         // Assumption: all configs are for the same project
         for dir in get_source_walker(&configs[0]) {
@@ -210,97 +455,576 @@ fn collect_tracemap(configs: &[Config]) -> Result<(TraceMap, i32), RunError> {
     Ok((tracemap, ret))
 }
 
-pub fn report_tracemap(configs: &[Config], tracemap: TraceMap) -> Result<(), RunError> {
+pub fn report_tracemap(configs: &[Config], tracemap: &TraceMap) -> Result<(), RunError> {
     let mut reported = false;
+    let reports = |c: &Config| !c.no_run || c.merge_only();
     for c in configs.iter() {
-        if c.no_run || c.name != "report" {
+        if !reports(c) || c.name != "report" {
             continue;
         }
 
-        report_coverage_with_check(c, &tracemap)?;
+        report_coverage_with_check(c, tracemap)?;
         reported = true;
     }
 
-    if !reported && !configs.is_empty() && !configs[0].no_run {
-        report_coverage_with_check(&configs[0], &tracemap)?;
+    if !reported && !configs.is_empty() && reports(&configs[0]) {
+        report_coverage_with_check(&configs[0], tracemap)?;
     }
 
     Ok(())
 }
 
 fn report_coverage_with_check(c: &Config, tracemap: &TraceMap) -> Result<(), RunError> {
-    report_coverage(c, tracemap)?;
-    check_fail_threshold(tracemap, c)
+    let patch = if tracemap.is_empty() {
+        None
+    } else {
+        report::patch::patch_coverage(tracemap, c)?
+    };
+    report::report_coverage_with_patch(c, tracemap, patch.as_ref())?;
+    check_fail_threshold(patch.as_ref().unwrap_or(tracemap), c)?;
+    check_coverage_decrease(tracemap, c)
+}
+
+/// With `fail_on_decrease` set fails if the coverage has decreased from the baseline by more
+/// than the tolerance
+fn check_coverage_decrease(traces: &TraceMap, config: &Config) -> Result<(), RunError> {
+    if !config.fail_on_decrease {
+        return Ok(());
+    }
+    let baseline = match report::load_baseline(config)? {
+        Some(baseline) => baseline,
+        None => {
+            warn!("fail-on-decrease is set without a baseline to compare to");
+            return Ok(());
+        }
+    };
+    let decrease = (baseline.coverage_percentage() - traces.coverage_percentage()) * 100.0;
+    let tolerance = config.decrease_tolerance.unwrap_or_default();
+    if decrease > tolerance {
+        let error = RunError::CoverageDecrease(decrease, tolerance);
+        error!("{}", error);
+        Err(error)
+    } else {
+        Ok(())
+    }
 }
 
 /// Launches tarpaulin with the given configuration.
 pub fn launch_tarpaulin(
     config: &Config,
     logger: &Option<EventLog>,
+) -> Result<(TraceMap, i32), RunError> {
+    launch_recording(config, logger, &TestResults::default())
+}
+
+/// Launches tarpaulin like [`launch_tarpaulin`], recording the results of the tests in `results`
+fn launch_recording(
+    config: &Config,
+    logger: &Option<EventLog>,
+    results: &TestResults,
 ) -> Result<(TraceMap, i32), RunError> {
     if !config.name.is_empty() {
         info!("Running config {}", config.name);
     }
 
+    let mut result = TraceMap::new();
+    if config.merge_only() {
+        info!("Merging previous results without building the tests");
+        merge_input_files(&mut result, config)?;
+        let gitignore = GitIgnore::new(config);
+        result.retain_files(|x| !config.exclude_path(x) && !gitignore.is_ignored(x));
+        return Ok((result, 0));
+    }
+
     info!("Running Tarpaulin");
+    if config.profraws.is_empty() {
+        config.check_engine()?;
+    } else {
+        // The binaries were built and run elsewhere so only the profiles need to be read
+        config.set_engine(TraceEngine::Llvm);
+    }
+    if config.engine() == TraceEngine::Llvm {
+        llvm_tools::check_tools(config)?;
+    }
 
-    let mut result = TraceMap::new();
     let mut return_code = 0i32;
-    info!("Building project");
-    let executables = cargo::get_tests(config)?;
+    let mut executables = if config.input_binaries.is_empty() {
+        info!("Building project");
+        let targets = cargo::runnable_targets(config);
+        if targets.is_empty() {
+            return Err(RunError::TestLaunch(format!(
+                "none of the targets {} can run on this host, set target-runner to run them with an emulator",
+                config.target.join(", ")
+            )));
+        }
+        let mut executables = CargoOutput::default();
+        for target in &targets {
+            if targets.len() > 1 {
+                info!("Building for target {}", target.target[0]);
+            }
+            executables.extend(cargo::get_tests(target)?);
+        }
+        if config.no_run {
+            cargo::save_built_binaries(config, &executables)?;
+        }
+        executables
+    } else {
+        info!("Using previously built test binaries");
+        cargo::load_input_binaries(config)?
+    };
     if !config.no_run {
-        let project_analysis = SourceAnalysis::get_analysis(config);
+        // Test executables which ran none of their tests are caught by `fail_on_empty_binary`,
+        // merging previous results or covering build scripts doesn't need any tests
+        let build_scripts = config.cover_build_scripts && !executables.build_scripts.is_empty();
+        if executables.test_binaries.is_empty() && config.input_files.is_empty() && !build_scripts {
+            return Err(RunError::NoTests);
+        }
+        cargo::select_shard(config, &mut executables.test_binaries);
+        let mut project_analysis = SourceAnalysis::get_analysis(config);
+        let branch_analysis = std::mem::take(&mut project_analysis.branches);
         let project_analysis = project_analysis.lines;
         let mut other_bins = config.objects().to_vec();
         other_bins.extend(executables.binaries.iter().cloned());
-        for exe in &executables.test_binaries {
-            if exe.should_panic() {
-                info!("Running a test executable that is expected to panic");
-            }
-            let coverage =
-                get_test_coverage(exe, &other_bins, &project_analysis, config, false, logger);
-
-            let coverage = match coverage {
-                Ok(coverage) => coverage,
-                Err(run_error) => {
-                    if config.no_fail_fast {
-                        info!("No failing fast!");
-                        return_code = 101;
-                        None
-                    } else {
-                        return Err(run_error);
-                    }
+        if !config.profraws.is_empty() {
+            info!("Collecting coverage from profiles");
+            let mut binaries = executables
+                .test_binaries
+                .iter()
+                .map(|x| x.path().to_path_buf())
+                .collect::<Vec<_>>();
+            binaries.extend(other_bins.iter().filter(|x| x.exists()).cloned());
+            result = statemachine::instrumented::coverage_from_profraws(
+                &binaries,
+                &project_analysis,
+                config,
+            )?;
+        } else {
+            let runs = config.run_count.max(1);
+            let mut covered_by_run = vec![];
+            for run in 1..=runs {
+                if runs > 1 {
+                    info!("Running the tests, run {} of {}", run, runs);
                 }
+                // Each run starts from empty traces so the lines it covered can be compared
+                let (coverage, code) = run_tests(
+                    &executables.test_binaries,
+                    &other_bins,
+                    &project_analysis,
+                    config,
+                    logger,
+                    results,
+                )?;
+                if runs > 1 {
+                    covered_by_run.push(coverage.covered_lines());
+                }
+                result.merge(&coverage);
+                return_code |= code;
+            }
+            for (file, lines) in covered_in_some_runs(&covered_by_run) {
+                result.add_flaky(&file, lines);
+            }
+        }
+        if config.cover_build_scripts && !executables.build_scripts.is_empty() {
+            info!("Collecting coverage from build scripts");
+            result.merge(&statemachine::instrumented::build_script_coverage(
+                &executables.build_scripts,
+                &project_analysis,
+                config,
+            )?);
+        }
+        result.dedup();
+        for (file, analysis) in &project_analysis {
+            if result.contains_file(file) {
+                result.add_functions(file, analysis.functions.iter().cloned());
+                result.add_unsafe_lines(file, analysis.unsafe_lines.iter().map(|x| *x as u64));
+            }
+        }
+        for (file, analysis) in &branch_analysis {
+            if result.contains_file(file) {
+                result.add_branches(file, analysis.branch_points());
+            }
+        }
+        result.hash_sources();
+        merge_input_files(&mut result, config)?;
+        // Previous runs may have been collected with different file filters
+        let gitignore = GitIgnore::new(config);
+        if let Some(path) = config.dump_trace_map.as_ref() {
+            TraceMapDump::new(&result, &project_analysis, config, &gitignore).write(path)?;
+        }
+        if config.granularity == Granularity::Statement {
+            result.uncover_partial_lines();
+        }
+        result.retain_files(|x| !config.exclude_path(x) && !gitignore.is_ignored(x));
+    }
+    Ok((result, return_code))
+}
+
+/// Runs every test executable once, returning the coverage collected and the return code
+fn run_tests(
+    test_binaries: &[TestBinary],
+    other_bins: &[PathBuf],
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+    logger: &Option<EventLog>,
+    results: &TestResults,
+) -> Result<(TraceMap, i32), RunError> {
+    let mut result = TraceMap::new();
+    let mut return_code = 0i32;
+    let mut empty_binaries = vec![];
+    let jobs = config.test_jobs();
+    if jobs > 1 && logger.is_none() {
+        info!("Running up to {} test executables at once", jobs);
+        let runs =
+            run_tests_in_parallel(test_binaries, other_bins, analysis, config, jobs, results);
+        for (exe, res) in test_binaries.iter().zip(runs) {
+            let (coverage, code) = res?;
+            empty_binaries.extend(empty_binary(exe, &coverage, code, config, results));
+            result.merge(&coverage);
+            return_code |= code;
+        }
+    } else {
+        for exe in test_binaries {
+            let (coverage, code) =
+                run_test_binary(exe, other_bins, analysis, config, logger, results)?;
+            empty_binaries.extend(empty_binary(exe, &coverage, code, config, results));
+            result.merge(&coverage);
+            return_code |= code;
+        }
+    }
+    if !empty_binaries.is_empty() {
+        return Err(RunError::EmptyBinaries(empty_binaries));
+    }
+    Ok((result, return_code))
+}
+
+/// With `fail_on_empty_binary` returns the path of a test executable which exited successfully
+/// without running any tests, such as one where every test is ignored, or without covering any
+/// lines along with why it's empty. Whether it ran any tests is only known when its harness
+/// printed how many tests it was running
+fn empty_binary(
+    exe: &TestBinary,
+    coverage: &TraceMap,
+    code: i32,
+    config: &Config,
+    results: &TestResults,
+) -> Option<(PathBuf, String)> {
+    if !config.fail_on_empty_binary || code != 0 {
+        return None;
+    }
+    let reason = match results.tests_run(exe) {
+        Some(0) => "ran none of its tests".to_string(),
+        _ if coverage.total_covered() > 0 => return None,
+        Some(run) => format!("ran {run} tests without covering any lines"),
+        None => "passed without covering any lines".to_string(),
+    };
+    error!("{} {}", exe.path().display(), reason);
+    Some((exe.path().to_path_buf(), reason))
+}
+
+/// Runs a test executable, and again for the ignored tests if they're included, returning the
+/// coverage collected and the return code
+fn run_test_binary(
+    exe: &TestBinary,
+    other_bins: &[PathBuf],
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+    logger: &Option<EventLog>,
+    results: &TestResults,
+) -> Result<(TraceMap, i32), RunError> {
+    let mut result = TraceMap::new();
+    let mut return_code = 0i32;
+    if exe.should_panic() {
+        info!("Running a test executable that is expected to panic");
+    }
+    match get_test_coverage(exe, other_bins, analysis, config, false, logger, results) {
+        Ok(Some(res)) => {
+            result.merge(&res.0);
+            return_code |= if exe.should_panic() {
+                (res.1 == 0).into()
+            } else {
+                res.1
             };
-            if let Some(res) = coverage {
+        }
+        Ok(None) => {}
+        Err(run_error) => {
+            if config.no_fail_fast {
+                info!("No failing fast!");
+                return_code = 101;
+            } else {
+                return Err(run_error);
+            }
+        }
+    }
+    // Examples have no ignored tests to run
+    if config.run_ignored && exe.example().is_none() {
+        match get_test_coverage(exe, other_bins, analysis, config, true, logger, results) {
+            Ok(Some(res)) => {
                 result.merge(&res.0);
-                return_code |= if exe.should_panic() {
-                    (res.1 == 0).into()
+                return_code |= res.1;
+            }
+            Ok(None) => {}
+            Err(run_error) => {
+                if config.no_fail_fast {
+                    return_code = 101;
                 } else {
-                    res.1
-                };
+                    return Err(run_error);
+                }
             }
-            if config.run_ignored {
-                let coverage =
-                    get_test_coverage(exe, &other_bins, &project_analysis, config, true, logger);
-                let coverage = match coverage {
-                    Ok(coverage) => coverage,
-                    Err(run_error) => {
-                        if config.no_fail_fast {
-                            return_code = 101;
-                            None
-                        } else {
-                            return Err(run_error);
-                        }
+        }
+    }
+    Ok((result, return_code))
+}
+
+/// Runs the test executables on up to `jobs` threads. The results are returned in the order of
+/// the executables so merging them doesn't depend on which finished first. If failing fast no
+/// more executables are started after one fails.
+fn run_tests_in_parallel(
+    executables: &[TestBinary],
+    other_bins: &[PathBuf],
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+    jobs: usize,
+    test_results: &TestResults,
+) -> Vec<Result<(TraceMap, i32), RunError>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(Vec::with_capacity(executables.len()));
+    thread::scope(|s| {
+        for _ in 0..jobs.min(executables.len()) {
+            let config = config.clone();
+            let (next, failed, results) = (&next, &failed, &results);
+            s.spawn(move || {
+                while !failed.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let exe = match executables.get(index) {
+                        Some(exe) => exe,
+                        None => break,
+                    };
+                    let res =
+                        run_test_binary(exe, other_bins, analysis, &config, &None, test_results);
+                    if res.is_err() {
+                        failed.store(true, Ordering::SeqCst);
                     }
-                };
-                if let Some(res) = coverage {
-                    result.merge(&res.0);
-                    return_code |= res.1;
+                    results.lock().unwrap().push((index, res));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, res)| res).collect()
+}
+
+/// Merges the coverage results from previous runs into the results of this one
+fn merge_input_files(result: &mut TraceMap, config: &Config) -> Result<(), RunError> {
+    for input in &config.input_files {
+        info!("Merging coverage results from {}", input.display());
+        let file = File::open(input).map_err(|e| {
+            RunError::CovReport(format!("Failed to open {}: {}", input.display(), e))
+        })?;
+        let other: TraceMap = serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            RunError::CovReport(format!("Failed to read {}: {}", input.display(), e))
+        })?;
+        if let Some(file) = result.find_source_mismatch(&other) {
+            return Err(RunError::CovReport(format!(
+                "{} was collected from different source code, {} has changed",
+                input.display(),
+                file.display()
+            )));
+        }
+        result.merge(&other);
+    }
+    result.dedup();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use std::{env, fs};
+
+    #[test]
+    fn package_coverage_nested_packages() {
+        let mut traces = TraceMap::new();
+        let mut hit = Trace::new_stub(1);
+        hit.stats = CoverageStat::Line(1);
+        traces.add_trace(Path::new("/ws/core/src/lib.rs"), hit.clone());
+        traces.add_trace(Path::new("/ws/core/src/lib.rs"), Trace::new_stub(2));
+        traces.add_trace(Path::new("/ws/core/playground/src/lib.rs"), hit);
+        traces.add_trace(Path::new("/ws/other/src/lib.rs"), Trace::new_stub(1));
+        traces.add_file(Path::new("/ws/empty/src/lib.rs"));
+
+        let packages = vec![
+            ("core".to_string(), PathBuf::from("/ws/core")),
+            (
+                "playground".to_string(),
+                PathBuf::from("/ws/core/playground"),
+            ),
+            ("empty".to_string(), PathBuf::from("/ws/empty")),
+        ];
+        let coverage = package_coverage(&traces, &packages);
+        assert_eq!(
+            coverage,
+            vec![
+                ("core".to_string(), 50.0),
+                ("playground".to_string(), 100.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn coverage_decrease_from_baseline() {
+        let mut hit = Trace::new_stub(1);
+        hit.stats = CoverageStat::Line(1);
+        let mut baseline = TraceMap::new();
+        baseline.add_trace(Path::new("/src/lib.rs"), hit.clone());
+        baseline.add_trace(Path::new("/src/removed.rs"), hit.clone());
+        let path = env::temp_dir().join(format!("tarpaulin-baseline-{}.json", std::process::id()));
+        fs::write(&path, serde_json::to_string(&baseline).unwrap()).unwrap();
+
+        // 50% coverage against a baseline of 100%
+        let mut traces = TraceMap::new();
+        traces.add_trace(Path::new("/src/lib.rs"), hit);
+        traces.add_trace(Path::new("/src/new.rs"), Trace::new_stub(1));
+
+        let mut config = Config::default();
+        config.baseline = Some(path.clone());
+        assert!(check_coverage_decrease(&traces, &config).is_ok());
+
+        config.fail_on_decrease = true;
+        assert!(matches!(
+            check_coverage_decrease(&traces, &config),
+            Err(RunError::CoverageDecrease(d, t)) if d == 50.0 && t == 0.0
+        ));
+
+        config.decrease_tolerance = Some(50.0);
+        assert!(check_coverage_decrease(&traces, &config).is_ok());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn file_thresholds_checked() {
+        let path = env::temp_dir().join(format!(
+            "tarpaulin-coveragethresholds-{}",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            "# most specific glob wins\nsrc/** = 50\n\"src/report/*.rs\" = 90\ntests/** = 0\n",
+        )
+        .unwrap();
+        let mut traces = TraceMap::new();
+        let mut add_file = |file: &str, covered: u64, uncovered: u64| {
+            for line in 0..(covered + uncovered) {
+                let mut trace = Trace::new_stub(line + 1);
+                if line < covered {
+                    trace.stats = CoverageStat::Line(1);
                 }
+                traces.add_trace(Path::new(file), trace);
             }
+        };
+        add_file("/project/src/lib.rs", 3, 1);
+        add_file("/project/src/report/html.rs", 1, 1);
+        add_file("/project/tests/a.rs", 0, 1);
+        add_file("/project/build.rs", 0, 1);
+
+        let mut config = Config::default();
+        config.set_manifest(PathBuf::from("/project/Cargo.toml"));
+        config.coverage_thresholds = Some(path.clone());
+        match check_file_thresholds(&traces, &config) {
+            Err(RunError::BelowFileThreshold(files)) => assert_eq!(
+                files,
+                vec![(PathBuf::from("src/report/html.rs"), 50.0, 90.0)]
+            ),
+            e => panic!("Expected file threshold error: {:?}", e),
         }
-        result.dedup();
+
+        // fail-under is the minimum for files matching a glob
+        config.fail_under = Some(80.0);
+        match check_file_thresholds(&traces, &config) {
+            Err(RunError::BelowFileThreshold(files)) => assert_eq!(
+                files.into_iter().map(|x| x.0).collect::<Vec<_>>(),
+                vec![
+                    PathBuf::from("src/lib.rs"),
+                    PathBuf::from("src/report/html.rs"),
+                    PathBuf::from("tests/a.rs")
+                ]
+            ),
+            e => panic!("Expected file threshold error: {:?}", e),
+        }
+
+        config.coverage_thresholds = None;
+        assert!(check_file_thresholds(&traces, &config).is_ok());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn empty_binaries_found() {
+        let exe = TestBinary::new(PathBuf::from("/target/debug/deps/empty-1234"), None);
+        let mut coverage = TraceMap::new();
+        coverage.add_trace(Path::new("/src/lib.rs"), Trace::new_stub(1));
+        let mut config = Config::default();
+        let results = TestResults::default();
+        let empty = |reason: &str| {
+            Some((
+                PathBuf::from("/target/debug/deps/empty-1234"),
+                reason.to_string(),
+            ))
+        };
+        assert_eq!(empty_binary(&exe, &coverage, 0, &config, &results), None);
+
+        config.fail_on_empty_binary = true;
+        assert_eq!(
+            empty_binary(&exe, &coverage, 0, &config, &results),
+            empty("passed without covering any lines")
+        );
+        // Failing tests are already reported
+        assert_eq!(empty_binary(&exe, &coverage, 101, &config, &results), None);
+
+        let mut hit = Trace::new_stub(2);
+        hit.stats = CoverageStat::Line(1);
+        coverage.add_trace(Path::new("/src/lib.rs"), hit);
+        assert_eq!(empty_binary(&exe, &coverage, 0, &config, &results), None);
+
+        // Coverage from a shared setup doesn't hide an executable which ran none of its tests
+        let output = "running 2 tests\ntest a ... ignored\ntest b ... ignored\n";
+        results.record(&exe, output, Duration::from_secs(1), None);
+        assert_eq!(
+            empty_binary(&exe, &coverage, 0, &config, &results),
+            empty("ran none of its tests")
+        );
+        let ran = TestResults::default();
+        ran.record(&exe, "running 2 tests\n", Duration::from_secs(1), None);
+        assert_eq!(
+            empty_binary(&exe, &TraceMap::new(), 0, &config, &ran),
+            empty("ran 2 tests without covering any lines")
+        );
+    }
+
+    #[test]
+    fn error_exit_codes() {
+        let mut config = Config::default();
+        let below = RunError::BelowThreshold(50.0, 80.0);
+        assert_eq!(error_exit_code(&[config.clone()], &below), 3);
+        assert_eq!(error_exit_code(&[config.clone()], &RunError::TestFailed), 1);
+        assert_eq!(
+            error_exit_code(&[config.clone()], &RunError::Engine(String::new())),
+            2
+        );
+        assert_eq!(error_exit_code(&[config.clone()], &RunError::Internal), 101);
+        assert_eq!(error_exit_code(&[config.clone()], &RunError::NoTests), 4);
+        let diff = RunError::DiffDecrease(80.0, 75.0);
+        assert_eq!(error_exit_code(&[config.clone()], &diff), 3);
+        assert_eq!(
+            diff.to_string(),
+            "Coverage decreased by 5.00% from 80.00% to 75.00%"
+        );
+
+        config.exit_code_on_threshold = Some(0);
+        assert_eq!(error_exit_code(&[config.clone()], &below), 0);
+        let decrease = RunError::CoverageDecrease(5.0, 1.0);
+        assert_eq!(error_exit_code(&[config.clone()], &decrease), 0);
+        assert_eq!(error_exit_code(&[config], &RunError::TestFailed), 1);
     }
-    Ok((result, return_code))
 }
@@ -0,0 +1,268 @@
+//! Finding and running `llvm-profdata` and `llvm-cov` for the llvm engine. Tarpaulin reads the
+//! profiles and coverage mappings itself, the tools are only run when it can't read them such as
+//! profiles written by a newer version of LLVM than it supports
+use crate::cargo::{host_target, rustc_output};
+use crate::config::Config;
+use crate::errors::RunError;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info};
+
+/// An LLVM tool tarpaulin can read the coverage with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LlvmTool {
+    Profdata,
+    Cov,
+}
+
+impl LlvmTool {
+    /// Name of the executable, without the platform's suffix
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Profdata => "llvm-profdata",
+            Self::Cov => "llvm-cov",
+        }
+    }
+
+    /// The environment variable the path of the tool can be set with
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Self::Profdata => "LLVM_PROFDATA",
+            Self::Cov => "LLVM_COV",
+        }
+    }
+
+    fn option(self) -> &'static str {
+        match self {
+            Self::Profdata => "llvm-profdata-path",
+            Self::Cov => "llvm-cov-path",
+        }
+    }
+
+    /// The path set with the config option or else the environment variable
+    fn set_path(self, config: &Config) -> Option<PathBuf> {
+        let option = match self {
+            Self::Profdata => config.llvm_profdata_path.as_ref(),
+            Self::Cov => config.llvm_cov_path.as_ref(),
+        };
+        option.cloned().or_else(|| {
+            env::var_os(self.env_var())
+                .filter(|x| !x.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+}
+
+/// Finds `tool` at the path set with its option or environment variable, otherwise in the rustup
+/// `llvm-tools` component of the toolchain and then in `PATH`. A path set which is only a file
+/// name, such as `llvm-profdata-17`, is looked for in `PATH`
+pub fn find_tool(config: &Config, tool: LlvmTool) -> Result<PathBuf, RunError> {
+    let set_path = tool.set_path(config);
+    let toolchain_bin = match set_path {
+        Some(_) => None,
+        None => toolchain_bin(config),
+    };
+    find_tool_in(tool, set_path, toolchain_bin, env::var_os("PATH")).map_err(|searched| {
+        RunError::Engine(format!(
+            "{} not found, searched {}. Install it with `rustup component add llvm-tools` or set its path with {} or {}",
+            tool.name(),
+            searched
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            tool.option(),
+            tool.env_var()
+        ))
+    })
+}
+
+/// The directory the rustup `llvm-tools` component installs the tools in
+fn toolchain_bin(config: &Config) -> Option<PathBuf> {
+    let sysroot = rustc_output(config, &["--print", "sysroot"])?;
+    let host = host_target(config)?;
+    Some(
+        Path::new(&sysroot)
+            .join("lib/rustlib")
+            .join(host)
+            .join("bin"),
+    )
+}
+
+/// The path of `tool` from the path set for it or the first of the toolchain's `bin` directory
+/// and the directories in `path` which has it, otherwise the paths searched
+fn find_tool_in(
+    tool: LlvmTool,
+    set_path: Option<PathBuf>,
+    toolchain_bin: Option<PathBuf>,
+    path: Option<OsString>,
+) -> Result<PathBuf, Vec<PathBuf>> {
+    let path_dirs = || {
+        path.as_ref()
+            .map(|x| env::split_paths(x).collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+    let candidates = match set_path {
+        Some(set) if set.components().count() == 1 && !set.is_file() => {
+            path_dirs().into_iter().map(|x| x.join(&set)).collect()
+        }
+        Some(set) => vec![set],
+        None => {
+            let name = format!("{}{}", tool.name(), env::consts::EXE_SUFFIX);
+            toolchain_bin
+                .into_iter()
+                .chain(path_dirs())
+                .map(|x| x.join(&name))
+                .collect::<Vec<_>>()
+        }
+    };
+    match candidates.iter().find(|x| x.is_file()) {
+        Some(found) => Ok(found.clone()),
+        None => Err(candidates),
+    }
+}
+
+/// The version line printed by `--version`
+fn tool_version(path: &Path) -> Option<String> {
+    let output = Command::new(path).arg("--version").output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|x| x.contains("version"))
+        .map(str::to_string)
+}
+
+/// Checks the tools with a path set exist and prints the version of each tool found in verbose
+/// mode. Tools without a path set are only needed if tarpaulin can't read the coverage itself so
+/// aren't an error if they're missing
+pub fn check_tools(config: &Config) -> Result<(), RunError> {
+    for tool in [LlvmTool::Profdata, LlvmTool::Cov] {
+        match find_tool(config, tool) {
+            Ok(path) => debug!(
+                "Using {} {}",
+                path.display(),
+                tool_version(&path).unwrap_or_else(|| "of an unknown version".to_string())
+            ),
+            Err(e) if tool.set_path(config).is_some() => return Err(e),
+            Err(e) => debug!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Runs the command returning its stdout, failing with its stderr if it doesn't succeed
+fn run(command: &mut Command) -> Result<String, RunError> {
+    let output = command.output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(RunError::TestCoverage(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Merges the profiles with `llvm-profdata` and exports the coverage of the binaries from them
+/// with `llvm-cov`, returning the coverage as an lcov report. The merged profile is written to
+/// the profraw directory as `<name>.profdata` while it's used
+pub fn export_lcov(
+    config: &Config,
+    profraws: &[PathBuf],
+    binaries: &[PathBuf],
+    name: &str,
+) -> Result<String, RunError> {
+    let profdata = find_tool(config, LlvmTool::Profdata)?;
+    let cov = find_tool(config, LlvmTool::Cov)?;
+    let merged = config.profraw_dir().join(format!("{name}.profdata"));
+    info!("Merging coverage reports with {}", profdata.display());
+    run(Command::new(profdata)
+        .args(["merge", "-sparse"])
+        .args(profraws)
+        .arg("-o")
+        .arg(&merged))?;
+    let mut export = Command::new(cov);
+    export
+        .args(["export", "-format=lcov"])
+        .arg(format!("-instr-profile={}", merged.display()));
+    for (i, binary) in binaries.iter().enumerate() {
+        if i > 0 {
+            export.arg("-object");
+        }
+        export.arg(binary);
+    }
+    let lcov = run(&mut export);
+    let _ = fs::remove_file(merged);
+    lcov
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_search_order() {
+        let dir = env::temp_dir().join(format!("tarpaulin-llvm-tools-{}", std::process::id()));
+        let toolchain = dir.join("toolchain");
+        let path = dir.join("path");
+        fs::create_dir_all(&toolchain).unwrap();
+        fs::create_dir_all(&path).unwrap();
+        let exe = |name: &str| format!("{}{}", name, env::consts::EXE_SUFFIX);
+        let path_var = Some(OsString::from(path.as_os_str()));
+
+        let searched = find_tool_in(
+            LlvmTool::Cov,
+            None,
+            Some(toolchain.clone()),
+            path_var.clone(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            searched,
+            vec![toolchain.join(exe("llvm-cov")), path.join(exe("llvm-cov"))]
+        );
+
+        fs::write(path.join(exe("llvm-cov")), "").unwrap();
+        assert_eq!(
+            find_tool_in(
+                LlvmTool::Cov,
+                None,
+                Some(toolchain.clone()),
+                path_var.clone()
+            ),
+            Ok(path.join(exe("llvm-cov")))
+        );
+        fs::write(toolchain.join(exe("llvm-cov")), "").unwrap();
+        assert_eq!(
+            find_tool_in(
+                LlvmTool::Cov,
+                None,
+                Some(toolchain.clone()),
+                path_var.clone()
+            ),
+            Ok(toolchain.join(exe("llvm-cov")))
+        );
+
+        // A versioned name is looked for in PATH and a set path isn't searched for elsewhere
+        fs::write(path.join("llvm-profdata-17"), "").unwrap();
+        assert_eq!(
+            find_tool_in(
+                LlvmTool::Profdata,
+                Some(PathBuf::from("llvm-profdata-17")),
+                None,
+                path_var.clone()
+            ),
+            Ok(path.join("llvm-profdata-17"))
+        );
+        let missing = dir.join("missing/llvm-profdata");
+        assert_eq!(
+            find_tool_in(LlvmTool::Profdata, Some(missing.clone()), None, path_var),
+            Err(vec![missing])
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
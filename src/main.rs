@@ -1,12 +1,17 @@
 #![cfg(not(tarpaulin_include))]
-use cargo_tarpaulin::cargo::{rust_flags, rustdoc_flags};
+use cargo_tarpaulin::cargo::{run_rustc_wrapper, rust_flags, rustdoc_flags};
+use cargo_tarpaulin::config::schema::{config_schema, starter_config};
 use cargo_tarpaulin::config::{
-    Color, Config, ConfigWrapper, Mode, OutputFile, RunType, TraceEngine,
+    parse_duration, Color, Config, ConfigWrapper, Granularity, Mode, OutputFile, RunType,
+    TraceEngine,
 };
-use cargo_tarpaulin::{run, setup_logging};
+use cargo_tarpaulin::errors::exit_code;
+use cargo_tarpaulin::report::diff::diff_results;
+use cargo_tarpaulin::{error_exit_code, run, setup_logging_with_level};
 use clap::{crate_version, value_t, App, Arg, ArgMatches, ArgSettings, SubCommand};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tracing::level_filters::LevelFilter;
 use tracing::{info, trace};
 
 fn is_dir(d: String) -> Result<(), String> {
@@ -17,6 +22,10 @@ fn is_dir(d: String) -> Result<(), String> {
     }
 }
 
+fn is_duration(d: String) -> Result<(), String> {
+    parse_duration(&d).map(|_| ())
+}
+
 fn print_env(seen_rustflags: HashMap<String, Vec<String>>, prefix: &str, default_val: &str) {
     info!("Printing `{}`", prefix);
     if seen_rustflags.is_empty() {
@@ -38,16 +47,90 @@ travis-ci, travis-pro, circle-ci, semaphore, jenkins and codeship.
 If you are interfacing with coveralls.io or another site you can \
 also specify a name that they will recognise. Refer to their documentation for this.";
 
-fn main() -> Result<(), String> {
+fn main() {
+    if let Some(code) = run_rustc_wrapper() {
+        std::process::exit(code);
+    }
+    if let Err((code, message)) = tarpaulin() {
+        eprintln!("Error: {message}");
+        std::process::exit(code);
+    }
+}
+
+/// Writes the starter config to `--config` or `tarpaulin.toml` in the root directory, an existing
+/// config is only overwritten with `--force`
+fn generate_config(args: &ArgMatches) -> Result<(), (i32, String)> {
+    let (path, existing) = match args.value_of("config") {
+        Some(path) => (PathBuf::from(path), vec![PathBuf::from(path)]),
+        None => {
+            let root = Path::new(args.value_of("root").unwrap_or("."));
+            (
+                root.join("tarpaulin.toml"),
+                vec![root.join("tarpaulin.toml"), root.join(".tarpaulin.toml")],
+            )
+        }
+    };
+    if !args.is_present("force") {
+        if let Some(existing) = existing.iter().find(|x| x.exists()) {
+            return Err((
+                exit_code::CONFIG_ERROR,
+                format!(
+                    "{} already exists, use --force to overwrite it",
+                    existing.display()
+                ),
+            ));
+        }
+    }
+    std::fs::write(&path, starter_config()).map_err(|e| {
+        (
+            exit_code::INTERNAL_ERROR,
+            format!("Failed to write {}: {}", path.display(), e),
+        )
+    })?;
+    println!("Wrote a starter config to {}", path.display());
+    Ok(())
+}
+
+/// Runs tarpaulin with the command line arguments, on failure returning the exit code and the
+/// error message
+fn tarpaulin() -> Result<(), (i32, String)> {
     let args = from_args();
 
-    setup_logging(
+    if args.is_present("print-config-schema") {
+        let schema = serde_json::to_string_pretty(&config_schema())
+            .map_err(|e| (exit_code::INTERNAL_ERROR, e.to_string()))?;
+        println!("{schema}");
+        return Ok(());
+    }
+
+    if args.is_present("generate-config") {
+        return generate_config(&args);
+    }
+
+    if let Some(args) = args.subcommand_matches("diff") {
+        return diff_results(
+            Path::new(args.value_of("OLD").unwrap()),
+            Path::new(args.value_of("NEW").unwrap()),
+        )
+        .map_err(|e| (e.exit_code(), e.to_string()));
+    }
+
+    let level = if args.is_present("debug") {
+        LevelFilter::TRACE
+    } else if args.is_present("verbose") {
+        LevelFilter::DEBUG
+    } else if args.is_present("quiet") {
+        LevelFilter::ERROR
+    } else {
+        LevelFilter::INFO
+    };
+    setup_logging_with_level(
         value_t!(args.value_of("color"), Color).unwrap_or(Color::Auto),
-        args.is_present("debug"),
-        args.is_present("verbose"),
+        level,
     );
 
-    let config = ConfigWrapper::from(&args);
+    let config =
+        ConfigWrapper::load(&args).map_err(|e| (exit_code::CONFIG_ERROR, e.to_string()))?;
 
     trace!("Config vector: {:#?}", config);
 
@@ -63,9 +146,7 @@ fn main() -> Result<(), String> {
 
     trace!("Debug mode activated");
 
-    // Since this is the last function we run and don't do any error mitigations (other than
-    // printing the error to the user it's fine to unwrap here
-    run(&config.0).map_err(|e| e.to_string())
+    run(&config.0).map_err(|e| (error_exit_code(&config.0, &e), e.to_string()))
 }
 
 fn from_args() -> ArgMatches<'static> {
@@ -78,23 +159,26 @@ fn from_args() -> ArgMatches<'static> {
             .about("Tool to analyse test coverage of cargo projects")
             .version(concat!("version: ", crate_version!()))
             .args_from_usage(
-                 "--config [FILE] 'Path to a toml file specifying a list of options this will override any other options set'
+                 "--config [FILE] 'Path to a toml file specifying a list of options, no other config files are searched for and command line options take priority over it'
                  --ignore-config 'Ignore any project config files'
                  --lib 'Test only this package's library unit tests'
                  --bin [NAME]... 'Test only the specified binary`
                  --bins 'Test all binaries'
                  --example [NAME]... 'Test only the specified example'
                  --examples 'Test all examples'
+                 --run-example [NAME]... 'Build the example with cargo build and run it, with its example-args from the config'
                  --test [NAME]... 'Test only the specified test target'
                  --tests 'Test all tests'
                  --bench [NAME]... 'Test only the specified bench target'
                  --benches 'Test all benches'
                  --doc 'Test only this library's documentation'
+                 --run-doctests 'Run the doctests as well as the tests selected by other options'
                  --all-targets 'Test all targets (excluding doctests)'
                  --no-fail-fast 'Run all tests regardless of failure'
                  --profile [NAME] 'Build artefacts with the specified profile'
                  --debug 'Show debug output - this is used for diagnosing issues with tarpaulin'
                  --dump-traces 'Log tracing events and save to a json file. Also, enabled when --debug is used'
+                 --dump-trace-map [PATH] 'Write the trace map before the results are filtered to a json file, with the reason any files or lines are left out of the results'
                  --verbose -v 'Show extra output'
                  --ignore-tests 'Ignore lines of test functions when collecting coverage (default)'
                  --no-dead-code 'Stops tarpaulin from building projects with -Clink-dead-code'
@@ -105,36 +189,79 @@ fn from_args() -> ArgMatches<'static> {
                  --line -l    'Line coverage'
                  --skip-clean 'The opposite of --force-clean'
                  --force-clean 'Adds a clean stage to work around cargo bugs that may affect coverage results'
+                 --cover-build-scripts 'Collect coverage of build scripts run during the build, needs the llvm engine'
                  --fail-under [PERCENTAGE] 'Sets a percentage threshold for failure ranging from 0-100, if coverage is below exit with a non-zero code'
-                 --branch -b  'Branch coverage: NOT IMPLEMENTED'
+                 --exit-code-on-threshold [CODE] 'Exit code to use when coverage is below a threshold or decreased from the baseline, defaults to 3'
+                 --branch -b  'Branch coverage derived from line hits, reported in the cobertura output'
                  --forward -f 'Forwards unexpected signals to test. This is now the default behaviour'
                  --coveralls [KEY]  'Coveralls key, either the repo token, or if you're using travis use $TRAVIS_JOB_ID and specify travis-{ci|pro} in --ciserver'
                  --report-uri [URI] 'URI to send report to, only used if the option --coveralls is used'
+                 --changed-since [REF] 'Only report coverage for lines changed since the given git reference'
                  --no-default-features 'Do not include default features'
                  --features [FEATURES]... 'Features to be included in the target project'
                  --all-features 'Build all available features'
+                 --disable-features [FEATURES]... 'Features which are never enabled, taking precedence over --features, --all-features and the default features'
                  --all        'Alias for --workspace (deprecated)'
                  --workspace 'Test all packages in the workspace'
                  --packages -p [PACKAGE]... 'Package id specifications for which package should be build. See cargo help pkgid for more info'
                  --exclude -e [PACKAGE]... 'Package id specifications to exclude from coverage. See cargo help pkgid for more info'
+                 --instrument-coverage-packages [PACKAGE]... 'Workspace members to instrument for coverage with the llvm engine, other packages are built without instrumentation'
+                 --instrument-deps 'Instrument dependencies which aren't workspace members for coverage with the llvm engine'
                  --exclude-files [FILE]... 'Exclude given files from coverage results has * wildcard'
-                 --timeout -t [SECONDS] 'Integer for the maximum time in seconds without response from test before timeout (default is 1 minute).'
-                 --post-test-delay [SECONDS] 'Delay after test to collect coverage profiles'
+                 --exclude-line-patterns [REGEX]... 'Exclude lines matching any of the given regexes from coverage results'
+                 --no-gitignore 'Include files ignored by git in the coverage results'
+                 --include-generated 'Include files marked as @generated in the coverage results'
+                 --include-macro-lines 'Include the lines of todo!, unimplemented!, unreachable! and --exclude-macros calls in the coverage results'
+                 --exclude-macros [MACRO]... 'Other macros whose calls are excluded from the coverage results like todo!'
+                 --exclude-modules [MODULE]... 'Modules to exclude from the coverage results by their path from the crate root, along with their submodules'
+                 --include-files [FILE]... 'Only include files matching the given patterns in the coverage results, --exclude-files takes precedence'
+                 --graceful-shutdown-signal [SIGNAL] 'Signal to stop timed out tests with, and any processes a test spawned left running when it exits'
+                 --dry-run 'Print the cargo commands used to build the tests and exit without running them'
+                 --watch 'Rerun coverage whenever a source file in the project changes, until stopped with Ctrl-C'
+                 --retries [N] 'Number of times to rerun a test executable that fails or crashes, only coverage from the final run is kept'
+                 --run-count [N] 'Number of times to run all the tests, merging the coverage and listing the lines only some runs covered'
+                 --shard-index [INDEX] 'Which of the --shard-count shards of the test executables to run, counting from 0'
+                 --shard-count [COUNT] 'Number of shards to split the test executables into, each one always lands in the same shard'
                  --follow-exec 'Follow executed processes capturing coverage information if they're part of your project.'
+                 --follow-forks 'Keep tracing processes forked by the test after it exits until they exit, capturing their coverage'
                  --release   'Build in release mode.'
-                 --no-run 'Compile tests but don't run coverage'
+                 --no-run 'Compile tests but don't run coverage, writing the path of each test binary to target/tarpaulin/test-binaries.txt'
+                 --test-threads [N] 'Number of threads to run each test executable with, passed to it as `--test-threads`'
+                 --test-filter [FILTER] 'Only run tests with names containing FILTER, passed to each test executable like `cargo test FILTER`'
                  --implicit-test-threads 'Don't supply an explicit `--test-threads` argument to test executable. By default tarpaulin will infer the default rustc would pick if not ran via tarpaulin and set it'
                  --locked 'Do not update Cargo.lock'
                  --frozen 'Do not update Cargo.lock or any caches'
-                 --target [TRIPLE] 'Compilation target triple'
+                 --target [TRIPLE]... 'Compilation target triples, coverage is collected for each target the host can run'
                  --target-dir [DIR] 'Directory for all generated artifacts'
+                 --target-runner [COMMAND] 'Command to run the test executables with such as an emulator, defaults to the target.<triple>.runner cargo uses'
+                 --llvm-profdata-path [PATH] 'Path of llvm-profdata, used with the llvm engine when it can't read the profiles itself'
+                 --llvm-cov-path [PATH] 'Path of llvm-cov, used with the llvm engine when it can't read the profiles itself'
                  --offline 'Run without accessing the network'
+                 --print-config-schema 'Print a JSON Schema of the config file format and exit'
+                 --generate-config 'Write a config file with every option commented out to --config or tarpaulin.toml in the root and exit'
+                 --force 'Overwrite an existing config file with --generate-config'
                  --print-rust-flags 'Print the RUSTFLAGS options that tarpaulin will compile your program with and exit'
                  --print-rustdoc-flags 'Print the RUSTDOCFLAGS options that tarpaulin will compile any doctests with and exit'
                  --avoid-cfg-tarpaulin 'Remove --cfg=tarpaulin from the RUSTFLAG'
-                 -j --jobs [N] 'Number of parallel jobs, defaults to # of CPUs'
+                 -j --jobs [N] 'Number of parallel jobs, 0 uses every CPU, defaults to CARGO_BUILD_JOBS or cargo's default of # of CPUs'
                  --rustflags [FLAGS] 'rustflags to add when building project (can also be set via RUSTFLAGS env var)'
                 --objects [objects]...   'Other object files to load which contain information for llvm coverage - must have been compiled with llvm coverage instrumentation (ignored for ptrace)'
+                 --input-files [FILE]... 'Coverage results saved by previous tarpaulin runs to merge into the report'
+                 --input-binaries [PATH]... 'Test binaries built by a previous --no-run build to run instead of building the tests'
+                 --profraws [PATH]... 'Profiles written by running the --input-binaries outside of tarpaulin to collect coverage from instead of running the tests'
+                 --baseline [FILE] 'Coverage results saved by a previous tarpaulin run to compare this run against'
+                 --coverage-thresholds [FILE] 'File of `glob = percent` lines with the minimum coverage of the files matching each glob, defaults to .coveragethresholds in the project root'
+                 --fail-on-decrease 'Exit with a non-zero code if coverage decreased from the baseline'
+                 --fail-on-empty-binary 'Fail if a test executable exits successfully without running tests or covering any lines'
+                 --decrease-tolerance [PERCENTAGE] 'Percentage coverage can decrease from the baseline by without failing with --fail-on-decrease'
+                 --warn-if-hits-below [HITS] 'List the lines that are covered but hit fewer than HITS times'
+                 --summary-only 'Only print the coverage summary, without writing or printing any of the --out reports'
+                 --report-unsafe 'Print the coverage of the lines inside unsafe blocks and list the uncovered ones'
+                 --print-summary-table 'Print the coverage of each directory as a tree with the lowest coverage first'
+                 --hotspot-limit [COUNT] 'Number of functions listed by --out Hotspots, defaults to 10'
+                 --post-report-command [CMD] 'Shell command to run after the reports are written, with TARPAULIN_REPORT_DIR and TARPAULIN_COVERAGE set'
+                 --post-report-required 'Fail if the post report command fails'
+                 --cfg [CFG]... 'Custom cfgs to set with --cfg when building the tests, such as loom'
                  -Z [FEATURES]...   'List of unstable nightly only flags'")
             .args(&[
                 Arg::from_usage("--out -o [FMT]   'Output format of coverage report'")
@@ -145,7 +272,14 @@ fn from_args() -> ArgMatches<'static> {
                     .possible_values(&TraceEngine::variants())
                     .case_insensitive(true)
                     .multiple(false),
+                Arg::from_usage("--granularity [GRANULARITY] 'How the coverage of lines with several llvm counters is reported'")
+                    .possible_values(&Granularity::variants())
+                    .case_insensitive(true)
+                    .multiple(false),
                 Arg::from_usage("--output-dir [PATH] 'Specify a custom directory to write report files'"),
+                Arg::from_usage("--absolute-paths 'Write absolute paths to source files in the reports instead of paths relative to the workspace root'")
+                    .conflicts_with("path-prefix"),
+                Arg::from_usage("--path-prefix [PATH] 'Base to write the relative paths to source files in the reports under'"),
                 Arg::from_usage("--run-types [TYPE]... 'Type of the coverage run'")
                     .possible_values(&RunType::variants())
                     .case_insensitive(true)
@@ -153,20 +287,40 @@ fn from_args() -> ArgMatches<'static> {
                 Arg::from_usage("--color [WHEN] 'Coloring: auto, always, never'")
                     .case_insensitive(true)
                     .possible_values(&Color::variants()),
-                Arg::from_usage("--command [CMD] 'cargo subcommand to run. So far only test and build are supported'")
+                Arg::from_usage("--command [CMD] 'cargo subcommand to run. So far only test, build, nextest and bench are supported'")
                     .case_insensitive(true)
                     .possible_values(&Mode::variants()),
                 Arg::from_usage("--root -r [DIR]  'Calculates relative paths to root directory. If --manifest-path isn't specified it will look for a Cargo.toml in root'")
                     .validator(is_dir),
                 Arg::from_usage("--manifest-path [PATH] 'Path to Cargo.toml'"),
+                Arg::from_usage("--timeout -t [DURATION] 'Maximum time without response from a test before it times out, as seconds or a duration like 2m or 1h30m (default is 1 minute)'")
+                    .validator(is_duration),
+                Arg::from_usage("--post-test-delay [DURATION] 'Delay after test to collect coverage profiles, as seconds or a duration like 500ms'")
+                    .validator(is_duration),
+                Arg::from_usage("--shutdown-timeout [DURATION] 'Time a process sent the shutdown signal has to exit before it's killed, as seconds or a duration like 5s'")
+                    .validator(is_duration),
+                Arg::from_usage("--quiet -q 'Only show the coverage summary and errors, passed to cargo as `--quiet`'")
+                    .conflicts_with_all(&["verbose", "debug"]),
                 Arg::from_usage("--ciserver [SERVICE] 'CI server being used, if unspecified tarpaulin may automatically infer for coveralls uploads'")
                     .help(CI_SERVER_HELP),
                 Arg::with_name("args")
                     .set(ArgSettings::Last)
                     .multiple(true)
                     .help("Arguments to be passed to the test executables can be used to filter or skip certain tests")
-            ]))
-        .get_matches();
+            ])
+            .subcommand(SubCommand::with_name("diff")
+                .about("Prints the change in coverage between the results saved by two runs without running any tests, exiting with a non-zero code if the coverage decreased")
+                .args_from_usage(
+                    "<OLD> 'Coverage results saved by the earlier run, such as target/tarpaulin/<project>-coverage.json'
+                     <NEW> 'Coverage results saved by the later run'")))
+        .get_matches_safe()
+        .unwrap_or_else(|e| {
+            if e.use_stderr() {
+                eprintln!("{}", e.message);
+                std::process::exit(exit_code::CONFIG_ERROR);
+            }
+            e.exit()
+        });
 
     let args = args.subcommand_matches("tarpaulin").unwrap_or(&args);
 
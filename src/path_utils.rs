@@ -1,4 +1,5 @@
 use crate::config::Config;
+use git2::Repository;
 use std::env::var;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
@@ -85,13 +86,51 @@ pub fn is_coverable_file_path(
     ignorable_paths && is_part_of_project(e, root.as_ref())
 }
 
+/// Checks paths against the ignore rules of the git repository holding the project. Nothing is
+/// ignored if `no_gitignore` is set or the project isn't in a git repository
+pub struct GitIgnore {
+    repo: Option<(Repository, PathBuf)>,
+}
+
+impl GitIgnore {
+    pub fn new(config: &Config) -> Self {
+        let repo = if config.no_gitignore {
+            None
+        } else {
+            Repository::discover(config.root()).ok().and_then(|repo| {
+                let workdir = repo.workdir()?;
+                let workdir = workdir
+                    .canonicalize()
+                    .map(|x| fix_unc_path(&x))
+                    .unwrap_or_else(|_| workdir.to_path_buf());
+                Some((repo, workdir))
+            })
+        };
+        Self { repo }
+    }
+
+    /// Returns true if git ignores the path, paths outside of the repository aren't ignored
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.repo
+            .as_ref()
+            .and_then(|(repo, workdir)| {
+                let relative = path.strip_prefix(workdir).ok()?;
+                repo.is_path_ignored(relative).ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
 pub fn get_source_walker(config: &Config) -> impl Iterator<Item = DirEntry> + '_ {
     let root = config.root();
     let target = config.target_dir();
+    let gitignore = GitIgnore::new(config);
 
     let walker = WalkDir::new(&root).into_iter();
     walker
-        .filter_entry(move |e| is_coverable_file_path(e.path(), &root, &target))
+        .filter_entry(move |e| {
+            is_coverable_file_path(e.path(), &root, &target) && !gitignore.is_ignored(e.path())
+        })
         .filter_map(Result::ok)
         .filter(move |e| !(config.exclude_path(e.path())))
         .filter(is_source_file)
@@ -102,9 +141,15 @@ pub fn get_profile_walker(config: &Config) -> impl Iterator<Item = DirEntry> {
     walker.filter_map(Result::ok).filter(is_profraw_file)
 }
 
+pub fn get_build_script_profile_walker(config: &Config) -> impl Iterator<Item = DirEntry> {
+    let walker = WalkDir::new(config.build_script_profraw_dir()).into_iter();
+    walker.filter_map(Result::ok).filter(is_profraw_file)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{env, fs};
 
     #[test]
     #[cfg(unix)]
@@ -140,6 +185,46 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn gitignored_sources_skipped() {
+        let root = env::temp_dir().join(format!("tarpaulin-gitignore-{}", std::process::id()));
+        fs::create_dir_all(root.join("src/generated")).unwrap();
+        let root = root.canonicalize().unwrap();
+        Repository::init(&root).unwrap();
+        fs::write(root.join(".gitignore"), "generated/\n/src/extra.rs\n").unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"ignored\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        for file in ["src/lib.rs", "src/extra.rs", "src/generated/mod.rs"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+
+        let sources = |config: &Config| {
+            let mut sources = get_source_walker(config)
+                .map(|x| x.path().strip_prefix(&root).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            sources.sort();
+            sources
+        };
+        assert_eq!(sources(&config), vec![Path::new("src/lib.rs")]);
+        assert!(GitIgnore::new(&config).is_ignored(&root.join("src/extra.rs")));
+
+        config.no_gitignore = true;
+        assert_eq!(
+            sources(&config),
+            vec![
+                Path::new("src/extra.rs"),
+                Path::new("src/generated/mod.rs"),
+                Path::new("src/lib.rs"),
+            ]
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
     #[test]
     fn is_hidden_check() {
         // From issue#682
@@ -1,15 +1,19 @@
 use crate::config::types::Mode;
 use crate::errors::*;
-use crate::process_handling::execute_test;
+use crate::process_handling::{execute_test, TestOutput};
 use crate::ptrace_control::*;
 use crate::Config;
 use crate::TestBinary;
 use crate::TestHandle;
 use lazy_static::lazy_static;
+use nix::fcntl::OFlag;
+use nix::libc::STDOUT_FILENO;
 use nix::sched::*;
 use nix::sys::personality;
 use nix::unistd::*;
 use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
 use tracing::{info, warn};
 
@@ -17,12 +21,12 @@ lazy_static! {
     static ref NUM_CPUS: usize = num_cpus::get();
 }
 
-/// Returns the coverage statistics for a test executable in the given workspace
-pub fn get_test_coverage(
+/// Launches a test executable in the given workspace, with its output when it's captured
+pub(crate) fn get_test_coverage(
     test: &TestBinary,
     config: &Config,
     ignored: bool,
-) -> Result<Option<TestHandle>, RunError> {
+) -> Result<Option<(TestHandle, Option<TestOutput>)>, RunError> {
     if !test.path().exists() {
         warn!("Test at {} doesn't exist", test.path().display());
         return Ok(None);
@@ -35,13 +39,30 @@ pub fn get_test_coverage(
         warn!("Failed to set processor affinity {}", e);
     }
 
+    // Close on exec so tests launched from other threads don't hold the pipe open
+    let stdout = if config.capture_test_output() {
+        Some(pipe2(OFlag::O_CLOEXEC)?)
+    } else {
+        None
+    };
+
     unsafe {
         match fork() {
-            Ok(ForkResult::Parent { child }) => Ok(Some(TestHandle::Id(child))),
+            Ok(ForkResult::Parent { child }) => {
+                let output = stdout.map(|(read, write)| {
+                    let _ = close(write);
+                    TestOutput::capture(File::from_raw_fd(read))
+                });
+                Ok(Some((TestHandle::Id(child), output)))
+            }
             Ok(ForkResult::Child) => {
+                if let Some((_, write)) = stdout {
+                    dup2(write, STDOUT_FILENO)?;
+                }
                 let bin_type = match config.command {
-                    Mode::Test => "test",
+                    Mode::Test | Mode::Nextest => "test",
                     Mode::Build => "binary",
+                    Mode::Bench => "benchmark",
                 };
                 info!("Launching {}", bin_type);
                 execute_test(test, &[], ignored, config, Some(threads))?;
@@ -1,15 +1,21 @@
-use crate::config::Color;
+use crate::config::{Color, Mode, RunType};
 use crate::generate_tracemap;
 use crate::path_utils::get_profile_walker;
-use crate::statemachine::{create_state_machine, TestState};
+use crate::report::junit::TestResults;
+use crate::statemachine::{create_state_machine, TestState, TIMEOUT_EXIT_CODE};
 use crate::traces::*;
 use crate::{Config, EventLog, LineAnalysis, RunError, TestBinary, TraceEngine};
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
-use tracing::{debug, error, info, trace_span};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracing::{debug, error, info, trace_span, warn};
+
+mod output;
+pub(crate) use output::TestOutput;
 
 /// Handle to a test currently either PID or a `std::process::Child`
 pub enum TestHandle {
@@ -25,6 +31,9 @@ pub struct RunningProcessHandle {
     pub(crate) child: Child,
     /// maintain a list of existing profraws in the project root to avoid picking up old results
     pub(crate) existing_profraws: Vec<PathBuf>,
+    /// Start of the name of every profile written by this launch of the test, these are only
+    /// read for this launch even if others of the same executable are running
+    pub(crate) profile_prefix: String,
     /// Extra binaries we may need to look to
     pub(crate) extra_binaries: Vec<PathBuf>,
     /// The flag showing if it should panic
@@ -37,6 +46,7 @@ impl RunningProcessHandle {
         extra_binaries: Vec<PathBuf>,
         cmd: &mut Command,
         config: &Config,
+        profile_prefix: String,
     ) -> Result<Self, RunError> {
         let existing_profraws = get_profile_walker(config)
             .map(|x| x.path().to_path_buf())
@@ -48,6 +58,7 @@ impl RunningProcessHandle {
             extra_binaries,
             child,
             existing_profraws,
+            profile_prefix,
             should_panic: test.should_panic(),
         })
     }
@@ -81,13 +92,52 @@ pub fn get_test_coverage(
     config: &Config,
     ignored: bool,
     logger: &Option<EventLog>,
+    results: &TestResults,
 ) -> Result<Option<(TraceMap, i32)>, RunError> {
-    let handle = launch_test(test, other_binaries, config, ignored, logger)?;
-    if let Some(handle) = handle {
-        let t = collect_coverage(test.path(), handle, analysis, config, logger)?;
-        Ok(Some(t))
-    } else {
-        Ok(None)
+    let mut attempt = 0;
+    loop {
+        let start = Instant::now();
+        let mut output = None;
+        let result =
+            launch_test(test, other_binaries, config, ignored, logger).and_then(|launched| {
+                match launched {
+                    Some((handle, captured)) => {
+                        output = captured;
+                        collect_coverage(test.path(), handle, analysis, config, logger).map(Some)
+                    }
+                    None => Ok(None),
+                }
+            });
+        let failure = match &result {
+            Err(e) => Some(e.to_string()),
+            Ok(Some((_, TIMEOUT_EXIT_CODE))) => {
+                Some(format!("timed out after {:?}", config.test_timeout))
+            }
+            Ok(Some((_, code))) if *code != 0 && !test.should_panic() => {
+                Some(format!("exit code {code}"))
+            }
+            _ => None,
+        };
+        match failure.as_deref() {
+            Some(reason) if attempt < config.retries && !crate::watch::interrupted() => {
+                attempt += 1;
+                // The traces of the failed attempt are dropped here, and with llvm its profraws
+                // already exist when the next attempt starts so won't be read again
+                warn!(
+                    "{} failed ({}), retrying (attempt {} of {})",
+                    test.path().display(),
+                    reason,
+                    attempt,
+                    config.retries
+                );
+            }
+            _ => {
+                if let Some(output) = output {
+                    results.record(test, &output.finish(), start.elapsed(), failure.as_deref());
+                }
+                return result;
+            }
+        }
     }
 }
 
@@ -97,7 +147,10 @@ fn launch_test(
     config: &Config,
     ignored: bool,
     logger: &Option<EventLog>,
-) -> Result<Option<TestHandle>, RunError> {
+) -> Result<Option<(TestHandle, Option<TestOutput>)>, RunError> {
+    if crate::watch::interrupted() {
+        return Err(RunError::Interrupted);
+    }
     if let Some(log) = logger.as_ref() {
         log.push_binary(test.clone());
     }
@@ -114,8 +167,14 @@ fn launch_test(
         }
         TraceEngine::Llvm => {
             // 1 test thread because https://github.com/rust-lang/rust/issues/91092
-            let res = execute_test(test, other_binaries, ignored, config, Some(1))?;
-            Ok(Some(res))
+            let mut res = execute_test(test, other_binaries, ignored, config, Some(1))?;
+            let output = match &mut res {
+                TestHandle::Process(process) => {
+                    process.child.stdout.take().map(TestOutput::capture)
+                }
+                TestHandle::Id(_) => None,
+            };
+            Ok(Some((res, output)))
         }
         e => {
             error!(
@@ -172,9 +231,21 @@ pub(crate) fn collect_coverage(
             }
         }
     }
+    if ret_code == TIMEOUT_EXIT_CODE {
+        traces.add_timed_out(test_path);
+    }
     Ok((traces, ret_code))
 }
 
+/// The `test_filter` to pass to the test executable. Doctests are filtered by cargo when they're
+/// built and executables limited to one test by nextest were filtered when listing the tests
+fn test_filter<'a>(test: &TestBinary, config: &'a Config) -> Option<&'a String> {
+    config
+        .test_filter
+        .as_ref()
+        .filter(|_| test.test_name().is_none() && test.run_type() != Some(RunType::Doctests))
+}
+
 fn get_env_vars(test: &TestBinary, config: &Config) -> Vec<(String, String)> {
     let mut envars: Vec<(String, String)> = Vec::new();
 
@@ -183,6 +254,9 @@ fn get_env_vars(test: &TestBinary, config: &Config) -> Vec<(String, String)> {
         if key == "LD_LIBRARY_PATH" && test.has_linker_paths() {
             continue;
         }
+        if config.env_remove.contains(&key) {
+            continue;
+        }
         envars.push((key.to_string(), value.to_string()));
     }
     if config.verbose {
@@ -203,6 +277,11 @@ fn get_env_vars(test: &TestBinary, config: &Config) -> Vec<(String, String)> {
     if test.has_linker_paths() {
         envars.push(("LD_LIBRARY_PATH".to_string(), test.ld_library_path()));
     }
+    // User provided variables take priority over anything tarpaulin sets
+    for (key, value) in &config.env {
+        envars.retain(|(k, _)| k != key);
+        envars.push((key.clone(), value.clone()));
+    }
 
     envars
 }
@@ -216,64 +295,123 @@ fn execute_test(
     num_threads: Option<usize>,
 ) -> Result<TestHandle, RunError> {
     info!("running {}", test.path().display());
-    let _ = match test.manifest_dir() {
-        Some(md) => env::set_current_dir(md),
-        None => env::set_current_dir(config.root()),
+    let working_dir = match test.manifest_dir() {
+        Some(md) => md.clone(),
+        None => config.root(),
     };
 
-    debug!("Current working dir: {:?}", env::current_dir());
+    debug!("Current working dir: {:?}", working_dir);
 
     let mut envars = get_env_vars(test, config);
 
     let mut argv = vec![];
-    if ignored {
-        argv.push("--ignored".to_string());
-    }
-    argv.extend_from_slice(&config.varargs);
-    if config.color != Color::Auto {
-        argv.push("--color".to_string());
-        argv.push(config.color.to_string().to_ascii_lowercase());
+    if let Some(example) = test.example() {
+        // Examples aren't run by the test harness so don't get any of its arguments
+        argv.extend(
+            config
+                .example_args
+                .get(example)
+                .into_iter()
+                .flatten()
+                .cloned(),
+        );
+    } else {
+        if let Some(name) = test.test_name() {
+            argv.push(name.clone());
+            argv.push("--exact".to_string());
+        }
+        if ignored {
+            argv.push("--ignored".to_string());
+        }
+        if config.command == Mode::Bench {
+            // Without this the harness runs each benchmark once as a test
+            argv.push("--bench".to_string());
+        }
+        if let Some(filter) = test_filter(test, config) {
+            argv.push(filter.clone());
+        }
+        argv.extend_from_slice(&config.varargs);
+        if config.color != Color::Auto {
+            argv.push("--color".to_string());
+            argv.push(config.color.to_string().to_ascii_lowercase());
+        }
     }
-    let no_test_env = if let Ok(threads) = env::var("RUST_TEST_THREADS") {
-        envars.push(("RUST_TEST_THREADS".to_string(), threads));
+    let no_test_env = if config.env.contains_key("RUST_TEST_THREADS") {
+        // Already added when getting the environment variables
         false
+    } else if let Ok(threads) = env::var("RUST_TEST_THREADS") {
+        if config.env_remove.iter().any(|x| x == "RUST_TEST_THREADS") {
+            true
+        } else {
+            envars.push(("RUST_TEST_THREADS".to_string(), threads));
+            false
+        }
     } else {
         true
     };
 
-    if no_test_env
-        && test.is_test_type()
-        && !config.implicit_test_threads
+    if test.is_test_type()
+        && config.command != Mode::Bench
         && !config.varargs.iter().any(|x| x.contains("--test-threads"))
     {
-        if let Some(threads) = num_threads {
-            argv.push("--test-threads".to_string());
-            argv.push(threads.to_string());
+        if let Some(threads) = config.test_threads {
+            // Passed as an argument so it takes priority over RUST_TEST_THREADS
+            argv.push(format!("--test-threads={threads}"));
+        } else if no_test_env && !config.implicit_test_threads {
+            if let Some(threads) = num_threads {
+                argv.push("--test-threads".to_string());
+                argv.push(threads.to_string());
+            }
         }
     }
 
     match config.engine() {
         TraceEngine::Llvm => {
             info!("Setting LLVM_PROFILE_FILE");
-            // Used for llvm coverage to avoid report naming clashes TODO could have clashes
-            // between runs
+            // Each launch gets its own id in the profile names as tests running in parallel may
+            // share an executable, with nextest every test is run from the same one
+            static LAUNCHES: AtomicUsize = AtomicUsize::new(0);
+            let launch = LAUNCHES.fetch_add(1, Ordering::Relaxed);
+            let profile_prefix = format!("{}_{}_", test.file_name(), launch);
             let profile_dir = config
                 .profraw_dir()
-                .join(format!("{}_%m-%p.profraw", test.file_name()));
+                .join(format!("{profile_prefix}%m-%p.profraw"));
             envars.push((
                 "LLVM_PROFILE_FILE".to_string(),
                 profile_dir.display().to_string(),
             ));
             debug!("Env vars: {:?}", envars);
             debug!("Args: {:?}", argv);
-            let mut child = Command::new(test.path());
-            child.envs(envars).args(&argv);
+            // Tests may be running on other threads so the working directory is only set for the
+            // child
+            let mut child = match test.runner() {
+                [runner, runner_args @ ..] => {
+                    debug!("Runner: {:?}", test.runner());
+                    let mut child = Command::new(runner);
+                    child.args(runner_args).arg(test.path());
+                    child
+                }
+                [] => Command::new(test.path()),
+            };
+            child.envs(envars).args(&argv).current_dir(working_dir);
+            if config.capture_test_output() {
+                child.stdout(Stdio::piped());
+            }
+            // The test leads its own process group so the processes it spawns which are still
+            // running when it exits can be sent the shutdown signal
+            #[cfg(unix)]
+            if config.graceful_shutdown_signal.is_some() {
+                use std::os::unix::process::CommandExt;
+                child.process_group(0);
+            }
             let others = other_binaries.to_vec();
-            let hnd = RunningProcessHandle::new(test, others, &mut child, config)?;
+            let hnd = RunningProcessHandle::new(test, others, &mut child, config, profile_prefix)?;
             Ok(hnd.into())
         }
         #[cfg(ptrace_supported)]
         TraceEngine::Ptrace => {
+            // This is run in the forked child so can change the working directory
+            let _ = env::set_current_dir(working_dir);
             argv.insert(0, test.path().display().to_string());
             debug!("Env vars: {:?}", envars);
             debug!("Args: {:?}", argv);
@@ -318,4 +456,49 @@ mod tests {
         let res = res.unwrap();
         assert!(res.contains("/usr/local/lib/foo"));
     }
+
+    #[test]
+    fn env_overrides_and_removals() {
+        let mut binary = TestBinary::new(PathBuf::from("dummy"), None);
+        binary
+            .linker_paths
+            .push(PathBuf::from("/usr/local/lib/foo"));
+        let mut config = Config::default();
+        config
+            .env
+            .insert("TARPAULIN_TEST_VAR".to_string(), "1".to_string());
+        config
+            .env
+            .insert("LD_LIBRARY_PATH".to_string(), "/custom".to_string());
+        config.env_remove.push("PATH".to_string());
+
+        let vars = get_env_vars(&binary, &config);
+        assert!(vars
+            .iter()
+            .any(|(k, v)| k == "TARPAULIN_TEST_VAR" && v == "1"));
+        let ld_paths = vars
+            .iter()
+            .filter(|(k, _)| k == "LD_LIBRARY_PATH")
+            .collect::<Vec<_>>();
+        assert_eq!(ld_paths.len(), 1);
+        assert_eq!(ld_paths[0].1, "/custom");
+        assert!(!vars.iter().any(|(k, _)| k == "PATH"));
+    }
+
+    #[test]
+    fn test_filter_passed_to_executables() {
+        let mut config = Config::default();
+        let binary = TestBinary::new(PathBuf::from("dummy"), None);
+        assert_eq!(test_filter(&binary, &config), None);
+
+        config.test_filter = Some("parser::".to_string());
+        assert_eq!(
+            test_filter(&binary, &config).map(String::as_str),
+            Some("parser::")
+        );
+        let lib = TestBinary::new(PathBuf::from("dummy"), Some(RunType::Lib));
+        assert!(test_filter(&lib, &config).is_some());
+        let doctest = TestBinary::new(PathBuf::from("dummy"), Some(RunType::Doctests));
+        assert_eq!(test_filter(&doctest, &config), None);
+    }
 }
@@ -0,0 +1,48 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for the end of the output once the test has exited
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// The stdout of a test executable, which is still printed as it's read
+pub(crate) struct TestOutput {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    done: Receiver<()>,
+}
+
+impl TestOutput {
+    pub(crate) fn capture(mut reader: impl Read + Send + 'static) -> Self {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        let (finished, done) = mpsc::channel();
+        let captured = Arc::clone(&buffer);
+        thread::spawn(move || {
+            let mut chunk = [0u8; 4096];
+            loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let mut stdout = io::stdout().lock();
+                        let _ = stdout.write_all(&chunk[..n]);
+                        let _ = stdout.flush();
+                        captured.lock().unwrap().extend_from_slice(&chunk[..n]);
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+            let _ = finished.send(());
+        });
+        Self { buffer, done }
+    }
+
+    /// The output once the test has exited. Processes spawned by the test can keep it open
+    /// after the test exits so this only waits a short time for the end of the output
+    pub(crate) fn finish(self) -> String {
+        let _ = self.done.recv_timeout(OUTPUT_GRACE);
+        let buffer = self.buffer.lock().unwrap();
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
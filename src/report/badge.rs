@@ -0,0 +1,81 @@
+//! A coverage badge to embed in a README without a badge service. The SVG has a fixed size and
+//! only uses fonts installed on the system so it renders as an image in GitHub markdown
+use crate::config::{Config, OutputFile};
+use crate::errors::RunError;
+use crate::traces::TraceMap;
+use std::fs;
+
+/// Colours of the badge at 0%, 50% and 100% coverage, other percentages blend between them
+const RED: (u8, u8, u8) = (0xe0, 0x5d, 0x44);
+const YELLOW: (u8, u8, u8) = (0xdf, 0xb3, 0x17);
+const GREEN: (u8, u8, u8) = (0x44, 0xcc, 0x11);
+
+pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError> {
+    let file_path = config
+        .output_file(OutputFile::Badge)
+        .expect("badges are written to a file");
+    let percentage = if coverage_data.total_coverable() == 0 {
+        0.0
+    } else {
+        coverage_data.coverage_percentage() * 100.0
+    };
+    fs::write(file_path, badge(percentage))
+        .map_err(|e| RunError::Badge(format!("File is not writeable: {e}")))
+}
+
+/// The colour of the badge for a percentage, fading from red to yellow to green
+fn colour(percentage: f64) -> String {
+    let percentage = percentage.clamp(0.0, 100.0);
+    let (from, to, ratio) = if percentage < 50.0 {
+        (RED, YELLOW, percentage / 50.0)
+    } else {
+        (YELLOW, GREEN, (percentage - 50.0) / 50.0)
+    };
+    let blend = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * ratio).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        blend(from.0, to.0),
+        blend(from.1, to.1),
+        blend(from.2, to.2)
+    )
+}
+
+/// The SVG of the badge, it's wide enough for `100.00%` so its size doesn't change with the
+/// coverage
+fn badge(percentage: f64) -> String {
+    let value = format!("{percentage:.2}%");
+    let colour = colour(percentage);
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="116" height="20" role="img" aria-label="coverage: {value}">
+<title>coverage: {value}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="116" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="61" height="20" fill="#555"/><rect x="61" width="55" height="20" fill="{colour}"/><rect width="116" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+<text x="30.5" y="15" fill="#010101" fill-opacity=".3">coverage</text><text x="30.5" y="14">coverage</text>
+<text x="88.5" y="15" fill="#010101" fill-opacity=".3">{value}</text><text x="88.5" y="14">{value}</text>
+</g>
+</svg>
+"##
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn badge_colours() {
+        assert_eq!(colour(0.0), "#e05d44");
+        assert_eq!(colour(50.0), "#dfb317");
+        assert_eq!(colour(100.0), "#44cc11");
+        assert_eq!(colour(120.0), colour(100.0));
+
+        let svg = badge(84.256);
+        assert!(svg.contains(r#"aria-label="coverage: 84.26%""#));
+        assert!(svg.contains(&format!(r#"fill="{}""#, colour(84.256))));
+        assert!(
+            svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="116" height="20""#)
+        );
+    }
+}
@@ -45,16 +45,14 @@ use std::fs::File;
 use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use quick_xml::{
     events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event},
     Writer,
 };
 
-use chrono::offset::Utc;
-
-use crate::config::Config;
+use crate::config::{Config, OutputFile};
+use crate::report::metadata::{report_time, ReportMetadata};
 use crate::traces::{CoverageStat, Trace, TraceMap};
 
 pub fn report(traces: &TraceMap, config: &Config) -> Result<(), Error> {
@@ -91,19 +89,24 @@ pub struct Report {
     branch_rate: f64,
     sources: Vec<PathBuf>,
     packages: Vec<Package>,
+    metadata: ReportMetadata,
 }
 
 impl Report {
     pub fn render(config: &Config, traces: &TraceMap) -> Result<Self, Error> {
-        let timestamp = Utc::now().timestamp();
+        let timestamp = report_time().timestamp();
         let sources = render_sources(config);
         let packages = render_packages(config, traces);
         let mut line_rate = 0.0;
-        let mut branch_rate = 0.0;
+        let (branches_covered, branches_valid) = branch_totals(
+            packages
+                .iter()
+                .flat_map(|x| x.classes.iter())
+                .flat_map(|x| x.lines.iter()),
+        );
 
         if !packages.is_empty() {
             line_rate = traces.coverage_percentage();
-            branch_rate = 0.0;
         }
 
         Ok(Report {
@@ -111,23 +114,37 @@ impl Report {
             lines_covered: traces.total_covered(),
             lines_valid: traces.total_coverable(),
             line_rate,
-            branches_covered: 0,
-            branches_valid: 0,
-            branch_rate,
+            branches_covered,
+            branches_valid,
+            branch_rate: branch_rate(branches_covered, branches_valid),
             sources,
             packages,
+            metadata: ReportMetadata::new(config),
         })
     }
 
     pub fn export(&self, config: &Config) -> Result<(), Error> {
-        let file_path = config.output_dir().join("cobertura.xml");
+        let file_path = config
+            .output_file(OutputFile::Xml)
+            .expect("xml reports are written to a file");
         let mut file = File::create(file_path)
             .map_err(|e| Error::ExportError(quick_xml::Error::Io(Arc::new(e))))?;
 
+        let result = self.render_xml()?;
+        file.write_all(&result)
+            .map_err(|e| Error::ExportError(quick_xml::Error::Io(Arc::new(e))))
+    }
+
+    fn render_xml(&self) -> Result<Vec<u8>, Error> {
         let mut writer = Writer::new(Cursor::new(vec![]));
         writer
             .write_event(Event::Decl(BytesDecl::new("1.0", None, None)))
             .map_err(Error::ExportError)?;
+        // Cobertura has nowhere for these so they're a comment, `--` can't be in a comment
+        let comment = format!(" {} ", self.metadata.summary().replace("--", "- -"));
+        writer
+            .write_event(Event::Comment(BytesText::new(&comment)))
+            .map_err(Error::ExportError)?;
 
         let cov_tag = "coverage";
         let mut cov = BytesStart::new(cov_tag);
@@ -142,12 +159,7 @@ impl Report {
         cov.push_attribute(("branch-rate", self.branch_rate.to_string().as_ref()));
         cov.push_attribute(("complexity", "0"));
         cov.push_attribute(("version", "1.9"));
-
-        let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(s) => s.as_secs().to_string(),
-            Err(_) => String::from("0"),
-        };
-        cov.push_attribute(("timestamp", secs.as_ref()));
+        cov.push_attribute(("timestamp", self.timestamp.to_string().as_ref()));
 
         writer
             .write_event(Event::Start(cov))
@@ -163,9 +175,7 @@ impl Report {
             .write_event(Event::End(BytesEnd::new(cov_tag)))
             .map_err(Error::ExportError)?;
 
-        let result = writer.into_inner().into_inner();
-        file.write_all(&result)
-            .map_err(|e| Error::ExportError(quick_xml::Error::Io(Arc::new(e))))
+        Ok(writer.into_inner().into_inner())
     }
 
     fn export_header<T: Write>(&self, writer: &mut Writer<T>) -> Result<(), quick_xml::Error> {
@@ -253,19 +263,91 @@ impl Report {
                 } => {
                     l.push_attribute(("number", number.to_string().as_ref()));
                     l.push_attribute(("hits", hits.to_string().as_ref()));
+                    writer.write_event(Event::Empty(l))?;
+                }
+                Line::Branch {
+                    ref number,
+                    ref hits,
+                    ref conditions,
+                } => {
+                    let (covered, valid) = branch_totals(std::iter::once(line));
+                    let coverage =
+                        format!("{}% ({}/{})", percentage(covered, valid), covered, valid);
+                    l.push_attribute(("number", number.to_string().as_ref()));
+                    l.push_attribute(("hits", hits.to_string().as_ref()));
+                    l.push_attribute(("branch", "true"));
+                    l.push_attribute(("condition-coverage", coverage.as_ref()));
+                    writer.write_event(Event::Start(l))?;
+                    self.export_conditions(conditions, writer)?;
+                    writer.write_event(Event::End(BytesEnd::new(line_tag)))?;
                 }
-                Line::Branch { .. } => {}
             }
-            writer.write_event(Event::Empty(l))?;
         }
         writer
             .write_event(Event::End(BytesEnd::new(lines_tag)))
             .map(|_| ())
     }
+
+    fn export_conditions<T: Write>(
+        &self,
+        conditions: &[Condition],
+        writer: &mut Writer<T>,
+    ) -> Result<(), quick_xml::Error> {
+        let conditions_tag = "conditions";
+        let condition_tag = "condition";
+
+        writer.write_event(Event::Start(BytesStart::new(conditions_tag)))?;
+        for condition in conditions {
+            let coverage = format!("{}%", percentage(condition.covered, condition.valid));
+            let mut c = BytesStart::new(condition_tag);
+            c.push_attribute(("number", condition.number.to_string().as_ref()));
+            c.push_attribute(("type", condition.cond_type.as_str()));
+            c.push_attribute(("coverage", coverage.as_ref()));
+            writer.write_event(Event::Empty(c))?;
+        }
+        writer
+            .write_event(Event::End(BytesEnd::new(conditions_tag)))
+            .map(|_| ())
+    }
+}
+
+/// Number of branches covered and the number of branches in the lines
+fn branch_totals<'a>(lines: impl Iterator<Item = &'a Line>) -> (usize, usize) {
+    lines
+        .filter_map(|x| match x {
+            Line::Branch { conditions, .. } => Some(conditions),
+            Line::Plain { .. } => None,
+        })
+        .flatten()
+        .fold((0, 0), |(covered, valid), x| {
+            (covered + x.covered, valid + x.valid)
+        })
+}
+
+fn branch_rate(covered: usize, valid: usize) -> f64 {
+    if valid > 0 {
+        covered as f64 / valid as f64
+    } else {
+        0.0
+    }
+}
+
+/// Whole number percentage as used in the condition coverage attributes
+fn percentage(covered: usize, valid: usize) -> usize {
+    (covered * 100).checked_div(valid).unwrap_or_default()
 }
 
 fn render_sources(config: &Config) -> Vec<PathBuf> {
-    vec![config.get_base_dir()]
+    // The file names are relative to the workspace root so with relative paths it's the base
+    // they're written under
+    if config.relative_paths {
+        vec![config
+            .path_prefix
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("."))]
+    } else {
+        vec![config.get_base_dir()]
+    }
 }
 
 #[derive(Debug)]
@@ -300,12 +382,16 @@ fn render_package(config: &Config, traces: &TraceMap, pkg: &Path) -> Package {
         0.0
     };
 
+    let classes = render_classes(config, traces, pkg);
+    let (branches_covered, branches_valid) =
+        branch_totals(classes.iter().flat_map(|x| x.lines.iter()));
+
     Package {
         name,
         line_rate,
-        branch_rate: 0.0,
+        branch_rate: branch_rate(branches_covered, branches_valid),
         complexity: 0.0,
-        classes: render_classes(config, traces, pkg),
+        classes,
     }
 }
 
@@ -351,13 +437,17 @@ fn render_class(config: &Config, traces: &TraceMap, file: &Path) -> Option<Class
     } else {
         let covered = traces.covered_in_path(file) as f64;
         let line_rate = covered / coverable as f64;
-        let lines = traces.get_child_traces(file).map(render_line).collect();
+        let lines = traces
+            .get_child_traces(file)
+            .map(|x| render_line(traces, file, x))
+            .collect::<Vec<_>>();
+        let (branches_covered, branches_valid) = branch_totals(lines.iter());
 
         Some(Class {
             name,
             file_name,
             line_rate,
-            branch_rate: 0.0,
+            branch_rate: branch_rate(branches_covered, branches_valid),
             complexity: 0.0,
             lines,
             methods: vec![],
@@ -396,17 +486,46 @@ enum Line {
     },
 }
 
-fn render_line(trace: &Trace) -> Line {
+// Line hits only give branch coverage via the branch points found in source analysis, these
+// are numbered from 0 for each line.
+fn render_line(traces: &TraceMap, file: &Path, trace: &Trace) -> Line {
+    let number = trace.line as usize;
     match &trace.stats {
-        CoverageStat::Line(hits) => Line::Plain {
-            number: trace.line as usize,
-            hits: *hits as usize,
+        CoverageStat::Line(hits) => {
+            let conditions = traces
+                .get_branches(file)
+                .iter()
+                .filter(|x| x.line == trace.line)
+                .enumerate()
+                .map(|(i, x)| Condition::new(i, &traces.branches_taken(file, x)))
+                .collect::<Vec<_>>();
+            if conditions.is_empty() {
+                Line::Plain {
+                    number,
+                    hits: *hits as usize,
+                }
+            } else {
+                Line::Branch {
+                    number,
+                    hits: *hits as usize,
+                    conditions,
+                }
+            }
+        }
+        CoverageStat::Branch(state) => Line::Branch {
+            number,
+            hits: (state.been_true || state.been_false).into(),
+            conditions: vec![Condition::new(0, &[state.been_true, state.been_false])],
+        },
+        CoverageStat::Condition(states) => Line::Branch {
+            number,
+            hits: states.iter().any(|x| x.been_true || x.been_false).into(),
+            conditions: states
+                .iter()
+                .enumerate()
+                .map(|(i, x)| Condition::new(i, &[x.been_true, x.been_false]))
+                .collect(),
         },
-
-        // TODO: Branches in cobertura are given a fresh number as a label,
-        // which would require having some form of context when rendering.
-        //
-        _ => panic!("Not currently supported"),
     }
 }
 
@@ -414,7 +533,19 @@ fn render_line(trace: &Trace) -> Line {
 struct Condition {
     number: usize,
     cond_type: ConditionType,
-    coverage: f64,
+    covered: usize,
+    valid: usize,
+}
+
+impl Condition {
+    fn new(number: usize, taken: &[bool]) -> Self {
+        Self {
+            number,
+            cond_type: ConditionType::Jump,
+            covered: taken.iter().filter(|x| **x).count(),
+            valid: taken.len(),
+        }
+    }
 }
 
 // Condition types
@@ -423,13 +554,116 @@ enum ConditionType {
     Jump,
 }
 
+impl ConditionType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConditionType::Jump => "jump",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::traces::*;
-    use std::collections::HashSet;
+    use quick_xml::Reader;
+    use regex::Regex;
+    use std::collections::{BTreeSet, HashMap};
     use std::path::PathBuf;
 
+    /// Checks the report is valid under the Cobertura DTD: each element's children match its
+    /// content model and it has every required attribute and no undeclared ones
+    fn validate_dtd(xml: &str) {
+        let dtd = include_str!("coverage-04.dtd");
+        let elements = Regex::new(r"<!ELEMENT\s+(\S+)\s+([^>]+)>").unwrap();
+        let attributes =
+            Regex::new(r#"<!ATTLIST\s+(\S+)\s+(\S+)\s+CDATA\s+(#REQUIRED|"[^"]*")>"#).unwrap();
+        let names = Regex::new(r"[\w-]+").unwrap();
+        // The content models as regexes matching the names of the children each followed by a
+        // comma, `None` for text only
+        let mut models = HashMap::new();
+        for x in elements.captures_iter(dtd) {
+            let model = match x[2].trim() {
+                "EMPTY" => Some(String::new()),
+                "(#PCDATA)" => None,
+                model => Some(
+                    names
+                        .replace_all(&model.replace(',', " "), "(?:$0,)")
+                        .replace(' ', ""),
+                ),
+            };
+            let model = model.map(|x| Regex::new(&format!("^{x}$")).unwrap());
+            models.insert(x[1].to_string(), model);
+        }
+        let mut declared = HashMap::<String, Vec<(String, bool)>>::new();
+        for x in attributes.captures_iter(dtd) {
+            declared
+                .entry(x[1].to_string())
+                .or_default()
+                .push((x[2].to_string(), &x[3] == "#REQUIRED"));
+        }
+
+        let mut reader = Reader::from_str(xml);
+        // The open elements with the names of their children and whether they have text
+        let mut open: Vec<(String, String, bool)> = vec![];
+        let check_end = |(name, children, text): (String, String, bool)| match &models[&name] {
+            Some(model) => {
+                assert!(!text, "{} can't have text", name);
+                assert!(
+                    model.is_match(&children),
+                    "{} can't have {}",
+                    name,
+                    children
+                );
+            }
+            None => assert!(children.is_empty(), "{} can only have text", name),
+        };
+        loop {
+            let (start, end) = match reader.read_event().unwrap() {
+                Event::Start(e) => (Some(e.into_owned()), false),
+                Event::Empty(e) => (Some(e.into_owned()), true),
+                Event::End(_) => (None, true),
+                Event::Text(e) => {
+                    if let Some(parent) = open.last_mut() {
+                        parent.2 |= !e.unescape().unwrap().trim().is_empty();
+                    }
+                    (None, false)
+                }
+                Event::Eof => break,
+                _ => (None, false),
+            };
+            if let Some(element) = start {
+                let name = String::from_utf8(element.name().as_ref().to_vec()).unwrap();
+                assert!(models.contains_key(&name), "{} isn't in the DTD", name);
+                match open.last_mut() {
+                    Some(parent) => parent.1.push_str(&format!("{},", name)),
+                    None => assert_eq!(name, "coverage"),
+                }
+                let attrs = element
+                    .attributes()
+                    .map(|x| String::from_utf8(x.unwrap().key.as_ref().to_vec()).unwrap())
+                    .collect::<Vec<_>>();
+                let declared = declared.get(&name).cloned().unwrap_or_default();
+                for attr in &attrs {
+                    assert!(
+                        declared.iter().any(|(x, _)| x == attr),
+                        "{} can't have {}",
+                        name,
+                        attr
+                    );
+                }
+                for (attr, _) in declared.iter().filter(|(_, required)| *required) {
+                    assert!(attrs.contains(attr), "{} needs {}", name, attr);
+                }
+                open.push((name, String::new(), false));
+            }
+            if end {
+                check_end(open.pop().unwrap());
+            }
+        }
+        assert!(open.is_empty());
+    }
+
     #[test]
     fn package_coverage() {
         let mut config = Config::default();
@@ -439,7 +673,7 @@ mod tests {
         map.add_file(&PathBuf::from("fake/examples/foo.rs"));
 
         let empty_trace = Trace::new_stub(2);
-        let mut address = HashSet::new();
+        let mut address = BTreeSet::new();
         address.insert(2);
         let hit_trace = Trace::new(3, address, 1, None);
 
@@ -463,5 +697,50 @@ mod tests {
         assert_eq!(report.line_rate, 0.5);
         assert_eq!(report.packages.len(), 2);
         assert_eq!(report.sources.len(), 1);
+
+        let xml = String::from_utf8(report.render_xml().unwrap()).unwrap();
+        validate_dtd(&xml);
+        let comment = xml.split("?>").nth(1).unwrap();
+        assert!(
+            comment.starts_with("<!-- Generated by tarpaulin "),
+            "{}",
+            xml
+        );
+        assert!(xml.contains(&format!(r#"timestamp="{}""#, report.timestamp)));
+    }
+
+    #[test]
+    fn branch_coverage() {
+        let mut config = Config::default();
+        config.set_manifest(PathBuf::from("fake/Cargo.toml"));
+        let source_file = PathBuf::from("fake/src/lib.rs");
+        let mut map = TraceMap::new();
+        for (line, hits) in [(2, 3), (3, 3), (5, 0), (8, 1)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            map.add_trace(&source_file, trace);
+        }
+        map.add_branches(
+            &source_file,
+            vec![BranchPoint {
+                line: 2,
+                arms: vec![(2, 4), (4, 6)],
+                implicit_default: false,
+            }],
+        );
+
+        let report = Report::render(&config, &map).unwrap();
+        assert_eq!(report.branches_covered, 1);
+        assert_eq!(report.branches_valid, 2);
+        assert_eq!(report.branch_rate, 0.5);
+        assert_eq!(report.packages[0].branch_rate, 0.5);
+
+        let xml = String::from_utf8(report.render_xml().unwrap()).unwrap();
+        validate_dtd(&xml);
+        assert!(xml.contains(
+            r#"<line number="2" hits="3" branch="true" condition-coverage="50% (1/2)"><conditions><condition number="0" type="jump" coverage="50%"/></conditions></line>"#
+        ));
+        assert!(xml.contains(r#"<line number="8" hits="1"/>"#));
+        assert!(xml.contains(r#"branches-covered="1" branches-valid="2" branch-rate="0.5""#));
     }
 }
@@ -0,0 +1,227 @@
+//! Comparing the coverage results saved by two runs without running any tests, for reviewing
+//! the coverage a change gained or lost
+use crate::errors::RunError;
+use crate::report::load_results;
+use crate::traces::{CoverageStat, TraceMap};
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The coverage of a file in each of the results, `None` if it isn't in them
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    /// Covered and coverable lines in the old results
+    pub old: Option<(usize, usize)>,
+    /// Covered and coverable lines in the new results
+    pub new: Option<(usize, usize)>,
+}
+
+/// The change in coverage between two results
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverageDiff {
+    /// Every file with coverable lines in either of the results, in path order
+    pub files: Vec<FileDiff>,
+    /// Lines covered in the old results which are coverable but not covered in the new ones
+    pub newly_uncovered: Vec<(PathBuf, u64)>,
+    /// Percentage of the coverable lines covered in the old results, ranging from 0-100
+    pub old_coverage: f64,
+    /// Percentage of the coverable lines covered in the new results, ranging from 0-100
+    pub new_coverage: f64,
+}
+
+/// Percentage of `covered` out of `coverable`, 0 if nothing is coverable
+fn percent(covered: usize, coverable: usize) -> f64 {
+    if coverable == 0 {
+        0.0
+    } else {
+        100.0 * covered as f64 / coverable as f64
+    }
+}
+
+/// The covered and coverable lines of a file, `None` if it has no coverable lines
+fn file_totals(result: &TraceMap, file: &Path) -> Option<(usize, usize)> {
+    let coverable = result.coverable_in_path(file);
+    (coverable > 0).then(|| (result.covered_in_path(file), coverable))
+}
+
+/// The lines of a file with hits if `covered` is set or otherwise without
+fn lines_with(result: &TraceMap, file: &Path, covered: bool) -> BTreeSet<u64> {
+    result
+        .get_child_traces(file)
+        .filter_map(|x| match x.stats {
+            CoverageStat::Line(hits) if (hits > 0) == covered => Some(x.line),
+            _ => None,
+        })
+        .collect()
+}
+
+impl CoverageDiff {
+    pub fn new(old: &TraceMap, new: &TraceMap) -> Self {
+        let paths = old
+            .files()
+            .into_iter()
+            .chain(new.files())
+            .collect::<BTreeSet<_>>();
+        let mut files = vec![];
+        let mut newly_uncovered = vec![];
+        for path in paths {
+            let diff = FileDiff {
+                path: path.clone(),
+                old: file_totals(old, path),
+                new: file_totals(new, path),
+            };
+            if diff.old.is_none() && diff.new.is_none() {
+                continue;
+            }
+            let covered = lines_with(old, path, true);
+            newly_uncovered.extend(
+                lines_with(new, path, false)
+                    .intersection(&covered)
+                    .map(|line| (path.clone(), *line)),
+            );
+            files.push(diff);
+        }
+        Self {
+            files,
+            newly_uncovered,
+            old_coverage: percent(old.total_covered(), old.total_coverable()),
+            new_coverage: percent(new.total_covered(), new.total_coverable()),
+        }
+    }
+
+    /// Change in the overall coverage percentage from the old results to the new ones
+    pub fn delta(&self) -> f64 {
+        self.new_coverage - self.old_coverage
+    }
+
+    /// Returns true if the overall coverage is lower in the new results
+    pub fn regressed(&self) -> bool {
+        self.delta() < 0.0
+    }
+
+    /// The diff as printed by `cargo tarpaulin diff`, with paths shown by `display`
+    pub fn lines(&self, display: impl Fn(&Path) -> PathBuf) -> Vec<String> {
+        let mut lines = vec!["|| Tested/Total Lines:".to_string()];
+        for file in &self.files {
+            let path = display(&file.path);
+            lines.push(match (file.old, file.new) {
+                (Some((old_covered, old_coverable)), Some((covered, coverable))) => format!(
+                    "|| {}: {}/{} -> {}/{} {:+.2}%",
+                    path.display(),
+                    old_covered,
+                    old_coverable,
+                    covered,
+                    coverable,
+                    percent(covered, coverable) - percent(old_covered, old_coverable)
+                ),
+                (None, Some((covered, coverable))) => {
+                    format!("|| {}: added, {}/{}", path.display(), covered, coverable)
+                }
+                (Some((covered, coverable)), None) => format!(
+                    "|| {}: removed, was {}/{}",
+                    path.display(),
+                    covered,
+                    coverable
+                ),
+                (None, None) => unreachable!("files without coverable lines are left out"),
+            });
+        }
+        if !self.newly_uncovered.is_empty() {
+            lines.push("|| Newly uncovered lines:".to_string());
+            for (file, line) in &self.newly_uncovered {
+                lines.push(format!("|| {}:{}", display(file).display(), line));
+            }
+        }
+        lines.push("|| ".to_string());
+        lines.push(format!(
+            "{:.2}% -> {:.2}% coverage, {:+.2}% change in coverage",
+            self.old_coverage,
+            self.new_coverage,
+            self.delta()
+        ));
+        lines
+    }
+}
+
+/// Prints the change in coverage between the results saved in `old` and `new`, failing with
+/// [`RunError::DiffDecrease`] if the coverage is lower in `new`. Either can be a JSON report,
+/// with relative paths in it relative to the current directory
+pub fn diff_results(old: &Path, new: &Path) -> Result<(), RunError> {
+    let current_dir = env::current_dir().unwrap_or_default();
+    let diff = CoverageDiff::new(
+        &load_results(old, &current_dir)?,
+        &load_results(new, &current_dir)?,
+    );
+    let display = |path: &Path| {
+        path.strip_prefix(&current_dir)
+            .unwrap_or(path)
+            .to_path_buf()
+    };
+    for line in diff.lines(display) {
+        println!("{line}");
+    }
+    if diff.regressed() {
+        Err(RunError::DiffDecrease(diff.old_coverage, diff.new_coverage))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::Trace;
+
+    fn results(files: &[(&str, &[(u64, u64)])]) -> TraceMap {
+        let mut result = TraceMap::new();
+        for (file, lines) in files {
+            for (line, hits) in lines.iter() {
+                let mut trace = Trace::new_stub(*line);
+                trace.stats = CoverageStat::Line(*hits);
+                result.add_trace(Path::new(file), trace);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn diff_of_results() {
+        let old = results(&[
+            ("/src/lib.rs", &[(1, 1), (2, 1), (3, 0), (4, 1)]),
+            ("/src/removed.rs", &[(1, 1)]),
+        ]);
+        let new = results(&[
+            ("/src/lib.rs", &[(1, 1), (2, 0), (3, 1), (4, 0)]),
+            ("/src/added.rs", &[(1, 0), (2, 1)]),
+        ]);
+        let diff = CoverageDiff::new(&old, &new);
+        assert!(diff.regressed());
+        assert_eq!(
+            diff.newly_uncovered,
+            vec![
+                (PathBuf::from("/src/lib.rs"), 2),
+                (PathBuf::from("/src/lib.rs"), 4)
+            ]
+        );
+        assert_eq!(
+            diff.lines(Path::to_path_buf),
+            vec![
+                "|| Tested/Total Lines:",
+                "|| /src/added.rs: added, 1/2",
+                "|| /src/lib.rs: 3/4 -> 2/4 -25.00%",
+                "|| /src/removed.rs: removed, was 1/1",
+                "|| Newly uncovered lines:",
+                "|| /src/lib.rs:2",
+                "|| /src/lib.rs:4",
+                "|| ",
+                "80.00% -> 50.00% coverage, -30.00% change in coverage",
+            ]
+        );
+
+        let diff = CoverageDiff::new(&new, &new);
+        assert!(!diff.regressed());
+        assert!(diff.newly_uncovered.is_empty());
+        assert!(!CoverageDiff::new(&TraceMap::new(), &new).regressed());
+    }
+}
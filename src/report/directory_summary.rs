@@ -0,0 +1,134 @@
+use crate::config::Config;
+use crate::traces::TraceMap;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Coverage of the files in a directory and all of its subdirectories
+#[derive(Debug, Default)]
+struct Directory {
+    covered: usize,
+    coverable: usize,
+    /// Whether any files are directly in this directory rather than a subdirectory
+    has_files: bool,
+    children: BTreeMap<OsString, Directory>,
+}
+
+impl Directory {
+    fn percentage(&self) -> f64 {
+        if self.coverable == 0 {
+            0.0
+        } else {
+            100.0 * self.covered as f64 / self.coverable as f64
+        }
+    }
+
+    /// The subdirectories with the lowest coverage first
+    fn sorted_children(&self) -> Vec<(&OsString, &Directory)> {
+        let mut children = self.children.iter().collect::<Vec<_>>();
+        children.sort_by(|(_, a), (_, b)| a.percentage().total_cmp(&b.percentage()));
+        children
+    }
+}
+
+/// The covered and coverable lines of each directory in the project as an indented tree with
+/// the lowest coverage first at each level. A directory which only contains a single
+/// subdirectory is shown joined with it, so `src/a/b` is one entry when `src/a` has no files
+fn directory_summary(config: &Config, result: &TraceMap) -> Vec<String> {
+    let mut root = Directory::default();
+    for file in result.files() {
+        let coverable = result.coverable_in_path(file);
+        if coverable == 0 {
+            continue;
+        }
+        let covered = result.covered_in_path(file);
+        let path = config.strip_base_dir(file);
+        let mut dir = &mut root;
+        for component in path.parent().unwrap_or_else(|| Path::new("")).iter() {
+            dir.covered += covered;
+            dir.coverable += coverable;
+            dir = dir.children.entry(component.to_os_string()).or_default();
+        }
+        dir.covered += covered;
+        dir.coverable += coverable;
+        dir.has_files = true;
+    }
+
+    let mut lines = vec![];
+    for (name, dir) in root.sorted_children() {
+        add_directory(&mut lines, PathBuf::from(name), dir, 0);
+    }
+    lines
+}
+
+fn add_directory(lines: &mut Vec<String>, mut path: PathBuf, mut dir: &Directory, depth: usize) {
+    while dir.children.len() == 1 && !dir.has_files {
+        let (name, child) = dir.children.iter().next().unwrap();
+        path.push(name);
+        dir = child;
+    }
+    lines.push(format!(
+        "|| {}{}/: {}/{} ({:.2}%)",
+        "  ".repeat(depth),
+        path.display(),
+        dir.covered,
+        dir.coverable,
+        dir.percentage()
+    ));
+    for (name, child) in dir.sorted_children() {
+        add_directory(lines, path.join(name), child, depth + 1);
+    }
+}
+
+/// Prints the coverage of each directory in the project
+pub fn print_directory_summary(config: &Config, result: &TraceMap) {
+    println!("|| Tested/Total Lines by directory:");
+    for line in directory_summary(config, result) {
+        println!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::{CoverageStat, Trace};
+
+    fn add_file(result: &mut TraceMap, file: &Path, covered: u64, uncovered: u64) {
+        for line in 0..covered {
+            let mut trace = Trace::new_stub(line + 1);
+            trace.stats = CoverageStat::Line(1);
+            result.add_trace(file, trace);
+        }
+        for line in 0..uncovered {
+            result.add_trace(file, Trace::new_stub(covered + line + 1));
+        }
+    }
+
+    #[test]
+    fn directories_summarised() {
+        let mut config = Config::default();
+        config.set_manifest(PathBuf::from("/project/Cargo.toml"));
+        let mut result = TraceMap::new();
+        add_file(&mut result, Path::new("/project/src/lib.rs"), 3, 1);
+        add_file(&mut result, Path::new("/project/src/report/html.rs"), 1, 3);
+        add_file(&mut result, Path::new("/project/src/config/mod.rs"), 2, 0);
+        add_file(&mut result, Path::new("/project/src/config/parse.rs"), 1, 1);
+        add_file(
+            &mut result,
+            Path::new("/project/tests/data/a/src/lib.rs"),
+            0,
+            2,
+        );
+        add_file(&mut result, Path::new("/project/build.rs"), 1, 0);
+
+        assert_eq!(
+            directory_summary(&config, &result),
+            vec![
+                "|| tests/data/a/src/: 0/2 (0.00%)",
+                "|| src/: 7/12 (58.33%)",
+                "||   src/report/: 1/4 (25.00%)",
+                "||   src/config/: 3/4 (75.00%)",
+            ]
+        );
+    }
+}
@@ -1,23 +1,43 @@
-use crate::config::Config;
+use crate::closest_package;
+use crate::config::{Config, Granularity, OutputFile};
 use crate::errors::*;
-use crate::report::{get_previous_result, safe_json};
+use crate::path_utils::fix_unc_path;
+use crate::report::{get_previous_result, metadata::ReportMetadata, safe_json};
 use crate::traces::{Trace, TraceMap};
 use serde::Serialize;
 use std::fs::{read_to_string, File};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize)]
 struct SourceFile {
     pub path: Vec<String>,
+    /// Name of the workspace member the file is in
+    pub package: Option<String>,
     pub content: String,
     pub traces: Vec<Trace>,
+    pub not_instrumented: Vec<u64>,
+    pub partial: Vec<u64>,
+    /// The line, start and end column of the counters which didn't run on partial lines, only
+    /// set with the `Region` granularity
+    pub missed_columns: Vec<(u64, u64, Option<u64>)>,
     pub covered: usize,
     pub coverable: usize,
 }
 
+/// A workspace member with files in the report, the viewer groups the files by package when
+/// there's more than one of these
+#[derive(Debug, PartialEq, Serialize)]
+struct Package {
+    pub name: String,
+    /// Components of the package's root directory
+    pub path: Vec<String>,
+}
+
 #[derive(Serialize)]
 struct CoverageReport {
     pub files: Vec<SourceFile>,
+    pub packages: Vec<Package>,
 }
 
 #[derive(PartialEq)]
@@ -26,8 +46,56 @@ enum Context {
     PreviousResults,
 }
 
-fn get_json(coverage_data: &TraceMap, context: Context) -> Result<String, RunError> {
-    let mut report = CoverageReport { files: Vec::new() };
+fn path_components(path: &Path) -> Vec<String> {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+/// The name and root directory of each workspace member from the cargo metadata
+fn workspace_packages(config: &Config) -> Vec<(String, PathBuf)> {
+    match *config.get_metadata() {
+        Some(ref meta) => meta
+            .packages
+            .iter()
+            .filter(|x| meta.workspace_members.contains(&x.id))
+            .filter_map(|x| {
+                let dir = fix_unc_path(x.manifest_path.parent()?.as_std_path());
+                Some((x.name.clone(), dir))
+            })
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The packages which have files in the report, sorted by name
+fn packages_in_report(
+    files: &[SourceFile],
+    packages: &[(String, PathBuf)],
+    config: &Config,
+) -> Vec<Package> {
+    let mut result = packages
+        .iter()
+        .filter(|(name, _)| files.iter().any(|x| x.package.as_ref() == Some(name)))
+        .map(|(name, dir)| Package {
+            name: name.clone(),
+            path: path_components(&config.report_path(dir)),
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    result
+}
+
+fn get_json(
+    coverage_data: &TraceMap,
+    context: Context,
+    packages: &[(String, PathBuf)],
+    config: &Config,
+) -> Result<String, RunError> {
+    let mut report = CoverageReport {
+        files: Vec::new(),
+        packages: Vec::new(),
+    };
 
     for (path, traces) in coverage_data.iter() {
         let content = match read_to_string(path) {
@@ -45,31 +113,40 @@ fn get_json(coverage_data: &TraceMap, context: Context) -> Result<String, RunErr
         };
 
         report.files.push(SourceFile {
-            path: path
-                .components()
-                .map(|c| c.as_os_str().to_string_lossy().to_string())
-                .collect(),
+            path: path_components(&config.report_path(path)),
+            package: closest_package(path, packages).map(|(name, _)| name.clone()),
             content,
             traces: traces.clone(),
+            not_instrumented: coverage_data.get_not_instrumented(path),
+            partial: coverage_data.get_partial(path),
+            missed_columns: if config.granularity == Granularity::Region {
+                coverage_data.get_missed_columns(path)
+            } else {
+                vec![]
+            },
             covered: coverage_data.covered_in_path(path),
             coverable: coverage_data.coverable_in_path(path),
         });
     }
+    report.packages = packages_in_report(&report.files, packages, config);
 
     safe_json::to_string_safe(&report)
         .map_err(|e| RunError::Html(format!("Report isn't serializable: {e}")))
 }
 
 pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError> {
-    let file_path = config.output_dir().join("tarpaulin-report.html");
+    let file_path = config
+        .output_file(OutputFile::Html)
+        .expect("html reports are written to a file");
     let mut file = match File::create(file_path) {
         Ok(k) => k,
         Err(e) => return Err(RunError::Html(format!("File is not writeable: {e}"))),
     };
 
-    let report_json = get_json(coverage_data, Context::CurrentResults)?;
+    let packages = workspace_packages(config);
+    let report_json = get_json(coverage_data, Context::CurrentResults, &packages, config)?;
     let previous_report_json = match get_previous_result(config) {
-        Some(result) => get_json(&result, Context::PreviousResults)?,
+        Some(result) => get_json(&result, Context::PreviousResults, &packages, config)?,
         None => String::from("null"),
     };
 
@@ -83,9 +160,11 @@ pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError>
 </head>
 <body>
     <div id="root"></div>
+    <footer class="report-metadata">{}</footer>
     <script>
         var data = {};
         var previousData = {};
+        var hitsThreshold = {};
     </script>
     <script crossorigin>{}</script>
     <script crossorigin>{}</script>
@@ -93,8 +172,12 @@ pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError>
 </body>
 </html>"##,
         include_str!("report_viewer.css"),
+        quick_xml::escape::escape(&ReportMetadata::new(config).summary()),
         report_json,
         previous_report_json,
+        config
+            .warn_if_hits_below
+            .map_or_else(|| "null".to_string(), |x| x.to_string()),
         include_str!("react.production.min.js"),
         include_str!("react-dom.production.min.js"),
         include_str!("report_viewer.js"),
@@ -105,3 +188,52 @@ pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_grouped_by_package() {
+        let mut config = Config::default();
+        config.relative_paths = false;
+        let packages = vec![
+            ("root".to_string(), PathBuf::from("/ws")),
+            ("core".to_string(), PathBuf::from("/ws/crates/core")),
+            ("unused".to_string(), PathBuf::from("/ws/crates/unused")),
+        ];
+        let mut traces = TraceMap::new();
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        traces.add_trace(&manifest.join("src/lib.rs"), Trace::new_stub(1));
+        let json = get_json(&traces, Context::CurrentResults, &packages, &config).unwrap();
+        assert!(json.contains(r#""package":null"#));
+
+        let file = |path: &str| SourceFile {
+            path: path_components(Path::new(path)),
+            package: closest_package(Path::new(path), &packages).map(|x| x.0.clone()),
+            content: String::new(),
+            traces: vec![],
+            not_instrumented: vec![],
+            partial: vec![],
+            missed_columns: vec![],
+            covered: 0,
+            coverable: 0,
+        };
+        let files = vec![file("/ws/crates/core/src/lib.rs"), file("/ws/src/main.rs")];
+        assert_eq!(files[0].package.as_deref(), Some("core"));
+        assert_eq!(files[1].package.as_deref(), Some("root"));
+        assert_eq!(
+            packages_in_report(&files, &packages, &config),
+            vec![
+                Package {
+                    name: "core".to_string(),
+                    path: path_components(Path::new("/ws/crates/core")),
+                },
+                Package {
+                    name: "root".to_string(),
+                    path: path_components(Path::new("/ws")),
+                },
+            ]
+        );
+    }
+}
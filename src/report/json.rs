@@ -1,50 +1,367 @@
-use crate::config::Config;
+use crate::cargo::cargo_command_lines;
+use crate::config::{Config, OutputFile};
 use crate::errors::*;
-use crate::traces::{Trace, TraceMap};
-use serde::Serialize;
+use crate::report::metadata::ReportMetadata;
+use crate::traces::{amount_coverable, amount_covered, CoverageStat, Trace, TraceMap};
+use lazy_static::lazy_static;
+use rustc_demangle::demangle;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::convert::From;
-use std::{fs, io::Write};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The stdout tarpaulin was started with while [`StdoutRedirect`] sends stdout to stderr
+    static ref JSON_STDOUT: Mutex<Option<File>> = Mutex::new(None);
+}
 
 #[derive(Serialize)]
 struct SourceFile {
     path: Vec<String>,
     content: String,
     traces: Vec<Trace>,
+    /// Lines where some of the llvm counters ran and some didn't, they're counted as covered
+    partial: Vec<u64>,
+    /// Lines covered in only some of the runs when the tests are run several times
+    flaky: Vec<u64>,
+    /// Coverable lines inside `unsafe` blocks
+    unsafe_lines: Vec<u64>,
     covered: usize,
     coverable: usize,
 }
 
+/// The coverage of a function found by source analysis
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FunctionCoverage {
+    /// Name of the function, methods are prefixed with the name of their type
+    pub name: String,
+    /// Components of the path of the file the function is in
+    pub path: Vec<String>,
+    /// Line the function signature starts on
+    pub start_line: u64,
+    /// Last line of the function body
+    pub end_line: u64,
+    /// Number of coverable lines in the function which were covered
+    pub covered: usize,
+    /// Number of coverable lines in the function
+    pub coverable: usize,
+    /// Percentage of the coverable lines covered, ranging from 0-100
+    pub percent: f64,
+    /// Names of the symbols found in the debug information for this function,
+    /// generic functions can have one per monomorphization
+    pub symbols: Vec<FunctionSymbol>,
+}
+
+/// The names of a symbol of a function
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct FunctionSymbol {
+    /// Name of the symbol in the binary, `None` if the debug information only has the
+    /// function's name
+    pub mangled: Option<String>,
+    /// The mangled name demangled without its hash, otherwise the function's name
+    pub demangled: String,
+}
+
+impl FunctionSymbol {
+    /// The names of the function a trace is the entry of, `None` if it isn't one
+    fn of_trace(trace: &Trace) -> Option<Self> {
+        let demangled = match &trace.symbol {
+            Some(mangled) => format!("{:#}", demangle(mangled)),
+            None => trace.fn_name.clone()?,
+        };
+        Some(Self {
+            mangled: trace.symbol.clone(),
+            demangled,
+        })
+    }
+}
+
+/// The coverage of the lines inside `unsafe` blocks
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct UnsafeCoverage {
+    /// Percentage of the coverable unsafe lines covered, ranging from 0-100
+    pub coverage: f64,
+    /// Number of coverable unsafe lines which were covered
+    pub covered: usize,
+    /// Number of coverable unsafe lines
+    pub coverable: usize,
+    /// The unsafe lines without hits, in path and line order
+    pub uncovered: Vec<UncoveredLine>,
+}
+
+/// A line without hits
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UncoveredLine {
+    pub path: String,
+    pub line: u64,
+}
+
+/// Version of the shape of [`CombinedReport`], increased whenever a field is removed or its
+/// meaning changes. Fields can be added without a new version
+pub const COMBINED_SCHEMA_VERSION: u32 = 1;
+
+/// Every result of a run in one document, printed to stdout by `--out StdoutJson` for scripts
+/// that don't want to read a report back from a file
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CombinedReport {
+    /// Version of the shape of this document, see [`COMBINED_SCHEMA_VERSION`]
+    pub schema_version: u32,
+    /// Details of the run, the same as the `metadata` of the JSON report
+    pub metadata: ReportMetadata,
+    /// Cargo commands used to build the tests, with the environment variables tarpaulin sets
+    pub cargo_commands: Vec<String>,
+    /// Percentage of the coverable lines covered, ranging from 0-100
+    pub coverage: f64,
+    /// Number of coverable lines which were covered
+    pub covered: usize,
+    /// Number of coverable lines
+    pub coverable: usize,
+    /// Coverage of each source file, in path order
+    pub files: Vec<FileCoverage>,
+    /// Coverage of each function found by source analysis
+    pub functions: Vec<FunctionCoverage>,
+    /// Coverage of the lines inside `unsafe` blocks
+    #[serde(default)]
+    pub unsafe_coverage: UnsafeCoverage,
+    /// Test executables stopped after timing out, their coverage ends at the timeout
+    #[serde(default)]
+    pub timed_out: Vec<String>,
+}
+
+/// The coverage of a source file in a [`CombinedReport`]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileCoverage {
+    /// Path of the file
+    pub path: String,
+    /// Number of coverable lines which were covered
+    pub covered: usize,
+    /// Number of coverable lines
+    pub coverable: usize,
+    /// The hits of each coverable line, in line order
+    pub lines: Vec<LineCoverage>,
+    /// Lines where some of the llvm counters ran and some didn't, they're counted as covered
+    pub partial: Vec<u64>,
+    /// Lines covered in only some of the runs when the tests are run several times
+    pub flaky: Vec<u64>,
+}
+
+/// The hits of a coverable line
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LineCoverage {
+    pub line: u64,
+    pub hits: u64,
+    /// Set if the line is inside an `unsafe` block
+    #[serde(default)]
+    pub is_unsafe: bool,
+}
+
+impl CombinedReport {
+    pub fn new(coverage_data: &TraceMap, config: &Config) -> Self {
+        let files = coverage_data
+            .iter()
+            .map(|(path, traces)| {
+                let mut lines = traces
+                    .iter()
+                    .filter_map(|x| match x.stats {
+                        CoverageStat::Line(hits) => Some(LineCoverage {
+                            line: x.line,
+                            hits,
+                            is_unsafe: coverage_data.is_unsafe(path, x.line),
+                        }),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                lines.sort_by_key(|x| x.line);
+                FileCoverage {
+                    path: config.report_path(path).display().to_string(),
+                    covered: coverage_data.covered_in_path(path),
+                    coverable: coverage_data.coverable_in_path(path),
+                    lines,
+                    partial: coverage_data.get_partial(path),
+                    flaky: coverage_data.get_flaky(path),
+                }
+            })
+            .collect();
+        Self {
+            schema_version: COMBINED_SCHEMA_VERSION,
+            metadata: ReportMetadata::new(config),
+            cargo_commands: cargo_command_lines(config),
+            coverage: coverage_data.coverage_percentage() * 100.0,
+            covered: coverage_data.total_covered(),
+            coverable: coverage_data.total_coverable(),
+            files,
+            functions: function_coverage(coverage_data, |x| config.report_path(x)),
+            unsafe_coverage: unsafe_coverage(coverage_data, |x| config.report_path(x)),
+            timed_out: coverage_data
+                .timed_out()
+                .into_iter()
+                .map(|x| config.report_path(x).display().to_string())
+                .collect(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct CoverageReport {
+    /// Details of the run, only known when the report is made with the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<ReportMetadata>,
     files: Vec<SourceFile>,
+    functions: Vec<FunctionCoverage>,
+    unsafe_coverage: UnsafeCoverage,
+    /// Test executables stopped after timing out, their coverage ends at the timeout
+    timed_out: Vec<PathBuf>,
+}
+
+fn path_components(path: &Path) -> Vec<String> {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+/// The source files in the report, with `report_path` giving the path written for each one
+fn source_files(
+    coverage_data: &TraceMap,
+    report_path: impl Fn(&Path) -> PathBuf,
+) -> Vec<SourceFile> {
+    coverage_data
+        .iter()
+        .map(|(path, traces)| -> Result<SourceFile, RunError> {
+            let content = fs::read_to_string(path).map_err(RunError::from)?;
+            Ok(SourceFile {
+                path: path_components(&report_path(path)),
+                content,
+                traces: traces.clone(),
+                partial: coverage_data.get_partial(path),
+                flaky: coverage_data.get_flaky(path),
+                unsafe_lines: coverage_data
+                    .get_unsafe_traces(path)
+                    .map(|x| x.line)
+                    .collect(),
+                covered: coverage_data.covered_in_path(path),
+                coverable: coverage_data.coverable_in_path(path),
+            })
+        })
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// The coverage of each function, with `report_path` giving the path written for its file
+fn function_coverage(
+    coverage_data: &TraceMap,
+    report_path: impl Fn(&Path) -> PathBuf,
+) -> Vec<FunctionCoverage> {
+    let mut result = vec![];
+    for (path, traces) in coverage_data.iter() {
+        // Source analysis gives us one span per function regardless of how many
+        // monomorphizations exist so the traces within it already cover them all
+        for function in coverage_data.get_functions(path) {
+            let traces = traces
+                .iter()
+                .filter(|x| x.line >= function.start && x.line <= function.end)
+                .collect::<Vec<_>>();
+            let covered = amount_covered(traces.iter().copied());
+            let coverable = amount_coverable(traces.iter().copied());
+            let percent = if coverable > 0 {
+                (covered as f64 / coverable as f64) * 100.0
+            } else {
+                0.0
+            };
+            let symbols = traces
+                .iter()
+                .filter_map(|x| FunctionSymbol::of_trace(x))
+                .collect::<BTreeSet<_>>();
+            result.push(FunctionCoverage {
+                name: function.name.clone(),
+                path: path_components(&report_path(path)),
+                start_line: function.start,
+                end_line: function.end,
+                covered,
+                coverable,
+                percent,
+                symbols: symbols.into_iter().collect(),
+            });
+        }
+    }
+    result
+}
+
+/// The coverage of the lines inside `unsafe` blocks, with `report_path` giving the path written
+/// for the uncovered lines
+fn unsafe_coverage(
+    coverage_data: &TraceMap,
+    report_path: impl Fn(&Path) -> PathBuf,
+) -> UnsafeCoverage {
+    let covered = coverage_data.total_unsafe_covered();
+    let coverable = coverage_data.total_unsafe_coverable();
+    let coverage = if coverable > 0 {
+        (covered as f64 / coverable as f64) * 100.0
+    } else {
+        0.0
+    };
+    let mut uncovered = vec![];
+    for path in coverage_data.files() {
+        let mut lines = coverage_data
+            .get_unsafe_traces(path)
+            .filter(|x| x.stats == CoverageStat::Line(0))
+            .map(|x| x.line)
+            .collect::<Vec<_>>();
+        lines.dedup();
+        let report_path = report_path(path).display().to_string();
+        uncovered.extend(lines.into_iter().map(|line| UncoveredLine {
+            path: report_path.clone(),
+            line,
+        }));
+    }
+    UnsafeCoverage {
+        coverage,
+        covered,
+        coverable,
+        uncovered,
+    }
 }
 
 impl From<&TraceMap> for Vec<SourceFile> {
     fn from(coverage_data: &TraceMap) -> Self {
-        coverage_data
-            .iter()
-            .map(|(path, traces)| -> Result<SourceFile, RunError> {
-                let content = fs::read_to_string(path).map_err(RunError::from)?;
-                Ok(SourceFile {
-                    path: path
-                        .components()
-                        .map(|c| c.as_os_str().to_string_lossy().to_string())
-                        .collect(),
-                    content,
-                    traces: traces.clone(),
-                    covered: coverage_data.covered_in_path(path),
-                    coverable: coverage_data.coverable_in_path(path),
-                })
-            })
-            .filter_map(Result::ok)
-            .collect()
+        source_files(coverage_data, Path::to_path_buf)
+    }
+}
+
+impl From<&TraceMap> for Vec<FunctionCoverage> {
+    fn from(coverage_data: &TraceMap) -> Self {
+        function_coverage(coverage_data, Path::to_path_buf)
     }
 }
 
 impl From<&TraceMap> for CoverageReport {
     fn from(coverage_data: &TraceMap) -> Self {
         CoverageReport {
+            metadata: None,
             files: Vec::<SourceFile>::from(coverage_data),
+            functions: Vec::<FunctionCoverage>::from(coverage_data),
+            unsafe_coverage: unsafe_coverage(coverage_data, Path::to_path_buf),
+            timed_out: coverage_data.timed_out().into_iter().cloned().collect(),
+        }
+    }
+}
+
+impl CoverageReport {
+    /// The report with the paths of the files written as set by `relative-paths` and
+    /// `path-prefix`
+    pub fn new(coverage_data: &TraceMap, config: &Config) -> Self {
+        CoverageReport {
+            metadata: Some(ReportMetadata::new(config)),
+            files: source_files(coverage_data, |x| config.report_path(x)),
+            functions: function_coverage(coverage_data, |x| config.report_path(x)),
+            unsafe_coverage: unsafe_coverage(coverage_data, |x| config.report_path(x)),
+            timed_out: coverage_data
+                .timed_out()
+                .into_iter()
+                .map(|x| config.report_path(x))
+                .collect(),
         }
     }
 }
@@ -57,10 +374,261 @@ impl From<&TraceMap> for JsonStringResult {
     }
 }
 
+/// Prints the [`CombinedReport`] on a single line, to the original stdout if it's been
+/// redirected by [`redirect_stdout`]
+pub fn print_combined(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError> {
+    let report = serde_json::to_string(&CombinedReport::new(coverage_data, config))
+        .map_err(|e| RunError::OutFormat(format!("Report isn't serializable: {e}")))?;
+    match JSON_STDOUT.lock().unwrap().as_mut() {
+        Some(stdout) => writeln!(stdout, "{report}")?,
+        None => println!("{report}"),
+    }
+    Ok(())
+}
+
+/// Sends stdout to stderr until it's dropped, this is where the processes tarpaulin runs write as
+/// well so the `--out StdoutJson` document is the only output on stdout
+pub struct StdoutRedirect;
+
+/// Redirects stdout to stderr, keeping the original stdout for [`print_combined`]. This is only
+/// done on Unix, on other platforms the document is the last line of stdout
+pub fn redirect_stdout() -> StdoutRedirect {
+    #[cfg(unix)]
+    {
+        use std::io;
+        use std::os::unix::io::{AsFd, AsRawFd};
+        let _ = io::stdout().flush();
+        match io::stdout().as_fd().try_clone_to_owned() {
+            Ok(stdout) => {
+                match nix::unistd::dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()) {
+                    Ok(_) => *JSON_STDOUT.lock().unwrap() = Some(File::from(stdout)),
+                    Err(e) => tracing::warn!("Unable to send stdout to stderr: {}", e),
+                }
+            }
+            Err(e) => tracing::warn!("Unable to send stdout to stderr: {}", e),
+        }
+    }
+    StdoutRedirect
+}
+
+impl Drop for StdoutRedirect {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(stdout) = JSON_STDOUT.lock().unwrap().take() {
+            use std::io;
+            use std::os::unix::io::AsRawFd;
+            let _ = io::stdout().flush();
+            let _ = nix::unistd::dup2(stdout.as_raw_fd(), io::stdout().as_raw_fd());
+        }
+    }
+}
+
 pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError> {
-    let file_path = config.output_dir().join("tarpaulin-report.json");
-    let report: JsonStringResult = coverage_data.into();
+    let file_path = config
+        .output_file(OutputFile::Json)
+        .expect("json reports are written to a file");
+    let report = serde_json::to_string(&CoverageReport::new(coverage_data, config))?;
     fs::File::create(file_path)?
-        .write_all(report?.as_bytes())
+        .write_all(report.as_bytes())
         .map_err(RunError::from)
 }
+
+/// The coverage of a file as read back from a `--out Json` report
+#[derive(Deserialize)]
+struct ReportFile {
+    path: Vec<String>,
+    traces: Vec<Trace>,
+}
+
+/// The parts of a `--out Json` report needed to read its coverage back
+#[derive(Deserialize)]
+struct ReportFiles {
+    files: Vec<ReportFile>,
+}
+
+/// The JSON documents tarpaulin writes coverage results to
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonResults {
+    /// The results saved in `target/tarpaulin` after each run
+    Saved(TraceMap),
+    /// The `--out StdoutJson` document
+    Combined(CombinedReport),
+    /// The `--out Json` report
+    Report(ReportFiles),
+}
+
+/// Reads the coverage in any of the JSON documents tarpaulin writes: the results it saves after
+/// each run, the `--out Json` report or the `--out StdoutJson` document. Only the line coverage
+/// is read from the reports, with relative paths in them taken as relative to `root`
+pub fn read_results(json: &str, root: &Path) -> Result<TraceMap, String> {
+    let results = serde_json::from_str(json).map_err(|_| {
+        "it isn't results saved by tarpaulin or a JSON report from tarpaulin".to_string()
+    })?;
+    Ok(match results {
+        JsonResults::Saved(traces) => traces,
+        JsonResults::Combined(report) => {
+            let mut traces = TraceMap::new();
+            for file in report.files {
+                for line in file.lines {
+                    let mut trace = Trace::new_stub(line.line);
+                    trace.stats = CoverageStat::Line(line.hits);
+                    traces.add_trace(&root.join(&file.path), trace);
+                }
+            }
+            traces
+        }
+        JsonResults::Report(report) => {
+            let mut traces = TraceMap::new();
+            for file in report.files {
+                let path = root.join(file.path.iter().collect::<PathBuf>());
+                for trace in file.traces {
+                    traces.add_trace(&path, trace);
+                }
+            }
+            traces
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TraceEngine;
+    use crate::report::metadata::head_commit;
+    use crate::traces::FunctionSpan;
+
+    #[test]
+    fn results_read_from_reports() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let file = root.join("src/lib.rs");
+        let mut traces = TraceMap::new();
+        for (line, hits) in [(1, 2), (3, 0)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            traces.add_trace(&file, trace);
+        }
+        let lines = |traces: &TraceMap| {
+            traces
+                .get_child_traces(&file)
+                .map(|x| (x.line, x.stats.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let saved = serde_json::to_string(&traces).unwrap();
+        let combined = serde_json::to_string(&CombinedReport::new(&traces, &config)).unwrap();
+        let report = serde_json::to_string(&CoverageReport::new(&traces, &config)).unwrap();
+        for json in [saved, combined, report] {
+            let read = read_results(&json, root).unwrap();
+            assert_eq!(read.files(), vec![&file]);
+            assert_eq!(lines(&read), lines(&traces));
+        }
+        assert!(read_results(r#"{"files": 1}"#, root).is_err());
+    }
+
+    #[test]
+    fn combined_report_shape() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        config.set_engine(TraceEngine::Llvm);
+        let file = root.join("src/lib.rs");
+        let mut traces = TraceMap::new();
+        for (line, hits) in [(3, 0), (1, 2)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            traces.add_trace(&file, trace);
+        }
+        traces.add_unsafe_lines(&file, vec![3]);
+
+        let report = CombinedReport::new(&traces, &config);
+        assert_eq!(report.schema_version, COMBINED_SCHEMA_VERSION);
+        assert_eq!(report.metadata.engine, TraceEngine::Llvm);
+        assert_eq!(report.metadata.commit, head_commit(root));
+        assert_eq!(report.metadata.features, vec!["default".to_string()]);
+        assert!(!report.cargo_commands.is_empty());
+        assert_eq!(report.coverage, 50.0);
+        assert_eq!(
+            PathBuf::from(&report.files[0].path),
+            Path::new("src/lib.rs")
+        );
+        assert_eq!(
+            report.files[0].lines,
+            vec![
+                LineCoverage {
+                    line: 1,
+                    hits: 2,
+                    is_unsafe: false
+                },
+                LineCoverage {
+                    line: 3,
+                    hits: 0,
+                    is_unsafe: true
+                }
+            ]
+        );
+        assert_eq!(report.unsafe_coverage.coverable, 1);
+        assert_eq!(report.unsafe_coverage.coverage, 0.0);
+        assert_eq!(
+            report.unsafe_coverage.uncovered,
+            vec![UncoveredLine {
+                path: report.files[0].path.clone(),
+                line: 3
+            }]
+        );
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.starts_with(r#"{"schema_version":1,"#));
+        assert_eq!(
+            serde_json::from_str::<CombinedReport>(&json).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn function_symbol_names() {
+        let file = Path::new("src/lib.rs");
+        let mut traces = TraceMap::new();
+        traces.add_functions(
+            file,
+            [FunctionSpan {
+                name: "foo".to_string(),
+                start: 1,
+                end: 4,
+            }],
+        );
+        // Two monomorphizations of the function and a line without its symbol
+        for (line, symbol) in [
+            (1, Some("_ZN8my_crate3foo17h0123456789abcdefE")),
+            (1, Some("_ZN8my_crate3foo17hfedcba9876543210E")),
+            (2, None),
+        ] {
+            let mut trace = Trace::new(line, BTreeSet::from([line * 10]), 1, None);
+            trace.fn_name = Some("foo".to_string());
+            trace.symbol = symbol.map(str::to_string);
+            traces.add_trace(file, trace);
+        }
+
+        let functions = Vec::<FunctionCoverage>::from(&traces);
+        assert_eq!(functions.len(), 1);
+        assert_eq!(
+            functions[0].symbols,
+            vec![
+                FunctionSymbol {
+                    mangled: None,
+                    demangled: "foo".to_string()
+                },
+                FunctionSymbol {
+                    mangled: Some("_ZN8my_crate3foo17h0123456789abcdefE".to_string()),
+                    demangled: "my_crate::foo".to_string()
+                },
+                FunctionSymbol {
+                    mangled: Some("_ZN8my_crate3foo17hfedcba9876543210E".to_string()),
+                    demangled: "my_crate::foo".to_string()
+                },
+            ]
+        );
+    }
+}
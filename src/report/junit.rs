@@ -0,0 +1,484 @@
+use crate::config::{Config, OutputFile};
+use crate::errors::RunError;
+use crate::TestBinary;
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event},
+    Writer,
+};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// A test from the output of a libtest harness
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub status: TestStatus,
+    /// Only known when the harness reports the time of each test
+    pub time: Option<Duration>,
+    /// The output libtest prints for a failed test, normally the panic message
+    pub message: Option<String>,
+}
+
+/// The tests run by a test executable
+#[derive(Clone, Debug)]
+pub struct TestSuite {
+    pub name: String,
+    path: PathBuf,
+    pub time: Duration,
+    pub cases: Vec<TestCase>,
+}
+
+/// The results of the test executables run for the JUnit report and `fail-on-empty-binary`,
+/// executables running in parallel record to the same results
+#[derive(Debug, Default)]
+pub struct TestResults {
+    suites: Mutex<Vec<TestSuite>>,
+    /// The number of tests each executable ran, if its harness said how many it was running
+    tests_run: Mutex<HashMap<PathBuf, usize>>,
+}
+
+impl TestResults {
+    /// Records the tests in the output of a run of a test executable. When an executable is run
+    /// again for its ignored tests those results replace the ignored tests of the first run.
+    /// `failure` is why the executable failed, if it failed without any of its tests failing,
+    /// such as a crash, a failed test for the executable is recorded with the reason and output
+    pub(crate) fn record(
+        &self,
+        test: &TestBinary,
+        output: &str,
+        time: Duration,
+        failure: Option<&str>,
+    ) {
+        if let Some(run) = count_tests_run(output) {
+            let mut tests_run = self.tests_run.lock().unwrap();
+            *tests_run.entry(test.path().to_path_buf()).or_default() += run;
+        }
+        let mut cases = parse_libtest(output);
+        if let [case] = cases.as_mut_slice() {
+            // Nextest runs each test on its own so the time of the executable is the time of the
+            // test
+            case.time = case.time.or(Some(time));
+        }
+        if let Some(reason) = failure {
+            if !cases.iter().any(|x| x.status == TestStatus::Failed) {
+                let name = test.test_name().clone().unwrap_or_else(|| suite_name(test));
+                cases.retain(|x| x.name != name);
+                cases.push(TestCase {
+                    name,
+                    status: TestStatus::Failed,
+                    time: Some(time),
+                    message: Some(format!("{reason}\n{}", output.trim_end())),
+                });
+            }
+        }
+        if cases.is_empty() {
+            return;
+        }
+        let mut suites = self.suites.lock().unwrap();
+        add_cases(&mut suites, test, time, cases);
+    }
+
+    /// The number of tests the executable ran in all of its runs, `None` if its output wasn't
+    /// captured or doesn't say how many tests it's running
+    pub(crate) fn tests_run(&self, test: &TestBinary) -> Option<usize> {
+        self.tests_run.lock().unwrap().get(test.path()).copied()
+    }
+
+    pub fn export(&self, config: &Config) -> Result<(), RunError> {
+        let suites = self.suites.lock().unwrap().clone();
+        if suites.is_empty() {
+            warn!("No test results were captured so the JUnit report isn't written");
+            return Ok(());
+        }
+        let file_path = config
+            .output_file(OutputFile::Junit)
+            .expect("JUnit reports are written to a file");
+        if let Some(parent) = file_path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut file = File::create(file_path)
+            .map_err(|e| RunError::Junit(format!("File is not writeable: {e}")))?;
+        file.write_all(&render(suites)?)?;
+        Ok(())
+    }
+}
+
+/// Adds the cases from a run of the test executable to its suite, replacing earlier results of
+/// the same tests
+fn add_cases(suites: &mut Vec<TestSuite>, test: &TestBinary, time: Duration, cases: Vec<TestCase>) {
+    match suites.iter_mut().find(|x| x.path == test.path()) {
+        Some(suite) => {
+            suite.time += time;
+            for case in cases {
+                match suite.cases.iter_mut().find(|x| x.name == case.name) {
+                    Some(existing) => *existing = case,
+                    None => suite.cases.push(case),
+                }
+            }
+        }
+        None => suites.push(TestSuite {
+            name: suite_name(test),
+            path: test.path().to_path_buf(),
+            time,
+            cases,
+        }),
+    }
+}
+
+/// The executable's file name without the hash cargo adds, so the name is the same each build
+fn suite_name(test: &TestBinary) -> String {
+    let name = test.file_name();
+    match name.rsplit_once('-') {
+        Some((stem, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            stem.to_string()
+        }
+        _ => name,
+    }
+}
+
+/// Removes the ANSI escape sequences from a line of output printed with `--color always`
+fn strip_colour(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Parses a `test <name> ... <result>` line, the time is only there with `--report-time`
+fn parse_test_line(line: &str) -> Option<TestCase> {
+    let (name, result) = line.strip_prefix("test ")?.rsplit_once(" ... ")?;
+    let name = name.strip_suffix(" - should panic").unwrap_or(name);
+    let mut words = result.split_whitespace();
+    let status = match words.next()?.trim_end_matches(',') {
+        "ok" | "bench:" => TestStatus::Passed,
+        "FAILED" => TestStatus::Failed,
+        "ignored" => TestStatus::Ignored,
+        _ => return None,
+    };
+    let time = words
+        .find_map(|x| x.strip_prefix('<')?.strip_suffix("s>")?.parse::<f64>().ok())
+        .map(Duration::from_secs_f64);
+    Some(TestCase {
+        name: name.to_string(),
+        status,
+        time,
+        message: None,
+    })
+}
+
+/// The number of tests a libtest harness ran, from the `running N tests` lines it starts each run
+/// with less the tests it ignored. `None` if there's no such line
+pub fn count_tests_run(output: &str) -> Option<usize> {
+    let listed = output
+        .lines()
+        .map(strip_colour)
+        .filter_map(|line| {
+            let count = line.strip_prefix("running ")?;
+            let count = count
+                .strip_suffix(" tests")
+                .or_else(|| count.strip_suffix(" test"))?;
+            count.parse::<usize>().ok()
+        })
+        .reduce(|a, b| a + b)?;
+    let ignored = parse_libtest(output)
+        .iter()
+        .filter(|x| x.status == TestStatus::Ignored)
+        .count();
+    Some(listed.saturating_sub(ignored))
+}
+
+/// The tests in the output of a libtest harness with the messages printed for the failures
+pub fn parse_libtest(output: &str) -> Vec<TestCase> {
+    let mut cases: Vec<TestCase> = vec![];
+    let mut failure: Option<(String, Vec<String>)> = None;
+    let mut messages = vec![];
+    for line in output.lines().map(strip_colour) {
+        let header = line
+            .strip_prefix("---- ")
+            .and_then(|x| x.strip_suffix(" stdout ----"));
+        if header.is_some() || line == "failures:" || line == "successes:" {
+            messages.extend(failure.take());
+        }
+        if let Some(name) = header {
+            failure = Some((name.to_string(), vec![]));
+        } else if let Some((_, message)) = failure.as_mut() {
+            message.push(line);
+        } else if let Some(case) = parse_test_line(&line) {
+            cases.push(case);
+        }
+    }
+    messages.extend(failure);
+    for (name, message) in messages {
+        let message = message.join("\n").trim().to_string();
+        if let Some(case) = cases
+            .iter_mut()
+            .find(|x| x.name == name && x.status == TestStatus::Failed)
+        {
+            case.message = Some(message).filter(|x| !x.is_empty());
+        }
+    }
+    cases
+}
+
+fn time_attr(time: Duration) -> String {
+    format!("{:.3}", time.as_secs_f64())
+}
+
+fn count(suite: &TestSuite, status: TestStatus) -> usize {
+    suite.cases.iter().filter(|x| x.status == status).count()
+}
+
+fn write_suites(writer: &mut Writer<impl Write>, suites: &[TestSuite]) -> quick_xml::Result<()> {
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    let total = |status| suites.iter().map(|x| count(x, status)).sum::<usize>();
+    let mut root = BytesStart::new("testsuites");
+    root.push_attribute(("name", "tarpaulin"));
+    root.push_attribute((
+        "tests",
+        suites
+            .iter()
+            .map(|x| x.cases.len())
+            .sum::<usize>()
+            .to_string()
+            .as_str(),
+    ));
+    root.push_attribute(("failures", total(TestStatus::Failed).to_string().as_str()));
+    root.push_attribute(("skipped", total(TestStatus::Ignored).to_string().as_str()));
+    root.push_attribute((
+        "time",
+        time_attr(suites.iter().map(|x| x.time).sum()).as_str(),
+    ));
+    writer.write_event(Event::Start(root))?;
+    for suite in suites {
+        let mut start = BytesStart::new("testsuite");
+        start.push_attribute(("name", suite.name.as_str()));
+        start.push_attribute(("tests", suite.cases.len().to_string().as_str()));
+        start.push_attribute((
+            "failures",
+            count(suite, TestStatus::Failed).to_string().as_str(),
+        ));
+        start.push_attribute((
+            "skipped",
+            count(suite, TestStatus::Ignored).to_string().as_str(),
+        ));
+        start.push_attribute(("time", time_attr(suite.time).as_str()));
+        writer.write_event(Event::Start(start))?;
+        for case in &suite.cases {
+            let mut start = BytesStart::new("testcase");
+            start.push_attribute(("name", case.name.as_str()));
+            start.push_attribute(("classname", suite.name.as_str()));
+            if let Some(time) = case.time {
+                start.push_attribute(("time", time_attr(time).as_str()));
+            }
+            match case.status {
+                TestStatus::Passed => writer.write_event(Event::Empty(start))?,
+                TestStatus::Ignored => {
+                    writer.write_event(Event::Start(start))?;
+                    writer.write_event(Event::Empty(BytesStart::new("skipped")))?;
+                    writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+                }
+                TestStatus::Failed => {
+                    writer.write_event(Event::Start(start))?;
+                    let mut failure = BytesStart::new("failure");
+                    let message = case.message.as_deref().unwrap_or_default();
+                    let first = message
+                        .lines()
+                        .find(|x| x.starts_with("thread '"))
+                        .or_else(|| message.lines().next());
+                    if let Some(first) = first {
+                        failure.push_attribute(("message", first));
+                    }
+                    writer.write_event(Event::Start(failure))?;
+                    writer.write_event(Event::Text(BytesText::new(message)))?;
+                    writer.write_event(Event::End(BytesEnd::new("failure")))?;
+                    writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+                }
+            }
+        }
+        writer.write_event(Event::End(BytesEnd::new("testsuite")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("testsuites")))?;
+    Ok(())
+}
+
+/// The JUnit report of the recorded results, with the suites in order of name so running the
+/// executables in parallel doesn't change it
+fn render(mut suites: Vec<TestSuite>) -> Result<Vec<u8>, RunError> {
+    suites.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.path.cmp(&b.path)));
+    let mut writer = Writer::new_with_indent(vec![], b' ', 2);
+    write_suites(&mut writer, &suites).map_err(|e| RunError::Junit(e.to_string()))?;
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "
+running 4 tests
+test tests::ignored ... ignored, needs a database
+test tests::passes ... ok
+test tests::panics - should panic ... ok
+test tests::fails ... \u{1b}[31mFAILED\u{1b}[0m
+
+failures:
+
+---- tests::fails stdout ----
+thread 'tests::fails' panicked at src/lib.rs:10:9:
+assertion `left == right` failed
+  left: 1
+ right: 2
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    tests::fails
+
+test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+
+    #[test]
+    fn libtest_output_parsed() {
+        let cases = parse_libtest(OUTPUT);
+        let statuses = cases
+            .iter()
+            .map(|x| (x.name.as_str(), x.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                ("tests::ignored", TestStatus::Ignored),
+                ("tests::passes", TestStatus::Passed),
+                ("tests::panics", TestStatus::Passed),
+                ("tests::fails", TestStatus::Failed),
+            ]
+        );
+        assert!(cases.iter().all(|x| x.time.is_none()));
+        let message = cases[3].message.as_ref().unwrap();
+        assert!(message.starts_with("thread 'tests::fails' panicked at src/lib.rs:10:9:"));
+        assert!(message.ends_with("to display a backtrace"));
+        assert!(cases[..3].iter().all(|x| x.message.is_none()));
+
+        let timed = parse_libtest("test a::b ... ok <0.250s>\ntest result: ok. 1 passed");
+        assert_eq!(timed.len(), 1);
+        assert_eq!(timed[0].time, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn tests_run_counted() {
+        assert_eq!(count_tests_run(OUTPUT), Some(3));
+        let ignored = "running 1 test\ntest only ... ignored\n\ntest result: ok. 0 passed; 0 failed; 1 ignored\n\nrunning 0 tests\n";
+        assert_eq!(count_tests_run(ignored), Some(0));
+        assert_eq!(count_tests_run("a custom harness"), None);
+    }
+
+    #[test]
+    fn junit_report_rendered() {
+        let suites = vec![
+            TestSuite {
+                name: "project".to_string(),
+                path: PathBuf::from("target/debug/deps/project-0123456789abcdef"),
+                time: Duration::from_millis(1500),
+                cases: parse_libtest(OUTPUT),
+            },
+            TestSuite {
+                name: "integration".to_string(),
+                path: PathBuf::from("target/debug/deps/integration-0123456789abcdef"),
+                time: Duration::from_millis(20),
+                cases: parse_libtest("test a ... ok <0.010s>"),
+            },
+        ];
+        let report = String::from_utf8(render(suites).unwrap()).unwrap();
+        assert!(report.contains(
+            r#"<testsuites name="tarpaulin" tests="5" failures="1" skipped="1" time="1.520">"#
+        ));
+        let integration = report
+            .find(
+                r#"<testsuite name="integration" tests="1" failures="0" skipped="0" time="0.020">"#,
+            )
+            .unwrap();
+        let project = report
+            .find(r#"<testsuite name="project" tests="4" failures="1" skipped="1" time="1.500">"#)
+            .unwrap();
+        assert!(integration < project);
+        assert!(report.contains(r#"<testcase name="a" classname="integration" time="0.010"/>"#));
+        assert!(report.contains(
+            "<testcase name=\"tests::ignored\" classname=\"project\">\n      <skipped/>"
+        ));
+        assert!(report.contains(
+            r#"<failure message="thread &apos;tests::fails&apos; panicked at src/lib.rs:10:9:">"#
+        ));
+        assert!(report.contains("assertion `left == right` failed"));
+    }
+
+    #[test]
+    fn hash_removed_from_suite_name() {
+        let test = TestBinary::new(
+            PathBuf::from("target/debug/deps/my_crate-0123456789abcdef"),
+            None,
+        );
+        assert_eq!(suite_name(&test), "my_crate");
+        let test = TestBinary::new(PathBuf::from("target/debug/examples/an-example"), None);
+        assert_eq!(suite_name(&test), "an-example");
+    }
+
+    #[test]
+    fn failed_executable_without_failed_tests() {
+        let results = TestResults::default();
+        let crash = TestBinary::new(
+            PathBuf::from("target/debug/deps/crash-0123456789abcdef"),
+            None,
+        );
+        let output = "running 2 tests\ntest passes ... ok\ntest crashes ... ";
+        let time = Duration::from_millis(5);
+        results.record(&crash, output, time, Some("exit code 134"));
+        let failing = TestBinary::new(
+            PathBuf::from("target/debug/deps/project-0123456789abcdef"),
+            None,
+        );
+        results.record(&failing, OUTPUT, time, Some("exit code 101"));
+        results.record(&failing, "", time, None);
+
+        let suites = results.suites.into_inner().unwrap();
+        let cases = suites[0]
+            .cases
+            .iter()
+            .map(|x| (x.name.as_str(), x.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cases,
+            vec![
+                ("passes", TestStatus::Passed),
+                ("crash", TestStatus::Failed)
+            ]
+        );
+        let message = suites[0].cases[1].message.as_ref().unwrap();
+        assert!(message.starts_with("exit code 134\nrunning 2 tests"));
+        assert!(message.ends_with("test crashes ..."));
+        // An executable with a failed test doesn't get another failure for the executable
+        assert_eq!(suites[1].cases.len(), 4);
+        assert_eq!(suites.len(), 2);
+    }
+}
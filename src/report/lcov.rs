@@ -1,33 +1,59 @@
-use crate::config::Config;
+use crate::config::{Config, OutputFile};
 use crate::errors::RunError;
 use crate::traces::{CoverageStat, TraceMap};
 use std::fs::File;
 use std::io::Write;
 
 pub fn export(coverage_data: &TraceMap, config: &Config) -> Result<(), RunError> {
-    let file_path = config.output_dir().join("lcov.info");
+    let file_path = config
+        .output_file(OutputFile::Lcov)
+        .expect("lcov reports are written to a file");
     let file = match File::create(file_path) {
         Ok(k) => k,
         Err(e) => return Err(RunError::Lcov(format!("File is not writeable: {e}"))),
     };
 
-    write_lcov(file, coverage_data)
+    write_lcov(file, coverage_data, config)
 }
 
-fn write_lcov(mut file: impl Write, coverage_data: &TraceMap) -> Result<(), RunError> {
+fn write_lcov(
+    mut file: impl Write,
+    coverage_data: &TraceMap,
+    config: &Config,
+) -> Result<(), RunError> {
     for (path, traces) in coverage_data.iter() {
         if traces.is_empty() {
             continue;
         }
         writeln!(file, "TN:")?;
-        writeln!(file, "SF:{}", path.to_str().unwrap())?;
+        writeln!(file, "SF:{}", config.report_path(path).display())?;
 
         let mut fns: Vec<String> = vec![];
         let mut fnda: Vec<String> = vec![];
+        let mut fn_hit = 0;
         let mut da: Vec<(u64, u64)> = vec![];
 
+        let functions = coverage_data.get_functions(path);
+        // Function spans from source analysis are used when available, otherwise fall back to
+        // the traces where the function name was found in the debug information
+        for function in functions {
+            let fn_hits = traces
+                .iter()
+                .filter(|x| x.line >= function.start && x.line <= function.end)
+                .find_map(|x| match x.stats {
+                    CoverageStat::Line(hits) => Some(hits),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            fns.push(format!("FN:{},{}", function.start, function.name));
+            fnda.push(format!("FNDA:{},{}", fn_hits, function.name));
+            if fn_hits > 0 {
+                fn_hit += 1;
+            }
+        }
+
         for trace in traces {
-            if trace.fn_name.is_some() {
+            if functions.is_empty() && trace.fn_name.is_some() {
                 let fn_name = trace.fn_name.clone().unwrap();
                 let fn_hits = match trace.stats {
                     CoverageStat::Line(hits) => hits,
@@ -40,6 +66,9 @@ fn write_lcov(mut file: impl Write, coverage_data: &TraceMap) -> Result<(), RunE
 
                 fns.push(format!("FN:{},{}", trace.line, fn_name));
                 fnda.push(format!("FNDA:{fn_hits},{fn_name}"));
+                if fn_hits > 0 {
+                    fn_hit += 1;
+                }
             }
 
             if let CoverageStat::Line(hits) = trace.stats {
@@ -51,12 +80,39 @@ fn write_lcov(mut file: impl Write, coverage_data: &TraceMap) -> Result<(), RunE
             writeln!(file, "{fn_line}",)?;
         }
 
-        writeln!(file, "FNF:{}", fns.len())?;
-
         for fnda_line in fnda {
             writeln!(file, "{fnda_line}")?;
         }
 
+        writeln!(file, "FNF:{}", fns.len())?;
+        writeln!(file, "FNH:{fn_hit}")?;
+
+        // BRDA:<line number>,<block number>,<branch number>,<taken> where taken is - if no
+        // branch was taken. Branches are found by source analysis and counted
+        // from the line hits so each branch point is a single block.
+        // More at http://ltp.sourceforge.net/coverage/lcov/geninfo.1.php
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        for (block, branch) in coverage_data.get_branches(path).iter().enumerate() {
+            let hits = coverage_data.branch_hits(path, branch);
+            let reached = hits.iter().any(|x| *x > 0);
+            for (number, hits) in hits.into_iter().enumerate() {
+                if reached {
+                    writeln!(file, "BRDA:{},{},{},{}", branch.line, block, number, hits)?;
+                } else {
+                    writeln!(file, "BRDA:{},{},{},-", branch.line, block, number)?;
+                }
+                branches_found += 1;
+                if hits > 0 {
+                    branches_hit += 1;
+                }
+            }
+        }
+        if branches_found > 0 {
+            writeln!(file, "BRF:{branches_found}")?;
+            writeln!(file, "BRH:{branches_hit}")?;
+        }
+
         for (line, hits) in &da {
             writeln!(file, "DA:{line},{hits}")?;
         }
@@ -68,12 +124,6 @@ fn write_lcov(mut file: impl Write, coverage_data: &TraceMap) -> Result<(), RunE
             da.iter().filter(|(_, hits)| *hits != 0).count()
         )?;
 
-        // TODO: add support for branching
-        // BRDA (BRDA:<line number>,<block number>,<branch number>,<hits>)
-        // BRF (branches found)
-        // BRH (branches hit)
-        // More at http://ltp.sourceforge.net/coverage/lcov/geninfo.1.php
-
         writeln!(file, "end_of_record")?;
     }
     Ok(())
@@ -87,6 +137,59 @@ mod tests {
     use std::io::Cursor;
     use std::path::{Path, PathBuf};
 
+    #[test]
+    fn lcov_matches_expected() {
+        let mut traces = TraceMap::new();
+        let lib = Path::new("/src/lib.rs");
+        for (line, hits) in [(1, 2), (2, 2), (5, 1), (6, 1), (7, 0), (9, 0), (10, 0)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            traces.add_trace(lib, trace);
+        }
+        let span = |name: &str, start, end| FunctionSpan {
+            name: name.to_string(),
+            start,
+            end,
+        };
+        traces.add_functions(
+            lib,
+            vec![
+                span("add", 1, 3),
+                span("check", 5, 8),
+                span("unused", 9, 11),
+            ],
+        );
+        traces.add_branches(
+            lib,
+            vec![
+                BranchPoint {
+                    line: 6,
+                    arms: vec![(7, 8)],
+                    implicit_default: true,
+                },
+                BranchPoint {
+                    line: 9,
+                    arms: vec![(10, 11)],
+                    implicit_default: false,
+                },
+            ],
+        );
+
+        let mut trace = Trace::new_stub(3);
+        trace.stats = CoverageStat::Line(1);
+        trace.fn_name = Some("main".to_string());
+        traces.add_trace(Path::new("/src/main.rs"), trace);
+
+        let mut config = Config::default();
+        config.relative_paths = false;
+        let mut data = vec![];
+        write_lcov(Cursor::new(&mut data), &traces, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            include_str!("lcov_expected.info")
+        );
+    }
+
     #[test]
     fn generate_valid_lcov() {
         let mut traces = TraceMap::new();
@@ -98,6 +201,7 @@ mod tests {
                 address: Default::default(),
                 length: 0,
                 fn_name: None,
+                symbol: None,
             },
         );
         traces.add_trace(
@@ -108,6 +212,7 @@ mod tests {
                 address: Default::default(),
                 length: 0,
                 fn_name: None,
+                symbol: None,
             },
         );
 
@@ -119,13 +224,14 @@ mod tests {
                 address: Default::default(),
                 length: 0,
                 fn_name: Some("baz".to_string()),
+                symbol: None,
             },
         );
 
         let mut data = vec![];
         let cursor = Cursor::new(&mut data);
 
-        write_lcov(cursor, &traces).unwrap();
+        write_lcov(cursor, &traces, &Config::default()).unwrap();
 
         let reader = Reader::new(data.as_slice());
         let mut items = 0;
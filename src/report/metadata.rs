@@ -0,0 +1,137 @@
+//! Details of the run a report comes from, so archived reports say how they were made
+use crate::cargo::resolved_features;
+use crate::config::{Config, TraceEngine};
+use chrono::{DateTime, TimeZone, Utc};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::Path;
+use tracing::warn;
+
+/// When and how the coverage in a report was collected
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ReportMetadata {
+    /// When the report was made, in RFC 3339 format
+    pub timestamp: String,
+    /// Version of tarpaulin that made the report
+    pub tarpaulin_version: String,
+    /// Hash of the commit checked out in the project, `None` if it isn't in a git repository
+    pub commit: Option<String>,
+    /// Engine the coverage was collected with
+    pub engine: TraceEngine,
+    /// Features enabled in the packages built, prefixed with the package when more than one
+    /// package is built
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl ReportMetadata {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            timestamp: report_time().to_rfc3339(),
+            tarpaulin_version: env!("CARGO_PKG_VERSION").to_string(),
+            commit: head_commit(&config.root()),
+            engine: config.engine(),
+            features: resolved_features(config),
+        }
+    }
+
+    /// The metadata on one line for formats that can only hold it as a comment
+    pub fn summary(&self) -> String {
+        format!(
+            "Generated by tarpaulin {} at {}, commit {}, engine {}, features {}",
+            self.tarpaulin_version,
+            self.timestamp,
+            self.commit.as_deref().unwrap_or("unknown"),
+            self.engine.to_string().to_lowercase(),
+            if self.features.is_empty() {
+                "none".to_string()
+            } else {
+                self.features.join(" ")
+            }
+        )
+    }
+}
+
+/// When the reports are made, `SOURCE_DATE_EPOCH` if it's set so reports of the same coverage
+/// are identical and otherwise the current time
+pub fn report_time() -> DateTime<Utc> {
+    time_from_epoch(env::var("SOURCE_DATE_EPOCH").ok().as_deref())
+}
+
+/// The time `epoch` seconds after the Unix epoch, the current time if it's `None` or invalid
+fn time_from_epoch(epoch: Option<&str>) -> DateTime<Utc> {
+    let epoch = match epoch {
+        Some(epoch) => epoch,
+        None => return Utc::now(),
+    };
+    match epoch
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|x| Utc.timestamp_opt(x, 0).single())
+    {
+        Some(time) => time,
+        None => {
+            warn!(
+                "SOURCE_DATE_EPOCH {} isn't a number of seconds, using the current time",
+                epoch
+            );
+            Utc::now()
+        }
+    }
+}
+
+/// The hash of the commit checked out in the repository holding `root`
+pub fn head_commit(root: &Path) -> Option<String> {
+    let repo = Repository::discover(root).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_metadata() {
+        let mut metadata = ReportMetadata {
+            timestamp: "2024-01-02T03:04:05+00:00".to_string(),
+            tarpaulin_version: "0.1.0".to_string(),
+            commit: None,
+            engine: TraceEngine::Llvm,
+            features: vec![],
+        };
+        assert_eq!(
+            metadata.summary(),
+            "Generated by tarpaulin 0.1.0 at 2024-01-02T03:04:05+00:00, commit unknown, engine llvm, features none"
+        );
+        metadata.commit = Some("abc123".to_string());
+        metadata.features = vec!["a/x".to_string(), "b/y".to_string()];
+        assert!(metadata
+            .summary()
+            .ends_with("commit abc123, engine llvm, features a/x b/y"));
+    }
+
+    #[test]
+    fn time_from_source_date_epoch() {
+        assert_eq!(
+            time_from_epoch(Some("1704164645")).to_rfc3339(),
+            "2024-01-02T03:04:05+00:00"
+        );
+        // An invalid epoch is ignored
+        assert!(time_from_epoch(Some("yesterday")) > time_from_epoch(Some("0")));
+        assert!(time_from_epoch(None) > time_from_epoch(Some("0")));
+    }
+
+    #[test]
+    fn no_commit_outside_repository() {
+        let dir = env::temp_dir().join(format!("tarpaulin-no-repo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // The temporary directory could be in a repository so check there's no repository first
+        if Repository::discover(&dir).is_err() {
+            assert_eq!(head_commit(&dir), None);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -5,16 +5,30 @@ use crate::test_loader::TracerData;
 use crate::traces::*;
 use cargo_metadata::Metadata;
 use serde::Serialize;
-use std::fs::{create_dir_all, File};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, create_dir_all, File};
 use std::io::BufReader;
-use tracing::{error, info};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{error, info, warn};
 
+pub mod badge;
 pub mod cobertura;
 pub mod coveralls;
+pub mod diff;
+mod directory_summary;
 pub mod html;
 pub mod json;
+pub mod junit;
 pub mod lcov;
+pub mod metadata;
+pub mod patch;
 mod safe_json;
+pub mod teamcity;
+
+/// Number of functions listed by `--out Hotspots` when `hotspot_limit` isn't set
+const DEFAULT_HOTSPOT_LIMIT: usize = 10;
+
 /// Trait for report formats to implement.
 /// Currently reports must be serializable using serde
 pub trait Report<Out: Serialize> {
@@ -34,8 +48,33 @@ fn coverage_report_name(config: &Config) -> String {
 /// Reports the test coverage using the users preferred method. See config.rs
 /// or help text for details.
 pub fn report_coverage(config: &Config, result: &TraceMap) -> Result<(), RunError> {
-    if !result.is_empty() {
-        generate_requested_reports(config, result)?;
+    let patch = if result.is_empty() {
+        None
+    } else {
+        patch::patch_coverage(result, config)?
+    };
+    report_coverage_with_patch(config, result, patch.as_ref())
+}
+
+/// Reports the test coverage like [`report_coverage`] with the coverage of the lines changed
+/// since `changed_since` already found by [`patch::patch_coverage`]
+pub fn report_coverage_with_patch(
+    config: &Config,
+    result: &TraceMap,
+    patch: Option<&TraceMap>,
+) -> Result<(), RunError> {
+    if !result.is_empty() && config.summary_only {
+        // Skips serializing the results for any reports or the run report in the target folder
+        print_summary(config, patch.unwrap_or(result));
+        Ok(())
+    } else if !result.is_empty() {
+        match patch {
+            Some(patch) => {
+                generate_requested_reports(config, patch)?;
+                print_full_summary(result);
+            }
+            None => generate_requested_reports(config, result)?,
+        }
         let mut report_dir = config.target_dir();
         report_dir.push("tarpaulin");
         if !report_dir.exists() {
@@ -46,8 +85,8 @@ pub fn report_coverage(config: &Config, result: &TraceMap) -> Result<(), RunErro
             .map_err(|_| RunError::CovReport("Failed to create run report".to_string()))?;
         serde_json::to_writer(&file, &result)
             .map_err(|_| RunError::CovReport("Failed to save run report".to_string()))?;
-        Ok(())
-    } else if !config.no_run {
+        run_post_report_command(config, result)
+    } else if !config.no_run || config.merge_only() {
         Err(RunError::CovReport(
             "No coverage results collected.".to_string(),
         ))
@@ -56,19 +95,88 @@ pub fn report_coverage(config: &Config, result: &TraceMap) -> Result<(), RunErro
     }
 }
 
+/// Runs the `post_report_command` in a shell from the project root. If it fails the run fails
+/// with `post_report_required`, otherwise there's a warning
+fn run_post_report_command(config: &Config, result: &TraceMap) -> Result<(), RunError> {
+    let command = match config.post_report_command.as_ref() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    info!("Running post report command: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let status = shell
+        .arg(command)
+        .current_dir(config.root())
+        .env("TARPAULIN_REPORT_DIR", config.output_dir())
+        .env(
+            "TARPAULIN_COVERAGE",
+            format!("{:.2}", result.coverage_percentage() * 100.0),
+        )
+        .status();
+    let failure = match status {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => match status.code() {
+            Some(code) => format!("`{command}` exited with code {code}"),
+            None => format!("`{command}` was killed by a signal"),
+        },
+        Err(e) => format!("couldn't run `{command}`: {e}"),
+    };
+    if config.post_report_required {
+        Err(RunError::PostReport(failure))
+    } else {
+        warn!("Post report command failed: {}", failure);
+        Ok(())
+    }
+}
+
 fn generate_requested_reports(config: &Config, result: &TraceMap) -> Result<(), RunError> {
-    if config.is_coveralls() {
-        coveralls::export(result, config)?;
-        info!("Coverage data sent");
+    // Check no two formats are written to the same file before writing any of them
+    let mut output_files: HashMap<PathBuf, OutputFile> = HashMap::new();
+    let mut parents = HashSet::new();
+    for format in &config.generate {
+        if let Some(path) = config.output_file(*format) {
+            match output_files.get(&path) {
+                Some(other) if other != format => {
+                    return Err(RunError::OutFormat(format!(
+                        "{} and {} reports are both set to be written to {}",
+                        other,
+                        format,
+                        path.display()
+                    )));
+                }
+                _ => {}
+            }
+            if let Some(parent) = path.parent() {
+                parents.insert(parent.to_path_buf());
+            }
+            output_files.insert(path, *format);
+        }
     }
-    info!("Coverage Results:");
 
-    if !config.is_default_output_dir() && create_dir_all(&config.output_dir()).is_err() {
+    // Every report, including the coveralls report saved in debug mode, is written here
+    if !config.is_default_output_dir() && create_dir_all(config.output_dir()).is_err() {
         return Err(RunError::OutFormat(format!(
             "Failed to create or locate custom output directory: {:?}",
             config.output_directory,
         )));
     }
+    for parent in &parents {
+        create_dir_all(parent)?;
+    }
+
+    if config.is_coveralls() {
+        coveralls::export(result, config)?;
+        info!("Coverage data sent");
+    }
+    info!("Coverage Results:");
 
     if config.verbose || config.generate.is_empty() {
         print_missing_lines(config, result);
@@ -87,12 +195,22 @@ fn generate_requested_reports(config: &Config, result: &TraceMap) -> Result<(),
             OutputFile::Json => {
                 json::export(result, config)?;
             }
+            OutputFile::Badge => {
+                badge::export(result, config)?;
+            }
             OutputFile::Stdout => {
                 // Already reported the missing lines
                 if !config.verbose {
                     print_missing_lines(config, result);
                 }
             }
+            // Printed after the summary so it isn't mixed in with the other output
+            OutputFile::UncoveredLines
+            | OutputFile::TeamCity
+            | OutputFile::Hotspots
+            | OutputFile::StdoutJson => {}
+            // Written once the tests have run so failing tests are reported
+            OutputFile::Junit => {}
             _ => {
                 return Err(RunError::OutFormat(
                     "Output format is currently not supported!".to_string(),
@@ -100,11 +218,214 @@ fn generate_requested_reports(config: &Config, result: &TraceMap) -> Result<(),
             }
         }
     }
+    if let Some(threshold) = config.warn_if_hits_below {
+        println!("|| Lines hit fewer than {threshold} times:");
+        for line in low_hit_lines(config, result, threshold) {
+            println!("{line}");
+        }
+    }
+    if config.run_count > 1 {
+        println!(
+            "|| Lines covered in only some of the {} runs:",
+            config.run_count
+        );
+        for line in flaky_lines(config, result) {
+            println!("{line}");
+        }
+    }
+    if config.report_unsafe {
+        for line in unsafe_lines(config, result) {
+            println!("{line}");
+        }
+    }
+    let timed_out = result.timed_out();
+    if !timed_out.is_empty() {
+        println!("|| Test executables stopped after timing out:");
+        for exe in timed_out {
+            println!("|| {}", config.strip_base_dir(exe).display());
+        }
+    }
+    if config.print_summary_table {
+        directory_summary::print_directory_summary(config, result);
+    }
     // We always want to report the short summary
     print_summary(config, result);
+    if config.generate.contains(&OutputFile::UncoveredLines) {
+        for line in uncovered_lines(config, result) {
+            println!("{line}");
+        }
+    }
+    if config.generate.contains(&OutputFile::Hotspots) {
+        let limit = config.hotspot_limit.unwrap_or(DEFAULT_HOTSPOT_LIMIT);
+        for line in hotspots(config, result, limit) {
+            println!("{line}");
+        }
+    }
+    if config.generate.contains(&OutputFile::TeamCity) {
+        for message in teamcity::service_messages(result) {
+            println!("{message}");
+        }
+    }
+    // Printed last so it's the final line of the output where stdout isn't sent to stderr
+    if config.generate.contains(&OutputFile::StdoutJson) {
+        json::print_combined(result, config)?;
+    }
     Ok(())
 }
 
+/// Every uncovered line as `path:line: source` with files and lines in order, without the
+/// `||` prefix of the summary so it can be easily parsed
+fn uncovered_lines(config: &Config, result: &TraceMap) -> Vec<String> {
+    let mut lines = vec![];
+    for (file, traces) in result.iter() {
+        let mut uncovered = traces
+            .iter()
+            .filter(|x| x.stats == CoverageStat::Line(0))
+            .map(|x| x.line)
+            .collect::<Vec<_>>();
+        if uncovered.is_empty() {
+            continue;
+        }
+        uncovered.sort_unstable();
+        uncovered.dedup();
+        let source = fs::read_to_string(file).unwrap_or_default();
+        let source = source.lines().collect::<Vec<_>>();
+        let path = config.strip_base_dir(file);
+        for line in uncovered {
+            let text = source
+                .get(line.saturating_sub(1) as usize)
+                .map(|x| x.trim())
+                .unwrap_or_default();
+            lines.push(format!("{}:{}: {}", path.display(), line, text));
+        }
+    }
+    lines
+}
+
+/// The functions with the most uncovered lines as `path:line: name (uncovered lines)`, using the
+/// function spans from source analysis. Functions with every line covered aren't listed
+fn hotspots(config: &Config, result: &TraceMap, limit: usize) -> Vec<String> {
+    let mut functions = vec![];
+    for (file, traces) in result.iter() {
+        for function in result.get_functions(file) {
+            let uncovered = traces
+                .iter()
+                .filter(|x| x.line >= function.start && x.line <= function.end)
+                .filter(|x| x.stats == CoverageStat::Line(0))
+                .count();
+            if uncovered > 0 {
+                functions.push((uncovered, file, function));
+            }
+        }
+    }
+    // Largest first, ties in order of file and line so the list is stable
+    functions.sort_by(|(a, a_file, a_fn), (b, b_file, b_fn)| {
+        b.cmp(a)
+            .then_with(|| a_file.cmp(b_file))
+            .then_with(|| a_fn.start.cmp(&b_fn.start))
+    });
+    functions
+        .into_iter()
+        .take(limit)
+        .map(|(uncovered, file, function)| {
+            format!(
+                "{}:{}: {} ({} uncovered {})",
+                config.strip_base_dir(file).display(),
+                function.start,
+                function.name,
+                uncovered,
+                if uncovered == 1 { "line" } else { "lines" }
+            )
+        })
+        .collect()
+}
+
+/// The lines in each file which are covered but hit fewer times than the threshold, as
+/// `|| path: line (hits), ...`
+fn low_hit_lines(config: &Config, result: &TraceMap, threshold: u64) -> Vec<String> {
+    let mut lines = vec![];
+    for (file, traces) in result.iter() {
+        let mut low_hits = traces
+            .iter()
+            .filter_map(|x| match x.stats {
+                CoverageStat::Line(hits) if hits > 0 && hits < threshold => Some((x.line, hits)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if low_hits.is_empty() {
+            continue;
+        }
+        low_hits.sort_unstable();
+        let low_hits = low_hits
+            .iter()
+            .map(|(line, hits)| format!("{line} ({hits})"))
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "|| {}: {}",
+            config.strip_base_dir(file).display(),
+            low_hits.join(", ")
+        ));
+    }
+    lines
+}
+
+/// The lines covered in only some of the runs of the tests in the form `|| path: 1, 2`, one
+/// entry per file
+fn flaky_lines(config: &Config, result: &TraceMap) -> Vec<String> {
+    result
+        .files()
+        .into_iter()
+        .filter_map(|file| {
+            let flaky = result.get_flaky(file);
+            if flaky.is_empty() {
+                return None;
+            }
+            let flaky = flaky.iter().map(ToString::to_string).collect::<Vec<_>>();
+            Some(format!(
+                "|| {}: {}",
+                config.strip_base_dir(file).display(),
+                flaky.join(", ")
+            ))
+        })
+        .collect()
+}
+
+/// The coverage of the lines inside `unsafe` blocks followed by the uncovered ones in the form
+/// `|| path: 1, 2`, one entry per file
+fn unsafe_lines(config: &Config, result: &TraceMap) -> Vec<String> {
+    let covered = result.total_unsafe_covered();
+    let coverable = result.total_unsafe_coverable();
+    let percent = if coverable > 0 {
+        100.0f64 * covered as f64 / coverable as f64
+    } else {
+        0.0
+    };
+    let mut lines = vec![format!(
+        "|| {percent:.2}% coverage of unsafe lines, {covered}/{coverable} lines covered"
+    )];
+    for file in result.files() {
+        let mut uncovered = result
+            .get_unsafe_traces(file)
+            .filter(|x| x.stats == CoverageStat::Line(0))
+            .map(|x| x.line)
+            .collect::<Vec<_>>();
+        if uncovered.is_empty() {
+            continue;
+        }
+        uncovered.dedup();
+        let uncovered = uncovered
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "|| Uncovered unsafe lines in {}: {}",
+            config.strip_base_dir(file).display(),
+            uncovered.join(", ")
+        ));
+    }
+    lines
+}
+
 fn print_missing_lines(config: &Config, result: &TraceMap) {
     println!("|| Uncovered Lines:");
     for (key, value) in result.iter() {
@@ -129,7 +450,31 @@ fn print_missing_lines(config: &Config, result: &TraceMap) {
     }
 }
 
+/// Loads coverage results saved by a previous run, like the ones in `target/tarpaulin`, or the
+/// line coverage in a JSON report. Relative paths in a report are relative to `root`
+pub fn load_results(path: &Path, root: &Path) -> Result<TraceMap, RunError> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| RunError::CovReport(format!("Failed to open {}: {}", path.display(), e)))?;
+    json::read_results(&json, root)
+        .map_err(|e| RunError::CovReport(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+/// Loads the baseline results set in the config, returns `None` if there's no baseline set
+pub fn load_baseline(config: &Config) -> Result<Option<TraceMap>, RunError> {
+    config
+        .baseline
+        .as_deref()
+        .map(|x| load_results(x, &config.root()))
+        .transpose()
+}
+
 fn get_previous_result(config: &Config) -> Option<TraceMap> {
+    if config.baseline.is_some() {
+        return load_baseline(config)
+            .map_err(|e| warn!("Not comparing coverage to the baseline. {}", e))
+            .ok()
+            .flatten();
+    }
     // Check for previous report
     let mut report_dir = config.target_dir();
     report_dir.push("tarpaulin");
@@ -148,9 +493,10 @@ fn get_previous_result(config: &Config) -> Option<TraceMap> {
 }
 
 fn print_summary(config: &Config, result: &TraceMap) {
+    // The previous results cover the whole project so can't be compared to patch coverage
     let last = match get_previous_result(config) {
-        Some(l) => l,
-        None => TraceMap::new(),
+        Some(l) if config.changed_since.is_none() => l,
+        _ => TraceMap::new(),
     };
     println!("|| Tested/Total Lines:");
     for file in result.files() {
@@ -178,24 +524,113 @@ fn print_summary(config: &Config, result: &TraceMap) {
             );
         }
     }
-    let percent = result.coverage_percentage() * 100.0f64;
-    if last.is_empty() {
-        println!(
-            "|| \n{:.2}% coverage, {}/{} lines covered",
-            percent,
-            result.total_covered(),
-            result.total_coverable()
-        );
+    for file in last.files() {
+        if !result.contains_file(file) && last.coverable_in_path(file) > 0 {
+            println!(
+                "|| {}: not in this run, was {}/{}",
+                config.strip_base_dir(file).display(),
+                last.covered_in_path(file),
+                last.coverable_in_path(file)
+            );
+        }
+    }
+    println!("|| \n{}", summary_line(result, &last));
+    if config.run_count > 1 {
+        println!("{}", every_run_line(result, config.run_count));
+    }
+}
+
+/// The coverage of the lines covered in every one of the runs of the tests, the summary line
+/// has the coverage of all the runs merged
+fn every_run_line(result: &TraceMap, runs: usize) -> String {
+    let covered = result.total_covered_in_every_run();
+    let coverable = result.total_coverable();
+    let percent = if coverable > 0 {
+        100.0f64 * covered as f64 / coverable as f64
+    } else {
+        0.0
+    };
+    format!("{percent:.2}% coverage in every one of the {runs} runs, {covered}/{coverable} lines covered")
+}
+
+/// The final line of the summary in the form
+/// `{percent:.2}% coverage, {covered}/{coverable} lines covered` followed by
+/// `, {delta:+.2}% change in coverage` if there's a previous run to compare to, then
+/// `, {covered}/{total} functions covered` and `, {covered}/{total} branches covered` when
+/// the results have functions or branches
+fn summary_line(result: &TraceMap, last: &TraceMap) -> String {
+    // An empty shard has nothing coverable, it's reported as no coverage rather than NaN
+    let percent = if result.total_coverable() > 0 {
+        result.coverage_percentage() * 100.0f64
     } else {
+        0.0
+    };
+    let mut summary = format!(
+        "{:.2}% coverage, {}/{} lines covered",
+        percent,
+        result.total_covered(),
+        result.total_coverable()
+    );
+    if last.total_coverable() > 0 {
         let delta = percent - 100.0f64 * last.coverage_percentage();
-        println!(
-            "|| \n{:.2}% coverage, {}/{} lines covered, {:+.2}% change in coverage",
-            percent,
-            result.total_covered(),
-            result.total_coverable(),
-            delta
-        );
+        summary.push_str(&format!(", {delta:+.2}% change in coverage"));
+    }
+    let (covered, total) = function_totals(result);
+    if total > 0 {
+        summary.push_str(&format!(", {covered}/{total} functions covered"));
     }
+    let (covered, total) = branch_totals(result);
+    if total > 0 {
+        summary.push_str(&format!(", {covered}/{total} branches covered"));
+    }
+    summary
+}
+
+/// Number of functions with a hit line and the number of functions, a function is hit if the
+/// first line with a trace in its span is hit like in the lcov report
+fn function_totals(result: &TraceMap) -> (usize, usize) {
+    let mut covered = 0;
+    let mut total = 0;
+    for (file, traces) in result.iter() {
+        for function in result.get_functions(file) {
+            let hits = traces
+                .iter()
+                .filter(|x| x.line >= function.start && x.line <= function.end)
+                .find_map(|x| match x.stats {
+                    CoverageStat::Line(hits) => Some(hits),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            total += 1;
+            if hits > 0 {
+                covered += 1;
+            }
+        }
+    }
+    (covered, total)
+}
+
+/// Number of branches taken and the number of branches from the branch points in the results
+fn branch_totals(result: &TraceMap) -> (usize, usize) {
+    let mut covered = 0;
+    let mut total = 0;
+    for file in result.files() {
+        for branch in result.get_branches(file) {
+            let taken = result.branches_taken(file, branch);
+            total += taken.len();
+            covered += taken.into_iter().filter(|x| *x).count();
+        }
+    }
+    (covered, total)
+}
+
+fn print_full_summary(result: &TraceMap) {
+    println!(
+        "{:.2}% coverage of the whole project, {}/{} lines covered",
+        result.coverage_percentage() * 100.0f64,
+        result.total_covered(),
+        result.total_coverable()
+    );
 }
 
 fn accumulate_lines(
@@ -223,3 +658,284 @@ fn accumulate_lines(
         (acc, group)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+
+    #[test]
+    fn reports_written_to_output_dir() {
+        let output_dir = env::temp_dir()
+            .join(format!("tarpaulin-output-dir-{}", std::process::id()))
+            .join("nested");
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        config.output_directory = Some(output_dir.clone());
+        config.generate = vec![
+            OutputFile::Html,
+            OutputFile::Lcov,
+            OutputFile::Xml,
+            OutputFile::Json,
+            OutputFile::Badge,
+        ];
+        let mut result = TraceMap::new();
+        let mut trace = Trace::new_stub(1);
+        trace.stats = CoverageStat::Line(1);
+        result.add_trace(&root.join("src").join("lib.rs"), trace);
+
+        generate_requested_reports(&config, &result).unwrap();
+        for report in [
+            "tarpaulin-report.html",
+            "lcov.info",
+            "cobertura.xml",
+            "tarpaulin-report.json",
+            "coverage-badge.svg",
+        ] {
+            assert!(output_dir.join(report).is_file(), "{} not written", report);
+        }
+        let _ = std::fs::remove_dir_all(output_dir.parent().unwrap());
+    }
+
+    #[test]
+    fn summary_line_counts() {
+        let file = Path::new("/lib.rs");
+        let mut result = TraceMap::new();
+        let mut hit = Trace::new_stub(2);
+        hit.stats = CoverageStat::Line(1);
+        result.add_trace(file, hit);
+        result.add_trace(file, Trace::new_stub(3));
+        result.add_trace(file, Trace::new_stub(4));
+        assert_eq!(
+            summary_line(&result, &TraceMap::new()),
+            "33.33% coverage, 1/3 lines covered"
+        );
+
+        let mut last = TraceMap::new();
+        last.add_trace(file, Trace::new_stub(2));
+        result.add_functions(
+            file,
+            vec![FunctionSpan {
+                name: "a".to_string(),
+                start: 1,
+                end: 4,
+            }],
+        );
+        result.add_branches(
+            file,
+            vec![BranchPoint {
+                line: 2,
+                arms: vec![(3, 3)],
+                implicit_default: true,
+            }],
+        );
+        assert_eq!(
+            summary_line(&result, &last),
+            "33.33% coverage, 1/3 lines covered, +33.33% change in coverage, 1/1 functions covered, 1/2 branches covered"
+        );
+    }
+
+    #[test]
+    fn uncovered_lines_listed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let file = root.join("tests/data/simple_project/src/lib.rs");
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let mut result = TraceMap::new();
+        let mut hit = Trace::new_stub(5);
+        hit.stats = CoverageStat::Line(1);
+        result.add_trace(&file, hit);
+        result.add_trace(&file, Trace::new_stub(8));
+        result.add_trace(&file, Trace::new_stub(4));
+
+        let path = config.strip_base_dir(&file);
+        assert_eq!(
+            uncovered_lines(&config, &result),
+            vec![
+                format!(
+                    "{}:4: pub fn branch_test_one(x: i32) -> i32 {{",
+                    path.display()
+                ),
+                format!("{}:8: 5", path.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn hotspots_listed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let lib = root.join("src/lib.rs");
+        let main = root.join("src/main.rs");
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let mut result = TraceMap::new();
+        let function = |name: &str, start, end| FunctionSpan {
+            name: name.to_string(),
+            start,
+            end,
+        };
+        for line in 1..=10 {
+            let mut trace = Trace::new_stub(line);
+            if line <= 2 || line == 9 {
+                trace.stats = CoverageStat::Line(1);
+            }
+            result.add_trace(&lib, trace);
+        }
+        result.add_functions(
+            &lib,
+            vec![
+                function("covered", 1, 2),
+                function("small", 3, 4),
+                function("big", 5, 10),
+            ],
+        );
+        result.add_trace(&main, Trace::new_stub(3));
+        result.add_trace(&main, Trace::new_stub(4));
+        result.add_functions(&main, vec![function("main", 2, 4)]);
+
+        let listed = hotspots(&config, &result, 10);
+        assert_eq!(
+            listed,
+            vec![
+                "src/lib.rs:5: big (5 uncovered lines)",
+                "src/lib.rs:3: small (2 uncovered lines)",
+                "src/main.rs:2: main (2 uncovered lines)",
+            ]
+        );
+        assert_eq!(hotspots(&config, &result, 1), listed[..1]);
+    }
+
+    #[test]
+    fn low_hit_lines_listed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let file = root.join("src/lib.rs");
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let mut result = TraceMap::new();
+        for (line, hits) in [(1, 5), (2, 0), (3, 1), (4, 3), (5, 2)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            result.add_trace(&file, trace);
+        }
+        let mut other = Trace::new_stub(1);
+        other.stats = CoverageStat::Line(10);
+        result.add_trace(&root.join("src/main.rs"), other);
+
+        let path = config.strip_base_dir(&file);
+        assert_eq!(
+            low_hit_lines(&config, &result, 3),
+            vec![format!("|| {}: 3 (1), 5 (2)", path.display())]
+        );
+        assert!(low_hit_lines(&config, &result, 1).is_empty());
+    }
+
+    #[test]
+    fn flaky_lines_listed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let file = root.join("src/lib.rs");
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let mut result = TraceMap::new();
+        for line in 1..=4 {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line((line != 4).into());
+            result.add_trace(&file, trace);
+        }
+        result.add_trace(&root.join("src/main.rs"), Trace::new_stub(1));
+        result.add_flaky(&file, vec![3, 1]);
+
+        let path = config.strip_base_dir(&file);
+        assert_eq!(
+            flaky_lines(&config, &result),
+            vec![format!("|| {}: 1, 3", path.display())]
+        );
+        assert_eq!(
+            every_run_line(&result, 3),
+            "20.00% coverage in every one of the 3 runs, 1/5 lines covered"
+        );
+    }
+
+    #[test]
+    fn unsafe_lines_listed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let file = root.join("src/lib.rs");
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        let mut result = TraceMap::new();
+        for line in 1..=4 {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(line % 2);
+            result.add_trace(&file, trace);
+        }
+        result.add_trace(&root.join("src/main.rs"), Trace::new_stub(1));
+        result.add_unsafe_lines(&file, vec![1, 2, 4, 7]);
+
+        let path = config.strip_base_dir(&file);
+        assert_eq!(
+            unsafe_lines(&config, &result),
+            vec![
+                "|| 33.33% coverage of unsafe lines, 1/3 lines covered".to_string(),
+                format!("|| Uncovered unsafe lines in {}: 2, 4", path.display())
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn post_report_command_ran() {
+        let output_dir = env::temp_dir().join(format!("tarpaulin-hook-{}", std::process::id()));
+        fs::create_dir_all(&output_dir).unwrap();
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut config = Config::default();
+        config.set_manifest(root.join("Cargo.toml"));
+        config.output_directory = Some(output_dir.clone());
+        config.post_report_command = Some(
+            r#"echo "$TARPAULIN_REPORT_DIR $TARPAULIN_COVERAGE" > "$TARPAULIN_REPORT_DIR/hook""#
+                .to_string(),
+        );
+        let mut result = TraceMap::new();
+        let mut trace = Trace::new_stub(1);
+        trace.stats = CoverageStat::Line(1);
+        result.add_trace(&root.join("src/lib.rs"), trace);
+        result.add_trace(&root.join("src/lib.rs"), Trace::new_stub(2));
+        result.add_trace(&root.join("src/lib.rs"), Trace::new_stub(3));
+
+        run_post_report_command(&config, &result).unwrap();
+        let written = fs::read_to_string(output_dir.join("hook")).unwrap();
+        let _ = fs::remove_dir_all(&output_dir);
+        assert_eq!(written.trim(), format!("{} 33.33", output_dir.display()));
+
+        config.post_report_command = Some("exit 3".to_string());
+        assert!(run_post_report_command(&config, &result).is_ok());
+        config.post_report_required = true;
+        match run_post_report_command(&config, &result) {
+            Err(RunError::PostReport(e)) => assert_eq!(e, "`exit 3` exited with code 3"),
+            res => panic!("Expected the post report command to fail: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn output_file_clash() {
+        let output_dir = env::temp_dir().join(format!("tarpaulin-clash-{}", std::process::id()));
+        let mut config = Config::default();
+        config.output_directory = Some(output_dir.clone());
+        config.generate = vec![OutputFile::Xml, OutputFile::Json];
+        for format in &config.generate {
+            config
+                .output_files
+                .insert(*format, PathBuf::from("reports/cobertura.xml"));
+        }
+        let mut result = TraceMap::new();
+        result.add_trace(Path::new("lib.rs"), Trace::new_stub(1));
+
+        assert!(matches!(
+            generate_requested_reports(&config, &result),
+            Err(RunError::OutFormat(_))
+        ));
+        // Nothing is created when the reports clash
+        assert!(!output_dir.exists());
+        let _ = std::fs::remove_dir_all(output_dir);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::Config;
+use crate::errors::RunError;
+use crate::path_utils::fix_unc_path;
+use crate::traces::TraceMap;
+use git2::{DiffOptions, Repository};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Lines added or modified in each file since the given reference, this includes uncommitted
+/// and untracked changes in the working directory
+pub fn changed_lines(
+    root: &Path,
+    reference: &str,
+) -> Result<HashMap<PathBuf, HashSet<u64>>, RunError> {
+    let repo = Repository::discover(root)
+        .map_err(|e| RunError::Git(format!("failed to open repository: {e}")))?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| RunError::Git("repository has no working directory".to_string()))?;
+    let workdir = workdir
+        .canonicalize()
+        .map(|x| fix_unc_path(&x))
+        .unwrap_or_else(|_| workdir.to_path_buf());
+    let tree = repo
+        .revparse_single(reference)
+        .and_then(|x| x.peel_to_tree())
+        .map_err(|e| RunError::Git(format!("failed to find {reference}: {e}")))?;
+
+    let mut opts = DiffOptions::new();
+    opts.context_lines(0)
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    let diff = repo
+        .diff_tree_to_workdir_with_index(Some(&tree), Some(&mut opts))
+        .map_err(|e| RunError::Git(format!("failed to diff against {reference}: {e}")))?;
+
+    let mut result: HashMap<PathBuf, HashSet<u64>> = HashMap::new();
+    diff.foreach(
+        &mut |_, _| true,
+        None,
+        None,
+        Some(&mut |delta, _, line| {
+            if let (Some(path), Some(line_no)) = (delta.new_file().path(), line.new_lineno()) {
+                if line.origin() == '+' {
+                    result
+                        .entry(workdir.join(path))
+                        .or_default()
+                        .insert(u64::from(line_no));
+                }
+            }
+            true
+        }),
+    )
+    .map_err(|e| RunError::Git(format!("failed to read diff: {e}")))?;
+    Ok(result)
+}
+
+/// Filters the traces down to only the lines that have been changed
+pub fn filter_changed(result: &TraceMap, changes: &HashMap<PathBuf, HashSet<u64>>) -> TraceMap {
+    let mut patch = TraceMap::new();
+    for (file, traces) in result.iter() {
+        if let Some(lines) = changes.get(file) {
+            let mut added = false;
+            for trace in traces.iter().filter(|x| lines.contains(&x.line)) {
+                patch.add_trace(file, trace.clone());
+                added = true;
+            }
+            if added {
+                patch.add_functions(file, result.get_functions(file).iter().cloned());
+                patch.add_unsafe_lines(
+                    file,
+                    lines.iter().copied().filter(|x| result.is_unsafe(file, *x)),
+                );
+            }
+        }
+    }
+    patch
+}
+
+/// Gets the coverage of the lines changed since the reference set in the config, returns
+/// `None` if there's no reference set
+pub fn patch_coverage(result: &TraceMap, config: &Config) -> Result<Option<TraceMap>, RunError> {
+    match config.changed_since.as_ref() {
+        Some(reference) => {
+            info!("Filtering coverage to lines changed since {}", reference);
+            let changes = changed_lines(&config.root(), reference)?;
+            Ok(Some(filter_changed(result, &changes)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::{CoverageStat, Trace};
+
+    #[test]
+    fn only_changed_lines_kept() {
+        let mut result = TraceMap::new();
+        let mut hit = Trace::new_stub(1);
+        hit.stats = CoverageStat::Line(1);
+        result.add_trace(Path::new("/a.rs"), hit);
+        result.add_trace(Path::new("/a.rs"), Trace::new_stub(2));
+        result.add_trace(Path::new("/a.rs"), Trace::new_stub(3));
+        result.add_trace(Path::new("/b.rs"), Trace::new_stub(1));
+
+        let mut changes = HashMap::new();
+        changes.insert(PathBuf::from("/a.rs"), [1, 3, 10].iter().copied().collect());
+        let patch = filter_changed(&result, &changes);
+        assert_eq!(patch.files(), vec![Path::new("/a.rs")]);
+        assert_eq!(patch.total_coverable(), 2);
+        assert_eq!(patch.total_covered(), 1);
+    }
+}
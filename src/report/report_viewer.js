@@ -35,6 +35,21 @@ function findCommonPath(files) {
   return commonPath;
 }
 
+function folderNode(path, parent, children) {
+  return {
+    is_folder: true,
+    path,
+    parent,
+    children,
+    covered: children.reduce((sum, file) => sum + file.covered, 0),
+    coverable: children.reduce((sum, file) => sum + file.coverable, 0),
+    prevRun: {
+      covered: children.reduce((sum, file) => sum + file.prevRun.covered, 0),
+      coverable: children.reduce((sum, file) => sum + file.prevRun.coverable, 0),
+    }
+  };
+}
+
 function findFolders(files) {
   if (!files || !files.length) {
     return [];
@@ -55,18 +70,7 @@ function findFolders(files) {
 
     const children = findFolders(filesInFolder); // recursion
 
-    return {
-      is_folder: true,
-      path: [folder],
-      parent: files[0].parent,
-      children,
-      covered: children.reduce((sum, file) => sum + file.covered, 0),
-      coverable: children.reduce((sum, file) => sum + file.coverable, 0),
-      prevRun: {
-        covered: children.reduce((sum, file) => sum + file.prevRun.covered, 0),
-        coverable: children.reduce((sum, file) => sum + file.prevRun.coverable, 0),
-      }
-    };
+    return folderNode([folder], files[0].parent, children);
   });
 
   return [
@@ -75,6 +79,29 @@ function findFolders(files) {
   ];
 }
 
+// Groups the files into a folder for each package with its files relative to the package
+// directory, sorted with the lowest coverage first. Files outside of the packages follow them
+function findPackages(files, packages) {
+  const packageFolders = packages.map(pkg => {
+    const packageFiles = files
+      .filter(file => file.package === pkg.name)
+      .map(file => ({
+        ...file,
+        path: [...file.parent, ...file.path].slice(pkg.path.length),
+        parent: pkg.path,
+      }));
+    return {
+      ...folderNode([pkg.name], [], findFolders(packageFiles)),
+      is_package: true,
+    };
+  });
+  const percentage = folder => folder.coverable ? folder.covered / folder.coverable : Infinity;
+  packageFolders.sort((a, b) => percentage(a) - percentage(b));
+
+  const others = files.filter(file => !packages.some(pkg => pkg.name === file.package));
+  return [...packageFolders, ...findFolders(others)];
+}
+
 class App extends React.Component {
   constructor(...args) {
     super(...args);
@@ -181,7 +208,8 @@ function File({file, onClick}) {
         + (coverage >= 0 && coverage < 50 ? ' files-list__file_low': '')
         + (coverage >= 50 && coverage < 80 ? ' files-list__file_medium': '')
         + (coverage >= 80 ? ' files-list__file_high': '')
-        + (file.is_folder ? ' files-list__file_folder': ''),
+        + (file.is_folder && !file.is_package ? ' files-list__file_folder': '')
+        + (file.is_package ? ' files-list__file_package': ''),
       onClick: () => onClick(file),
     },
     e('td', null, e('a', null, pathToString(file.path))),
@@ -217,18 +245,129 @@ function FileHeader({file, onBack}) {
   );
 }
 
+const RUST_KEYWORDS = new Set([
+  'as', 'async', 'await', 'break', 'const', 'continue', 'crate', 'dyn', 'else', 'enum',
+  'extern', 'false', 'fn', 'for', 'if', 'impl', 'in', 'let', 'loop', 'match', 'mod', 'move',
+  'mut', 'pub', 'ref', 'return', 'self', 'Self', 'static', 'struct', 'super', 'trait', 'true',
+  'type', 'union', 'unsafe', 'use', 'where', 'while',
+]);
+
+// Each rule is tried at the current position and the first match wins. Comments and strings can
+// span lines so the whole file is tokenised at once.
+const RUST_RULES = [
+  ['comment', /\/\/[^\n]*/y],
+  ['comment', /\/\*[\s\S]*?(\*\/|$)/y],
+  ['string', /b?r(#*)"[\s\S]*?("\1|$)/y],
+  ['string', /b?"(\\[\s\S]|[^"\\])*("|$)/y],
+  ['string', /b?'(\\.[^']*|[^'\\])'/y],
+  ['lifetime', /'[A-Za-z_]\w*/y],
+  ['attribute', /#!?\[[^\]\n]*\]?/y],
+  ['number', /\d\w*(\.\d\w*)?/y],
+  ['macro', /[A-Za-z_]\w*!/y],
+  ['ident', /[A-Za-z_]\w*/y],
+  ['text', /(\s+|[^\w\s'"#/]+|.)/y],
+];
+
+function tokenizeRust(content) {
+  const tokens = [];
+  let pos = 0;
+  while (pos < content.length) {
+    for (const [type, regex] of RUST_RULES) {
+      regex.lastIndex = pos;
+      const m = regex.exec(content);
+      if (m && m[0].length) {
+        let kind = type;
+        if (type === 'ident') {
+          if (RUST_KEYWORDS.has(m[0])) {
+            kind = 'keyword';
+          } else if (/^[A-Z]/.test(m[0])) {
+            kind = 'type';
+          } else {
+            kind = 'text';
+          }
+        }
+        tokens.push([kind, m[0]]);
+        pos += m[0].length;
+        break;
+      }
+    }
+  }
+  // Split the tokens into lines, breaking up any that cross a line boundary
+  const lines = [[]];
+  for (const [kind, text] of tokens) {
+    text.split(/\r?\n/).forEach((part, i) => {
+      if (i > 0) {
+        lines.push([]);
+      }
+      if (part.length) {
+        lines[lines.length - 1].push([kind, part]);
+      }
+    });
+  }
+  return lines;
+}
+
+// Renders the tokens of a line, the parts inside the column spans of counters which didn't run
+// are wrapped so they're highlighted. Columns are counted from 1 and a span without an end
+// carries on to the end of the line
+function renderTokens(tokens, spans) {
+  const missed = column => spans.some(([start, end]) =>
+    column >= start && (end === null || column < end));
+  const token = (kind, text, key) => kind === 'text'
+    ? text
+    : e('span', {key, className: 'token_' + kind}, text);
+  if (!spans.length) {
+    return tokens.map(([kind, text], i) => token(kind, text, i));
+  }
+  const parts = [];
+  let column = 1;
+  tokens.forEach(([kind, text], i) => {
+    let start = 0;
+    for (let end = 1; end <= text.length; end++) {
+      const inside = missed(column + start);
+      if (end === text.length || missed(column + end) !== inside) {
+        const part = token(kind, text.slice(start, end), i + '-' + start);
+        parts.push(inside
+          ? e('span', {key: 'missed-' + i + '-' + start, className: 'code-line__missed-region'}, part)
+          : part);
+        start = end;
+      }
+    }
+    column += text.length;
+  });
+  return parts;
+}
+
 function FileContent({file}) {
+  const lines = tokenizeRust(file.content);
   return e('div', {className: 'file-content'},
-    file.content.split(/\r?\n/).map((line, index) => {
+    lines.map((tokens, index) => {
       const trace = file.traces.find(trace => trace.line === index + 1);
-      const covered = trace && trace.stats.Line;
+      const partial = trace && (file.partial || []).includes(index + 1);
+      const covered = trace && trace.stats.Line && !partial;
       const uncovered = trace && !trace.stats.Line;
-      return e('pre', {
+      const lowHits = covered && hitsThreshold !== null && trace.stats.Line < hitsThreshold;
+      const notInstrumented = !trace && (file.not_instrumented || []).includes(index + 1);
+      const missedColumns = (file.missed_columns || [])
+        .filter(([line]) => line === index + 1)
+        .map(([, start, end]) => [start, end]);
+      return e('div', {
           className: 'code-line'
             + (covered ? ' code-line_covered' : '')
-            + (uncovered ? ' code-line_uncovered' : ''),
-          title: trace ? JSON.stringify(trace.stats, null, 2) : null,
-        }, line);
+            + (partial ? ' code-line_partial' : '')
+            + (uncovered ? ' code-line_uncovered' : '')
+            + (lowHits ? ' code-line_low-hits' : '')
+            + (notInstrumented ? ' code-line_not-instrumented' : ''),
+          title: trace
+            ? (partial ? 'Partially covered\n' : '') + JSON.stringify(trace.stats, null, 2)
+            : (notInstrumented ? 'Not instrumented' : null),
+        },
+        e('span', {className: 'code-line__gutter'},
+          e('span', {className: 'code-line__number'}, index + 1),
+          e('span', {className: 'code-line__hits'}, trace ? trace.stats.Line : '')
+        ),
+        e('pre', {className: 'code-line__source'}, renderTokens(tokens, missedColumns))
+      );
     })
   );
 }
@@ -253,20 +392,12 @@ function FileContent({file}) {
     };
   });
 
-  const children = findFolders(files);
+  // Workspaces with several packages start with a list of the packages
+  const children = data.packages && data.packages.length > 1
+    ? findPackages(files, data.packages)
+    : findFolders(files);
 
-  const root = {
-    is_folder: true,
-    children,
-    path: commonPath,
-    parent: [],
-    covered: children.reduce((sum, file) => sum + file.covered, 0),
-    coverable: children.reduce((sum, file) => sum + file.coverable, 0),
-    prevRun: {
-      covered: children.reduce((sum, file) => sum + file.prevRun.covered, 0),
-      coverable: children.reduce((sum, file) => sum + file.prevRun.coverable, 0),
-    }
-  };
+  const root = folderNode(commonPath, [], children);
 
   ReactDOM.render(e(App, {root, prevFilesMap}), document.getElementById('root'));
 }());
@@ -0,0 +1,114 @@
+use super::{branch_totals, function_totals};
+use crate::traces::TraceMap;
+
+/// TeamCity build statistics for the coverage as `##teamcity[buildStatisticValue ...]` service
+/// messages, one per line. Function and branch statistics are only included when the results
+/// have function spans or branch points
+pub fn service_messages(result: &TraceMap) -> Vec<String> {
+    let mut stats = vec![
+        ("CodeCoverageAbsLCovered", result.total_covered()),
+        ("CodeCoverageAbsLTotal", result.total_coverable()),
+    ];
+
+    let (functions_covered, functions_total) = function_totals(result);
+    if functions_total > 0 {
+        stats.push(("CodeCoverageAbsMCovered", functions_covered));
+        stats.push(("CodeCoverageAbsMTotal", functions_total));
+    }
+    let (branches_covered, branches_total) = branch_totals(result);
+    if branches_total > 0 {
+        stats.push(("CodeCoverageAbsBCovered", branches_covered));
+        stats.push(("CodeCoverageAbsBTotal", branches_total));
+    }
+
+    stats
+        .into_iter()
+        .map(|(key, value)| {
+            format!(
+                "##teamcity[buildStatisticValue key='{}' value='{}']",
+                escape(key),
+                escape(&value.to_string())
+            )
+        })
+        .collect()
+}
+
+/// Escapes a value for a service message attribute, `|` is the escape character
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '|' => escaped.push_str("||"),
+            '\'' => escaped.push_str("|'"),
+            '\n' => escaped.push_str("|n"),
+            '\r' => escaped.push_str("|r"),
+            '[' => escaped.push_str("|["),
+            ']' => escaped.push_str("|]"),
+            '\u{0085}' => escaped.push_str("|x"),
+            '\u{2028}' => escaped.push_str("|l"),
+            '\u{2029}' => escaped.push_str("|p"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::{BranchPoint, CoverageStat, FunctionSpan, Trace};
+    use std::path::Path;
+
+    #[test]
+    fn coverage_statistics() {
+        let mut result = TraceMap::new();
+        let file = Path::new("/lib.rs");
+        let mut hit = Trace::new_stub(2);
+        hit.stats = CoverageStat::Line(3);
+        result.add_trace(file, hit);
+        result.add_trace(file, Trace::new_stub(3));
+        result.add_trace(file, Trace::new_stub(6));
+        result.add_functions(
+            file,
+            vec![
+                FunctionSpan {
+                    name: "a".to_string(),
+                    start: 1,
+                    end: 4,
+                },
+                FunctionSpan {
+                    name: "b".to_string(),
+                    start: 5,
+                    end: 7,
+                },
+            ],
+        );
+        result.add_branches(
+            file,
+            vec![BranchPoint {
+                line: 2,
+                arms: vec![(3, 4)],
+                implicit_default: true,
+            }],
+        );
+
+        assert_eq!(
+            service_messages(&result),
+            vec![
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsLCovered' value='1']",
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsLTotal' value='3']",
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsMCovered' value='1']",
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsMTotal' value='2']",
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsBCovered' value='1']",
+                "##teamcity[buildStatisticValue key='CodeCoverageAbsBTotal' value='2']",
+            ]
+        );
+    }
+
+    #[test]
+    fn special_characters_escaped() {
+        assert_eq!(escape("a'b|c[d]\ne\r"), "a|'b||c|[d|]|ne|r");
+        assert_eq!(escape("\u{0085}\u{2028}\u{2029}"), "|x|l|p");
+        assert_eq!(escape("CodeCoverageAbsLTotal"), "CodeCoverageAbsLTotal");
+    }
+}
@@ -10,6 +10,17 @@ mod predicates {
             .to_string()
             .ends_with("test")
     }
+
+    /// Returns true for `no_coverage` or the `coverage(off)` attribute that replaced it
+    pub fn is_no_coverage_attribute(attr: &syn::Meta) -> bool {
+        match attr {
+            syn::Meta::Path(p) => p.is_ident("no_coverage"),
+            syn::Meta::List(ml) if ml.path.is_ident("coverage") => ml.nested.iter().any(
+                |x| matches!(x, syn::NestedMeta::Meta(syn::Meta::Path(p)) if p.is_ident("off")),
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl SourceAnalysis {
@@ -47,7 +58,7 @@ pub(crate) fn check_cfg_attr(attr: &Meta) -> bool {
     let mut ignore_span = false;
     let id = attr.path();
 
-    if id.is_ident("no_coverage") {
+    if predicates::is_no_coverage_attribute(attr) {
         ignore_span = true;
     } else if id.is_ident("cfg") {
         if let Meta::List(ml) = attr {
@@ -68,12 +79,11 @@ pub(crate) fn check_cfg_attr(attr: &Meta) -> bool {
         }
     } else if id.is_ident("cfg_attr") {
         if let Meta::List(ml) = attr {
-            let tarp_cfged_ignores = &["no_coverage"];
-            if let NestedMeta::Meta(Meta::Path(ref i)) = ml.nested[0] {
+            if let Some(NestedMeta::Meta(Meta::Path(ref i))) = ml.nested.first() {
                 if i.is_ident("tarpaulin") {
                     for p in ml.nested.iter().skip(1) {
-                        if let NestedMeta::Meta(Meta::Path(ref path)) = p {
-                            if tarp_cfged_ignores.iter().any(|x| path.is_ident(x)) {
+                        if let NestedMeta::Meta(ref m) = p {
+                            if predicates::is_no_coverage_attribute(m) {
                                 ignore_span = true;
                                 break;
                             }
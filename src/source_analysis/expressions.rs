@@ -275,6 +275,10 @@ impl SourceAnalysis {
         let u_line = unsafe_expr.unsafe_token.span().start().line;
         let mut res = SubResult::Ok;
         let blk = &unsafe_expr.block;
+        let analysis = self.get_line_analysis(ctx.file.to_path_buf());
+        analysis
+            .unsafe_lines
+            .extend(u_line..=blk.brace_token.span.end().line);
         if u_line != blk.brace_token.span.start().line || blk.stmts.is_empty() {
             let analysis = self.get_line_analysis(ctx.file.to_path_buf());
             analysis.ignore_tokens(unsafe_expr.unsafe_token);
@@ -1,6 +1,6 @@
 use crate::source_analysis::prelude::*;
 use std::path::PathBuf;
-use syn::{spanned::Spanned, *};
+use syn::{ext::IdentExt, spanned::Spanned, *};
 
 impl SourceAnalysis {
     pub(crate) fn process_items(&mut self, items: &[Item], ctx: &Context) -> SubResult {
@@ -16,7 +16,7 @@ impl SourceAnalysis {
                     analysis.ignore_tokens(i);
                 }
                 Item::Mod(i) => self.visit_mod(i, ctx),
-                Item::Fn(i) => self.visit_fn(i, ctx, false),
+                Item::Fn(i) => self.visit_fn(i, ctx, false, None),
                 Item::Struct(i) => {
                     let analysis = self.get_line_analysis(ctx.file.to_path_buf());
                     analysis.ignore_tokens(i);
@@ -47,7 +47,20 @@ impl SourceAnalysis {
     }
 
     fn visit_mod(&mut self, module: &ItemMod, ctx: &Context) {
+        let path = self.module.as_ref().map(|x| {
+            let mut path = x.clone();
+            path.push(module.ident.unraw().to_string());
+            path
+        });
         let analysis = self.get_line_analysis(ctx.file.to_path_buf());
+        if path.as_ref().is_some_and(|x| ctx.config.exclude_module(x)) {
+            // A module declared without a body is excluded when its own file is analysed
+            analysis.ignore_tokens(module);
+            if let Some((ref braces, _)) = module.content {
+                analysis.ignore_span(braces.span);
+            }
+            return;
+        }
         analysis.ignore_tokens(module.mod_token);
         let mut check_insides = true;
         for attr in &module.attrs {
@@ -78,7 +91,9 @@ impl SourceAnalysis {
         }
         if check_insides {
             if let Some((_, ref items)) = module.content {
+                let parent = std::mem::replace(&mut self.module, path);
                 self.process_items(items, ctx);
+                self.module = parent;
             }
         } else {
             // Get the file or directory name of the module
@@ -94,7 +109,7 @@ impl SourceAnalysis {
         }
     }
 
-    fn visit_fn(&mut self, func: &ItemFn, ctx: &Context, force_cover: bool) {
+    fn visit_fn(&mut self, func: &ItemFn, ctx: &Context, force_cover: bool, owner: Option<&str>) {
         let mut test_func = false;
         let mut ignored_attr = false;
         let mut is_inline = false;
@@ -149,6 +164,15 @@ impl SourceAnalysis {
             let stmts_start = func.block.span().start().line;
             let lines = decl_start..=stmts_start;
             analysis.add_to_ignore(lines);
+            let name = match owner {
+                Some(owner) => format!("{}::{}", owner, func.sig.ident),
+                None => func.sig.ident.to_string(),
+            };
+            analysis.functions.push(FunctionSpan {
+                name,
+                start: line_number as u64,
+                end: func.block.span().end().line as u64,
+            });
         }
     }
 
@@ -168,7 +192,8 @@ impl SourceAnalysis {
                                 block: Box::new(block),
                             };
                             // We visit the function and force cover it
-                            self.visit_fn(&item_fn, ctx, true);
+                            let owner = trait_item.ident.to_string();
+                            self.visit_fn(&item_fn, ctx, true, Some(&owner));
                         } else {
                             let analysis = self.get_line_analysis(ctx.file.to_path_buf());
                             analysis.ignore_tokens(i);
@@ -193,6 +218,10 @@ impl SourceAnalysis {
     fn visit_impl(&mut self, impl_blk: &ItemImpl, ctx: &Context) {
         let check_cover = self.check_attr_list(&impl_blk.attrs, ctx);
         if check_cover {
+            let owner = match *impl_blk.self_ty {
+                Type::Path(ref p) => p.path.segments.last().map(|x| x.ident.to_string()),
+                _ => None,
+            };
             for item in &impl_blk.items {
                 if let ImplItem::Method(ref i) = *item {
                     let item = i.clone();
@@ -206,7 +235,7 @@ impl SourceAnalysis {
                     // If the impl is on a generic, we need to force cover
                     let force_cover = !impl_blk.generics.params.is_empty();
 
-                    self.visit_fn(&item_fn, ctx, force_cover);
+                    self.visit_fn(&item_fn, ctx, force_cover, owner.as_deref());
                 }
             }
             self.visit_generics(&impl_blk.generics, ctx);
@@ -16,9 +16,18 @@ impl SourceAnalysis {
         }) = mac.path.segments.last()
         {
             let ident_s = ident.to_string();
-            let unreachable = ident == "unreachable";
-            let standard_ignores =
-                ident == "unimplemented" || ident == "include" || ident == "cfg" || ident == "todo";
+            let exclude_macros = ctx.config.exclude_macro_lines;
+            let unreachable = exclude_macros && ident == "unreachable";
+            let standard_ignores = ident == "include"
+                || ident == "cfg"
+                || (exclude_macros
+                    && (ident == "unimplemented"
+                        || ident == "todo"
+                        || ctx
+                            .config
+                            .exclude_macros
+                            .iter()
+                            .any(|x| x.trim_end_matches('!') == ident_s)));
             let ignore_panic = ctx.config.ignore_panics
                 && (ident == "panic"
                     || ident_s.starts_with("assert")
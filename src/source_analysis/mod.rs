@@ -1,8 +1,9 @@
 use crate::branching::BranchAnalysis;
 use crate::config::{Config, RunType};
-use crate::path_utils::{get_source_walker, is_source_file};
+use crate::path_utils::{fix_unc_path, get_source_walker, is_source_file};
+use crate::traces::FunctionSpan;
 use lazy_static::lazy_static;
-use proc_macro2::{Span, TokenStream};
+use proc_macro2::{Span, TokenStream, TokenTree};
 use quote::ToTokens;
 use regex::Regex;
 use std::cell::RefCell;
@@ -13,7 +14,7 @@ use std::io::{self, BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
 use syn::spanned::Spanned;
 use syn::*;
-use tracing::{trace, warn};
+use tracing::{debug, trace, warn};
 use walkdir::WalkDir;
 
 mod attributes;
@@ -30,6 +31,9 @@ pub(crate) mod prelude {
     pub(crate) use macros::*;
 }
 
+/// How many lines from the top of a file are checked for an `@generated` marker
+const GENERATED_MARKER_LINES: usize = 5;
+
 /// Enumeration representing which lines to ignore
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Lines {
@@ -54,6 +58,10 @@ pub struct LineAnalysis {
     /// mapping from physical line to logical line to prevent false positives
     /// from expressions split across physical lines
     pub logical_lines: HashMap<usize, usize>,
+    /// Functions found in the file that haven't been ignored
+    pub functions: Vec<FunctionSpan>,
+    /// Lines inside `unsafe` blocks
+    pub unsafe_lines: HashSet<usize>,
     /// Shows the line length of the provided file
     max_line: usize,
 }
@@ -263,6 +271,8 @@ pub struct SourceAnalysis {
     pub lines: HashMap<PathBuf, LineAnalysis>,
     pub branches: HashMap<PathBuf, BranchAnalysis>,
     ignored_modules: Vec<PathBuf>,
+    /// Path from the crate root of the module being analysed, `None` if it isn't known
+    module: Option<Vec<String>>,
 }
 
 impl SourceAnalysis {
@@ -290,10 +300,21 @@ impl SourceAnalysis {
         let mut result = Self::new();
         let mut ignored_files: HashSet<PathBuf> = HashSet::new();
         let root = config.root();
+        let test_paths = if config.include_tests() {
+            vec![]
+        } else {
+            config.integration_test_paths()
+        };
+        let roots = if config.exclude_modules.is_empty() {
+            vec![]
+        } else {
+            crate_roots(config)
+        };
 
         for e in get_source_walker(config) {
+            result.module = file_module_path(e.path(), &roots);
             if !ignored_files.contains(e.path()) {
-                result.analyse_package(e.path(), &root, config, &mut ignored_files);
+                result.analyse_package(e.path(), &root, &test_paths, config, &mut ignored_files);
             } else {
                 let mut analysis = LineAnalysis::new();
                 analysis.ignore_all();
@@ -317,13 +338,17 @@ impl SourceAnalysis {
         &mut self,
         path: &Path,
         root: &Path,
+        test_paths: &[PathBuf],
         config: &Config,
         filtered_files: &mut HashSet<PathBuf>,
     ) {
         if let Some(file) = path.to_str() {
-            let skip_cause_test = !config.include_tests() && path.starts_with(root.join("tests"));
+            let skip_cause_test = test_paths.iter().any(|x| path.starts_with(x));
+            // Examples run with `run-examples` are like tests of the library so are only
+            // included with the tests
             let skip_cause_example = path.starts_with(root.join("examples"))
-                && !config.run_types.contains(&RunType::Examples);
+                && !config.run_types.contains(&RunType::Examples)
+                && (config.run_examples.is_empty() || !config.include_tests());
             if (skip_cause_test || skip_cause_example) || self.is_ignored_module(path) {
                 let mut analysis = LineAnalysis::new();
                 analysis.ignore_all();
@@ -340,6 +365,25 @@ impl SourceAnalysis {
                         );
                         return;
                     }
+                    if config.exclude_generated && is_generated(&content) {
+                        debug!("Excluding generated file {}", path.display());
+                        let mut analysis = LineAnalysis::new();
+                        analysis.ignore_all();
+                        self.lines.insert(path.to_path_buf(), analysis);
+                        return;
+                    }
+                    if let Some(module) = self.module.as_ref().filter(|x| config.exclude_module(x))
+                    {
+                        debug!(
+                            "Excluding module {} in {}",
+                            module.join("::"),
+                            path.display()
+                        );
+                        let mut analysis = LineAnalysis::new();
+                        analysis.ignore_all();
+                        self.lines.insert(path.to_path_buf(), analysis);
+                        return;
+                    }
                     let file = parse_file(&content);
                     if let Ok(file) = file {
                         let ctx = Context {
@@ -351,6 +395,8 @@ impl SourceAnalysis {
                         if self.check_attr_list(&file.attrs, &ctx) {
                             self.find_ignorable_lines(&ctx);
                             self.process_items(&file.items, &ctx);
+                            self.find_ignore_comments(&ctx);
+                            self.find_excluded_lines(&ctx);
 
                             let mut ignored_files = ctx.ignore_mods.into_inner();
                             for f in ignored_files.drain() {
@@ -434,6 +480,78 @@ impl SourceAnalysis {
         analysis.add_to_ignore(lines);
     }
 
+    /// Finds lines excluded with a `// cov:ignore` comment or between `// cov:ignore-start` and
+    /// `// cov:ignore-end` comments. This is done on the tokenised source so markers that appear
+    /// within string literals aren't mistaken for comments.
+    pub(crate) fn find_ignore_comments(&mut self, ctx: &Context) {
+        lazy_static! {
+            static ref IGNORE_COMMENT: Regex =
+                Regex::new(r"//\s*cov:ignore(-start|-end)?(\s|$)").unwrap();
+        }
+        let mut literals = vec![];
+        if let Ok(tokens) = ctx.file_contents.parse::<TokenStream>() {
+            get_literal_spans(tokens, &mut literals);
+        }
+        let in_literal = |line: usize, column: usize| {
+            literals.iter().any(|span: &Span| {
+                let (start, end) = (span.start(), span.end());
+                (start.line, start.column) <= (line, column)
+                    && (line, column) < (end.line, end.column)
+            })
+        };
+        let mut lines = vec![];
+        let mut block_start = None;
+        for (i, line) in ctx.file_contents.lines().enumerate() {
+            let line_number = i + 1;
+            let marker = IGNORE_COMMENT.captures_iter(line).find(|c| {
+                let column = line[..c.get(0).unwrap().start()].chars().count();
+                !in_literal(line_number, column)
+            });
+            if let Some(marker) = marker {
+                match marker.get(1).map(|x| x.as_str()) {
+                    Some("-start") => {
+                        if block_start.is_none() {
+                            block_start = Some(line_number);
+                        }
+                    }
+                    Some(_) => {
+                        if let Some(start) = block_start.take() {
+                            lines.extend(start..=line_number);
+                        }
+                    }
+                    None => lines.push(line_number),
+                }
+            }
+        }
+        if let Some(start) = block_start {
+            warn!(
+                "cov:ignore-start without a matching cov:ignore-end in {}",
+                ctx.file.display()
+            );
+            lines.extend(start..=ctx.file_contents.lines().count());
+        }
+        let analysis = self.get_line_analysis(ctx.file.to_path_buf());
+        analysis.add_to_ignore(lines);
+    }
+
+    /// Finds lines matching one of the `exclude-line-patterns` regexes. Unlike the ignore
+    /// comments this is purely textual, so a pattern can match within a string literal or comment
+    pub(crate) fn find_excluded_lines(&mut self, ctx: &Context) {
+        let patterns = ctx.config.excluded_lines();
+        if patterns.is_empty() {
+            return;
+        }
+        let lines = ctx
+            .file_contents
+            .lines()
+            .enumerate()
+            .filter(|&(_, x)| patterns.iter().any(|p| p.is_match(x)))
+            .map(|(i, _)| i + 1)
+            .collect::<Vec<_>>();
+        let analysis = self.get_line_analysis(ctx.file.to_path_buf());
+        analysis.add_to_ignore(lines);
+    }
+
     pub(crate) fn visit_generics(&mut self, generics: &Generics, ctx: &Context) {
         if let Some(ref wh) = generics.where_clause {
             let analysis = self.get_line_analysis(ctx.file.to_path_buf());
@@ -486,6 +604,57 @@ impl SourceAnalysis {
     }
 }
 
+/// The root source files of the targets of the workspace members. Build scripts are left out as
+/// their directory is the package's so every file in the package would be under them
+fn crate_roots(config: &Config) -> Vec<PathBuf> {
+    match config.get_metadata().as_ref() {
+        Some(meta) => meta
+            .packages
+            .iter()
+            .filter(|x| meta.workspace_members.contains(&x.id))
+            .flat_map(|x| &x.targets)
+            .filter(|x| !x.kind.iter().any(|kind| kind == "custom-build"))
+            .map(|x| fix_unc_path(x.src_path.as_std_path()))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// The path of the module in a source file from the closest crate root, for a crate with the
+/// root `src/lib.rs` both `src/a/b.rs` and `src/a/b/mod.rs` are the module `a::b`. Modules with
+/// a `#[path]` attribute aren't followed so they get the path of where their file is. `None` if
+/// the file isn't under a crate root
+fn file_module_path(file: &Path, roots: &[PathBuf]) -> Option<Vec<String>> {
+    if roots.iter().any(|x| x == file) {
+        return Some(vec![]);
+    }
+    let dir = roots
+        .iter()
+        .filter_map(|x| x.parent())
+        .filter(|x| file.starts_with(x))
+        .max_by_key(|x| x.components().count())?;
+    let relative = file.strip_prefix(dir).ok()?.with_extension("");
+    let mut module = relative
+        .components()
+        .map(|x| x.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    if module.last().is_some_and(|x| x == "mod") {
+        module.pop();
+    }
+    Some(module)
+}
+
+/// Collects the spans of all literals in the token stream
+fn get_literal_spans(tokens: TokenStream, spans: &mut Vec<Span>) {
+    for token in tokens {
+        match token {
+            TokenTree::Literal(l) => spans.push(l.span()),
+            TokenTree::Group(g) => get_literal_spans(g.stream(), spans),
+            _ => {}
+        }
+    }
+}
+
 /// lib.rs:1 can often show up as a coverable line when it's not. This ignores
 /// that line as long as it's not a real source line. This can also affect
 /// the main files for binaries in a project as well.
@@ -501,3 +670,15 @@ fn maybe_ignore_first_line(file: &Path, result: &mut HashMap<PathBuf, LineAnalys
         }
     }
 }
+
+/// Code generators mark the files they write with `@generated` in a comment in the first few
+/// lines, either a line comment or a block comment which may span several lines
+pub(crate) fn is_generated(contents: &str) -> bool {
+    lazy_static! {
+        static ref GENERATED: Regex = Regex::new(r"^\s*(//|/\*|\*).*@generated\b").unwrap();
+    }
+    contents
+        .lines()
+        .take(GENERATED_MARKER_LINES)
+        .any(|x| GENERATED.is_match(x))
+}
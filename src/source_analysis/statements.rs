@@ -57,6 +57,9 @@ impl SourceAnalysis {
                             .insert(expr.span().start().line, base_line);
                     }
                     result += self.process_expr(expr, ctx);
+                } else if let Expr::Unsafe(_) = &**expr {
+                    // Single line lets aren't visited further
+                    analysis.unsafe_lines.insert(base_line);
                 }
             } else {
                 analysis.ignore_tokens(local);
@@ -1049,6 +1049,111 @@ fn tarpaulin_skip_impl_attrs() {
     assert!(lines.ignore.contains(&Lines::Line(10)));
 }
 
+#[test]
+fn tarpaulin_skip_async_fn() {
+    let config = Config::default();
+    let ctx = Context {
+        config: &config,
+        file_contents: "#[tarpaulin::skip]
+        async fn skipped(x: u32) -> u32 {
+            fn nested(y: u32) -> u32 {
+                y * 2
+            }
+            let add = |z| z + 1;
+            add(nested(x))
+        }
+
+        async fn covered() -> u32 {
+            5
+        }
+        ",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    for line in 1..=8 {
+        assert!(lines.ignore.contains(&Lines::Line(line)));
+    }
+    assert!(!lines.ignore.contains(&Lines::Line(11)));
+    assert_eq!(lines.functions.len(), 1);
+    assert_eq!(lines.functions[0].name, "covered");
+}
+
+#[test]
+fn tarpaulin_skip_methods() {
+    let config = Config::default();
+    let ctx = Context {
+        config: &config,
+        file_contents: "struct Foo;
+            impl Foo {
+                fn bar() {
+                    println!(\"Hello world\");
+                }
+
+                #[tarpaulin::skip]
+                fn not_covered(&self) {
+                    let inner = || {
+                        println!(\"hell world\");
+                    };
+                    inner();
+                }
+
+                #[cfg_attr(tarpaulin, coverage(off))]
+                fn also_not_covered() {
+                    println!(\"goodbye world\");
+                }
+
+                #[coverage(off)]
+                fn not_covered_either() {
+                    println!(\"oof\");
+                }
+            }
+        ",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    assert!(!lines.ignore.contains(&Lines::Line(3)));
+    assert!(!lines.ignore.contains(&Lines::Line(4)));
+    for line in (7..=13).chain(15..=18).chain(20..=23) {
+        assert!(lines.ignore.contains(&Lines::Line(line)));
+    }
+    assert_eq!(lines.functions.len(), 1);
+    assert_eq!(lines.functions[0].name, "Foo::bar");
+}
+
+#[test]
+fn unsafe_block_lines() {
+    let config = Config::default();
+    let ctx = Context {
+        config: &config,
+        file_contents: "fn foo(x: *const u32) -> u32 {
+            let y = unsafe { *x };
+            let z = unsafe {
+                let a = *x;
+                a + 1
+            };
+            y + z
+        }
+        ",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    let mut unsafe_lines = lines.unsafe_lines.iter().copied().collect::<Vec<_>>();
+    unsafe_lines.sort_unstable();
+    assert_eq!(unsafe_lines, vec![2, 3, 4, 5, 6]);
+}
+
 #[test]
 fn filter_block_contents() {
     let config = Config::default();
@@ -1550,3 +1655,235 @@ fn ignore_comment() {
     assert!(lines.ignore.contains(&Lines::Line(11)));
     assert!(lines.ignore.contains(&Lines::Line(12)));
 }
+
+#[test]
+fn function_spans() {
+    let config = Config::default();
+    let ctx = Context {
+        config: &config,
+        file_contents: "fn foo() {
+            println!(\"hello\");
+        }
+
+        struct Bar;
+
+        impl<T> Bar {
+            fn bar(&self, t: T)
+                -> usize {
+                0
+            }
+        }
+
+        #[cfg(not(tarpaulin_include))]
+        fn ignored() {
+        }",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    assert_eq!(
+        lines.functions,
+        vec![
+            FunctionSpan {
+                name: "foo".to_string(),
+                start: 1,
+                end: 3,
+            },
+            FunctionSpan {
+                name: "Bar::bar".to_string(),
+                start: 8,
+                end: 11,
+            },
+        ]
+    );
+}
+
+#[test]
+fn ignore_comments() {
+    let config = Config::default();
+    let ctx = Context {
+        config: &config,
+        file_contents: "fn foo(x: bool) {
+            if x { // cov:ignore
+                println!(\"hello\");
+            }
+            let s = \"// cov:ignore\";
+            // cov:ignore-start
+            match x {
+                true => println!(\"a\"),
+                false => println!(\"b\"),
+            }
+            // cov:ignore-end
+            println!(\"{}\", s);
+        }",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    analysis.find_ignore_comments(&ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    assert!(lines.ignore.contains(&Lines::Line(2)));
+    assert!(!lines.ignore.contains(&Lines::Line(3)));
+    assert!(!lines.ignore.contains(&Lines::Line(5)));
+    for i in 6..=11 {
+        assert!(lines.ignore.contains(&Lines::Line(i)));
+    }
+    assert!(!lines.ignore.contains(&Lines::Line(12)));
+}
+
+#[test]
+fn exclude_line_patterns() {
+    let mut config = Config::default();
+    config.exclude_line_patterns = vec![
+        r"unreachable!\(".to_string(),
+        r"^\s*log_debug\(".to_string(),
+    ];
+    let ctx = Context {
+        config: &config,
+        file_contents: "fn foo(x: u32) -> u32 {
+            log_debug(x);
+            match x {
+                0 => 1,
+                _ => unreachable!(\"only zero\"),
+            }
+        }
+        fn bar() {
+            let s = \"unreachable!()\";
+            println!(\"{}\", s); log_debug(s);
+        }",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    analysis.find_excluded_lines(&ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    assert!(lines.ignore.contains(&Lines::Line(2)));
+    assert!(!lines.ignore.contains(&Lines::Line(4)));
+    assert!(lines.ignore.contains(&Lines::Line(5)));
+    // Matching is textual so string literals match as well
+    assert!(lines.ignore.contains(&Lines::Line(9)));
+    assert!(!lines.ignore.contains(&Lines::Line(10)));
+}
+
+#[test]
+fn generated_markers() {
+    assert!(is_generated("// @generated by prost-build\npub struct A;"));
+    assert!(is_generated("//! This file is @generated\n"));
+    assert!(is_generated("/* @generated */\nfn a() {}"));
+    assert!(is_generated(
+        "/*\n * Copyright\n * @generated by a tool\n */\n"
+    ));
+    assert!(is_generated("// Licence header\n\n\n\n// @generated\n"));
+    // Only the first few lines are checked
+    assert!(!is_generated("\n\n\n\n\n// @generated\n"));
+    assert!(!is_generated("fn a() {}\n"));
+    assert!(!is_generated("const S: &str = \"@generated\";\n"));
+    assert!(!is_generated("// @generated_by_hand\n"));
+}
+
+#[test]
+fn macro_lines_excluded() {
+    let source = "fn stubs(x: u32) -> u32 {
+            let msg = \"todo!()\";
+            match x {
+                0 => todo!(),
+                1 => unimplemented!(),
+                2 => unreachable!(),
+                3 => bail!(),
+                _ => msg.len() as u32,
+            }
+        }";
+    let ignored = |config: &Config| {
+        let ctx = Context {
+            config,
+            file_contents: source,
+            file: Path::new(""),
+            ignore_mods: RefCell::new(HashSet::new()),
+        };
+        let parser = parse_file(ctx.file_contents).unwrap();
+        let mut analysis = SourceAnalysis::new();
+        analysis.process_items(&parser.items, &ctx);
+        let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+        (2..=7)
+            .filter(|x| lines.ignore.contains(&Lines::Line(*x)))
+            .collect::<Vec<_>>()
+    };
+
+    let mut config = Config::default();
+    // The string containing `todo!()` is kept
+    assert_eq!(ignored(&config), vec![4, 5, 6]);
+
+    config.exclude_macros = vec!["bail!".to_string()];
+    assert_eq!(ignored(&config), vec![4, 5, 6, 7]);
+
+    config.exclude_macro_lines = false;
+    assert!(ignored(&config).is_empty());
+}
+
+#[test]
+fn excluded_modules_ignored() {
+    let mut config = Config::default();
+    config.exclude_modules = vec!["internal::generated".to_string()];
+    let ctx = Context {
+        config: &config,
+        file_contents: "pub mod internal {
+            pub mod generated {
+                pub fn inline_gen() -> u32 {
+                    1
+                }
+            }
+            pub fn kept() -> u32 {
+                2
+            }
+        }",
+        file: Path::new(""),
+        ignore_mods: RefCell::new(HashSet::new()),
+    };
+    let parser = parse_file(ctx.file_contents).unwrap();
+    let mut analysis = SourceAnalysis::new();
+    analysis.module = Some(vec![]);
+    analysis.process_items(&parser.items, &ctx);
+    assert_eq!(analysis.module, Some(vec![]));
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    for line in 2..=6 {
+        assert!(lines.ignore.contains(&Lines::Line(line)), "{} kept", line);
+    }
+    assert!(!lines.ignore.contains(&Lines::Line(8)));
+
+    // Without knowing which module the file is the inline modules can't be matched
+    let mut analysis = SourceAnalysis::new();
+    analysis.process_items(&parser.items, &ctx);
+    let lines = analysis.get_line_analysis(ctx.file.to_path_buf());
+    assert!(!lines.ignore.contains(&Lines::Line(4)));
+}
+
+#[test]
+fn module_paths_of_files() {
+    let roots = vec![
+        PathBuf::from("/ws/src/lib.rs"),
+        PathBuf::from("/ws/src/bin/tool.rs"),
+    ];
+    let module = |x: &str| file_module_path(Path::new(x), &roots);
+    assert_eq!(module("/ws/src/lib.rs"), Some(vec![]));
+    assert_eq!(module("/ws/src/bin/tool.rs"), Some(vec![]));
+    assert_eq!(
+        module("/ws/src/internal/generated.rs"),
+        Some(vec!["internal".to_string(), "generated".to_string()])
+    );
+    assert_eq!(
+        module("/ws/src/internal/mod.rs"),
+        Some(vec!["internal".to_string()])
+    );
+    assert_eq!(
+        module("/ws/src/bin/helper.rs"),
+        Some(vec!["helper".to_string()])
+    );
+    assert_eq!(module("/ws/build.rs"), None);
+}
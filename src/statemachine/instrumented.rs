@@ -1,17 +1,24 @@
 #![allow(dead_code)]
 use crate::config::Config;
 use crate::errors::RunError;
-use crate::path_utils::{get_profile_walker, get_source_walker};
+use crate::llvm_tools;
+use crate::path_utils::{get_build_script_profile_walker, get_profile_walker, get_source_walker};
 use crate::process_handling::RunningProcessHandle;
 use crate::source_analysis::LineAnalysis;
 use crate::statemachine::*;
 use crate::TestHandle;
+use llvm_profparser::instrumentation_profile::types::InstrumentationProfile;
 use llvm_profparser::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
+use std::process::Child;
 use std::thread::sleep;
+use std::time::{Duration, Instant};
 use tracing::{info, warn};
 
+/// How often to check if the test has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub fn create_state_machine<'a>(
     test: impl Into<TestHandle>,
     traces: &'a mut TraceMap,
@@ -63,6 +70,373 @@ impl<'a> LlvmInstrumentedData<'a> {
             None => false,
         }
     }
+
+    /// Merges the profiles written by the test and adds their coverage to the traces
+    fn collect_profiles(&mut self) -> Result<(), RunError> {
+        let parent = match self.process.as_ref() {
+            Some(parent) => parent,
+            None => return Err(RunError::TestCoverage("Test was not launched".to_string())),
+        };
+        // Tests running in parallel write to the same directory so only the profiles from this
+        // launch of the test are read
+        let profraws = get_profile_walker(self.config)
+            .map(|x| x.path().to_path_buf())
+            .filter(|x| !parent.existing_profraws.contains(x))
+            .filter(|x| {
+                x.file_name()
+                    .map(|x| x.to_string_lossy().starts_with(&parent.profile_prefix))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+
+        info!(
+            "For binary: {}",
+            self.config.strip_base_dir(&parent.path).display()
+        );
+        for prof in &profraws {
+            let profraw_name = self.config.strip_base_dir(prof);
+            info!("Generated: {}", profraw_name.display());
+        }
+
+        let binary_path = parent.path.clone();
+        let mut binaries = parent
+            .extra_binaries
+            .iter()
+            .filter(|path| {
+                // extra binaries might not exist yet and be created
+                // later by the test suite
+                if path.exists() {
+                    true
+                } else {
+                    info!(
+                        "Skipping additional object '{}' since the file does not exist",
+                        path.display()
+                    );
+                    false
+                }
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        binaries.push(binary_path);
+        let report = match read_report(&profraws, &binaries) {
+            Ok(Some(report)) => report,
+            Ok(None) => {
+                warn!("profraw file has no records after merging. If this is unexpected it may be caused by a panic or signal used in a test that prevented the LLVM instrumentation runtime from serialising results");
+                return Ok(());
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to read the coverage: {}. Using llvm-profdata and llvm-cov",
+                    e
+                );
+                let name = format!("{}merged", parent.profile_prefix);
+                let lcov = llvm_tools::export_lcov(self.config, &profraws, &binaries, &name)
+                    .map_err(|tools| {
+                        let tools = match tools {
+                            RunError::TestCoverage(x) | RunError::Engine(x) => x,
+                            x => x.to_string(),
+                        };
+                        RunError::TestCoverage(format!(
+                            "{e}, and the LLVM tools couldn't read it either: {tools}"
+                        ))
+                    })?;
+                report_from_lcov(&lcov)
+            }
+        };
+        add_report(self.traces, &report, self.analysis, self.config);
+        Ok(())
+    }
+}
+
+/// The coverage of the binaries from the profiles, `None` if the profiles have no records
+fn read_report(
+    profraws: &[PathBuf],
+    binaries: &[PathBuf],
+) -> Result<Option<CoverageReport>, RunError> {
+    info!("Merging coverage reports");
+    let instrumentation = merge_profiles(profraws)?;
+    if instrumentation.is_empty() {
+        return Ok(None);
+    }
+    info!("Mapping coverage data to source");
+    let mapping = coverage_mapping(binaries, &instrumentation)?;
+    Ok(Some(mapping.generate_report()))
+}
+
+/// The line hits in an lcov report exported by `llvm-cov`, as a report with a region for each
+/// line. Lcov doesn't have the regions so lines can't be partially covered
+fn report_from_lcov(lcov: &str) -> CoverageReport {
+    let mut report = CoverageReport::default();
+    let mut file = None;
+    for line in lcov.lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            file = Some(PathBuf::from(path));
+        } else if line == "end_of_record" {
+            file = None;
+        } else if let (Some(file), Some(hits)) = (file.as_ref(), line.strip_prefix("DA:")) {
+            let mut hits = hits.split(',').map(|x| x.trim().parse::<usize>());
+            if let (Some(Ok(line)), Some(Ok(hits))) = (hits.next(), hits.next()) {
+                let loc = SourceLocation {
+                    line_start: line,
+                    column_start: 1,
+                    line_end: line,
+                    column_end: 1,
+                };
+                report
+                    .files
+                    .entry(file.clone())
+                    .or_default()
+                    .hits
+                    .insert(loc, hits);
+            }
+        }
+    }
+    report
+}
+
+fn coverage_mapping<'a>(
+    binaries: &[PathBuf],
+    instrumentation: &'a InstrumentationProfile,
+) -> Result<CoverageMapping<'a>, RunError> {
+    CoverageMapping::new(binaries, instrumentation).map_err(|e| {
+        error!("Failed to get coverage: {}", e);
+        RunError::TestCoverage(e.to_string())
+    })
+}
+
+/// Adds the hits in the coverage report to the traces, if there are no traces yet they're
+/// created from the report for the lines source analysis doesn't ignore
+fn add_report(
+    traces: &mut TraceMap,
+    report: &CoverageReport,
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+) {
+    if traces.is_empty() {
+        for source_file in get_source_walker(config) {
+            let file = source_file.path();
+            let analysis = analysis.get(file);
+            if let Some(result) = report.files.get(file) {
+                for (loc, hits) in result.hits.iter() {
+                    for line in loc.line_start..(loc.line_end + 1) {
+                        let include = match analysis.as_ref() {
+                            Some(analysis) => !analysis.should_ignore(line),
+                            None => true,
+                        };
+                        if include {
+                            let mut trace = Trace::new_stub(line as u64);
+                            trace.stats = CoverageStat::Line(*hits as u64);
+                            traces.add_trace(file, trace);
+                        }
+                    }
+                }
+            }
+            // Lines source analysis expects to be coverable without a counter have been
+            // optimised out or inlined away so they aren't uncovered
+            if let Some(analysis) = analysis {
+                let lines = analysis.cover.iter().map(|x| *x as u64);
+                traces.add_not_instrumented(file, lines);
+            }
+        }
+    } else {
+        traces.dedup();
+
+        for (file, result) in report.files.iter() {
+            if let Some(file_traces) = traces.file_traces_mut(file) {
+                let mut not_instrumented = vec![];
+                file_traces.retain_mut(|trace| match result.hits_for_line(trace.line as usize) {
+                    Some(hits) => {
+                        if let CoverageStat::Line(ref mut x) = trace.stats {
+                            *x = hits as _;
+                        }
+                        true
+                    }
+                    None => {
+                        not_instrumented.push(trace.line);
+                        false
+                    }
+                });
+                traces.add_not_instrumented(file, not_instrumented);
+            } else {
+                warn!("Couldn't find {} in {:?}", file.display(), traces.files());
+            }
+        }
+    }
+    for (file, result) in report.files.iter() {
+        traces.add_line_counters(file, line_counters(&result.hits));
+    }
+}
+
+/// The counters of each line with more than one. A line's counters are the regions starting on
+/// it and the innermost region it's inside of that started on an earlier line, this is how llvm
+/// finds the counts of a line so a closure or macro call which didn't run makes it partial
+fn line_counters(hits: &BTreeMap<SourceLocation, usize>) -> Vec<(u64, LineCounters)> {
+    let mut lines: BTreeMap<u64, Vec<(&SourceLocation, usize)>> = BTreeMap::new();
+    let mut enclosing: BTreeMap<u64, (&SourceLocation, usize)> = BTreeMap::new();
+    for (loc, hits) in hits {
+        lines
+            .entry(loc.line_start as u64)
+            .or_default()
+            .push((loc, *hits));
+        for line in (loc.line_start + 1)..=loc.line_end {
+            let innermost = enclosing.entry(line as u64).or_insert((loc, *hits));
+            if (loc.line_start, loc.column_start)
+                > (innermost.0.line_start, innermost.0.column_start)
+            {
+                *innermost = (loc, *hits);
+            }
+        }
+    }
+    for (line, region) in enclosing {
+        lines.entry(line).or_default().push(region);
+    }
+    lines
+        .into_iter()
+        .filter(|(_, regions)| regions.len() > 1)
+        .map(|(line, regions)| {
+            let mut counters = LineCounters::default();
+            for (loc, hits) in regions {
+                let id = (
+                    loc.line_start as u64,
+                    loc.column_start as u64,
+                    loc.line_end as u64,
+                    loc.column_end as u64,
+                );
+                if hits > 0 {
+                    counters.hit.insert(id);
+                } else {
+                    counters.missed.insert(id);
+                }
+            }
+            (line, counters)
+        })
+        .collect()
+}
+
+/// Collects the coverage from the `profraws` written by running the `input_binaries` outside of
+/// tarpaulin, for example in a sandbox tarpaulin can't launch tests in. Errors if the profiles
+/// weren't written by these binaries
+pub fn coverage_from_profraws(
+    binaries: &[PathBuf],
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+) -> Result<TraceMap, RunError> {
+    for profraw in &config.profraws {
+        if !profraw.is_file() {
+            return Err(RunError::TestCoverage(format!(
+                "profile {} doesn't exist",
+                profraw.display()
+            )));
+        }
+        info!(
+            "Using profile: {}",
+            config.strip_base_dir(profraw).display()
+        );
+    }
+    info!("Merging coverage reports");
+    let instrumentation = merge_profiles(&config.profraws)?;
+    let mut traces = TraceMap::new();
+    if instrumentation.records.is_empty() {
+        warn!("The profiles have no records after merging");
+        return Ok(traces);
+    }
+    info!("Mapping coverage data to source");
+    let mapping = coverage_mapping(binaries, &instrumentation)?;
+    check_profile_hashes(&mapping, &instrumentation)?;
+    add_report(&mut traces, &mapping.generate_report(), analysis, config);
+    Ok(traces)
+}
+
+/// Collects the coverage of the build scripts from the profiles they wrote while the tests were
+/// built. Build scripts cargo didn't rerun won't have written a profile
+pub fn build_script_coverage(
+    build_scripts: &[PathBuf],
+    analysis: &HashMap<PathBuf, LineAnalysis>,
+    config: &Config,
+) -> Result<TraceMap, RunError> {
+    let mut traces = TraceMap::new();
+    let profraws = get_build_script_profile_walker(config)
+        .map(|x| x.path().to_path_buf())
+        .collect::<Vec<_>>();
+    if profraws.is_empty() {
+        warn!("No build scripts run during the build so their coverage can't be collected, use --force-clean to rerun them");
+        return Ok(traces);
+    }
+    info!("Merging build script coverage reports");
+    let instrumentation = merge_profiles(&profraws)?;
+    if instrumentation.is_empty() {
+        return Ok(traces);
+    }
+    let binaries = build_scripts
+        .iter()
+        .filter(|x| x.exists())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mapping = coverage_mapping(&binaries, &instrumentation)?;
+    add_report(&mut traces, &mapping.generate_report(), analysis, config);
+    Ok(traces)
+}
+
+/// Checks the profiles were written by the binaries, a function in the profiles with a
+/// different structural hash to the function of the same name in the binaries means they came
+/// from a different build. Profiles which don't match any function are from other binaries
+fn check_profile_hashes(
+    mapping: &CoverageMapping,
+    instrumentation: &InstrumentationProfile,
+) -> Result<(), RunError> {
+    let mut functions: HashMap<u64, Vec<u64>> = HashMap::new();
+    for function in mapping.mapping_info.iter().flat_map(|x| x.cov_fun.iter()) {
+        // Functions which aren't in the binary such as unused generics have a hash of zero
+        if function.header.fn_hash != 0 {
+            functions
+                .entry(function.header.name_hash)
+                .or_default()
+                .push(function.header.fn_hash);
+        }
+    }
+    let (matched, mismatched) = check_hashes(
+        &functions,
+        instrumentation
+            .records
+            .iter()
+            .filter_map(|x| Some((x.name_hash?, x.hash?, x.name.as_deref()))),
+    );
+    if let Some(name) = mismatched.first() {
+        Err(RunError::TestCoverage(format!(
+            "the profiles don't match the binaries, {} functions have a different hash such as {}. Rebuild the binaries or run the ones the profiles were written by",
+            mismatched.len(),
+            name
+        )))
+    } else if matched == 0 {
+        Err(RunError::TestCoverage(
+            "none of the functions in the profiles are in the binaries".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Compares the name and structural hashes of the profile records with the functions in the
+/// binaries, returning the number with a matching function and the names of those where the
+/// function has a different hash
+fn check_hashes<'a>(
+    functions: &HashMap<u64, Vec<u64>>,
+    records: impl Iterator<Item = (u64, u64, Option<&'a str>)>,
+) -> (usize, Vec<String>) {
+    let mut matched = 0;
+    let mut mismatched = vec![];
+    for (name_hash, hash, name) in records {
+        match functions.get(&name_hash) {
+            Some(hashes) if hashes.contains(&hash) => matched += 1,
+            Some(_) => mismatched.push(
+                name.map(|x| x.to_string())
+                    .unwrap_or_else(|| format!("{name_hash:#x}")),
+            ),
+            None => {}
+        }
+    }
+    (matched, mismatched)
 }
 
 impl<'a> StateData for LlvmInstrumentedData<'a> {
@@ -77,135 +451,152 @@ impl<'a> StateData for LlvmInstrumentedData<'a> {
     }
 
     fn last_wait_attempt(&mut self) -> Result<Option<TestState>, RunError> {
-        unreachable!();
+        Ok(None)
     }
 
     fn wait(&mut self) -> Result<Option<TestState>, RunError> {
         let should_panic = self.should_panic();
-        if let Some(parent) = self.process.as_mut() {
-            match parent.child.wait() {
-                Ok(exit) => {
-                    if !exit.success() && !should_panic {
-                        return Err(RunError::TestFailed);
-                    }
-                    if let Some(delay) = self.config.post_test_delay {
-                        sleep(delay);
-                    }
-                    let profraws = get_profile_walker(self.config)
-                        .map(|x| x.path().to_path_buf())
-                        .filter(|x| !parent.existing_profraws.contains(x))
-                        .collect::<Vec<_>>();
+        let exit = match self.process.as_mut() {
+            Some(parent) => match parent.child.try_wait() {
+                Ok(Some(exit)) => {
+                    shutdown_spawned(&parent.child, self.config);
+                    exit
+                }
+                Ok(None) => {
+                    sleep(POLL_INTERVAL);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            },
+            None => return Err(RunError::TestCoverage("Test was not launched".to_string())),
+        };
+        if !exit.success() && !should_panic {
+            return Err(RunError::TestFailed);
+        }
+        sleep(self.config.profile_delay());
+        self.collect_profiles()?;
+        self.process = None;
+        let code = exit.code().unwrap_or(1);
+        Ok(Some(TestState::End(code)))
+    }
 
-                    info!(
-                        "For binary: {}",
-                        self.config.strip_base_dir(&parent.path).display()
-                    );
-                    for prof in &profraws {
-                        let profraw_name = self.config.strip_base_dir(prof);
-                        info!("Generated: {}", profraw_name.display());
-                    }
+    fn stop(&mut self) -> Result<TestState, RunError> {
+        unreachable!();
+    }
 
-                    let binary_path = parent.path.clone();
-                    info!("Merging coverage reports");
-                    let instrumentation = merge_profiles(&profraws)?;
-                    if instrumentation.is_empty() {
-                        warn!("profraw file has no records after merging. If this is unexpected it may be caused by a panic or signal used in a test that prevented the LLVM instrumentation runtime from serialising results");
-                        self.process = None;
-                        let code = exit.code().unwrap_or(1);
-                        return Ok(Some(TestState::End(code)));
-                    }
+    fn timeout(&mut self) -> Result<TestState, RunError> {
+        if let Some(parent) = self.process.as_mut() {
+            terminate(&mut parent.child, self.config);
+            shutdown_spawned(&parent.child, self.config);
+        }
+        warn!("The LLVM instrumentation runtime can't save the coverage of a test that's stopped, only coverage from any processes it spawned is kept");
+        self.collect_profiles()?;
+        self.process = None;
+        Ok(TestState::End(TIMEOUT_EXIT_CODE))
+    }
+}
 
-                    let mut binaries = parent
-                        .extra_binaries
-                        .iter()
-                        .filter(|path| {
-                            // extra binaries might not exist yet and be created
-                            // later by the test suite
-                            if path.exists() {
-                                true
-                            } else {
-                                info!(
-                                    "Skipping additional object '{}' since the file does not exist",
-                                    path.display()
-                                );
-                                false
-                            }
-                        })
-                        .cloned()
-                        .collect::<Vec<_>>();
-
-                    binaries.push(binary_path);
-                    info!("Mapping coverage data to source");
-                    let mapping =
-                        CoverageMapping::new(&binaries, &instrumentation).map_err(|e| {
-                            error!("Failed to get coverage: {}", e);
-                            RunError::TestCoverage(e.to_string())
-                        })?;
-                    let report = mapping.generate_report();
-
-                    if self.traces.is_empty() {
-                        for source_file in get_source_walker(self.config) {
-                            let file = source_file.path();
-                            let analysis = self.analysis.get(file);
-                            if let Some(result) = report.files.get(file) {
-                                for (loc, hits) in result.hits.iter() {
-                                    for line in loc.line_start..(loc.line_end + 1) {
-                                        let include = match analysis.as_ref() {
-                                            Some(analysis) => !analysis.should_ignore(line),
-                                            None => true,
-                                        };
-                                        if include {
-                                            let mut trace = Trace::new_stub(line as u64);
-                                            trace.stats = CoverageStat::Line(*hits as u64);
-                                            self.traces.add_trace(file, trace);
-                                        }
-                                    }
-                                }
-                            }
-                            if let Some(analysis) = analysis {
-                                for line in analysis.cover.iter() {
-                                    if !self.traces.contains_location(file, *line as u64) {
-                                        let mut trace = Trace::new_stub(*line as u64);
-                                        trace.stats = CoverageStat::Line(0);
-                                        self.traces.add_trace(file, trace);
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        self.traces.dedup();
-
-                        for (file, result) in report.files.iter() {
-                            if let Some(traces) = self.traces.file_traces_mut(file) {
-                                for trace in traces.iter_mut() {
-                                    if let Some(hits) = result.hits_for_line(trace.line as usize) {
-                                        if let CoverageStat::Line(ref mut x) = trace.stats {
-                                            *x = hits as _;
-                                        }
-                                    }
-                                }
-                            } else {
-                                warn!(
-                                    "Couldn't find {} in {:?}",
-                                    file.display(),
-                                    self.traces.files()
-                                );
-                            }
-                        }
-                    }
+/// Sends the shutdown signal to the test and kills it if it hasn't exited after the grace period
+fn terminate(child: &mut Child, config: &Config) {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::kill;
+        use nix::unistd::Pid;
+        let _ = kill(Pid::from_raw(child.id() as i32), shutdown_signal(config));
+        let grace_end = Instant::now() + shutdown_grace(config);
+        while Instant::now() < grace_end {
+            if let Ok(Some(_)) = child.try_wait() {
+                return;
+            }
+            sleep(POLL_INTERVAL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = config;
+    let _ = child.kill();
+    let _ = child.wait();
+}
 
-                    self.process = None;
-                    let code = exit.code().unwrap_or(1);
-                    Ok(Some(TestState::End(code)))
-                }
-                Err(e) => Err(e.into()),
+/// Sends the shutdown signal to the processes the test spawned which are still running after it
+/// exits, these are in the test's process group when `graceful_shutdown_signal` is set. Any that
+/// haven't exited after the grace period are killed
+fn shutdown_spawned(child: &Child, config: &Config) {
+    #[cfg(unix)]
+    if config.graceful_shutdown_signal.is_some() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        let group = Pid::from_raw(-(child.id() as i32));
+        // Fails if nothing is left in the group
+        if kill(group, shutdown_signal(config)).is_err() {
+            return;
+        }
+        let grace_end = Instant::now() + shutdown_grace(config);
+        while Instant::now() < grace_end {
+            if kill(group, None).is_err() {
+                return;
             }
-        } else {
-            Err(RunError::TestCoverage("Test was not launched".to_string()))
+            sleep(POLL_INTERVAL);
         }
+        warn!("Spawned processes didn't exit after the shutdown signal, killing them");
+        let _ = kill(group, Signal::SIGKILL);
     }
+    #[cfg(not(unix))]
+    let _ = (child, config);
+}
 
-    fn stop(&mut self) -> Result<TestState, RunError> {
-        unreachable!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lcov_from_llvm_cov() {
+        let lcov = "SF:/project/src/lib.rs\nFN:1,foo\nDA:1,3\nDA:2,0\nLF:2\nLH:1\nend_of_record\nSF:/project/src/main.rs\nDA:4,1\nend_of_record\n";
+        let report = report_from_lcov(lcov);
+        let hits = |file: &str| {
+            report.files[std::path::Path::new(file)]
+                .hits
+                .iter()
+                .map(|(loc, hits)| (loc.line_start, *hits))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hits("/project/src/lib.rs"), vec![(1, 3), (2, 0)]);
+        assert_eq!(hits("/project/src/main.rs"), vec![(4, 1)]);
+    }
+
+    #[test]
+    fn partial_line_counters() {
+        let loc = |line_start, column_start, line_end, column_end| SourceLocation {
+            line_start,
+            column_start,
+            line_end,
+            column_end,
+        };
+        let mut hits = BTreeMap::new();
+        // A function body with a closure that was never run on its second line
+        hits.insert(loc(1, 40, 4, 2), 1);
+        hits.insert(loc(2, 30, 2, 40), 0);
+        // An `if` on the third line which ran its block
+        hits.insert(loc(3, 10, 3, 20), 1);
+        let counters = line_counters(&hits).into_iter().collect::<BTreeMap<_, _>>();
+        assert_eq!(counters.keys().copied().collect::<Vec<_>>(), vec![2, 3]);
+        assert!(counters[&2].is_partial());
+        assert_eq!(counters[&2].missed.iter().next(), Some(&(2, 30, 2, 40)));
+        assert!(!counters[&3].is_partial());
+    }
+
+    #[test]
+    fn profile_hashes_checked() {
+        let mut functions = HashMap::new();
+        functions.insert(1, vec![10]);
+        functions.insert(2, vec![20, 21]);
+
+        let records = vec![(1, 10, Some("a")), (2, 21, None), (3, 30, Some("c"))];
+        assert_eq!(check_hashes(&functions, records.into_iter()), (2, vec![]));
+
+        let records = vec![(1, 11, Some("a")), (2, 22, None), (2, 20, None)];
+        assert_eq!(
+            check_hashes(&functions, records.into_iter()),
+            (1, vec!["a".to_string(), "0x2".to_string()])
+        );
     }
 }
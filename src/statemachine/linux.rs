@@ -8,7 +8,7 @@ use crate::source_analysis::LineAnalysis;
 use crate::statemachine::*;
 use crate::TestHandle;
 use nix::errno::Errno;
-use nix::sys::signal::Signal;
+use nix::sys::signal::{kill, Signal};
 use nix::sys::wait::*;
 use nix::unistd::Pid;
 use nix::Error as NixErr;
@@ -16,6 +16,8 @@ use procfs::process::{MMapPath, Process};
 use std::collections::{HashMap, HashSet};
 use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, trace, trace_span, warn};
 
 /// Handle to linux process state
@@ -41,8 +43,16 @@ pub struct LinuxData<'a> {
     processes: HashMap<Pid, TracedProcess>,
     /// Map from pids to their parent
     pid_map: HashMap<Pid, Pid>,
+    /// Forked processes still running, mapped to the process which forked them
+    forks: HashMap<Pid, Pid>,
     /// So if we have the exit code but we're also waiting for all the spawned processes to end
     exit_code: Option<i32>,
+    /// When the test has exited and the post test delay is set, the time to stop tracing any
+    /// processes it spawned which are still running
+    delay_end: Option<Instant>,
+    /// When the test has exited and the spawned processes still running have been sent the
+    /// graceful shutdown signal, the time to kill any which haven't exited
+    shutdown_end: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -167,6 +177,9 @@ impl<'a> StateData for LinuxData<'a> {
 
     fn last_wait_attempt(&mut self) -> Result<Option<TestState>, RunError> {
         if let Some(ec) = self.exit_code {
+            if self.shutdown_end.is_some() {
+                self.kill_spawned();
+            }
             let parent = self.parent;
             for (_, process) in self.processes.iter().filter(|(k, _)| **k != parent) {
                 if let Some(tm) = process.traces.as_ref() {
@@ -180,6 +193,14 @@ impl<'a> StateData for LinuxData<'a> {
     }
 
     fn wait(&mut self) -> Result<Option<TestState>, RunError> {
+        if matches!(self.delay_end, Some(end) if Instant::now() >= end) {
+            debug!("Post test delay elapsed, collecting coverage");
+            return self.last_wait_attempt();
+        }
+        if matches!(self.shutdown_end, Some(end) if Instant::now() >= end) {
+            warn!("Spawned processes didn't exit after the shutdown signal, killing them");
+            return self.last_wait_attempt();
+        }
         let mut result = Ok(None);
         let mut running = true;
         while running {
@@ -282,7 +303,11 @@ impl<'a> StateData for LinuxData<'a> {
                     Ok((TestState::wait_state(), TracerAction::Continue(c.into())))
                 }
                 WaitStatus::Stopped(c, s) => {
-                    let sig = if self.config.forward_signals {
+                    // The shutdown signal is delivered while the spawned processes are traced so
+                    // their shutdown code is covered
+                    let shutdown =
+                        self.shutdown_end.is_some() && *s == shutdown_signal(self.config);
+                    let sig = if self.config.forward_signals || shutdown {
                         Some(*s)
                     } else {
                         None
@@ -290,16 +315,34 @@ impl<'a> StateData for LinuxData<'a> {
                     let info = ProcessInfo::new(*c, sig);
                     Ok((TestState::wait_state(), TracerAction::TryContinue(info)))
                 }
+                WaitStatus::Signaled(c, _, _)
+                    if self.shutdown_end.is_some()
+                        && (self.processes.contains_key(c) || self.forks.contains_key(c)) =>
+                {
+                    // A spawned process stopped by the shutdown signal
+                    self.fork_exited(*c);
+                    if let Some(tm) = self.processes.remove(c).and_then(|x| x.traces) {
+                        self.traces.merge(&tm);
+                    }
+                    match self.exit_code {
+                        Some(ec) if self.processes.is_empty() && !self.forks_running() => {
+                            Ok((TestState::End(ec), TracerAction::Nothing))
+                        }
+                        _ => Ok((TestState::wait_state(), TracerAction::Nothing)),
+                    }
+                }
                 WaitStatus::Signaled(c, s, f) => {
+                    self.fork_exited(*c);
                     if let Ok(s) = self.handle_signaled(c, s, *f) {
                         Ok(s)
                     } else {
-                        Err(RunError::TestRuntime(
-                            "Attempting to handle tarpaulin being signaled".to_string(),
-                        ))
+                        Err(RunError::TestRuntime(format!(
+                            "Attempting to handle tarpaulin being signaled with {s}"
+                        )))
                     }
                 }
                 WaitStatus::Exited(child, ec) => {
+                    self.fork_exited(*child);
                     let mut parent = Pid::from_raw(0);
                     if let Some(proc) = self.get_traced_process_mut(*child) {
                         for ref mut value in proc.breakpoints.values_mut() {
@@ -307,7 +350,8 @@ impl<'a> StateData for LinuxData<'a> {
                         }
                         parent = proc.parent;
                     }
-                    if &parent == child {
+                    // The test's breakpoints are still needed by the forks being followed
+                    if &parent == child && !(parent == self.parent && self.forks_running()) {
                         if let Some(removed) = self.processes.remove(&parent) {
                             if parent != self.parent {
                                 let traces = removed.traces.unwrap();
@@ -316,8 +360,27 @@ impl<'a> StateData for LinuxData<'a> {
                         }
                     }
                     trace!("Exited {:?} parent {:?}", child, self.parent);
+                    let delay = self.config.post_test_delay.filter(|x| !x.is_zero());
                     if child == &self.parent {
-                        if self.processes.is_empty() || !self.config.follow_exec {
+                        if self.processes.is_empty() && !self.forks_running() {
+                            Ok((TestState::End(*ec), TracerAction::Nothing))
+                        } else if self.config.graceful_shutdown_signal.is_some() {
+                            self.exit_code = Some(*ec);
+                            self.shutdown_spawned();
+                            Ok((TestState::wait_state(), TracerAction::Nothing))
+                        } else if self.forks_running() {
+                            self.exit_code = Some(*ec);
+                            info!(
+                                "Test process exited, tracing the {} forked processes still running",
+                                self.forks.len()
+                            );
+                            Ok((TestState::wait_state(), TracerAction::Nothing))
+                        } else if let (Some(delay), false) = (delay, self.config.follow_exec) {
+                            self.exit_code = Some(*ec);
+                            self.delay_end = Some(Instant::now() + delay);
+                            info!("Test process exited, tracing spawned processes for {:?} before collecting coverage", delay);
+                            Ok((TestState::wait_state(), TracerAction::Nothing))
+                        } else if !self.config.follow_exec {
                             Ok((TestState::End(*ec), TracerAction::Nothing))
                         } else {
                             self.exit_code = Some(*ec);
@@ -326,7 +389,9 @@ impl<'a> StateData for LinuxData<'a> {
                         }
                     } else {
                         match self.exit_code {
-                            Some(ec) if self.processes.is_empty() => return Ok(TestState::End(ec)),
+                            Some(ec) if self.processes.is_empty() && !self.forks_running() => {
+                                return Ok(TestState::End(ec))
+                            }
                             _ => {
                                 // Process may have already been destroyed. This is just in case
                                 Ok((
@@ -390,6 +455,7 @@ impl<'a> StateData for LinuxData<'a> {
                 TracerAction::Detach(t) => {
                     continued = true;
                     actioned_pids.insert(t.pid);
+                    self.fork_exited(t.pid);
                     let _ = detach_child(t.pid);
                 }
                 TracerAction::Nothing => {}
@@ -408,9 +474,112 @@ impl<'a> StateData for LinuxData<'a> {
         }
         result
     }
+
+    fn timeout(&mut self) -> Result<TestState, RunError> {
+        if self.exit_code.is_some() {
+            // The test has exited and it's the processes it left running which timed out
+            self.kill_spawned();
+            return Ok(TestState::End(TIMEOUT_EXIT_CODE));
+        }
+        let parent = self.parent;
+        let signal = shutdown_signal(self.config);
+        let _ = kill(parent, signal);
+        let grace_end = Instant::now() + shutdown_grace(self.config);
+        let mut killed = false;
+        loop {
+            if !killed && Instant::now() >= grace_end {
+                debug!("Test didn't exit after {}, killing it", signal);
+                let _ = kill(parent, Signal::SIGKILL);
+                killed = true;
+            }
+            let wait = waitpid(
+                Pid::from_raw(-1),
+                Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL),
+            );
+            match wait {
+                Ok(WaitStatus::StillAlive) => sleep(Duration::from_millis(10)),
+                Ok(WaitStatus::Signaled(pid, _, _)) if pid == parent => break,
+                Ok(WaitStatus::Stopped(pid, s)) if s == signal => {
+                    // Signals aren't always forwarded so make sure this one gets to the test
+                    let _ = continue_exec(pid, Some(signal));
+                }
+                Ok(status) => {
+                    // Carry on collecting coverage until the test exits
+                    let exited = matches!(status, WaitStatus::Exited(pid, _) if pid == parent);
+                    self.wait_queue.push(status);
+                    if let Err(e) = self.stop() {
+                        debug!("Error while stopping test after timeout: {}", e);
+                    }
+                    if exited {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        // Keep the coverage of any processes the test spawned and stop them as well
+        self.kill_spawned();
+        Ok(TestState::End(TIMEOUT_EXIT_CODE))
+    }
 }
 
 impl<'a> LinuxData<'a> {
+    /// Sends the shutdown signal to the processes the test spawned which are still running,
+    /// they're traced until they exit or the shutdown timeout
+    fn shutdown_spawned(&mut self) {
+        let signal = shutdown_signal(self.config);
+        let grace = shutdown_grace(self.config);
+        info!(
+            "Test process exited, sending {} to the spawned processes still running and waiting up to {:?} for them to exit",
+            signal, grace
+        );
+        for pid in self.processes.keys().filter(|x| **x != self.parent) {
+            let _ = kill(*pid, signal);
+        }
+        if self.config.follow_forks {
+            for pid in self.forks.keys() {
+                let _ = kill(*pid, signal);
+            }
+        }
+        self.shutdown_end = Some(Instant::now() + grace);
+    }
+
+    /// Whether tracing should carry on after the test exits for the processes it forked
+    fn forks_running(&self) -> bool {
+        self.config.follow_forks && !self.forks.is_empty()
+    }
+
+    /// Stops following a forked process, releasing the test process once the test and all of
+    /// its forks have exited
+    fn fork_exited(&mut self, pid: Pid) {
+        self.forks.remove(&pid);
+        if self.exit_code.is_some() && !self.forks_running() {
+            self.processes.remove(&self.parent);
+        }
+    }
+
+    /// Keeps the coverage of any processes the test spawned and kills them along with every
+    /// process forked from the test or its descendants
+    fn kill_spawned(&mut self) {
+        let parent = self.parent;
+        let spawned = self
+            .processes
+            .keys()
+            .chain(self.forks.keys())
+            .filter(|x| **x != parent)
+            .copied()
+            .collect::<HashSet<_>>();
+        for pid in spawned {
+            if let Some(tm) = self.processes.get(&pid).and_then(|x| x.traces.as_ref()) {
+                self.traces.merge(tm);
+            }
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, Some(WaitPidFlag::__WALL));
+        }
+        self.processes.clear();
+        self.forks.clear();
+    }
+
     pub fn new(
         traces: &'a mut TraceMap,
         analysis: &'a HashMap<PathBuf, LineAnalysis>,
@@ -428,7 +597,10 @@ impl<'a> LinuxData<'a> {
             config,
             event_log,
             pid_map: HashMap::new(),
+            forks: HashMap::new(),
             exit_code: None,
+            delay_end: None,
+            shutdown_end: None,
         }
     }
 
@@ -633,9 +805,11 @@ impl<'a> LinuxData<'a> {
                         } else {
                             None
                         };
+                        let fork_child = Pid::from_raw(fork_child as _);
                         if let Some(parent) = parent {
-                            self.pid_map.insert(Pid::from_raw(fork_child as _), parent);
+                            self.pid_map.insert(fork_child, parent);
                         }
+                        self.forks.insert(fork_child, child);
                     } else {
                         trace!("No event data for child");
                     }
@@ -705,7 +879,7 @@ impl<'a> LinuxData<'a> {
         } else {
             trace!("Unexpected signal with ptrace event {event}");
             trace!("Signal: {:?}", sig);
-            Err(RunError::TestRuntime("Unexpected signal".to_string()))
+            Err(RunError::TestRuntime(format!("Unexpected signal {sig:?}")))
         }
     }
 
@@ -6,7 +6,7 @@ use crate::LineAnalysis;
 use crate::TestHandle;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tracing::error;
 
 pub mod instrumented;
@@ -16,6 +16,57 @@ cfg_if::cfg_if! {
         pub use linux::ProcessInfo;
     }
 }
+
+cfg_if::cfg_if! {
+    if #[cfg(unix)] {
+        use nix::sys::signal::Signal;
+
+        /// Parses a signal name such as `SIGINT` or `INT`
+        fn parse_signal(name: &str) -> Option<Signal> {
+            let name = name.trim().to_ascii_uppercase();
+            if name.starts_with("SIG") {
+                name.parse().ok()
+            } else {
+                format!("SIG{name}").parse().ok()
+            }
+        }
+
+        /// The signal to stop processes with, `SIGTERM` unless `graceful_shutdown_signal` is set
+        pub(crate) fn shutdown_signal(config: &Config) -> Signal {
+            config
+                .graceful_shutdown_signal
+                .as_deref()
+                .and_then(parse_signal)
+                .unwrap_or(Signal::SIGTERM)
+        }
+
+        pub(crate) fn is_signal(name: &str) -> bool {
+            parse_signal(name).is_some()
+        }
+    } else {
+        /// Processes are killed without a signal on this platform so any name is accepted
+        pub(crate) fn is_signal(_name: &str) -> bool {
+            true
+        }
+    }
+}
+
+/// Exit code given to a test executable which is stopped after timing out, this is the same as
+/// the code used by the `timeout` command
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Time a test executable has to exit after being sent SIGTERM on a timeout before it's killed
+pub(crate) const TIMEOUT_GRACE: Duration = Duration::from_secs(2);
+
+/// Time a process sent the shutdown signal has to exit before it's killed
+pub(crate) fn shutdown_grace(config: &Config) -> Duration {
+    match (config.shutdown_timeout, &config.graceful_shutdown_signal) {
+        (Some(timeout), _) => timeout,
+        (None, Some(_)) => config.test_timeout,
+        (None, None) => TIMEOUT_GRACE,
+    }
+}
+
 pub fn create_state_machine<'a>(
     test: impl Into<TestHandle>,
     traces: &'a mut TraceMap,
@@ -113,6 +164,10 @@ pub trait StateData {
     /// Handle a stop in the test executable. Coverage data will
     /// be collected here as well as other OS specific functions
     fn stop(&mut self) -> Result<TestState, RunError>;
+    /// Stops a test executable which has timed out, sending SIGTERM and then SIGKILL if it hasn't
+    /// exited after a grace period. Returns the end state keeping the coverage collected before
+    /// it was stopped
+    fn timeout(&mut self) -> Result<TestState, RunError>;
 }
 
 impl StateData for () {
@@ -141,6 +196,11 @@ impl StateData for () {
             "No valid coverage collector".to_string(),
         ))
     }
+    fn timeout(&mut self) -> Result<TestState, RunError> {
+        Err(RunError::StateMachine(
+            "No valid coverage collector".to_string(),
+        ))
+    }
 }
 
 impl<'a> StateData for Box<dyn StateData + 'a> {
@@ -163,6 +223,10 @@ impl<'a> StateData for Box<dyn StateData + 'a> {
     fn stop(&mut self) -> Result<TestState, RunError> {
         self.as_mut().stop()
     }
+
+    fn timeout(&mut self) -> Result<TestState, RunError> {
+        self.as_mut().timeout()
+    }
 }
 
 impl TestState {
@@ -207,9 +271,11 @@ impl TestState {
                     if let Some(s) = data.last_wait_attempt()? {
                         Ok(s)
                     } else {
-                        Err(RunError::TestRuntime(
-                            "Error: Timed out waiting for test response".to_string(),
-                        ))
+                        error!(
+                            "Timed out waiting for test response after {:?}, stopping the test",
+                            config.test_timeout
+                        );
+                        data.timeout()
                     }
                 } else {
                     Ok(TestState::Waiting { start_time })
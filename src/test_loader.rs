@@ -6,14 +6,15 @@ use gimli::read::Error;
 use gimli::*;
 use object::{read::ObjectSection, Object};
 use rustc_demangle::demangle;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
 use tracing::{debug, error, trace, warn};
 
-/// Describes a function as `low_pc`, `high_pc` and bool representing `is_test`.
-type FuncDesc = (u64, u64, FunctionType, Option<String>);
+/// Describes a function as `low_pc`, `high_pc` and bool representing `is_test`, followed by its
+/// name and the mangled name of its symbol.
+type FuncDesc = (u64, u64, FunctionType, Option<String>, Option<String>);
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum FunctionType {
@@ -66,6 +67,8 @@ pub struct TracerData {
     pub length: u64,
     /// Function name
     pub fn_name: Option<String>,
+    /// Mangled name of the function's symbol
+    pub symbol: Option<String>,
 }
 
 fn generate_func_desc<R, Offset>(
@@ -101,12 +104,14 @@ where
         Some(AttributeValue::Udata(x)) => x,
         _ => 0u64,
     };
+    let mut symbol = None;
     if let Some(AttributeValue::DebugStrRef(offset)) = linkage {
-        let name = debug_str
+        let mangled = debug_str
             .get_str(offset)
             .and_then(|r| r.to_string().map(|s| s.to_string()))
             .unwrap_or_else(|_| "".into());
-        let name = demangle(name.as_ref()).to_string();
+        let name = demangle(mangled.as_ref()).to_string();
+        symbol = Some(mangled).filter(|x| !x.is_empty());
         // Simplest test is whether it's in tests namespace.
         // Rust guidelines recommend all tests are in a tests module.
         func_type = if name.contains("tests::") {
@@ -117,7 +122,7 @@ where
             FunctionType::Standard
         };
     }
-    Ok((low, high, func_type, fn_name))
+    Ok((low, high, func_type, fn_name, symbol))
 }
 
 /// Finds all function entry points and returns a vector
@@ -149,7 +154,7 @@ where
 fn get_addresses_from_program<R, Offset>(
     prog: IncompleteLineProgram<R>,
     debug_strs: &DebugStr<R>,
-    entries: &[(u64, LineType, &Option<String>)],
+    entries: &[(u64, LineType, &Option<String>, &Option<String>)],
     config: &Config,
     result: &mut HashMap<SourceLocation, Vec<TracerData>>,
 ) -> Result<()>
@@ -190,12 +195,12 @@ where
                     }
                     if is_coverable_file_path(&path, &project, &config.target_dir()) {
                         let address = ln_row.address();
-                        let (desc, fn_name) = entries
+                        let (desc, fn_name, symbol) = entries
                             .iter()
-                            .filter(|&&(addr, _, _)| addr == address)
-                            .map(|&(_, t, fn_name)| (t, fn_name.clone()))
+                            .filter(|&&(addr, _, _, _)| addr == address)
+                            .map(|&(_, t, fn_name, symbol)| (t, fn_name.clone(), symbol.clone()))
                             .next()
-                            .unwrap_or((LineType::Unknown, None));
+                            .unwrap_or((LineType::Unknown, None, None));
                         let loc = SourceLocation {
                             path,
                             line: line.into(),
@@ -206,6 +211,7 @@ where
                                 trace_type: desc,
                                 length: 1,
                                 fn_name,
+                                symbol,
                             };
                             let tracerdata = result.entry(loc).or_default();
                             tracerdata.push(trace);
@@ -224,7 +230,11 @@ fn get_line_addresses<'data: 'file, 'file>(
     analysis: &HashMap<PathBuf, LineAnalysis>,
     config: &Config,
 ) -> Result<TraceMap> {
-    let project = config.root();
+    let test_paths = if config.include_tests() {
+        vec![]
+    } else {
+        config.integration_test_paths()
+    };
     let io_err = |e| {
         error!("IO error parsing section: {e}");
         Error::Io
@@ -258,10 +268,10 @@ fn get_line_addresses<'data: 'file, 'file>(
         let entry_points = get_entry_points(&cu, &abbr, &debug_strings);
         let entries = entry_points
             .iter()
-            .map(|(a, b, c, fn_name)| match c {
-                FunctionType::Test => (*a, LineType::TestEntry(*b), fn_name),
-                FunctionType::Standard => (*a, LineType::FunctionEntry(*b), fn_name),
-                FunctionType::Generated => (*a, LineType::TestMain, fn_name),
+            .map(|(a, b, c, fn_name, symbol)| match c {
+                FunctionType::Test => (*a, LineType::TestEntry(*b), fn_name, symbol),
+                FunctionType::Standard => (*a, LineType::FunctionEntry(*b), fn_name, symbol),
+                FunctionType::Generated => (*a, LineType::TestMain, fn_name, symbol),
             })
             .collect::<Vec<_>>();
 
@@ -284,9 +294,7 @@ fn get_line_addresses<'data: 'file, 'file>(
                 }
                 let temp_map = temp_map
                     .into_iter()
-                    .filter(|(ref k, _)| {
-                        !(!config.include_tests() && k.path.starts_with(project.join("tests")))
-                    })
+                    .filter(|(ref k, _)| !test_paths.iter().any(|x| k.path.starts_with(x)))
                     .filter(|(ref k, _)| !(config.exclude_path(&k.path)))
                     .filter(|(ref k, _)| {
                         !analysis.should_ignore(k.path.as_ref(), &(k.line as usize))
@@ -301,8 +309,9 @@ fn get_line_addresses<'data: 'file, 'file>(
                 let mut tracemap = TraceMap::new();
                 for (k, val) in temp_map.iter().filter(|(k, _)| k.line != 0) {
                     let rpath = config.strip_base_dir(&k.path);
-                    let mut address = HashSet::new();
+                    let mut address = BTreeSet::new();
                     let mut fn_name = None;
+                    let mut symbol = None;
                     for v in val.iter() {
                         if let Some(a) = v.address {
                             if a < base_addr.address()
@@ -321,6 +330,9 @@ fn get_line_addresses<'data: 'file, 'file>(
                         if fn_name.is_none() && v.fn_name.is_some() {
                             fn_name = v.fn_name.clone();
                         }
+                        if symbol.is_none() && v.symbol.is_some() {
+                            symbol = v.symbol.clone();
+                        }
                     }
                     if address.is_empty() {
                         trace!(
@@ -329,7 +341,9 @@ fn get_line_addresses<'data: 'file, 'file>(
                             k.line
                         );
                     }
-                    tracemap.add_trace(&k.path, Trace::new(k.line, address, 1, fn_name));
+                    let mut trace = Trace::new(k.line, address, 1, fn_name);
+                    trace.symbol = symbol;
+                    tracemap.add_trace(&k.path, trace);
                 }
                 result.merge(&tracemap);
             }
@@ -378,6 +392,23 @@ fn open_symbols_file(_test: &Path) -> io::Result<File> {
     ))
 }
 
+/// Returns true if the binary has what the engine collects coverage from, the coverage map llvm's
+/// instrumentation adds for the llvm engine or the line tables of the debug information for ptrace
+pub fn is_instrumented(binary: &Path, engine: TraceEngine) -> io::Result<bool> {
+    let file = object::read::ReadCache::new(File::open(binary)?);
+    let obj = object::File::parse(&file)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse binary"))?;
+    let found = obj.sections().any(|section| match section.name() {
+        // COFF truncates section names to 8 characters
+        Ok(name) if engine == TraceEngine::Llvm => {
+            name.contains("llvm_covmap") || name.starts_with(".lcovmap")
+        }
+        Ok(name) => name == ".debug_line",
+        Err(_) => false,
+    });
+    Ok(found)
+}
+
 pub fn generate_tracemap(
     test: &Path,
     analysis: &HashMap<PathBuf, LineAnalysis>,
@@ -0,0 +1,208 @@
+//! A diagnostic dump of the trace map before the coverage results are filtered. Every trace point
+//! and every line source analysis ignored is listed along with the reason it's left out of the
+//! results, so differences between the expected and reported coverage can be tracked down. This
+//! isn't a report format and its shape may change between versions.
+use crate::config::Config;
+use crate::errors::RunError;
+use crate::path_utils::GitIgnore;
+use crate::source_analysis::{LineAnalysis, Lines};
+use crate::traces::{CoverageStat, TraceMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Why a file or line is left out of the coverage results
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ExclusionReason {
+    /// The file matches `exclude-files` or doesn't match `include-files`
+    ExcludedFile,
+    /// The file is ignored by git
+    GitIgnored,
+    /// Source analysis ignores the whole file, such as a file with
+    /// `#![cfg(not(tarpaulin_include))]`
+    IgnoredFile,
+    /// Source analysis ignores the line, such as lines in tests or ignored functions and lines
+    /// matching `exclude-line-patterns`
+    IgnoredLine,
+    /// Source analysis expects the line to be coverable but the llvm engine has no counter for it
+    NotInstrumented,
+}
+
+/// A trace point, or a line without one which isn't in the results
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TracePoint {
+    pub line: u64,
+    /// Addresses of the instrumentation points, only found with the ptrace engine
+    pub address: BTreeSet<u64>,
+    pub fn_name: Option<String>,
+    /// The hits, `None` if there's no trace point on the line
+    pub stats: Option<CoverageStat>,
+    pub excluded: Option<ExclusionReason>,
+}
+
+/// The trace points in a source file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileDump {
+    /// Set if the whole file is left out of the results
+    pub excluded: Option<ExclusionReason>,
+    /// Ordered by line
+    pub points: Vec<TracePoint>,
+}
+
+/// The unfiltered trace map of a run
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TraceMapDump {
+    pub files: BTreeMap<PathBuf, FileDump>,
+}
+
+impl TraceMapDump {
+    pub fn new(
+        traces: &TraceMap,
+        analysis: &HashMap<PathBuf, LineAnalysis>,
+        config: &Config,
+        gitignore: &GitIgnore,
+    ) -> Self {
+        let paths = traces
+            .files()
+            .into_iter()
+            .chain(analysis.keys())
+            .map(PathBuf::as_path)
+            .collect::<BTreeSet<&Path>>();
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let dump = file_dump(path, traces, analysis.get(path), config, gitignore);
+                (path.to_path_buf(), dump)
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Writes the dump to `path` as JSON
+    pub fn write(&self, path: &Path) -> Result<(), RunError> {
+        info!("Writing the trace map to {}", path.display());
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+fn file_dump(
+    path: &Path,
+    traces: &TraceMap,
+    analysis: Option<&LineAnalysis>,
+    config: &Config,
+    gitignore: &GitIgnore,
+) -> FileDump {
+    let excluded = if config.exclude_path(path) {
+        Some(ExclusionReason::ExcludedFile)
+    } else if gitignore.is_ignored(path) {
+        Some(ExclusionReason::GitIgnored)
+    } else if analysis.is_some_and(|x| x.ignore.contains(&Lines::All)) {
+        Some(ExclusionReason::IgnoredFile)
+    } else {
+        None
+    };
+    let ignored = |line: u64| analysis.is_some_and(|x| x.should_ignore(line as usize));
+    let mut points = traces
+        .get_child_traces(path)
+        .map(|trace| TracePoint {
+            line: trace.line,
+            address: trace.address.clone(),
+            fn_name: trace.fn_name.clone(),
+            stats: Some(trace.stats.clone()),
+            excluded: excluded
+                .or_else(|| ignored(trace.line).then_some(ExclusionReason::IgnoredLine)),
+        })
+        .collect::<Vec<_>>();
+    let traced = points.iter().map(|x| x.line).collect::<BTreeSet<_>>();
+    let untraced = |line: u64, reason: ExclusionReason| TracePoint {
+        line,
+        address: BTreeSet::new(),
+        fn_name: None,
+        stats: None,
+        excluded: Some(excluded.unwrap_or(reason)),
+    };
+    points.extend(
+        traces
+            .get_not_instrumented(path)
+            .into_iter()
+            .filter(|x| !traced.contains(x))
+            .map(|x| untraced(x, ExclusionReason::NotInstrumented)),
+    );
+    if let Some(analysis) = analysis {
+        points.extend(
+            analysis
+                .ignore
+                .iter()
+                .filter_map(|x| match x {
+                    Lines::Line(line) => Some(*line as u64),
+                    Lines::All => None,
+                })
+                .filter(|x| !traced.contains(x))
+                .map(|x| untraced(x, ExclusionReason::IgnoredLine)),
+        );
+    }
+    points.sort_by_key(|x| x.line);
+    FileDump { excluded, points }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traces::Trace;
+
+    #[test]
+    fn exclusion_reasons() {
+        let config = Config::parse_config_toml(
+            r#"[dump]
+            exclude-files = ["excluded.rs"]"#,
+        )
+        .unwrap()
+        .remove(0);
+        let kept = PathBuf::from("kept.rs");
+        let excluded = PathBuf::from("excluded.rs");
+        let mut traces = TraceMap::new();
+        traces.add_trace(&kept, Trace::new(2, BTreeSet::from([0x10]), 1, None));
+        traces.add_trace(&excluded, Trace::new_stub(1));
+        traces.add_not_instrumented(&kept, vec![3]);
+        let mut analysis = HashMap::new();
+        let mut kept_analysis = LineAnalysis::default();
+        kept_analysis.ignore.insert(Lines::Line(5));
+        analysis.insert(kept.clone(), kept_analysis);
+        let mut ignored_analysis = LineAnalysis::default();
+        ignored_analysis.ignore.insert(Lines::All);
+        analysis.insert(PathBuf::from("ignored.rs"), ignored_analysis);
+
+        let dump = TraceMapDump::new(&traces, &analysis, &config, &GitIgnore::new(&config));
+
+        let kept = &dump.files[&kept];
+        assert_eq!(kept.excluded, None);
+        let reasons = kept
+            .points
+            .iter()
+            .map(|x| (x.line, x.excluded))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                (2, None),
+                (3, Some(ExclusionReason::NotInstrumented)),
+                (5, Some(ExclusionReason::IgnoredLine))
+            ]
+        );
+        assert_eq!(kept.points[0].stats, Some(CoverageStat::Line(0)));
+        assert_eq!(kept.points[1].stats, None);
+        assert_eq!(
+            dump.files[&excluded].excluded,
+            Some(ExclusionReason::ExcludedFile)
+        );
+        assert_eq!(
+            dump.files[&PathBuf::from("ignored.rs")].excluded,
+            Some(ExclusionReason::IgnoredFile)
+        );
+    }
+}
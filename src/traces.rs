@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::cmp::{Ord, Ordering};
 use std::collections::btree_map::Iter;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{Display, Formatter, Result};
 use std::ops::Add;
 use std::path::{Path, PathBuf};
@@ -66,13 +66,65 @@ pub struct Trace {
     /// Line the trace is on in the file
     pub line: u64,
     /// Optional address showing location in the test artefact
-    pub address: HashSet<u64>,
+    pub address: BTreeSet<u64>,
     /// Length of the instruction (useful to get entire condition/branch)
     pub length: usize,
     /// Coverage stats
     pub stats: CoverageStat,
     /// Function name
     pub fn_name: Option<String>,
+    /// Mangled name of the function's symbol, if the debug information has one
+    #[serde(default)]
+    pub symbol: Option<String>,
+}
+
+/// Location of a function in the source as identified by source analysis
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FunctionSpan {
+    /// Name of the function, methods are prefixed with the name of their type
+    pub name: String,
+    /// Line the function signature starts on
+    pub start: u64,
+    /// Last line of the function body
+    pub end: u64,
+}
+
+/// Expression in the source where execution takes one of several branches as identified by
+/// source analysis
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BranchPoint {
+    /// Line the branching expression starts on
+    pub line: u64,
+    /// Lines of each branch, the start is inclusive and the end exclusive
+    pub arms: Vec<(u64, u64)>,
+    /// Whether there's an implicit branch taken when no arm is i.e. an `if` without an `else`
+    pub implicit_default: bool,
+}
+
+/// The llvm coverage counters of a line with more than one, such as a line with a closure or
+/// macro call. Counters are identified by the start and end line and column of their region
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LineCounters {
+    /// Counters which ran at least once
+    pub hit: BTreeSet<(u64, u64, u64, u64)>,
+    /// Counters which were never run
+    pub missed: BTreeSet<(u64, u64, u64, u64)>,
+}
+
+impl LineCounters {
+    /// Adds the counters of another run of the same line, a counter missed in one run but hit
+    /// in another is hit
+    pub fn merge(&mut self, other: &LineCounters) {
+        self.hit.extend(other.hit.iter().copied());
+        self.missed.extend(other.missed.iter().copied());
+        let hit = &self.hit;
+        self.missed.retain(|x| !hit.contains(x));
+    }
+
+    /// Whether some of the counters ran and some didn't
+    pub fn is_partial(&self) -> bool {
+        !(self.hit.is_empty() || self.missed.is_empty())
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -84,38 +136,42 @@ pub struct Location {
 }
 
 impl Trace {
-    pub fn new(line: u64, address: HashSet<u64>, length: usize, fn_name: Option<String>) -> Self {
+    pub fn new(line: u64, address: BTreeSet<u64>, length: usize, fn_name: Option<String>) -> Self {
         Self {
             line,
             address,
             length,
             stats: CoverageStat::Line(0),
             fn_name,
+            symbol: None,
         }
     }
 
     pub fn new_stub(line: u64) -> Self {
         Self {
             line,
-            address: HashSet::new(),
+            address: BTreeSet::new(),
             length: 0,
             stats: CoverageStat::Line(0),
             fn_name: None,
+            symbol: None,
         }
     }
 }
 
 impl PartialOrd for Trace {
     fn partial_cmp(&self, other: &Trace) -> Option<Ordering> {
-        // Not sure if I care about the others
-        self.line.partial_cmp(&other.line)
+        Some(self.cmp(other))
     }
 }
 
-/// Implemented to allow Traces to be sorted by line number
+/// Implemented to allow Traces to be sorted by line number, traces on the same line are ordered
+/// by their addresses so the order doesn't depend on the order they were found in
 impl Ord for Trace {
     fn cmp(&self, other: &Trace) -> Ordering {
-        self.line.cmp(&other.line)
+        self.line
+            .cmp(&other.line)
+            .then_with(|| self.address.cmp(&other.address))
     }
     fn max(self, other: Trace) -> Trace {
         if self.line > other.line {
@@ -146,6 +202,31 @@ pub fn amount_coverable<'a>(traces: impl Iterator<Item = &'a Trace>) -> usize {
     result
 }
 
+/// The lines covered in some of the runs of the tests but not in all of them, from the lines
+/// covered in each run. The order of the runs doesn't matter
+pub fn covered_in_some_runs(
+    runs: &[BTreeMap<PathBuf, BTreeSet<u64>>],
+) -> BTreeMap<PathBuf, BTreeSet<u64>> {
+    let mut result: BTreeMap<PathBuf, BTreeSet<u64>> = BTreeMap::new();
+    for run in runs {
+        for (file, lines) in run {
+            result
+                .entry(file.clone())
+                .or_default()
+                .extend(lines.iter().copied());
+        }
+    }
+    for (file, lines) in result.iter_mut() {
+        lines.retain(|line| {
+            !runs
+                .iter()
+                .all(|run| run.get(file).is_some_and(|x| x.contains(line)))
+        });
+    }
+    result.retain(|_, lines| !lines.is_empty());
+    result
+}
+
 /// Amount of data covered in the provided trace slice
 pub fn amount_covered<'a>(traces: impl Iterator<Item = &'a Trace>) -> usize {
     let mut result = 0usize;
@@ -172,6 +253,31 @@ pub fn coverage_percentage<'a>(traces: impl Iterator<Item = &'a Trace>) -> f64 {
 pub struct TraceMap {
     /// Traces in the program mapped to the given file
     traces: BTreeMap<PathBuf, Vec<Trace>>,
+    /// Function spans in the program mapped to the given file
+    #[serde(default)]
+    functions: BTreeMap<PathBuf, Vec<FunctionSpan>>,
+    /// Branch points in the program mapped to the given file
+    #[serde(default)]
+    branches: BTreeMap<PathBuf, Vec<BranchPoint>>,
+    /// Hashes of the source files when the traces were collected
+    #[serde(default)]
+    source_hashes: BTreeMap<PathBuf, String>,
+    /// Lines expected to be coverable which have no coverage counter in the instrumented
+    /// binaries, these have no trace so aren't counted as coverable
+    #[serde(default)]
+    not_instrumented: BTreeMap<PathBuf, BTreeSet<u64>>,
+    /// Counters of the lines with more than one, used to find partially covered lines
+    #[serde(default)]
+    line_counters: BTreeMap<PathBuf, BTreeMap<u64, LineCounters>>,
+    /// Lines covered in only some of the runs when the tests are run several times
+    #[serde(default)]
+    flaky: BTreeMap<PathBuf, BTreeSet<u64>>,
+    /// Lines inside `unsafe` blocks
+    #[serde(default)]
+    unsafe_lines: BTreeMap<PathBuf, BTreeSet<u64>>,
+    /// Test executables stopped after timing out, so their coverage ends at the timeout
+    #[serde(default)]
+    timed_out: BTreeSet<PathBuf>,
 }
 
 impl TraceMap {
@@ -179,6 +285,14 @@ impl TraceMap {
     pub fn new() -> TraceMap {
         TraceMap {
             traces: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            branches: BTreeMap::new(),
+            source_hashes: BTreeMap::new(),
+            not_instrumented: BTreeMap::new(),
+            line_counters: BTreeMap::new(),
+            flaky: BTreeMap::new(),
+            unsafe_lines: BTreeMap::new(),
+            timed_out: BTreeSet::new(),
         }
     }
 
@@ -217,6 +331,38 @@ impl TraceMap {
                 }
             }
         }
+        for (k, values) in &other.functions {
+            self.add_functions(k, values.iter().cloned());
+        }
+        for (k, values) in &other.branches {
+            self.add_branches(k, values.iter().cloned());
+        }
+        for (k, hash) in &other.source_hashes {
+            self.source_hashes
+                .entry(k.clone())
+                .or_insert_with(|| hash.clone());
+        }
+        for (k, lines) in &other.not_instrumented {
+            self.add_not_instrumented(k, lines.iter().copied());
+        }
+        // A line instrumented in one binary may not be in another
+        let traces = &self.traces;
+        for (k, lines) in self.not_instrumented.iter_mut() {
+            if let Some(traces) = traces.get(k) {
+                lines.retain(|line| !traces.iter().any(|x| x.line == *line));
+            }
+        }
+        self.not_instrumented.retain(|_, lines| !lines.is_empty());
+        for (k, lines) in &other.line_counters {
+            self.add_line_counters(k, lines.iter().map(|(line, x)| (*line, x.clone())));
+        }
+        for (k, lines) in &other.flaky {
+            self.add_flaky(k, lines.iter().copied());
+        }
+        for (k, lines) in &other.unsafe_lines {
+            self.add_unsafe_lines(k, lines.iter().copied());
+        }
+        self.timed_out.extend(other.timed_out.iter().cloned());
     }
 
     /// This will collapse duplicate Traces into a single trace. Warning this
@@ -280,6 +426,294 @@ impl TraceMap {
         }
     }
 
+    /// Records a hash of the current contents of each source file so results from different runs
+    /// can be checked to come from the same code
+    pub fn hash_sources(&mut self) {
+        for file in self.traces.keys() {
+            if let Ok(hash) = git2::Oid::hash_file(git2::ObjectType::Blob, file) {
+                self.source_hashes.insert(file.clone(), hash.to_string());
+            }
+        }
+    }
+
+    /// Finds a file which has a different source hash in the other tracemap
+    pub fn find_source_mismatch<'a>(&self, other: &'a TraceMap) -> Option<&'a Path> {
+        other
+            .source_hashes
+            .iter()
+            .find(|(k, v)| matches!(self.source_hashes.get(*k), Some(x) if x != *v))
+            .map(|(k, _)| k.as_path())
+    }
+
+    /// Add function spans for the given file, spans already present are skipped
+    pub fn add_functions(
+        &mut self,
+        file: &Path,
+        functions: impl IntoIterator<Item = FunctionSpan>,
+    ) {
+        let existing = self.functions.entry(file.to_path_buf()).or_default();
+        for function in functions {
+            if !existing.contains(&function) {
+                existing.push(function);
+            }
+        }
+        existing.sort_unstable_by(|a, b| (a.start, a.end, &a.name).cmp(&(b.start, b.end, &b.name)));
+    }
+
+    /// Gets the function spans found in the given file
+    pub fn get_functions(&self, file: &Path) -> &[FunctionSpan] {
+        self.functions
+            .get(file)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Add branch points for the given file, branch points already present are skipped
+    pub fn add_branches(&mut self, file: &Path, branches: impl IntoIterator<Item = BranchPoint>) {
+        let existing = self.branches.entry(file.to_path_buf()).or_default();
+        for branch in branches {
+            if !existing.contains(&branch) {
+                existing.push(branch);
+            }
+        }
+        existing.sort_unstable();
+    }
+
+    /// Records lines which were expected to be coverable but have no coverage counter, lines
+    /// which already have a trace are skipped
+    pub fn add_not_instrumented(&mut self, file: &Path, lines: impl IntoIterator<Item = u64>) {
+        let traces = self.traces.get(file);
+        let lines = lines
+            .into_iter()
+            .filter(|line| !traces.is_some_and(|x| x.iter().any(|x| x.line == *line)))
+            .collect::<BTreeSet<_>>();
+        if !lines.is_empty() {
+            self.not_instrumented
+                .entry(file.to_path_buf())
+                .or_default()
+                .extend(lines);
+        }
+    }
+
+    /// Gets the lines in the given file which weren't instrumented, in order
+    pub fn get_not_instrumented(&self, file: &Path) -> Vec<u64> {
+        self.not_instrumented
+            .get(file)
+            .map(|x| x.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records the counters of lines with more than one, merging them with any already
+    /// recorded. Lines without a trace are skipped
+    pub fn add_line_counters(
+        &mut self,
+        file: &Path,
+        lines: impl IntoIterator<Item = (u64, LineCounters)>,
+    ) {
+        let traces = match self.traces.get(file) {
+            Some(traces) => traces,
+            None => return,
+        };
+        let mut lines = lines
+            .into_iter()
+            .filter(|(line, _)| traces.iter().any(|x| x.line == *line))
+            .peekable();
+        if lines.peek().is_some() {
+            let existing = self.line_counters.entry(file.to_path_buf()).or_default();
+            for (line, counters) in lines {
+                existing.entry(line).or_default().merge(&counters);
+            }
+        }
+    }
+
+    /// Gets the lines in the given file where some of the counters ran and some didn't, in
+    /// order. These are counted as covered like any other line with hits
+    pub fn get_partial(&self, file: &Path) -> Vec<u64> {
+        self.line_counters
+            .get(file)
+            .map(|x| {
+                x.iter()
+                    .filter(|(_, counters)| counters.is_partial())
+                    .map(|(line, _)| *line)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Marks the lines where only some of the counters ran as not covered, so a line is only
+    /// covered when every statement on it ran
+    pub fn uncover_partial_lines(&mut self) {
+        for (file, lines) in &self.line_counters {
+            if let Some(traces) = self.traces.get_mut(file) {
+                let partial = traces
+                    .iter_mut()
+                    .filter(|x| lines.get(&x.line).is_some_and(LineCounters::is_partial));
+                for trace in partial {
+                    if let CoverageStat::Line(ref mut hits) = trace.stats {
+                        *hits = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets the column spans of the counters which didn't run on the partially covered lines in
+    /// the given file, in order, as the line and the start and end column. Columns are counted
+    /// from 1 and the end is exclusive, the end is `None` if the counter's region carries on to
+    /// the next line
+    pub fn get_missed_columns(&self, file: &Path) -> Vec<(u64, u64, Option<u64>)> {
+        let mut columns = BTreeSet::new();
+        if let Some(lines) = self.line_counters.get(file) {
+            for (line, counters) in lines.iter().filter(|(_, x)| x.is_partial()) {
+                for (line_start, column_start, line_end, column_end) in &counters.missed {
+                    let start = if line_start == line { *column_start } else { 1 };
+                    let end = (line_end == line).then_some(*column_end);
+                    columns.insert((*line, start, end));
+                }
+            }
+        }
+        columns.into_iter().collect()
+    }
+
+    /// Records lines which were covered in only some of the runs of the tests
+    pub fn add_flaky(&mut self, file: &Path, lines: impl IntoIterator<Item = u64>) {
+        let mut lines = lines.into_iter().peekable();
+        if lines.peek().is_some() {
+            self.flaky
+                .entry(file.to_path_buf())
+                .or_default()
+                .extend(lines);
+        }
+    }
+
+    /// Gets the lines in the given file covered in only some of the runs of the tests, in order
+    pub fn get_flaky(&self, file: &Path) -> Vec<u64> {
+        self.flaky
+            .get(file)
+            .map(|x| x.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records a test executable which was stopped after timing out
+    pub fn add_timed_out(&mut self, executable: &Path) {
+        self.timed_out.insert(executable.to_path_buf());
+    }
+
+    /// The test executables which were stopped after timing out, in path order
+    pub fn timed_out(&self) -> Vec<&PathBuf> {
+        self.timed_out.iter().collect()
+    }
+
+    /// Records lines which are inside `unsafe` blocks
+    pub fn add_unsafe_lines(&mut self, file: &Path, lines: impl IntoIterator<Item = u64>) {
+        let mut lines = lines.into_iter().peekable();
+        if lines.peek().is_some() {
+            self.unsafe_lines
+                .entry(file.to_path_buf())
+                .or_default()
+                .extend(lines);
+        }
+    }
+
+    /// Returns true if the line in the given file is inside an `unsafe` block
+    pub fn is_unsafe(&self, file: &Path, line: u64) -> bool {
+        self.unsafe_lines
+            .get(file)
+            .is_some_and(|x| x.contains(&line))
+    }
+
+    /// Gets the traces in the given file on lines inside `unsafe` blocks
+    pub fn get_unsafe_traces<'a>(&'a self, file: &'a Path) -> impl Iterator<Item = &'a Trace> + 'a {
+        self.traces
+            .get(file)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(move |x| self.is_unsafe(file, x.line))
+    }
+
+    /// Number of coverable lines inside `unsafe` blocks
+    pub fn total_unsafe_coverable(&self) -> usize {
+        self.traces
+            .keys()
+            .map(|x| amount_coverable(self.get_unsafe_traces(x)))
+            .sum()
+    }
+
+    /// Number of covered lines inside `unsafe` blocks
+    pub fn total_unsafe_covered(&self) -> usize {
+        self.traces
+            .keys()
+            .map(|x| amount_covered(self.get_unsafe_traces(x)))
+            .sum()
+    }
+
+    /// The lines with hits in each file
+    pub fn covered_lines(&self) -> BTreeMap<PathBuf, BTreeSet<u64>> {
+        self.traces
+            .iter()
+            .map(|(file, traces)| {
+                let lines = traces
+                    .iter()
+                    .filter(|x| matches!(x.stats, CoverageStat::Line(hits) if hits > 0))
+                    .map(|x| x.line)
+                    .collect();
+                (file.clone(), lines)
+            })
+            .collect()
+    }
+
+    /// Gets the branch points found in the given file
+    pub fn get_branches(&self, file: &Path) -> &[BranchPoint] {
+        self.branches
+            .get(file)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Works out how many times each branch of a branch point has been taken from the line
+    /// hits. An arm is counted by the hits of the first line with a trace in it (other than the
+    /// line of the branch point), falling back to the hits of the branch point. The implicit
+    /// default is counted as the hits of the branch point not accounted for by the arms.
+    pub fn branch_hits(&self, file: &Path, branch: &BranchPoint) -> Vec<u64> {
+        let lines = self
+            .traces
+            .get(file)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|x| match x.stats {
+                CoverageStat::Line(hits) => Some((x.line, hits)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let hits = |line: u64| lines.get(&line).copied();
+        let branch_hits = hits(branch.line).unwrap_or_default();
+        let mut arm_hits = branch
+            .arms
+            .iter()
+            .map(|(start, end)| {
+                (*start..*end)
+                    .filter(|x| *x != branch.line)
+                    .find_map(hits)
+                    .unwrap_or(branch_hits)
+            })
+            .collect::<Vec<_>>();
+        if branch.implicit_default {
+            arm_hits.push(branch_hits.saturating_sub(arm_hits.iter().sum()));
+        }
+        arm_hits
+    }
+
+    /// Works out which branches of a branch point have been taken from the line hits, see
+    /// `branch_hits` for how each branch is counted
+    pub fn branches_taken(&self, file: &Path, branch: &BranchPoint) -> Vec<bool> {
+        self.branch_hits(file, branch)
+            .into_iter()
+            .map(|x| x > 0)
+            .collect()
+    }
+
     /// Gets an immutable reference to a trace from an address. Returns None if
     /// there is no trace at that address
     pub fn get_trace(&self, address: u64) -> Option<&Trace> {
@@ -373,6 +807,19 @@ impl TraceMap {
         self.traces.values_mut().flat_map(|x| x.iter_mut())
     }
 
+    /// Keeps only the files the predicate returns true for, removing the traces, functions and
+    /// branches of the others
+    pub fn retain_files(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.traces.retain(|k, _| keep(k));
+        self.functions.retain(|k, _| keep(k));
+        self.branches.retain(|k, _| keep(k));
+        self.source_hashes.retain(|k, _| keep(k));
+        self.not_instrumented.retain(|k, _| keep(k));
+        self.line_counters.retain(|k, _| keep(k));
+        self.flaky.retain(|k, _| keep(k));
+        self.unsafe_lines.retain(|k, _| keep(k));
+    }
+
     pub fn files(&self) -> Vec<&PathBuf> {
         self.traces.keys().collect()
     }
@@ -398,6 +845,18 @@ impl TraceMap {
         amount_covered(self.all_traces())
     }
 
+    /// The amount covered in every run when the tests are run several times, this leaves out
+    /// the lines only some of the runs covered
+    pub fn total_covered_in_every_run(&self) -> usize {
+        self.traces
+            .iter()
+            .map(|(file, traces)| match self.flaky.get(file) {
+                Some(flaky) => amount_covered(traces.iter().filter(|x| !flaky.contains(&x.line))),
+                None => amount_covered(traces.iter()),
+            })
+            .sum()
+    }
+
     /// Returns coverage percentage ranging from 0.0-1.0
     pub fn coverage_percentage(&self) -> f64 {
         coverage_percentage(self.all_traces())
@@ -453,7 +912,7 @@ mod tests {
     #[test]
     fn multiple_traces_per_line() {
         let mut t1 = TraceMap::new();
-        let mut address = HashSet::new();
+        let mut address = BTreeSet::new();
         address.insert(0);
         address.insert(128);
         let trace_1 = Trace {
@@ -462,6 +921,7 @@ mod tests {
             length: 0,
             stats: CoverageStat::Line(1),
             fn_name: Some(String::from("f")),
+            symbol: None,
         };
         t1.add_trace(Path::new("file.rs"), trace_1);
 
@@ -471,12 +931,261 @@ mod tests {
         assert_eq!(total_covered, 1);
     }
 
+    #[test]
+    fn serialized_in_sorted_order() {
+        let trace = |line, addresses: &[u64]| Trace {
+            address: addresses.iter().copied().collect(),
+            ..Trace::new_stub(line)
+        };
+        let traces = [
+            (Path::new("/b.rs"), trace(3, &[0x40, 0x10])),
+            (Path::new("/a.rs"), trace(7, &[])),
+            (Path::new("/b.rs"), trace(3, &[0x20])),
+            (Path::new("/b.rs"), trace(1, &[0x30, 0x50, 0x8])),
+        ];
+        let mut forward = TraceMap::new();
+        for (file, trace) in traces.iter().cloned() {
+            forward.add_trace(file, trace);
+        }
+        let mut backward = TraceMap::new();
+        for (file, trace) in traces.iter().rev().cloned() {
+            backward.add_trace(file, trace);
+        }
+        let json = serde_json::to_string(&forward).unwrap();
+        assert_eq!(json, serde_json::to_string(&backward).unwrap());
+        assert!(json.find("/a.rs").unwrap() < json.find("/b.rs").unwrap());
+        let lines = forward
+            .get_child_traces(Path::new("/b.rs"))
+            .map(|x| (x.line, x.address.iter().copied().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (1, vec![0x8, 0x30, 0x50]),
+                (3, vec![0x10, 0x40]),
+                (3, vec![0x20])
+            ]
+        );
+    }
+
+    #[test]
+    fn branches_from_line_hits() {
+        let file = Path::new("/a.rs");
+        let mut map = TraceMap::new();
+        for (line, hits) in [(1, 4), (2, 3), (4, 0), (6, 1), (10, 2)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            map.add_trace(file, trace);
+        }
+        let if_else = BranchPoint {
+            line: 1,
+            arms: vec![(1, 3), (3, 5)],
+            implicit_default: false,
+        };
+        assert_eq!(map.branches_taken(file, &if_else), vec![true, false]);
+
+        let if_no_else = BranchPoint {
+            line: 1,
+            arms: vec![(1, 3)],
+            implicit_default: true,
+        };
+        assert_eq!(map.branches_taken(file, &if_no_else), vec![true, true]);
+
+        let single_line = BranchPoint {
+            line: 10,
+            arms: vec![(10, 10), (10, 11)],
+            implicit_default: true,
+        };
+        assert_eq!(
+            map.branches_taken(file, &single_line),
+            vec![true, true, false]
+        );
+    }
+
+    #[test]
+    fn merge_separate_runs() {
+        let mut t1 = TraceMap::new();
+        let mut t2 = TraceMap::new();
+        let file = Path::new("file.rs");
+        let trace = |line, address, hits| Trace {
+            line,
+            address: [address].iter().copied().collect(),
+            length: 0,
+            stats: CoverageStat::Line(hits),
+            fn_name: None,
+            symbol: None,
+        };
+        t1.add_trace(file, trace(1, 5, 2));
+        t1.add_trace(file, trace(2, 6, 0));
+        t2.add_trace(file, trace(1, 15, 3));
+        t2.add_trace(file, trace(2, 16, 1));
+        t1.source_hashes.insert(file.to_path_buf(), "a".to_string());
+        t2.source_hashes.insert(file.to_path_buf(), "a".to_string());
+        assert_eq!(t1.find_source_mismatch(&t2), None);
+
+        t1.merge(&t2);
+        t1.dedup();
+        let all = t1.all_traces().collect::<Vec<_>>();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].stats, CoverageStat::Line(5));
+        assert_eq!(all[1].stats, CoverageStat::Line(1));
+        assert_eq!(t1.total_covered(), 2);
+
+        t2.source_hashes.insert(file.to_path_buf(), "b".to_string());
+        assert_eq!(t1.find_source_mismatch(&t2), Some(file));
+    }
+
+    #[test]
+    fn partial_lines() {
+        let mut t1 = TraceMap::new();
+        let mut t2 = TraceMap::new();
+        let file = Path::new("file.rs");
+        let counters = |hit: &[u64], missed: &[u64]| LineCounters {
+            hit: hit.iter().map(|x| (1, *x, 1, *x + 1)).collect(),
+            missed: missed.iter().map(|x| (1, *x, 1, *x + 1)).collect(),
+        };
+        t1.add_trace(file, Trace::new_stub(1));
+        t1.add_line_counters(
+            file,
+            vec![(1, counters(&[1], &[5, 9])), (2, counters(&[], &[1, 5]))],
+        );
+        assert_eq!(t1.get_partial(file), vec![1]);
+        assert!(!t1.line_counters[file].contains_key(&2));
+
+        // The counter missed in the first run is hit in the second
+        t2.add_trace(file, Trace::new_stub(1));
+        t2.add_line_counters(file, vec![(1, counters(&[5], &[1, 9]))]);
+        t1.merge(&t2);
+        assert_eq!(t1.line_counters[file][&1], counters(&[1, 5], &[9]));
+        assert_eq!(t1.get_partial(file), vec![1]);
+
+        let mut t3 = TraceMap::new();
+        t3.add_trace(file, Trace::new_stub(1));
+        t3.add_line_counters(file, vec![(1, counters(&[9], &[]))]);
+        t1.merge(&t3);
+        assert!(t1.get_partial(file).is_empty());
+
+        t1.retain_files(|_| false);
+        assert!(t1.get_partial(file).is_empty());
+    }
+
+    #[test]
+    fn statement_and_region_granularity() {
+        let mut traces = TraceMap::new();
+        let file = Path::new("file.rs");
+        let mut partial = Trace::new_stub(2);
+        partial.stats = CoverageStat::Line(3);
+        let mut covered = Trace::new_stub(3);
+        covered.stats = CoverageStat::Line(1);
+        traces.add_trace(file, partial);
+        traces.add_trace(file, covered);
+        // A closure starting on line 1 which didn't run and a statement on line 2 which did
+        let counters = LineCounters {
+            hit: BTreeSet::from([(2, 5, 2, 10)]),
+            missed: BTreeSet::from([(1, 20, 2, 4), (2, 12, 2, 30)]),
+        };
+        traces.add_line_counters(file, vec![(2, counters)]);
+        assert_eq!(
+            traces.get_missed_columns(file),
+            vec![(2, 1, Some(4)), (2, 12, Some(30))]
+        );
+
+        traces.uncover_partial_lines();
+        assert_eq!(traces.total_covered(), 1);
+        assert_eq!(traces.get_partial(file), vec![2]);
+        assert_eq!(
+            traces.get_child_traces(file).next().unwrap().stats,
+            CoverageStat::Line(0)
+        );
+    }
+
+    #[test]
+    fn lines_covered_in_some_runs() {
+        let file = Path::new("file.rs");
+        let other = Path::new("other.rs");
+        let run = |lines: &[(&Path, &[u64])]| {
+            lines
+                .iter()
+                .map(|(file, lines)| (file.to_path_buf(), lines.iter().copied().collect()))
+                .collect::<BTreeMap<_, BTreeSet<_>>>()
+        };
+        let runs = vec![
+            run(&[(file, &[1, 2, 3]), (other, &[1])]),
+            run(&[(file, &[1, 3, 4])]),
+            run(&[(file, &[1, 2, 3])]),
+        ];
+        let flaky = covered_in_some_runs(&runs);
+        assert_eq!(flaky, run(&[(file, &[2, 4]), (other, &[1])]));
+        let reversed = runs.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(covered_in_some_runs(&reversed), flaky);
+
+        let mut traces = TraceMap::new();
+        for line in 1..=5 {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line((line < 5).into());
+            traces.add_trace(file, trace);
+        }
+        assert_eq!(traces.covered_lines(), run(&[(file, &[1, 2, 3, 4])]));
+        traces.add_flaky(file, flaky[file].iter().copied());
+        assert_eq!(traces.get_flaky(file), vec![2, 4]);
+        assert_eq!(traces.total_covered(), 4);
+        assert_eq!(traces.total_covered_in_every_run(), 2);
+    }
+
+    #[test]
+    fn unsafe_line_coverage() {
+        let mut t1 = TraceMap::new();
+        let mut t2 = TraceMap::new();
+        let file = Path::new("file.rs");
+        for (line, hits) in [(1, 1), (2, 0), (3, 1), (4, 0)] {
+            let mut trace = Trace::new_stub(line);
+            trace.stats = CoverageStat::Line(hits);
+            t1.add_trace(file, trace);
+        }
+        t1.add_unsafe_lines(file, vec![2, 3]);
+        t2.add_unsafe_lines(file, vec![4, 5]);
+        t1.merge(&t2);
+        assert!(t1.is_unsafe(file, 5));
+        assert!(!t1.is_unsafe(file, 1));
+        let lines = t1
+            .get_unsafe_traces(file)
+            .map(|x| x.line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 3, 4]);
+        assert_eq!(t1.total_unsafe_coverable(), 3);
+        assert_eq!(t1.total_unsafe_covered(), 1);
+
+        t1.retain_files(|_| false);
+        assert!(!t1.is_unsafe(file, 2));
+    }
+
+    #[test]
+    fn not_instrumented_lines() {
+        let mut t1 = TraceMap::new();
+        let mut t2 = TraceMap::new();
+        let file = Path::new("file.rs");
+        t1.add_trace(file, Trace::new_stub(1));
+        t1.add_not_instrumented(file, vec![1, 2, 3]);
+        assert_eq!(t1.get_not_instrumented(file), vec![2, 3]);
+        assert_eq!(t1.total_coverable(), 1);
+
+        // Instrumented in another binary so it's coverable
+        t2.add_trace(file, Trace::new_stub(3));
+        t2.add_not_instrumented(file, vec![1, 4]);
+        t1.merge(&t2);
+        assert_eq!(t1.get_not_instrumented(file), vec![2, 4]);
+        assert_eq!(t1.total_coverable(), 2);
+
+        t1.retain_files(|_| false);
+        assert!(t1.get_not_instrumented(file).is_empty());
+    }
+
     #[test]
     fn merge_address_mismatch_and_dedup() {
         let mut t1 = TraceMap::new();
         let mut t2 = TraceMap::new();
 
-        let mut address = HashSet::new();
+        let mut address = BTreeSet::new();
         address.insert(5);
         let a_trace = Trace {
             line: 1,
@@ -484,16 +1193,18 @@ mod tests {
             length: 0,
             stats: CoverageStat::Line(1),
             fn_name: Some(String::from("f")),
+            symbol: None,
         };
         t1.add_trace(Path::new("file.rs"), a_trace.clone());
         t2.add_trace(
             Path::new("file.rs"),
             Trace {
                 line: 1,
-                address: HashSet::new(),
+                address: BTreeSet::new(),
                 length: 0,
                 stats: CoverageStat::Line(2),
                 fn_name: Some(String::from("f")),
+                symbol: None,
             },
         );
 
@@ -511,7 +1222,7 @@ mod tests {
         let mut t1 = TraceMap::new();
         let mut t2 = TraceMap::new();
 
-        let mut address = HashSet::new();
+        let mut address = BTreeSet::new();
         address.insert(5);
         let a_trace = Trace {
             line: 1,
@@ -519,16 +1230,18 @@ mod tests {
             length: 0,
             stats: CoverageStat::Line(1),
             fn_name: Some(String::from("f1")),
+            symbol: None,
         };
         t1.add_trace(Path::new("file.rs"), a_trace.clone());
         t2.add_trace(
             Path::new("file.rs"),
             Trace {
                 line: 2,
-                address: HashSet::new(),
+                address: BTreeSet::new(),
                 length: 0,
                 stats: CoverageStat::Line(2),
                 fn_name: Some(String::from("f2")),
+                symbol: None,
             },
         );
 
@@ -545,7 +1258,7 @@ mod tests {
         let mut t1 = TraceMap::new();
         let mut t2 = TraceMap::new();
 
-        let mut address = HashSet::new();
+        let mut address = BTreeSet::new();
         address.insert(1);
         t1.add_trace(
             Path::new("file.rs"),
@@ -555,6 +1268,7 @@ mod tests {
                 length: 0,
                 stats: CoverageStat::Line(5),
                 fn_name: Some(String::from("f")),
+                symbol: None,
             },
         );
         t2.add_trace(
@@ -565,6 +1279,7 @@ mod tests {
                 length: 0,
                 stats: CoverageStat::Line(2),
                 fn_name: Some(String::from("f")),
+                symbol: None,
             },
         );
         t1.merge(&t2);
@@ -577,6 +1292,7 @@ mod tests {
                 length: 0,
                 stats: CoverageStat::Line(7),
                 fn_name: Some(String::from("f")),
+                symbol: None,
             })
         );
         // Deduplicating should have no effect.
@@ -590,7 +1306,25 @@ mod tests {
                 length: 0,
                 stats: CoverageStat::Line(7),
                 fn_name: Some(String::from("f")),
+                symbol: None,
             })
         );
     }
+
+    #[test]
+    fn timed_out_executables_merged() {
+        let mut traces = TraceMap::new();
+        traces.add_timed_out(Path::new("target/debug/deps/slow-0123456789abcdef"));
+        let mut other = TraceMap::new();
+        other.add_timed_out(Path::new("target/debug/deps/hangs-0123456789abcdef"));
+        other.add_timed_out(Path::new("target/debug/deps/slow-0123456789abcdef"));
+        traces.merge(&other);
+        assert_eq!(
+            traces.timed_out(),
+            vec![
+                Path::new("target/debug/deps/hangs-0123456789abcdef"),
+                Path::new("target/debug/deps/slow-0123456789abcdef")
+            ]
+        );
+    }
 }
@@ -0,0 +1,136 @@
+use crate::config::Config;
+use crate::errors::RunError;
+use crate::path_utils::{is_coverable_file_path, GitIgnore};
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Time to wait after a change for any other changes, editors often write several files or the
+/// same file multiple times when saving
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How often to check for Ctrl-C while waiting for changes
+const POLL: Duration = Duration::from_millis(200);
+
+/// Set by the Ctrl-C handler in watch mode
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if Ctrl-C has been pressed while in watch mode
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Runs tarpaulin and then reruns it every time a source file or manifest in the project changes
+/// until interrupted with Ctrl-C. Failed runs are reported but don't stop watching
+pub fn watch<F>(configs: &[Config], mut run: F) -> Result<(), RunError>
+where
+    F: FnMut(&[Config]) -> Result<(), RunError>,
+{
+    let config = match configs.first() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+        warn!("Unable to handle Ctrl-C, tests may be left running: {}", e);
+    }
+    let root = config.root();
+    let gitignore = GitIgnore::new(config);
+
+    let watch_error = |e| RunError::IO(io::Error::other(e));
+    let (tx, rx) = channel::<DebounceEventResult>();
+    let mut debouncer = new_debouncer(DEBOUNCE, tx).map_err(watch_error)?;
+    debouncer
+        .watcher()
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+
+    loop {
+        if let Err(e) = run(configs) {
+            if interrupted() {
+                break;
+            }
+            error!("{}", e);
+        }
+        if interrupted() {
+            break;
+        }
+        info!("Watching {} for changes", root.display());
+        loop {
+            match rx.recv_timeout(POLL) {
+                Ok(Ok(events)) => {
+                    let changed = events
+                        .iter()
+                        .find(|e| should_rerun(&e.path, config, &gitignore));
+                    if let Some(changed) = changed {
+                        info!("{} changed, rerunning", changed.path.display());
+                        break;
+                    }
+                }
+                Ok(Err(e)) => warn!("Error watching for changes: {}", e),
+                Err(RecvTimeoutError::Timeout) if interrupted() => {
+                    info!("Stopping watching for changes");
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(RunError::Internal);
+                }
+            }
+        }
+    }
+    info!("Stopping watching for changes");
+    Ok(())
+}
+
+/// Returns true if a change to the path should rerun coverage. This is any rust source file or
+/// manifest in the project which isn't hidden, in the target directory or ignored by git
+fn should_rerun(path: &Path, config: &Config, gitignore: &GitIgnore) -> bool {
+    let relevant = path.extension() == Some(OsStr::new("rs"))
+        || path.file_name() == Some(OsStr::new("Cargo.toml"));
+    relevant
+        && is_coverable_file_path(path, config.root(), config.target_dir())
+        && !config.exclude_path(path)
+        && !gitignore.is_ignored(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rerun_on_source_changes() {
+        let mut config = Config::default();
+        config.set_manifest(
+            env::current_dir()
+                .unwrap()
+                .join("tests/data/simple_project/Cargo.toml"),
+        );
+        let root = config.root();
+        let gitignore = GitIgnore::new(&config);
+
+        assert!(should_rerun(&root.join("src/lib.rs"), &config, &gitignore));
+        assert!(should_rerun(&root.join("Cargo.toml"), &config, &gitignore));
+        assert!(!should_rerun(
+            &root.join("tarpaulin-report.html"),
+            &config,
+            &gitignore
+        ));
+        assert!(!should_rerun(
+            &config.target_dir().join("debug/build.rs"),
+            &config,
+            &gitignore
+        ));
+        assert!(!should_rerun(
+            &root.join(".hidden/lib.rs"),
+            &config,
+            &gitignore
+        ));
+    }
+}
@@ -0,0 +1,11 @@
+use bench_mode::fibonacci;
+use std::env;
+
+fn main() {
+    // Benchmarks are only run when the --bench argument is passed
+    if env::args().any(|x| x == "--bench") {
+        for i in 0..10 {
+            fibonacci(i);
+        }
+    }
+}
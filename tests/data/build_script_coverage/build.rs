@@ -0,0 +1,18 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn greeting() -> &'static str {
+    if env::var("CARGO_FEATURE_LOUD").is_ok() {
+        "HELLO"
+    } else {
+        "hello"
+    }
+}
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let code = format!("pub const GREETING: &str = {:?};", greeting());
+    fs::write(Path::new(&out_dir).join("greeting.rs"), code).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
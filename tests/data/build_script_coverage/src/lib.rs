@@ -0,0 +1,15 @@
+include!(concat!(env!("OUT_DIR"), "/greeting.rs"));
+
+pub fn greet(name: &str) -> String {
+    format!("{} {}", GREETING, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn greets() {
+        assert_eq!(greet("ferris"), "hello ferris");
+    }
+}
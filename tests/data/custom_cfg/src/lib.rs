@@ -0,0 +1,25 @@
+pub fn always() -> u32 {
+    1
+}
+
+#[cfg(my_flag)]
+pub fn flagged() -> u32 {
+    let value = always();
+    value + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn always_one() {
+        assert_eq!(always(), 1);
+    }
+
+    #[test]
+    #[cfg(my_flag)]
+    fn flagged_two() {
+        assert_eq!(flagged(), 2);
+    }
+}
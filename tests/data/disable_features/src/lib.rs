@@ -0,0 +1,18 @@
+pub fn uses_fast() -> bool {
+    cfg!(feature = "fast")
+}
+
+pub fn uses_allocator() -> bool {
+    cfg!(feature = "allocator")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocator_disabled() {
+        assert!(uses_fast());
+        assert!(!uses_allocator());
+    }
+}
@@ -0,0 +1,22 @@
+/// Next number in the collatz sequence, the odd branch is only covered by this doctest
+///
+/// ```
+/// assert_eq!(doctest_branch::collatz(3), 10);
+/// ```
+pub fn collatz(x: u64) -> u64 {
+    if x % 2 == 0 {
+        x / 2
+    } else {
+        3 * x + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even() {
+        assert_eq!(collatz(4), 2);
+    }
+}
@@ -0,0 +1,6 @@
+pub const TABLE: [u32; 3] = [1, 2, 3];
+
+pub fn lookup(index: usize) -> u32 {
+    let value = TABLE[index];
+    value * 2
+}
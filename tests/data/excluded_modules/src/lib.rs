@@ -0,0 +1,26 @@
+pub mod internal {
+    // Part of the module is here and the rest is in src/internal/generated/tables.rs
+    pub mod generated {
+        pub mod tables;
+
+        pub fn checked_lookup(index: usize) -> Option<u32> {
+            if index < tables::TABLE.len() {
+                Some(tables::lookup(index))
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn first() -> u32 {
+        generated::tables::TABLE[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn first_value() {
+        assert_eq!(super::internal::first(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs;
+use std::path::Path;
+
+/// Stands in for code that only runs some of the time, each run of the tests takes the other
+/// branch to the previous one
+pub fn parity(run: u32) -> &'static str {
+    if run % 2 == 0 {
+        "even"
+    } else {
+        "odd"
+    }
+}
+
+pub fn next_run() -> u32 {
+    let counter = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/runs");
+    let run = fs::read_to_string(&counter)
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(0);
+    fs::write(&counter, (run + 1).to_string()).unwrap();
+    run
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alternates() {
+        let run = next_run();
+        assert!(!parity(run).is_empty());
+    }
+}
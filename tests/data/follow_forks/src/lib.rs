@@ -0,0 +1,26 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+pub fn child_work(n: u32) -> u32 {
+    let mut total = 0;
+    for i in 0..n {
+        total += i;
+    }
+    total
+}
+
+#[test]
+fn fork_outlives_test() {
+    // Lets the test running tarpaulin kill the child if it isn't followed
+    let pid_file = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("target/fork-child.pid");
+    match unsafe { libc::fork() } {
+        0 => {
+            // Still running after the test exits
+            sleep(Duration::from_millis(500));
+            let code = if child_work(10) == 45 { 0 } else { 1 };
+            unsafe { libc::_exit(code) }
+        }
+        -1 => unreachable!(),
+        child => std::fs::write(pid_file, child.to_string()).unwrap(),
+    }
+}
@@ -0,0 +1,12 @@
+/*
+ * Code produced by a generator.
+ * @generated
+ */
+
+pub fn mul(a: u32, b: u32) -> u32 {
+    a * b
+}
+
+pub fn div(a: u32, b: u32) -> u32 {
+    a / b
+}
@@ -0,0 +1,21 @@
+pub mod block_comment;
+pub mod line_comment;
+
+pub fn double(x: u32) -> u32 {
+    line_comment::add(x, x)
+}
+
+pub fn triple(x: u32) -> u32 {
+    block_comment::mul(x, 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(double(2), 4);
+        assert_eq!(triple(2), 6);
+    }
+}
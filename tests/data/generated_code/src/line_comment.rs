@@ -0,0 +1,9 @@
+// This file is @generated by a code generator, do not edit it.
+
+pub fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
+
+pub fn sub(a: u32, b: u32) -> u32 {
+    a - b
+}
@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+extern "C" fn stop(_: libc::c_int) {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+fn shutdown() {
+    println!("Shutting down");
+}
+
+fn main() {
+    unsafe {
+        libc::signal(libc::SIGTERM, stop as libc::sighandler_t);
+    }
+    while RUNNING.load(Ordering::SeqCst) {
+        sleep(Duration::from_millis(10));
+    }
+    shutdown();
+}
@@ -0,0 +1,11 @@
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn leaves_server_running() {
+    Command::new(env!("CARGO_BIN_EXE_graceful_shutdown"))
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_millis(200));
+}
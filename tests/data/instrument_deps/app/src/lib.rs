@@ -0,0 +1,8 @@
+pub fn quadruple(x: i32) -> i32 {
+    dep::double(dep::double(x))
+}
+
+#[test]
+fn it_quadruples() {
+    assert_eq!(quadruple(2), 8);
+}
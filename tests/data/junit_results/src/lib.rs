@@ -0,0 +1,22 @@
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails() {
+        assert_eq!(add(2, 2), 5);
+    }
+
+    #[test]
+    fn passes() {
+        assert_eq!(add(2, 2), 4);
+    }
+
+    #[test]
+    #[ignore]
+    fn ignored() {}
+}
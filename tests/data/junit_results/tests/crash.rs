@@ -0,0 +1,5 @@
+#[test]
+fn crashes() {
+    // Exits before the harness prints a result for the test, as a crash would
+    std::process::exit(3);
+}
@@ -0,0 +1,13 @@
+pub fn clamp(x: i32) -> i32 {
+    if x < 0 { return 0; } x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positive_unchanged() {
+        assert_eq!(clamp(4), 4);
+    }
+}
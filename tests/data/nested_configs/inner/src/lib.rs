@@ -0,0 +1,3 @@
+pub fn inner() -> i32 {
+    2
+}
@@ -0,0 +1,3 @@
+pub fn root() -> i32 {
+    1
+}
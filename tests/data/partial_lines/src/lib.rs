@@ -0,0 +1,18 @@
+pub fn doubled(values: &[i32]) -> Vec<i32> {
+    values.iter().map(|x| x * 2).collect()
+}
+
+pub fn total(values: &[i32]) -> i32 {
+    values.iter().sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_doubled() {
+        assert!(doubled(&[]).is_empty());
+        assert_eq!(total(&[1, 2]), 3);
+    }
+}
@@ -0,0 +1,11 @@
+use run_examples::{greet, shout};
+
+fn main() {
+    let loud = std::env::args().any(|x| x == "--loud");
+    let message = greet("world");
+    if loud {
+        println!("{}", shout(&message));
+    } else {
+        println!("{}", message);
+    }
+}
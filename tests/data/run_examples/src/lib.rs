@@ -0,0 +1,7 @@
+pub fn greet(name: &str) -> String {
+    format!("Hello {}", name)
+}
+
+pub fn shout(message: &str) -> String {
+    message.to_uppercase()
+}
@@ -0,0 +1,21 @@
+pub fn setup(x: u32) -> u32 {
+    let y = x * 2;
+    y + 1
+}
+
+pub fn spin() -> ! {
+    loop {
+        std::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hangs() {
+        assert_eq!(setup(2), 5);
+        spin();
+    }
+}
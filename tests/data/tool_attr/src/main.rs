@@ -1,7 +1,27 @@
 #![feature(register_tool)]
 #![register_tool(tarpaulin)]
 
+struct Shim;
+
+impl Shim {
+    #[tarpaulin::skip]
+    fn value(&self) -> u32 {
+        let double = |x| x * 2;
+        double(21)
+    }
+}
+
+#[tarpaulin::skip]
+async fn fetch() -> u32 {
+    fn nested() -> u32 {
+        4
+    }
+    nested()
+}
+
 #[tarpaulin::skip]
 fn main() {
     println!("Hello, world!");
+    let _ = fetch();
+    println!("{}", Shim.value());
 }
@@ -0,0 +1,24 @@
+pub fn read(x: &u32) -> u32 {
+    let ptr = x as *const u32;
+    unsafe {
+        let value = *ptr;
+        value + 1
+    }
+}
+
+pub fn write(x: &mut u32, value: u32) {
+    let ptr = x as *mut u32;
+    unsafe {
+        *ptr = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads() {
+        assert_eq!(read(&1), 2);
+    }
+}
@@ -59,6 +59,34 @@ fn doc_test_coverage() {
     assert_eq!(res.total_covered(), 0);
 }
 
+#[test]
+fn doctests_with_tests() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    config.set_clean(false);
+    config.test_timeout = Duration::from_secs(60);
+    let test_dir = get_test_path("doctest_branch");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut manifest = test_dir;
+    manifest.push("Cargo.toml");
+    config.set_manifest(manifest);
+    config.set_profraw_folder(PathBuf::from("doctests_with_tests_1"));
+
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+
+    assert_eq!(ret, 0);
+    assert!(res.total_covered() > 0);
+    assert!(res.total_covered() < res.total_coverable());
+
+    config.run_doctests = true;
+    config.set_profraw_folder(PathBuf::from("doctests_with_tests_2"));
+
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+
+    assert_eq!(ret, 0);
+    assert_eq!(res.total_covered(), res.total_coverable());
+}
+
 #[test]
 fn doc_test_panics() {
     setup_logging(Color::Never, false, false);
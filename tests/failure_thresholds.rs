@@ -1,11 +1,11 @@
 use crate::utils::get_test_path;
 use cargo_tarpaulin::{
-    config::{Color, Config},
+    config::{Color, Config, OutputFile},
     errors::RunError,
 };
 use cargo_tarpaulin::{run, setup_logging};
 use rusty_fork::rusty_fork_test;
-use std::{env, path::PathBuf};
+use std::{env, fs, path::PathBuf};
 
 rusty_fork_test! {
 
@@ -82,4 +82,32 @@ fn report_coverage_fail() {
     }
 }
 
+#[test]
+fn summary_only_below_threshold() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("simple_project");
+    env::set_current_dir(&test_dir).unwrap();
+    let report_dir = test_dir.join("summary_only");
+    let _ = fs::remove_dir_all(&report_dir);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.fail_under = Some(100.0);
+    config.set_clean(false);
+    config.set_profraw_folder(PathBuf::from("summary_only_below_threshold"));
+    config.output_directory = Some(report_dir.clone());
+    config.generate = vec![
+        OutputFile::Html,
+        OutputFile::Json,
+        OutputFile::Lcov,
+        OutputFile::Xml,
+        OutputFile::Junit,
+    ];
+    config.summary_only = true;
+
+    let result = run(&[config]);
+
+    assert!(matches!(result, Err(RunError::BelowThreshold(..))));
+    assert!(!report_dir.exists());
+}
+
 }
@@ -1,11 +1,15 @@
 use crate::utils::get_test_path;
+use cargo_tarpaulin::traces::CoverageStat;
 use cargo_tarpaulin::{
-    config::{Color, Config, Mode},
+    config::{Color, Config, Mode, OutputFile},
     errors::RunError,
+    statemachine::TIMEOUT_EXIT_CODE,
 };
-use cargo_tarpaulin::{launch_tarpaulin, run, setup_logging};
+use cargo_tarpaulin::{launch_tarpaulin, run, run_coverage, setup_logging};
 use rusty_fork::rusty_fork_test;
 use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
 
 rusty_fork_test! {
 
@@ -30,6 +34,31 @@ fn error_if_build_script_fails() {
     }
 }
 
+#[test]
+fn error_if_lockfile_stale() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("stale_lockfile");
+    env::set_current_dir(&test_dir).unwrap();
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.set_clean(false);
+    config.set_locked(true);
+    config.set_offline(true);
+
+    let result = launch_tarpaulin(&config, &None);
+
+    // The lock file is left alone and the build never starts
+    assert!(
+        matches!(result, Err(RunError::StaleLockfile(ref p)) if p == &test_dir.join("Cargo.lock")),
+        "{:?}",
+        result.map(|x| x.1)
+    );
+    assert!(fs::read_to_string(test_dir.join("Cargo.lock"))
+        .unwrap()
+        .contains("version = \"0.1.0\""));
+    assert!(!test_dir.join("target/debug").exists());
+}
+
 #[test]
 fn error_if_compilation_fails() {
     setup_logging(Color::Never, false, false);
@@ -72,6 +101,72 @@ fn error_if_test_fails() {
     }
 }
 
+#[test]
+fn junit_report_of_test_results() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("junit_results");
+    env::set_current_dir(&test_dir).unwrap();
+    let report_dir = test_dir.join("junit");
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.set_clean(false);
+    config.generate = vec![OutputFile::Junit];
+    config.output_directory = Some(report_dir.clone());
+
+    let result = run(&[config]);
+    assert!(matches!(result, Err(RunError::TestFailed)), "{:?}", result);
+
+    let report = fs::read_to_string(report_dir.join("junit.xml")).unwrap();
+    let _ = fs::remove_dir_all(&report_dir);
+    assert!(report.contains(r#"<testsuite name="junit_results" tests="3" failures="1" skipped="1""#));
+    assert!(report.contains(r#"<testcase name="tests::passes" classname="junit_results"/>"#));
+    assert!(report.contains(r#"<testcase name="tests::ignored" classname="junit_results">"#));
+    let failure = report
+        .split(r#"<testcase name="tests::fails" classname="junit_results">"#)
+        .nth(1)
+        .unwrap();
+    assert!(failure.trim_start().starts_with("<failure message=\"thread &apos;tests::fails&apos;"));
+    assert!(failure.contains("right: 5"));
+
+    // The crash leaves no result for the test so the executable is reported as failed
+    assert!(report.contains(r#"<testsuite name="crash" tests="1" failures="1" skipped="0""#), "{}", report);
+    let crash = report
+        .split(r#"<testcase name="crash" classname="crash""#)
+        .nth(1)
+        .unwrap();
+    assert!(crash.contains("<failure message=\"exit code"), "{}", report);
+    assert!(crash.contains("test crashes ..."), "{}", report);
+}
+
+#[test]
+fn no_fail_fast_reports_coverage() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("workspace_with_fail_tests");
+    env::set_current_dir(&test_dir).unwrap();
+    let report_dir = test_dir.join("no_fail_fast");
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.set_clean(false);
+    config.no_fail_fast = true;
+    config.generate = vec![OutputFile::Json];
+    config.output_directory = Some(report_dir.clone());
+
+    let report = run_coverage(&[config.clone()]).unwrap();
+    let written = report_dir.join("tarpaulin-report.json").exists();
+    let _ = fs::remove_dir_all(&report_dir);
+
+    // bar's test fails and foo's passes, both binaries have run and their coverage is reported
+    assert!(report.tests_failed);
+    assert!(written);
+    for package in ["bar", "foo"] {
+        let lib = test_dir.join(package).join("src/lib.rs");
+        assert!(report.traces.covered_in_path(&lib) > 0, "{} isn't covered", package);
+    }
+    let result = run(&[config]);
+    let _ = fs::remove_dir_all(&report_dir);
+    assert!(matches!(result, Err(RunError::TestFailed)), "{:?}", result);
+}
+
 #[test]
 fn issue_610() {
     setup_logging(Color::Never, false, false);
@@ -93,4 +188,85 @@ fn issue_610() {
     assert!(result.is_err());
 }
 
+#[test]
+fn timeout_keeps_coverage() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("timeout_loop");
+    env::set_current_dir(&test_dir).unwrap();
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.set_clean(false);
+    config.test_timeout = Duration::from_secs(5);
+
+    let start = Instant::now();
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+    assert_eq!(ret, TIMEOUT_EXIT_CODE);
+    assert!(start.elapsed() < Duration::from_secs(60));
+    let timed_out = res.timed_out();
+    assert_eq!(timed_out.len(), 1);
+    assert!(timed_out[0].starts_with(test_dir.join("target")));
+
+    let lib = test_dir.join("src/lib.rs");
+    for line in 2..=3 {
+        let trace = res.get_child_traces(&lib).find(|x| x.line == line);
+        assert!(
+            matches!(trace, Some(t) if t.stats != CoverageStat::Line(0)),
+            "line {} not covered",
+            line
+        );
+    }
+}
+
+}
+
+#[test]
+fn exit_code_below_threshold() {
+    use cargo_tarpaulin::errors::exit_code;
+    use std::process::Command;
+
+    let test_dir = get_test_path("simple_project");
+    let target = env::temp_dir().join(format!("tarpaulin-exit-code-{}", std::process::id()));
+    let status = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cargo-tarpaulin"))
+            .arg("tarpaulin")
+            .arg("--root")
+            .arg(&test_dir)
+            .arg("--target-dir")
+            .arg(&target)
+            .args(["--skip-clean", "--fail-under", "100"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+    assert_eq!(status(&[]), Some(exit_code::BELOW_THRESHOLD));
+    assert_eq!(status(&["--exit-code-on-threshold", "42"]), Some(42));
+    let _ = std::fs::remove_dir_all(target);
+}
+
+#[test]
+fn no_tests_found() {
+    use cargo_tarpaulin::errors::exit_code;
+    use std::process::Command;
+
+    let test_dir = get_test_path("no_tests");
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-tarpaulin"))
+        .arg("tarpaulin")
+        .arg("--root")
+        .arg(&test_dir)
+        .args(["--skip-clean", "--color", "never"])
+        .output()
+        .unwrap();
+    let logs = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.status.code(), Some(exit_code::NO_TESTS), "{}", logs);
+    assert!(
+        logs.contains("No tests found to run; coverage is 0%"),
+        "{}",
+        logs
+    );
 }
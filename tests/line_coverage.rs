@@ -1,7 +1,7 @@
 use crate::utils::get_test_path;
 use cargo_tarpaulin::config::{Color, Config};
 use cargo_tarpaulin::traces::CoverageStat;
-use cargo_tarpaulin::{launch_tarpaulin, setup_logging};
+use cargo_tarpaulin::{launch_tarpaulin, run_coverage, setup_logging};
 use rusty_fork::rusty_fork_test;
 use std::env;
 use std::time::Duration;
@@ -104,4 +104,22 @@ fn test_threads_1() {
     }
 }
 
+#[test]
+fn library_run_coverage() {
+    let mut config = Config::default();
+    config.set_clean(false);
+    config.test_timeout = Duration::from_secs(60);
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("simple_project");
+    env::set_current_dir(&test_dir).unwrap();
+    config.set_manifest(test_dir.join("Cargo.toml"));
+
+    let report = run_coverage(&[config]).unwrap();
+    env::set_current_dir(restore_dir).unwrap();
+    assert!(!report.tests_failed);
+    assert!(report.traces.contains_file(&test_dir.join("src/unused.rs")));
+    assert!(report.coverage_percentage() > 0.0);
+    assert!(report.coverage_percentage() < 1.0);
+}
+
 }
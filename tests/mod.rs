@@ -1,10 +1,12 @@
 use crate::utils::get_test_path;
 use cargo_tarpaulin::config::{
-    Color, Config, ConfigWrapper, Mode, OutputFile, RunType, TraceEngine,
+    Color, Config, ConfigWrapper, Granularity, Mode, OutputFile, RunType, TraceEngine,
 };
 use cargo_tarpaulin::event_log::EventLog;
 use cargo_tarpaulin::path_utils::*;
-use cargo_tarpaulin::traces::TraceMap;
+use cargo_tarpaulin::report::diff::CoverageDiff;
+use cargo_tarpaulin::report::load_results;
+use cargo_tarpaulin::traces::{CoverageStat, TraceMap};
 use cargo_tarpaulin::{launch_tarpaulin, run, setup_logging};
 use clap::{App, Arg};
 #[cfg(windows)]
@@ -143,6 +145,21 @@ pub fn check_percentage_with_config(
     res
 }
 
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if path.is_dir() {
+            if entry.file_name() != "target" {
+                copy_dir(&path, &to.join(entry.file_name()));
+            }
+        } else {
+            fs::copy(&path, to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
 pub fn check_percentage(project_name: &str, minimum_coverage: f64, has_lines: bool) -> TraceMap {
     let mut config = Config::default();
     config.set_include_tests(true);
@@ -276,6 +293,29 @@ fn config_file_coverage() {
     check_percentage_with_cli_args(0.0f64, true, &args);
 }
 
+#[test]
+fn root_outside_current_dir() {
+    let temp = env::temp_dir().join(format!("tarpaulin-root-{}", std::process::id()));
+    let project = temp.join("simple_project");
+    let scratch = temp.join("scratch");
+    copy_dir(&get_test_path("simple_project"), &project);
+    fs::create_dir_all(&scratch).unwrap();
+    let restore_dir = env::current_dir().unwrap();
+    env::set_current_dir(&scratch).unwrap();
+
+    let args = vec![
+        "tarpaulin".to_string(),
+        "--root".to_string(),
+        "../simple_project".to_string(),
+    ];
+    let res = check_percentage_with_cli_args(0.4f64, true, &args);
+    env::set_current_dir(restore_dir).unwrap();
+    let project = project.canonicalize().unwrap();
+    assert!(res.files().iter().all(|x| x.starts_with(&project)));
+    assert!(res.contains_file(&project.join("src/unused.rs")));
+    let _ = fs::remove_dir_all(temp);
+}
+
 #[test]
 fn issue_966_follow_exec() {
     let test_dir = get_test_path("follow_exec_issue966");
@@ -319,6 +359,17 @@ fn benchmark_coverage() {
     check_percentage_with_config(test, 1.0f64, true, config);
 }
 
+#[test]
+fn bench_mode_coverage() {
+    let test = "bench_mode";
+    check_percentage(test, 0.0f64, true);
+
+    let mut config = Config::default();
+    config.command = Mode::Bench;
+    config.set_clean(false);
+    check_percentage_with_config(test, 1.0f64, true, config);
+}
+
 #[test]
 fn cargo_run_coverage() {
     let mut config = Config::default();
@@ -432,6 +483,30 @@ fn follow_exes_down() {
     check_percentage_with_config("follow_exe", 1.0f64, true, config);
 }
 
+#[test]
+fn graceful_shutdown_of_spawned_server() {
+    let mut config = Config::default();
+    config.follow_exec = true;
+    config.set_clean(false);
+    config.graceful_shutdown_signal = Some("SIGTERM".to_string());
+    config.shutdown_timeout = Some(Duration::from_secs(10));
+    check_percentage_with_config("graceful_shutdown", 1.0f64, true, config);
+}
+
+#[test]
+fn graceful_shutdown_of_spawned_server_llvm() {
+    let mut config = Config::default();
+    config.set_engine(TraceEngine::Llvm);
+    config.set_clean(false);
+    // Sharing a target dir with the ptrace test leaves it running the instrumented server, which
+    // writes its profiles to the project directory
+    config.set_target_dir(get_test_path("graceful_shutdown").join("target").join("llvm"));
+    config.set_profraw_folder("graceful_shutdown_llvm".into());
+    config.graceful_shutdown_signal = Some("SIGTERM".to_string());
+    config.shutdown_timeout = Some(Duration::from_secs(10));
+    check_percentage_with_config("graceful_shutdown", 1.0f64, true, config);
+}
+
 #[test]
 fn handle_module_level_exclude_attrs() {
     check_percentage("crate_level_ignores", 1.0f64, true);
@@ -485,6 +560,269 @@ fn dot_rs_in_dir_name() {
     }
 }
 
+#[test]
+fn generated_files_excluded() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("generated_code");
+    env::set_current_dir(&test_dir).unwrap();
+    for exclude_generated in [true, false] {
+        let mut config = Config::default();
+        config.set_clean(false);
+        config.set_manifest(test_dir.join("Cargo.toml"));
+        config.exclude_generated = exclude_generated;
+
+        let (res, _ret) = launch_tarpaulin(&config, &None).unwrap();
+
+        assert!(res.contains_file(&test_dir.join("src/lib.rs")));
+        for generated in ["src/line_comment.rs", "src/block_comment.rs"] {
+            assert_eq!(
+                res.contains_file(&test_dir.join(generated)),
+                !exclude_generated,
+                "{}",
+                generated
+            );
+        }
+    }
+    env::set_current_dir(&restore_dir).unwrap();
+}
+
+#[test]
+fn modules_excluded() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("excluded_modules");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut config = Config::default();
+    config.set_clean(false);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    let (all, _ret) = launch_tarpaulin(&config, &None).unwrap();
+    config.exclude_modules = vec!["crate::internal::generated".to_string()];
+    let (excluded, _ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    let lib = test_dir.join("src/lib.rs");
+    let tables = test_dir.join("src/internal/generated/tables.rs");
+    assert!(all.contains_file(&tables));
+    assert!(all.coverage_percentage() < 1.0);
+    // Only the inline part of the module in lib.rs is left out
+    assert!(!excluded.contains_file(&tables));
+    assert!(excluded.contains_file(&lib));
+    let lines = excluded
+        .get_child_traces(&lib)
+        .map(|x| x.line)
+        .collect::<Vec<_>>();
+    assert!(lines.iter().all(|x| !(3..=13).contains(x)), "{:?}", lines);
+    assert!(lines.contains(&16));
+    assert_eq!(excluded.coverage_percentage(), 1.0);
+}
+
+#[test]
+fn build_scripts_covered() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("build_script_coverage");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut config = Config::default();
+    config.set_engine(TraceEngine::Llvm);
+    config.set_clean(true);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.cover_build_scripts = true;
+
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    assert_eq!(ret, 0);
+    let build_script = test_dir.join("build.rs");
+    assert!(res.covered_in_path(&build_script) > 0);
+    // The loud feature isn't enabled
+    assert!(res.covered_in_path(&build_script) < res.coverable_in_path(&build_script));
+}
+
+#[test]
+fn custom_cfgs_built() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("custom_cfg");
+    env::set_current_dir(&test_dir).unwrap();
+    let lib = test_dir.join("src/lib.rs");
+    let mut covered = vec![];
+    for cfg in [vec![], vec!["my_flag".to_string()]] {
+        let mut config = Config::default();
+        config.set_clean(false);
+        config.set_manifest(test_dir.join("Cargo.toml"));
+        config.cfg = cfg;
+
+        let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+        assert_eq!(ret, 0);
+        let lines = res.covered_lines().remove(&lib).unwrap_or_default();
+        covered.push([6, 7, 8].iter().filter(|x| lines.contains(x)).count());
+    }
+    env::set_current_dir(&restore_dir).unwrap();
+
+    // The flagged function is only compiled and tested with the cfg
+    assert_eq!(covered, vec![0, 3]);
+}
+
+#[test]
+#[cfg(unix)]
+fn forked_children_followed() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("follow_forks");
+    env::set_current_dir(&test_dir).unwrap();
+    let lib = test_dir.join("src/lib.rs");
+    let mut covered = vec![];
+    for follow_forks in [true, false] {
+        let mut config = Config::default();
+        config.set_clean(false);
+        config.set_manifest(test_dir.join("Cargo.toml"));
+        config.follow_forks = follow_forks;
+
+        let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+        if !follow_forks {
+            // Without following it the child can be left behind after the test exits
+            let pid = fs::read_to_string(test_dir.join("target/fork-child.pid")).unwrap();
+            let _ = std::process::Command::new("kill").args(["-9", pid.trim()]).status();
+        }
+        assert_eq!(ret, 0);
+        let lines = res.covered_lines().remove(&lib).unwrap_or_default();
+        covered.push([5, 6, 7, 9].iter().filter(|x| lines.contains(x)).count());
+    }
+    env::set_current_dir(&restore_dir).unwrap();
+
+    // Only the child runs `child_work` and it's still running when the test exits
+    assert_eq!(covered, vec![4, 0]);
+}
+
+#[test]
+fn examples_ran() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("run_examples");
+    env::set_current_dir(&test_dir).unwrap();
+    let lib = test_dir.join("src/lib.rs");
+    let example = test_dir.join("examples/greeter.rs");
+    for loud in [false, true] {
+        let mut config = Config::default();
+        config.set_clean(false);
+        config.set_manifest(test_dir.join("Cargo.toml"));
+        config.run_examples.insert("greeter".to_string());
+        if loud {
+            config
+                .example_args
+                .insert("greeter".to_string(), vec!["--loud".to_string()]);
+        }
+        config.set_include_tests(loud);
+
+        let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+        assert_eq!(ret, 0);
+        let lines = res.covered_lines().remove(&lib).unwrap_or_default();
+        assert!(lines.contains(&2));
+        assert_eq!(lines.contains(&6), loud);
+        assert_eq!(res.contains_file(&example), loud);
+    }
+    env::set_current_dir(&restore_dir).unwrap();
+}
+
+#[test]
+fn unsafe_lines_reported() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("unsafe_lines");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut config = Config::default();
+    config.set_clean(false);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.report_unsafe = true;
+
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    assert_eq!(ret, 0);
+    let lib = test_dir.join("src/lib.rs");
+    assert!(res.is_unsafe(&lib, 4));
+    assert!(!res.is_unsafe(&lib, 2));
+    let uncovered = res
+        .get_unsafe_traces(&lib)
+        .filter(|x| x.stats == CoverageStat::Line(0))
+        .map(|x| x.line)
+        .collect::<Vec<_>>();
+    assert_eq!(uncovered, vec![12]);
+    assert!(res.total_unsafe_covered() > 0);
+    assert_eq!(
+        res.total_unsafe_covered() + 1,
+        res.total_unsafe_coverable()
+    );
+}
+
+#[test]
+fn repeated_runs_find_flaky_lines() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("flaky_paths");
+    env::set_current_dir(&test_dir).unwrap();
+    let _ = fs::remove_file(test_dir.join("target/runs"));
+    let mut config = Config::default();
+    config.set_clean(false);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    config.run_count = 2;
+
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    assert_eq!(ret, 0);
+    let lib = test_dir.join("src/lib.rs");
+    // Each run takes one of the branches so both are covered but neither in every run
+    assert_eq!(res.get_flaky(&lib), vec![8, 10]);
+    let covered = &res.covered_lines()[&lib];
+    assert!(covered.contains(&8) && covered.contains(&10));
+    assert_eq!(
+        res.total_covered_in_every_run() + 2,
+        res.covered_in_path(&lib)
+    );
+}
+
+#[test]
+fn closure_lines_partially_covered() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("partial_lines");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut config = Config::default();
+    config.set_engine(TraceEngine::Llvm);
+    config.set_clean(false);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+
+    let (res, _ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    // The closure passed to map never runs
+    let lib = test_dir.join("src/lib.rs");
+    assert_eq!(res.get_partial(&lib), vec![2]);
+    assert_eq!(res.covered_in_path(&lib), res.coverable_in_path(&lib));
+}
+
+#[test]
+fn multi_statement_line_granularity() {
+    let restore_dir = env::current_dir().unwrap();
+    let test_dir = get_test_path("multi_statement_line");
+    env::set_current_dir(&test_dir).unwrap();
+    let mut config = Config::default();
+    config.set_engine(TraceEngine::Llvm);
+    config.set_clean(false);
+    config.set_manifest(test_dir.join("Cargo.toml"));
+
+    let (line, _ret) = launch_tarpaulin(&config, &None).unwrap();
+    config.granularity = Granularity::Statement;
+    let (statement, _ret) = launch_tarpaulin(&config, &None).unwrap();
+    env::set_current_dir(&restore_dir).unwrap();
+
+    // The early return on the second line never runs
+    let lib = test_dir.join("src/lib.rs");
+    assert_eq!(line.get_partial(&lib), vec![2]);
+    assert_eq!(line.covered_in_path(&lib), line.coverable_in_path(&lib));
+    assert_eq!(
+        statement.covered_in_path(&lib) + 1,
+        statement.coverable_in_path(&lib)
+    );
+    let missed = line.get_missed_columns(&lib);
+    assert!(missed.iter().all(|(x, _, _)| *x == 2), "{:?}", missed);
+    // `{ return 0; }` starts at column 14
+    assert!(missed.iter().any(|(_, start, _)| *start >= 14), "{:?}", missed);
+}
+
 #[test]
 #[cfg(unix)]
 #[cfg(not(tarpaulin))]
@@ -673,4 +1011,143 @@ fn warning_flags_in_config() {
     check_percentage("config_warnings", 1.0f64, true);
 }
 
+#[test]
+fn reports_are_deterministic() {
+    // The reports record when they were made
+    env::set_var("SOURCE_DATE_EPOCH", "1704164645");
+    let test_dir = get_test_path("simple_project");
+    let mut reports = vec![];
+    for run in ["a", "b"] {
+        let report_dir = test_dir.join("reports").join(run);
+        let _ = fs::remove_dir_all(&report_dir);
+        fs::create_dir_all(&report_dir).unwrap();
+        let mut config = Config::default();
+        config.set_include_tests(true);
+        config.generate = vec![OutputFile::Json, OutputFile::Lcov, OutputFile::Xml];
+        config.output_directory = Some(report_dir.clone());
+        run_config("simple_project", config);
+        reports.push((
+            fs::read(report_dir.join("tarpaulin-report.json")).unwrap(),
+            fs::read(report_dir.join("lcov.info")).unwrap(),
+            fs::read(report_dir.join("cobertura.xml")).unwrap(),
+        ));
+    }
+    let _ = fs::remove_dir_all(test_dir.join("reports"));
+    assert!(reports[0] == reports[1], "reports differ between runs");
+}
+
+#[test]
+#[cfg(unix)]
+fn stdout_json_is_alone_on_stdout() {
+    use cargo_tarpaulin::report::json::CombinedReport;
+    use nix::unistd::{dup, dup2};
+    use std::os::unix::io::AsRawFd;
+    let output = get_test_path("simple_project").join("stdout.json");
+    let file = fs::File::create(&output).unwrap();
+    // Stdout is written to the file while tarpaulin runs, the test's own output included
+    let stdout = dup(1).unwrap();
+    dup2(file.as_raw_fd(), 1).unwrap();
+    let mut config = Config::default();
+    config.set_include_tests(true);
+    config.generate = vec![OutputFile::StdoutJson];
+    run_config("simple_project", config);
+    dup2(stdout, 1).unwrap();
+
+    let printed = fs::read_to_string(&output).unwrap();
+    let _ = fs::remove_file(&output);
+    let lines = printed.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1, "{printed}");
+    let report = serde_json::from_str::<CombinedReport>(lines[0]).unwrap();
+    assert!(report.covered > 0);
+}
+
+#[test]
+fn merge_input_files_without_running() {
+    let test_dir = get_test_path("simple_project");
+    let report_dir = test_dir.join("merged_reports");
+    let _ = fs::remove_dir_all(&report_dir);
+    fs::create_dir_all(report_dir.join("run")).unwrap();
+    let mut config = Config::default();
+    config.generate = vec![OutputFile::Lcov];
+    config.output_directory = Some(report_dir.join("run"));
+    run_config("simple_project", config);
+    let saved = report_dir.join("run.json");
+    fs::copy(
+        test_dir.join("target/tarpaulin/simple_project-coverage.json"),
+        &saved,
+    )
+    .unwrap();
+
+    fs::create_dir_all(report_dir.join("merged")).unwrap();
+    let mut config = Config::default();
+    config.no_run = true;
+    config.input_files = vec![saved];
+    config.generate = vec![OutputFile::Lcov];
+    config.output_directory = Some(report_dir.join("merged"));
+    run_config("simple_project", config);
+    let run = fs::read(report_dir.join("run/lcov.info")).unwrap();
+    let merged = fs::read(report_dir.join("merged/lcov.info")).unwrap();
+    let _ = fs::remove_dir_all(&report_dir);
+    assert!(run == merged, "merged report differs from the run's");
+}
+
+#[test]
+fn diff_of_json_reports() {
+    let test_dir = get_test_path("simple_project");
+    let report_dir = test_dir.join("diff_reports");
+    let _ = fs::remove_dir_all(&report_dir);
+    fs::create_dir_all(&report_dir).unwrap();
+    let mut config = Config::default();
+    config.generate = vec![OutputFile::Json];
+    config.output_directory = Some(report_dir.clone());
+    run_config("simple_project", config);
+
+    let report = load_results(&report_dir.join("tarpaulin-report.json"), &test_dir).unwrap();
+    let saved = test_dir.join("target/tarpaulin/simple_project-coverage.json");
+    let saved = load_results(&saved, &test_dir);
+    let _ = fs::remove_dir_all(&report_dir);
+    let saved = saved.unwrap();
+    assert!(report.total_coverable() > 0);
+    let diff = CoverageDiff::new(&saved, &report);
+    assert!(!diff.files.is_empty());
+    assert!(diff.files.iter().all(|x| x.old == x.new), "{:?}", diff.files);
+    assert!(diff.newly_uncovered.is_empty());
+    assert_eq!(diff.delta(), 0.0);
+}
+
+#[test]
+fn report_paths_relative() {
+    let test_dir = get_test_path("simple_project");
+    let report_dir = test_dir.join("portable_reports");
+    for prefix in [None, Some("crates/simple")] {
+        let _ = fs::remove_dir_all(&report_dir);
+        fs::create_dir_all(&report_dir).unwrap();
+        let mut config = Config::default();
+        config.generate = vec![
+            OutputFile::Json,
+            OutputFile::Lcov,
+            OutputFile::Html,
+            OutputFile::Xml,
+        ];
+        config.output_directory = Some(report_dir.clone());
+        config.path_prefix = prefix.map(Into::into);
+        run_config("simple_project", config);
+
+        let root = test_dir.display().to_string();
+        for report in [
+            "tarpaulin-report.json",
+            "lcov.info",
+            "tarpaulin-report.html",
+            "cobertura.xml",
+        ] {
+            let contents = fs::read_to_string(report_dir.join(report)).unwrap();
+            assert!(!contents.contains(&root), "{} has absolute paths", report);
+        }
+        let lcov = fs::read_to_string(report_dir.join("lcov.info")).unwrap();
+        let source = Path::new(prefix.unwrap_or_default()).join("src/lib.rs");
+        assert!(lcov.contains(&format!("SF:{}", source.display())));
+    }
+    let _ = fs::remove_dir_all(&report_dir);
+}
+
 }
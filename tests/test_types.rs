@@ -1,10 +1,10 @@
 use crate::utils::get_test_path;
-use cargo_tarpaulin::config::{types::RunType, Color, Config};
+use cargo_tarpaulin::config::{types::RunType, Color, Config, TraceEngine};
 use cargo_tarpaulin::{launch_tarpaulin, setup_logging};
 use rusty_fork::rusty_fork_test;
-use std::env;
 use std::path::PathBuf;
 use std::time::Duration;
+use std::{env, fs};
 
 rusty_fork_test! {
 
@@ -207,4 +207,51 @@ fn only_doctest_coverage() {
     }
 }
 
+
+#[test]
+fn no_run_then_input_binaries() {
+    setup_logging(Color::Never, false, false);
+    let mut config = Config::default();
+    let test_dir = get_test_path("simple_project");
+    env::set_current_dir(&test_dir).unwrap();
+    config.set_manifest(test_dir.join("Cargo.toml"));
+    let target = env::temp_dir().join(format!("tarpaulin-no-run-{}", std::process::id()));
+    config.set_target_dir(target.clone());
+    config.set_clean(false);
+    config.no_run = true;
+
+    let (res, _) = launch_tarpaulin(&config, &None).unwrap();
+    assert!(res.is_empty());
+
+    // Nothing is built in the second phase, only the saved binaries are run
+    let saved = fs::read_to_string(target.join("tarpaulin/test-binaries.json")).unwrap();
+    let saved: serde_json::Value = serde_json::from_str(&saved).unwrap();
+    let binaries = saved["output"]["test_binaries"].as_array().unwrap();
+    assert_eq!(binaries.len(), 1);
+    let list = fs::read_to_string(target.join("tarpaulin/test-binaries.txt")).unwrap();
+    assert_eq!(list, format!("{}\n", binaries[0]["path"].as_str().unwrap()));
+    config.no_run = false;
+    config.input_binaries = vec![PathBuf::from(binaries[0]["path"].as_str().unwrap())];
+    let (res, ret) = launch_tarpaulin(&config, &None).unwrap();
+    assert_eq!(ret, 0);
+    assert!(res.covered_in_path(&test_dir.join("src/lib.rs")) > 0);
+
+    config.release = true;
+    assert!(launch_tarpaulin(&config, &None).is_err());
+
+    // Without the saved build the binary is still checked for what the engine needs
+    if config.engine() == TraceEngine::Ptrace {
+        config.release = false;
+        config.set_engine(TraceEngine::Llvm);
+        config.set_target_dir(target.join("elsewhere"));
+        let err = launch_tarpaulin(&config, &None).unwrap_err();
+        assert!(
+            err.to_string().contains("isn't instrumented for the llvm engine"),
+            "{}",
+            err
+        );
+    }
+    let _ = fs::remove_dir_all(target);
+}
+
 }
@@ -94,3 +94,73 @@ fn config_relative_pathing() {
 }
 
 }
+
+#[test]
+#[cfg(unix)]
+fn rustc_wrapper_instruments_selected_packages() {
+    use std::process::Command;
+
+    let wrapped = |package: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_cargo-tarpaulin"))
+            .args(["echo", "--crate-name", "lib"])
+            .env("TARPAULIN_INSTRUMENT_PACKAGES", "foo,bar")
+            .env("TARPAULIN_INSTRUMENT_FLAGS", "-Cinstrument-coverage")
+            .env("CARGO_PKG_NAME", package)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(wrapped("foo"), "--crate-name lib -Cinstrument-coverage\n");
+    assert_eq!(wrapped("bar"), "--crate-name lib -Cinstrument-coverage\n");
+    assert_eq!(wrapped("baz"), "--crate-name lib\n");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn dependencies_not_instrumented() {
+    use std::fs;
+    use std::process::Command;
+
+    let test_dir = get_test_path("instrument_deps");
+    let target = env::temp_dir().join(format!("tarpaulin-instrument-deps-{}", std::process::id()));
+    // Whether the app's test executable and the path dependency's rlib have coverage mappings
+    let instrumented = |args: &[&str]| {
+        let _ = fs::remove_dir_all(&target);
+        let status = Command::new(env!("CARGO_BIN_EXE_cargo-tarpaulin"))
+            .arg("tarpaulin")
+            .arg("--root")
+            .arg(&test_dir)
+            .arg("--target-dir")
+            .arg(&target)
+            .args(["--engine", "llvm", "--no-run"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // The test executable has no extension, the dependency's `.rmeta` never has mappings
+        [("app-", None), ("libdep-", Some("rlib"))]
+            .iter()
+            .map(|(prefix, extension)| {
+                let artifact = fs::read_dir(target.join("debug/deps"))
+                    .unwrap()
+                    .filter_map(Result::ok)
+                    .map(|x| x.path())
+                    .find(|x| {
+                        let name = x.file_name().unwrap().to_string_lossy();
+                        name.starts_with(prefix)
+                            && x.extension().and_then(|x| x.to_str()) == *extension
+                    })
+                    .unwrap();
+                let contents = fs::read(artifact).unwrap();
+                contents.windows(13).any(|x| x == b"__llvm_covfun")
+            })
+            .collect::<Vec<_>>()
+    };
+    let only_members = instrumented(&[]);
+    let everything = instrumented(&["--instrument-deps"]);
+    let _ = fs::remove_dir_all(&target);
+
+    assert_eq!(only_members, vec![true, false]);
+    assert_eq!(everything, vec![true, true]);
+}